@@ -0,0 +1,113 @@
+//! Renders Prometheus alerting rules matching the metrics exposed by the backend
+//!
+//! Rules are rendered as a `PrometheusRule` (see https://github.com/prometheus-operator/prometheus-operator)
+//! so that they are always in sync with metric names.
+use crate::{
+    error::{Error, Result},
+    metrics::Metrics,
+};
+use serde_json::{json, Value};
+use std::env;
+
+pub const PROMETHEUS_RULE_NAME: &str = "playground-alerts";
+
+/// Thresholds triggering alerts
+#[derive(Debug, Clone)]
+pub struct AlertThresholds {
+    /// Ratio of failed deployments over 15 minutes
+    pub deploy_failure_ratio: f64,
+    /// 90th percentile of deployment duration, in seconds
+    pub deploy_duration_seconds: f64,
+    /// Number of failed undeployments over 15 minutes
+    pub undeploy_failures: u64,
+}
+
+impl Default for AlertThresholds {
+    fn default() -> Self {
+        AlertThresholds {
+            deploy_failure_ratio: 0.1,
+            deploy_duration_seconds: 120.0,
+            undeploy_failures: 5,
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &'static str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| Error::InvalidParameter(format!("{} can't be parsed", name))),
+        Err(_) => Ok(default),
+    }
+}
+
+impl AlertThresholds {
+    /// Reads thresholds from `ALERT_*` env variables, falling back to defaults
+    pub fn from_env() -> Result<Self> {
+        let defaults = AlertThresholds::default();
+        Ok(AlertThresholds {
+            deploy_failure_ratio: env_or(
+                "ALERT_DEPLOY_FAILURE_RATIO",
+                defaults.deploy_failure_ratio,
+            )?,
+            deploy_duration_seconds: env_or(
+                "ALERT_DEPLOY_DURATION_SECONDS",
+                defaults.deploy_duration_seconds,
+            )?,
+            undeploy_failures: env_or("ALERT_UNDEPLOY_FAILURES", defaults.undeploy_failures)?,
+        })
+    }
+}
+
+fn alert(name: &str, expr: String, summary: &str) -> Value {
+    json!({
+        "alert": name,
+        "expr": expr,
+        "for": "5m",
+        "labels": {
+            "severity": "warning",
+        },
+        "annotations": {
+            "summary": summary,
+        },
+    })
+}
+
+/// Renders the `spec` of the `PrometheusRule`
+pub fn prometheus_rule_spec(thresholds: &AlertThresholds) -> Value {
+    let deploy_counter = Metrics::qualified_name(Metrics::DEPLOY_COUNTER);
+    let deploy_failures_counter = Metrics::qualified_name(Metrics::DEPLOY_FAILURES_COUNTER);
+    let undeploy_failures_counter = Metrics::qualified_name(Metrics::UNDEPLOY_FAILURES_COUNTER);
+    let deploy_duration = Metrics::qualified_name(Metrics::DEPLOY_DURATION);
+    json!({
+        "groups": [{
+            "name": "playground",
+            "rules": [
+                alert(
+                    "PlaygroundDeployFailureRateHigh",
+                    format!(
+                        "sum(rate({}[15m])) / sum(rate({}[15m])) > {}",
+                        deploy_failures_counter, deploy_counter, thresholds.deploy_failure_ratio
+                    ),
+                    "Too many session deployments are failing",
+                ),
+                alert(
+                    "PlaygroundDeployDurationHigh",
+                    format!(
+                        "histogram_quantile(0.9, sum(rate({}_bucket[15m])) by (le)) > {}",
+                        deploy_duration, thresholds.deploy_duration_seconds
+                    ),
+                    "Sessions take too long to be deployed",
+                ),
+                alert(
+                    "PlaygroundUndeployFailures",
+                    format!(
+                        "sum(increase({}[15m])) > {}",
+                        undeploy_failures_counter, thresholds.undeploy_failures
+                    ),
+                    "Sessions can't be undeployed, expired sessions might accumulate",
+                ),
+            ],
+        }],
+    })
+}
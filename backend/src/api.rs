@@ -1,20 +1,32 @@
 //! HTTP endpoints exposed in /api context
 use crate::{
-    error::Result,
+    auth::Provider,
+    csrf,
+    error::{Error, Result},
     github::{current_user, orgs, GitHubUser},
+    graphql,
+    idempotency::Idempotency,
     kubernetes::Environment,
+    manager::users_from_csv,
+    oidc::{self, OidcUser, Role},
+    ratelimit::{self, limit, RateLimit, RetryAfter},
+    session_auth,
     types::{
-        LoggedUser, SessionConfiguration, SessionUpdateConfiguration, UserConfiguration,
-        UserUpdateConfiguration,
+        Canary, Entry, FaucetRequest, Identity, LoggedUser, OnboardingTransition, Org, Port,
+        Reservation, SessionBatch, SessionConfiguration, SessionEnvUpdate,
+        SessionUpdateConfiguration, TemplatePublication, TemplateQuery, UserConfiguration,
+        UserPreferencesUpdate, UserUpdateConfiguration,
     },
     Context,
 };
+use juniper::{FieldError, Value};
+use juniper_rocket::GraphQLResponse;
 use request::FormItems;
-use rocket::response::{content, status, Redirect};
+use rocket::response::{content, status, Redirect, Response};
 use rocket::{
     catch, delete, get,
     http::{Cookie, Cookies, SameSite, Status},
-    patch, put, Outcome, State,
+    patch, post, put, Data, Outcome, State,
 };
 use rocket::{
     http::uri::Origin,
@@ -26,30 +38,124 @@ use rocket_contrib::{
 };
 use rocket_oauth2::{OAuth2, TokenResponse};
 use serde::Serialize;
+use std::{error::Error as StdError, io::Read};
 use tokio::runtime::Runtime;
 
 const COOKIE_TOKEN: &str = "token";
+/// GitHub caps webhook payloads at 25MB, larger ones are not relevant here
+const GITHUB_PAYLOAD_LIMIT: u64 = 5 * 1024 * 1024;
+/// Artifacts of a workshop are stored in a ConfigMap, limited to 1MiB overall, see `Engine::publish_artifact`
+const ARTIFACT_LIMIT: u64 = 1024 * 1024;
+const TEMPLATE_LIMIT: u64 = 64 * 1024;
+/// Archives hold a few ConfigMaps, each limited to 1MiB
+const ARCHIVE_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Headers of a GitHub webhook delivery
+pub struct GitHubDelivery {
+    event: String,
+    signature: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for GitHubDelivery {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<GitHubDelivery, String> {
+        let headers = request.headers();
+        match headers.get_one("X-GitHub-Event") {
+            Some(event) => Outcome::Success(GitHubDelivery {
+                event: event.to_string(),
+                signature: headers.get_one("X-Hub-Signature-256").map(str::to_string),
+            }),
+            None => Outcome::Failure((Status::BadRequest, "Missing event".to_string())),
+        }
+    }
+}
+
+/// Headers set by nginx when delegating authentication of a request
+pub struct OriginalRequest {
+    url: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for OriginalRequest {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<OriginalRequest, String> {
+        Outcome::Success(OriginalRequest {
+            url: request
+                .headers()
+                .get_one("X-Original-URL")
+                .map(str::to_string),
+        })
+    }
+}
 
 // Extract a User from cookies
 impl<'a, 'r> FromRequest<'a, 'r> for LoggedUser {
     type Error = String;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<LoggedUser, String> {
-        let engine = &request
+        let state = request
             .guard::<State<Context>>()
-            .map_failure(|_f| (Status::BadRequest, "Can't access state".to_string()))?
-            .manager
-            .engine;
+            .map_failure(|_f| (Status::BadRequest, "Can't access state".to_string()))?;
+        let engine = &state.manager.engine;
         let mut cookies = request.cookies();
-        if let Some(token) = cookies.get_private(COOKIE_TOKEN) {
-            let token_value = token.value();
+        if let Some(cookie) = cookies.get_private(COOKIE_TOKEN) {
+            let key = cookie.value().to_string();
+            let auth_session = match state.manager.auth_sessions.get(&key) {
+                Some(auth_session) => auth_session,
+                None => {
+                    // Unknown, revoked or expired session
+                    clear(cookies);
+                    return Outcome::Forward(());
+                }
+            };
+            // Checked before reaching the identity provider. Until the token resolves to a user, calls are keyed by IP.
+            let limit_key = auth_session
+                .user_id
+                .clone()
+                .unwrap_or_else(|| ratelimit::client_key(request));
+            limit(request, &limit_key)?;
+
+            // Cookie based authentication is subject to CSRF
+            let csrf_token = cookies.get(csrf::COOKIE_CSRF).map(|c| c.value());
+            if let Err(err) = csrf::verify(request, &state.origins, csrf_token) {
+                return Outcome::Failure((Status::Forbidden, err));
+            }
+
+            let token_value = auth_session.token.as_str();
             let runtime = Runtime::new().map_err(|_| {
                 (
                     Status::ExpectationFailed,
                     "Failed to execute async fn".to_string(),
                 )
             })?;
-            let gh_user = runtime.block_on(current_user(token_value)).map_err(|err| {
+            // Organizations are either GitHub ones or groups provided by the OIDC issuer, kept apart
+            let identity: std::result::Result<_, Box<dyn StdError>> = match auth_session.provider {
+                Provider::GitHub => runtime.block_on(async {
+                    let gh_user = current_user(token_value).await?;
+                    let organizations: Vec<String> = orgs(token_value, &gh_user)
+                        .await
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|org| org.login)
+                        .collect();
+                    Ok((gh_user.login, organizations, None))
+                }),
+                Provider::Oidc => match &engine.configuration.oidc {
+                    Some(oidc) => runtime
+                        .block_on(oidc.user_info(token_value))
+                        .map(|identity| {
+                            let role = oidc.role(&identity.groups);
+                            (
+                                identity.subject,
+                                oidc::organizations(&identity.groups),
+                                role,
+                            )
+                        }),
+                    None => Err("OIDC is not configured".into()),
+                },
+            };
+            let (id, organizations, role) = identity.map_err(|err| {
                 // A token is present, but can't be used to access user details
                 clear(cookies);
                 log::warn!("Error while accessing user details: {}", err);
@@ -58,30 +164,64 @@ impl<'a, 'r> FromRequest<'a, 'r> for LoggedUser {
                     format!("Can't access user details {}", err),
                 )
             })?;
-            let id = gh_user.clone().login;
             let users = runtime.block_on(engine.clone().list_users()).map_err(|_| {
                 (
                     Status::FailedDependency,
                     "Missing users ConfigMap".to_string(),
                 )
             })?;
-            let organizations = runtime
-                .block_on(orgs(token_value, &gh_user))
-                .unwrap_or_default()
+            // Identities linked to a user resolve to that user
+            let linked = Identity {
+                provider: auth_session.provider,
+                subject: id,
+            };
+            let id = users
                 .iter()
-                .map(|org| org.clone().login)
+                .find(|(_, user)| user.identities.contains(&linked))
+                .map_or_else(
+                    || match linked.provider {
+                        Provider::GitHub => linked.subject.clone(),
+                        Provider::Oidc => oidc::user_id(&linked.subject),
+                    },
+                    |(id, _)| id.clone(),
+                );
+            let configured_orgs = runtime
+                .block_on(engine.list_orgs())
+                .map_err(|err| log::warn!("Failed to list organizations: {}", err))
+                .unwrap_or_default();
+            // Settings of organizations this user is a member of complement user level ones
+            let member_orgs: Vec<&Org> = organizations
+                .iter()
+                .filter_map(|org| configured_orgs.get(org))
                 .collect();
             let user = users.get(&id);
+            if user.map_or(false, |user| user.deleted_at.is_some()) {
+                return Outcome::Failure((Status::Forbidden, "User is deleted".to_string()));
+            }
             // If at least one non-admin user is defined, then users are only allowed if whitelisted
-            let filtered = users.values().any(|user| !user.admin);
-            if !filtered || user.is_some() {
+            // either directly, via a configured organization or via an OIDC role
+            // Implicit entries only record the state of users allowed otherwise
+            let filtered = users.values().any(|user| !user.admin && !user.implicit);
+            let whitelisted = user.map_or(false, |user| !user.implicit);
+            if !filtered || whitelisted || !member_orgs.is_empty() || role.is_some() {
+                state.manager.auth_sessions.bind(&key, &id);
                 Outcome::Success(LoggedUser {
                     id: id.clone(),
-                    admin: user.map_or(false, |user| user.admin),
-                    pool_affinity: user.and_then(|user| user.pool_affinity.clone()),
-                    can_customize_duration: user.map_or(false, |user| user.can_customize_duration),
+                    admin: user.map_or(false, |user| user.admin) || role == Some(Role::Admin),
+                    pool_affinity: user
+                        .and_then(|user| user.pool_affinity.clone())
+                        .or_else(|| member_orgs.iter().find_map(|org| org.pool_affinity.clone())),
+                    can_customize_duration: user.map_or(false, |user| user.can_customize_duration)
+                        || member_orgs.iter().any(|org| org.can_customize_duration),
                     can_customize_pool_affinity: user
-                        .map_or(false, |user| user.can_customize_pool_affinity),
+                        .map_or(false, |user| user.can_customize_pool_affinity)
+                        || member_orgs
+                            .iter()
+                            .any(|org| org.can_customize_pool_affinity),
+                    onboarding: user.map(|user| user.onboarding).unwrap_or_default(),
+                    accepted_terms_version: user
+                        .and_then(|user| user.accepted_terms_version.clone()),
+                    org_role: member_orgs.iter().find_map(|org| org.role.clone()),
                     organizations,
                 })
             } else {
@@ -101,16 +241,152 @@ fn result_to_jsonrpc<T: Serialize>(res: Result<T>) -> JsonValue {
     }
 }
 
+/// Like `result_to_jsonrpc`, with non fatal `warnings` returned alongside the result
+fn result_with_warnings_to_jsonrpc<T: Serialize>(res: Result<(T, Vec<String>)>) -> JsonValue {
+    match res {
+        Ok((val, warnings)) => json!({ "result": val, "warnings": warnings }),
+        Err(err) => json!({ "error": err.to_string() }),
+    }
+}
+
+/// The response of a mutation, or a conflict with a request running with the same `Idempotency-Key`
+type IdempotentResponse = std::result::Result<JsonValue, status::Conflict<JsonValue>>;
+
+/// Runs `f` with `body` at most once per `Idempotency-Key`, see `idempotency`
+fn idempotent<B: Serialize>(
+    state: &Context,
+    user: &LoggedUser,
+    idempotency: Idempotency,
+    body: B,
+    f: impl FnOnce(B) -> JsonValue,
+) -> IdempotentResponse {
+    match state.idempotency.run(&user.id, &idempotency, body, f) {
+        Ok(response) => Ok(response),
+        Err(err @ Error::Conflict(_)) => {
+            Err(status::Conflict(Some(json!({ "error": err.to_string() }))))
+        }
+        Err(err) => Ok(json!({ "error": err.to_string() })),
+    }
+}
+
 #[get("/")]
 pub fn get(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
     result_to_jsonrpc(state.manager.clone().get(user))
 }
 
 #[get("/", rank = 2)]
-pub fn get_unlogged(state: State<'_, Context>) -> JsonValue {
+pub fn get_unlogged(state: State<'_, Context>, _limit: RateLimit) -> JsonValue {
     result_to_jsonrpc(state.manager.get_unlogged())
 }
 
+// Templates
+
+/// Public, so that callers aren't resolved to a user. `strict` is only allowed to admins, see `list_templates_strict`.
+#[get("/templates?<q>&<tag>&<sort>&<page>&<per_page>&<strict>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+pub fn list_templates(
+    state: State<'_, Context>,
+    _limit: RateLimit,
+    q: Option<String>,
+    tag: Option<String>,
+    sort: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    strict: Option<bool>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_templates(
+        None,
+        TemplateQuery {
+            q,
+            tag,
+            sort,
+            page,
+            per_page,
+        },
+        strict.unwrap_or(false),
+    ))
+}
+
+/// With `strict`, restricted to admins, fails if some templates are invalid instead of ignoring them
+#[get("/templates?<q>&<tag>&<sort>&<page>&<per_page>&<strict>")]
+#[allow(clippy::too_many_arguments)]
+pub fn list_templates_strict(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    q: Option<String>,
+    tag: Option<String>,
+    sort: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    strict: bool,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_templates(
+        Some(&user),
+        TemplateQuery {
+            q,
+            tag,
+            sort,
+            page,
+            per_page,
+        },
+        strict,
+    ))
+}
+
+/// Validates a YAML or JSON template before it is submitted, e.g. from CI. `id` is reported in errors.
+#[post("/validate/template?<id>", data = "<data>")]
+pub fn validate_template(
+    state: State<'_, Context>,
+    _limit: RateLimit,
+    user: Option<LoggedUser>,
+    id: Option<String>,
+    data: Data,
+) -> JsonValue {
+    let mut value = String::new();
+    if let Err(err) = data.open().take(TEMPLATE_LIMIT).read_to_string(&mut value) {
+        return json!({ "error": err.to_string() });
+    }
+    result_to_jsonrpc(state.manager.validate_template(
+        user.as_ref(),
+        id.as_deref().unwrap_or("template"),
+        &value,
+    ))
+}
+
+#[put("/admin/templates/<id>/canary", data = "<canary>")]
+pub fn set_template_canary(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+    canary: Json<Canary>,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, canary.0, |canary| {
+        result_to_jsonrpc(state.manager.set_template_canary(&user, &id, canary))
+    })
+}
+
+#[post("/admin/templates/<id>/canary/promote")]
+pub fn promote_template_canary(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.promote_template_canary(&user, &id))
+}
+
+#[delete("/admin/templates/<id>/canary")]
+pub fn abort_template_canary(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.abort_template_canary(&user, &id))
+    })
+}
+
 // User resources. Only accessible to Admins.
 
 #[get("/users/<id>")]
@@ -118,34 +394,220 @@ pub fn get_user(state: State<'_, Context>, user: LoggedUser, id: String) -> Json
     result_to_jsonrpc(state.manager.get_user(&user, &id))
 }
 
-#[get("/users")]
-pub fn list_users(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
-    result_to_jsonrpc(state.manager.list_users(&user))
+/// With `strict`, fails if some users are invalid, including unknown fields
+#[get("/users?<strict>")]
+pub fn list_users(state: State<'_, Context>, user: LoggedUser, strict: Option<bool>) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_users(&user, strict.unwrap_or(false)))
 }
 
-#[put("/users/<id>", data = "<conf>")]
+/// With `dry_run`, returns the user that would be created without creating it
+#[put("/users/<id>?<dry_run>", data = "<conf>")]
 pub fn create_user(
     state: State<'_, Context>,
     user: LoggedUser,
+    idempotency: Idempotency,
     id: String,
+    dry_run: Option<bool>,
     conf: Json<UserConfiguration>,
-) -> JsonValue {
-    result_to_jsonrpc(state.manager.clone().create_user(&user, id, conf.0))
+) -> IdempotentResponse {
+    if dry_run.unwrap_or(false) {
+        return Ok(result_to_jsonrpc(
+            state.manager.plan_user(&user, id, conf.0),
+        ));
+    }
+    idempotent(&state, &user, idempotency, conf.0, |conf| {
+        result_to_jsonrpc(state.manager.clone().create_user(&user, id, conf))
+    })
 }
 
 #[patch("/users/<id>", data = "<conf>")]
 pub fn update_user(
     state: State<'_, Context>,
     user: LoggedUser,
+    idempotency: Idempotency,
     id: String,
     conf: Json<UserUpdateConfiguration>,
-) -> JsonValue {
-    result_to_jsonrpc(state.manager.clone().update_user(user, id, conf.0))
+) -> IdempotentResponse {
+    idempotent(&state, &user.clone(), idempotency, conf.0, |conf| {
+        result_to_jsonrpc(state.manager.clone().update_user(user, id, conf))
+    })
 }
 
 #[delete("/users/<id>")]
-pub fn delete_user(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
-    result_to_jsonrpc(state.manager.clone().delete_user(&user, id))
+pub fn delete_user(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.clone().delete_user(&user, id))
+    })
+}
+
+/// Restores a user during the deletion grace period
+#[post("/users/<id>/restore")]
+pub fn restore_user(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.restore_user(&user, &id))
+}
+
+/// Returns all data held about a user
+#[get("/users/<id>/export")]
+pub fn export_user(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.export_user(&user, &id))
+}
+
+#[get("/users/<id>/preferences")]
+pub fn get_user_preferences(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_user_preferences(&user, &id))
+}
+
+#[patch("/users/<id>/preferences", data = "<update>")]
+pub fn update_user_preferences(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+    update: Json<UserPreferencesUpdate>,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, update.0, |update| {
+        result_to_jsonrpc(state.manager.update_user_preferences(&user, &id, update))
+    })
+}
+
+#[post("/users/<id>/identities", data = "<identity>")]
+pub fn link_user_identity(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    identity: Json<Identity>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.link_user_identity(&user, &id, identity.0))
+}
+
+#[post("/users/<id>/onboarding", data = "<transition>")]
+pub fn update_user_onboarding(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    transition: Json<OnboardingTransition>,
+) -> JsonValue {
+    result_to_jsonrpc(
+        state
+            .manager
+            .update_user_onboarding(&user, &id, transition.0.state),
+    )
+}
+
+#[post("/admin/users:import", format = "json", data = "<users>")]
+pub fn import_users(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    users: Json<Vec<Entry<UserConfiguration>>>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.import_users(&user, users.0))
+}
+
+#[post("/admin/users:import", format = "text/csv", data = "<users>", rank = 2)]
+pub fn import_users_csv(state: State<'_, Context>, user: LoggedUser, users: String) -> JsonValue {
+    result_to_jsonrpc(
+        users_from_csv(&users).and_then(|users| state.manager.import_users(&user, users)),
+    )
+}
+
+#[get("/admin/users:export")]
+pub fn export_users(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.export_users(&user))
+}
+
+/// Returns a copy of the state managed by the playground, to be restored via `restore_state`
+#[get("/admin/backup")]
+pub fn backup_state(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.backup_state(&user))
+}
+
+/// Moves running sessions to another cluster. Returns the archive to restore there via `restore_state`.
+#[post("/admin/handoff")]
+pub fn handoff_sessions(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.handoff_sessions(&user))
+}
+
+#[post("/admin/restore", format = "json", data = "<data>")]
+pub fn restore_state(state: State<'_, Context>, user: LoggedUser, data: Data) -> JsonValue {
+    let mut archive = String::new();
+    if let Err(err) = data.open().take(ARCHIVE_LIMIT).read_to_string(&mut archive) {
+        return json!({ "error": err.to_string() });
+    }
+    result_to_jsonrpc(
+        serde_json::from_str(&archive)
+            .map_err(|err| Error::InvalidParameter(err.to_string()))
+            .and_then(|archive| state.manager.restore_state(&user, archive)),
+    )
+}
+
+#[get("/admin/usage?<from>&<to>")]
+pub fn get_usage(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_usage(&user, from, to))
+}
+
+/// Returns the format version of stored resources, per ConfigMap
+#[get("/admin/storage/version")]
+pub fn get_storage_version(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_storage_version(&user))
+}
+
+/// Returns popularity and failure rate of templates over the last `days`
+#[get("/admin/analytics/templates?<days>")]
+pub fn get_template_analytics(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    days: Option<u64>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_template_analytics(&user, days))
+}
+
+// Organizations
+
+/// Organizations are cached by each replica for 30 seconds: updates made via another replica might show up that late
+#[get("/orgs")]
+pub fn list_orgs(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_orgs(&user))
+}
+
+/// See `list_orgs` for caching
+#[get("/orgs/<id>")]
+pub fn get_org(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_org(&user, &id))
+}
+
+#[put("/orgs/<id>", data = "<org>")]
+pub fn update_org(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+    org: Json<Org>,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, org.0, |org| {
+        result_to_jsonrpc(state.manager.update_org(&user, &id, org))
+    })
+}
+
+#[delete("/orgs/<id>")]
+pub fn delete_org(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.delete_org(&user, &id))
+    })
 }
 
 // Current Session
@@ -156,7 +618,7 @@ pub fn get_current_session(state: State<'_, Context>, user: LoggedUser) -> JsonV
 }
 
 #[get("/session", rank = 2)]
-pub fn get_current_session_unlogged() -> status::Unauthorized<()> {
+pub fn get_current_session_unlogged(_limit: RateLimit) -> status::Unauthorized<()> {
     status::Unauthorized::<()>(None)
 }
 
@@ -171,21 +633,34 @@ fn session_id(id: &str) -> String {
 /// There is a short time window where multiple concurrent calls can succeed.
 /// As this call is idempotent this won't lead to multiple session creation.
 ///
-#[put("/session", data = "<conf>")]
+/// With `dry_run`, returns what would be created without creating it
+#[put("/session?<dry_run>", data = "<conf>")]
 pub fn create_current_session(
     state: State<'_, Context>,
     user: LoggedUser,
+    idempotency: Idempotency,
+    dry_run: Option<bool>,
     conf: Json<SessionConfiguration>,
-) -> JsonValue {
-    result_to_jsonrpc(
-        state
-            .manager
-            .create_session(&user, &session_id(&user.id), conf.0),
-    )
+) -> IdempotentResponse {
+    if dry_run.unwrap_or(false) {
+        return Ok(result_to_jsonrpc(state.manager.plan_session(
+            &user,
+            &session_id(&user.id),
+            conf.0,
+        )));
+    }
+    idempotent(&state, &user, idempotency, conf.0, |conf| {
+        result_to_jsonrpc(
+            state
+                .manager
+                .create_session(&user, &session_id(&user.id), conf),
+        )
+    })
 }
 
 #[put("/session", data = "<_conf>", rank = 2)]
 pub fn create_current_session_unlogged(
+    _limit: RateLimit,
     _conf: Json<SessionConfiguration>,
 ) -> status::Unauthorized<()> {
     status::Unauthorized::<()>(None)
@@ -195,29 +670,39 @@ pub fn create_current_session_unlogged(
 pub fn update_current_session(
     state: State<'_, Context>,
     user: LoggedUser,
+    idempotency: Idempotency,
     conf: Json<SessionUpdateConfiguration>,
-) -> JsonValue {
-    result_to_jsonrpc(
-        state
-            .manager
-            .update_session(&session_id(&user.id), &user, conf.0),
-    )
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, conf.0, |conf| {
+        result_to_jsonrpc(
+            state
+                .manager
+                .update_session(&session_id(&user.id), &user, conf),
+        )
+    })
 }
 
 #[patch("/session", data = "<_conf>", rank = 2)]
 pub fn update_current_session_unlogged(
+    _limit: RateLimit,
     _conf: Json<SessionUpdateConfiguration>,
 ) -> status::Unauthorized<()> {
     status::Unauthorized::<()>(None)
 }
 
 #[delete("/session")]
-pub fn delete_current_session(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
-    result_to_jsonrpc(state.manager.delete_session(&user, &session_id(&user.id)))
+pub fn delete_current_session(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.delete_session(&user, &session_id(&user.id)))
+    })
 }
 
 #[delete("/session", rank = 2)]
-pub fn delete_current_session_unlogged() -> status::Unauthorized<()> {
+pub fn delete_current_session_unlogged(_limit: RateLimit) -> status::Unauthorized<()> {
     status::Unauthorized::<()>(None)
 }
 
@@ -228,34 +713,403 @@ pub fn get_session(state: State<'_, Context>, user: LoggedUser, id: String) -> J
     result_to_jsonrpc(state.manager.get_session(&user, &id))
 }
 
+/// Returns the url to open session `id` with
+#[get("/sessions/<id>/url")]
+pub fn get_session_access_url(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_session_access_url(&user, &id))
+}
+
+/// Called by nginx for each request to the ingress, `host` being the requested host. Not rate limited: all calls come
+/// from the ingress controller, and sessions issue many of them.
+#[get("/sessions/<host>/authorize")]
+pub fn authorize_session(
+    state: State<'_, Context>,
+    host: String,
+    original: OriginalRequest,
+    mut cookies: Cookies<'_>,
+) -> Status {
+    let access = cookies
+        .get(session_auth::COOKIE_ACCESS)
+        .map(|cookie| cookie.value().to_string());
+    match state
+        .manager
+        .authorize_session_access(&host, original.url.as_deref(), access.as_deref())
+    {
+        Ok(Some(access)) => {
+            // Relayed by nginx, scoped to the session host
+            cookies.add(
+                Cookie::build(session_auth::COOKIE_ACCESS, access)
+                    .path("/")
+                    .http_only(true)
+                    .secure(state.manager.engine.env.secured)
+                    .same_site(SameSite::Lax)
+                    .finish(),
+            );
+            Status::Ok
+        }
+        Ok(None) => Status::Ok,
+        Err(_) => Status::Unauthorized,
+    }
+}
+
+#[get("/sessions/<id>/events")]
+pub fn list_session_events(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_session_events(&user, &id))
+}
+
+#[get("/sessions/<id>/eviction")]
+pub fn get_session_eviction(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_session_eviction(&user, &id))
+}
+
+#[get("/sessions/<id>/git")]
+pub fn get_session_git_state(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_session_git_state(&user, &id))
+}
+
+/// Forwards `request` to the configured faucet
+#[post("/sessions/<id>/faucet", data = "<request>")]
+pub fn request_funds(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    request: Json<FaucetRequest>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.request_funds(&user, &id, request.0))
+}
+
+/// Publishes a read-only view of the session. Returns its public url.
+#[post("/sessions/<id>/viewer")]
+pub fn publish_session_viewer(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.publish_session_viewer(&user, &id))
+}
+
+#[delete("/sessions/<id>/viewer")]
+pub fn unpublish_session_viewer(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.unpublish_session_viewer(&user, &id))
+    })
+}
+
+/// Recreates the pod of session `id`, keeping its url and remaining duration. Workspaces aren't backed by a volume:
+/// the restarted pod starts from a fresh workspace, uncommitted changes are lost.
+#[post("/sessions/<id>/restart")]
+pub fn restart_session(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.restart_session(&user, &id))
+}
+
+/// Signals activity of session `id`. `nonce` is the one returned by the previous heartbeat, if any.
+#[post("/sessions/<id>/heartbeat?<nonce>")]
+pub fn session_heartbeat(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    nonce: Option<String>,
+) -> JsonValue {
+    result_to_jsonrpc(
+        state
+            .manager
+            .session_heartbeat(&user, &id, nonce.as_deref()),
+    )
+}
+
+/// Returns the nonce expected by the next heartbeat of session `id`, once the previous one was lost
+#[post("/sessions/<id>/heartbeat/resync")]
+pub fn resync_session_heartbeat(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.resync_session_heartbeat(&user, &id))
+}
+
+/// Registers a new template from session `id`, including its workspace
+#[post("/sessions/<id>/publish-template", data = "<publication>")]
+pub fn publish_template(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    publication: Json<TemplatePublication>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.publish_template(&user, &id, publication.0))
+}
+
+#[patch("/sessions/<id>/env", data = "<update>")]
+pub fn update_session_env(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+    update: Json<SessionEnvUpdate>,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, update.0, |update| {
+        result_to_jsonrpc(state.manager.update_session_env(&user, &id, update))
+    })
+}
+
+#[post("/sessions/<id>/ports", data = "<port>")]
+pub fn add_session_port(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    port: Json<Port>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.add_session_port(&user, &id, port.0))
+}
+
+#[delete("/sessions/<id>/ports/<name>")]
+pub fn remove_session_port(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+    name: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.remove_session_port(&user, &id, &name))
+    })
+}
+
 #[get("/sessions")]
 pub fn list_sessions(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
-    result_to_jsonrpc(state.manager.list_sessions(&user))
+    result_with_warnings_to_jsonrpc(state.manager.list_sessions(&user))
 }
 
-#[put("/sessions/<id>", data = "<conf>")]
+/// With `dry_run`, returns what would be created without creating it
+#[put("/sessions/<id>?<dry_run>", data = "<conf>")]
 pub fn create_session(
     state: State<'_, Context>,
     user: LoggedUser,
+    idempotency: Idempotency,
     id: String,
+    dry_run: Option<bool>,
     conf: Json<SessionConfiguration>,
-) -> JsonValue {
-    result_to_jsonrpc(state.manager.create_session(&user, &id, conf.0))
+) -> IdempotentResponse {
+    if dry_run.unwrap_or(false) {
+        return Ok(result_to_jsonrpc(
+            state.manager.plan_session(&user, &id, conf.0),
+        ));
+    }
+    idempotent(&state, &user, idempotency, conf.0, |conf| {
+        result_to_jsonrpc(state.manager.create_session(&user, &id, conf))
+    })
 }
 
 #[patch("/sessions/<id>", data = "<conf>")]
 pub fn update_session(
     state: State<'_, Context>,
     user: LoggedUser,
+    idempotency: Idempotency,
     id: String,
     conf: Json<SessionUpdateConfiguration>,
-) -> JsonValue {
-    result_to_jsonrpc(state.manager.update_session(&id, &user, conf.0))
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, conf.0, |conf| {
+        result_to_jsonrpc(state.manager.update_session(&id, &user, conf))
+    })
 }
 
 #[delete("/sessions/<id>")]
-pub fn delete_session(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
-    result_to_jsonrpc(state.manager.delete_session(&user, &id))
+pub fn delete_session(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.delete_session(&user, &id))
+    })
+}
+
+/// Restores a session during the deletion grace period
+#[post("/sessions/<id>/restore")]
+pub fn restore_session(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.restore_session(&user, &id))
+}
+
+/// Moves a session to another pool, e.g. to drain a node
+#[post("/sessions/<id>/migrate?<pool>")]
+pub fn migrate_session(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    pool: String,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.migrate_session(&user, &id, pool))
+}
+
+/// Terminates a session on behalf of an admin. `reason` is recorded and, unless `tombstone` is false, shown to the session owner.
+#[delete("/admin/sessions/<id>?<reason>&<tombstone>")]
+pub fn terminate_session(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+    reason: String,
+    tombstone: Option<bool>,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.terminate_session(
+            &user,
+            &id,
+            reason,
+            tombstone.unwrap_or(true),
+        ))
+    })
+}
+
+/// Deletes, extends or migrates all sessions matching a filter. Returns the outcome for each session.
+#[post("/admin/sessions:batch", data = "<batch>")]
+pub fn run_session_batch(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    batch: Json<SessionBatch>,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, batch.0, |batch| {
+        result_to_jsonrpc(state.manager.run_session_batch(&user, batch))
+    })
+}
+
+/// Clears policy flags of a session, resuming it if it was suspended
+#[post("/admin/sessions/<id>/resume")]
+pub fn resume_session(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.resume_session(&user, &id))
+}
+
+#[post("/github/webhook", data = "<data>")]
+pub fn github_webhook(
+    state: State<'_, Context>,
+    delivery: GitHubDelivery,
+    data: Data,
+) -> JsonValue {
+    let mut payload = Vec::new();
+    if let Err(err) = data
+        .open()
+        .take(GITHUB_PAYLOAD_LIMIT)
+        .read_to_end(&mut payload)
+    {
+        return json!({ "error": err.to_string() });
+    }
+    result_to_jsonrpc(state.manager.handle_github_event(
+        &delivery.event,
+        delivery.signature.as_deref(),
+        &payload,
+    ))
+}
+
+#[get("/workshops/<id>/artifacts")]
+pub fn list_artifacts(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_artifacts(&user, &id))
+}
+
+/// Returns the raw artifact content
+#[get("/workshops/<id>/artifacts/<name>")]
+pub fn get_artifact(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    name: String,
+) -> Result<Option<Vec<u8>>, JsonValue> {
+    state
+        .manager
+        .get_artifact(&user, &id, &name)
+        .map_err(|err| json!({ "error": err.to_string() }))
+}
+
+#[post("/workshops/<id>/artifacts/<name>", data = "<data>")]
+pub fn publish_artifact(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    name: String,
+    data: Data,
+) -> JsonValue {
+    let mut content = Vec::new();
+    // Larger artifacts are rejected rather than truncated
+    if let Err(err) = data
+        .open()
+        .take(ARTIFACT_LIMIT + 1)
+        .read_to_end(&mut content)
+    {
+        return json!({ "error": err.to_string() });
+    }
+    if content.len() as u64 > ARTIFACT_LIMIT {
+        return json!({ "error": format!("Artifacts are limited to {} bytes", ARTIFACT_LIMIT) });
+    }
+    result_to_jsonrpc(state.manager.publish_artifact(&user, &id, &name, content))
+}
+
+#[get("/admin/reservations")]
+pub fn list_reservations(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_reservations(&user))
+}
+
+/// Reserves pool capacity for a workshop. Returns the reservation id.
+#[post("/admin/reservations", data = "<reservation>")]
+pub fn create_reservation(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    reservation: Json<Reservation>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.create_reservation(&user, reservation.0))
+}
+
+#[delete("/admin/reservations/<id>")]
+pub fn delete_reservation(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    idempotency: Idempotency,
+    id: String,
+) -> IdempotentResponse {
+    idempotent(&state, &user, idempotency, (), |_| {
+        result_to_jsonrpc(state.manager.delete_reservation(&user, &id))
+    })
+}
+
+/// Reports resources broken by external edits
+#[get("/admin/diagnostics")]
+pub fn get_diagnostics(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_diagnostics(&user))
+}
+
+#[get("/admin/audit")]
+pub fn list_audit_events(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_audit_events(&user))
+}
+
+/// Executes a GraphQL query, see `graphql`. Not found unless `GRAPHQL_ENABLED` is set.
+#[post("/graphql", data = "<request>")]
+pub fn graphql(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    _limit: RateLimit,
+    request: String,
+) -> Option<GraphQLResponse> {
+    let schema = state.graphql.as_ref()?;
+    let context = graphql::Context::new(state.manager.clone(), user);
+    Some(match graphql::execute(schema, &context, &request) {
+        Ok((true, response)) => GraphQLResponse(Status::Ok, response),
+        Ok((false, response)) => GraphQLResponse(Status::BadRequest, response),
+        Err(err) => GraphQLResponse::error(FieldError::new(err, Value::null())),
+    })
+}
+
+#[get("/admin/webhooks/deliveries")]
+pub fn list_webhook_deliveries(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
+    result_to_jsonrpc(state.manager.list_webhook_deliveries(&user))
 }
 
 // Pools
@@ -265,9 +1119,32 @@ pub fn get_pool(state: State<'_, Context>, user: LoggedUser, id: String) -> Json
     result_to_jsonrpc(state.manager.get_pool(&user, &id))
 }
 
-#[get("/pools")]
-pub fn list_pools(state: State<'_, Context>, user: LoggedUser) -> JsonValue {
-    result_to_jsonrpc(state.manager.list_pools(&user))
+/// Lists all pools, or only the ones the caller can use if `usable_by` is set
+#[get("/pools?<usable_by>")]
+pub fn list_pools(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    usable_by: Option<String>,
+) -> JsonValue {
+    match usable_by {
+        Some(usable_by) => result_to_jsonrpc(state.manager.list_usable_pools(&user, &usable_by)),
+        None => result_to_jsonrpc(state.manager.list_pools(&user)),
+    }
+}
+
+#[post("/admin/pools/<id>/prepull?<template>")]
+pub fn prepull_pool(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    id: String,
+    template: Option<String>,
+) -> JsonValue {
+    result_to_jsonrpc(state.manager.prepull_pool(&user, &id, template))
+}
+
+#[get("/admin/pools/<id>/prepull")]
+pub fn get_prepull_status(state: State<'_, Context>, user: LoggedUser, id: String) -> JsonValue {
+    result_to_jsonrpc(state.manager.get_prepull_status(&user, &id))
 }
 
 // GitHub login logic
@@ -296,6 +1173,8 @@ fn protocol(env: &Environment) -> String {
 pub fn github_login(
     state: State<'_, Context>,
     origin: &Origin,
+    // Guards run in order, so that limited calls never reach the provider
+    _limit: RateLimit,
     oauth2: OAuth2<GitHubUser>,
     mut cookies: Cookies<'_>,
 ) -> Redirect {
@@ -319,35 +1198,149 @@ pub fn github_login(
 /// and store it as a cookie
 #[get("/auth/github")]
 pub fn post_install_callback(
+    state: State<'_, Context>,
     origin: &Origin,
+    // Guards run in order, so that limited calls never reach the provider
+    _limit: RateLimit,
     token: TokenResponse<GitHubUser>,
+    cookies: Cookies<'_>,
+) -> Result<Redirect> {
+    let key = state
+        .manager
+        .auth_sessions
+        .create(token.access_token().to_string(), Provider::GitHub)?;
+    set_session_cookies(cookies, key);
+
+    Ok(Redirect::to(format!("/{}", query_segment(origin))))
+}
+
+// Gets called from UI when OIDC is configured. Then redirects to the issuer which itself redirects to `/auth/oidc`
+#[get("/login/oidc")]
+pub fn oidc_login(
+    state: State<'_, Context>,
+    origin: &Origin,
+    // Guards run in order, so that limited calls never reach the provider
+    _limit: RateLimit,
+    oauth2: OAuth2<OidcUser>,
     mut cookies: Cookies<'_>,
-) -> Redirect {
-    cookies.add_private(
-        Cookie::build(COOKIE_TOKEN, token.access_token().to_string())
-            .same_site(SameSite::Lax)
-            .finish(),
+) -> Result<Redirect> {
+    let manager = state.manager.clone();
+    let oidc = manager
+        .engine
+        .configuration
+        .oidc
+        .as_ref()
+        .ok_or(Error::MissingData("OIDC configuration"))?;
+    let redirect_uri = format!(
+        "{}://{}/api/auth/oidc{}",
+        protocol(&manager.engine.env),
+        manager.engine.env.host,
+        query_segment(origin)
     );
+    let scopes: Vec<&str> = oidc.scopes.iter().map(String::as_str).collect();
+    oauth2
+        .get_redirect_extras(&mut cookies, &scopes, &[("redirect_uri", &redirect_uri)])
+        .map_err(|err| Error::Failure(err.to_string().into()))
+}
+
+/// Callback to handle the authenticated token received from the OIDC issuer
+#[get("/auth/oidc")]
+pub fn oidc_callback(
+    state: State<'_, Context>,
+    origin: &Origin,
+    // Guards run in order, so that limited calls never reach the provider
+    _limit: RateLimit,
+    token: TokenResponse<OidcUser>,
+    cookies: Cookies<'_>,
+) -> Result<Redirect> {
+    let key = state
+        .manager
+        .auth_sessions
+        .create(token.access_token().to_string(), Provider::Oidc)?;
+    set_session_cookies(cookies, key);
 
-    Redirect::to(format!("/{}", query_segment(origin)))
+    Ok(Redirect::to(format!("/{}", query_segment(origin))))
 }
 
 #[get("/login?<bearer>")]
-pub fn login(mut cookies: Cookies<'_>, bearer: String) {
-    cookies.add_private(
-        Cookie::build(COOKIE_TOKEN, bearer)
-            .same_site(SameSite::Lax)
-            .finish(),
-    )
+pub fn login(
+    state: State<'_, Context>,
+    _limit: RateLimit,
+    cookies: Cookies<'_>,
+    bearer: String,
+) -> Result<()> {
+    let key = state
+        .manager
+        .auth_sessions
+        .create(bearer, Provider::GitHub)?;
+    set_session_cookies(cookies, key);
+    Ok(())
+}
+
+/// Extends the current authenticated session
+#[post("/auth/refresh")]
+pub fn refresh(
+    state: State<'_, Context>,
+    _user: LoggedUser,
+    mut cookies: Cookies<'_>,
+) -> JsonValue {
+    let key = cookies
+        .get_private(COOKIE_TOKEN)
+        .and_then(|cookie| state.manager.auth_sessions.refresh(cookie.value()));
+    match key {
+        Some(key) => {
+            set_session_cookies(cookies, key);
+            json!({ "result": () })
+        }
+        None => json!({ "error": "No session to refresh" }),
+    }
+}
+
+/// Issues a single-use ticket authenticating a connection to the control channel, see `ws`
+#[post("/ws/ticket")]
+pub fn create_ws_ticket(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    mut cookies: Cookies<'_>,
+) -> JsonValue {
+    match cookies.get_private(COOKIE_TOKEN) {
+        Some(cookie) => result_to_jsonrpc(state.manager.ws_tickets.issue(&user, cookie.value())),
+        None => json!({ "error": "No session" }),
+    }
+}
+
+/// Revokes all authenticated sessions of the current user
+#[delete("/auth/sessions")]
+pub fn revoke_sessions(
+    state: State<'_, Context>,
+    user: LoggedUser,
+    cookies: Cookies<'_>,
+) -> JsonValue {
+    let revoked = state.manager.auth_sessions.revoke_user(&user.id);
+    clear(cookies);
+    json!({ "result": revoked })
 }
 
 #[get("/logout")]
-pub fn logout(cookies: Cookies<'_>) {
+pub fn logout(state: State<'_, Context>, _limit: RateLimit, mut cookies: Cookies<'_>) {
+    if let Some(cookie) = cookies.get_private(COOKIE_TOKEN) {
+        state.manager.auth_sessions.revoke(cookie.value());
+    }
     clear(cookies)
 }
 
+fn set_session_cookies(mut cookies: Cookies<'_>, key: String) {
+    cookies.add_private(
+        Cookie::build(COOKIE_TOKEN, key)
+            .same_site(SameSite::Lax)
+            .finish(),
+    );
+    cookies.add(csrf::new_cookie());
+}
+
 fn clear(mut cookies: Cookies<'_>) {
     cookies.remove_private(Cookie::named(COOKIE_TOKEN));
+    cookies.remove(Cookie::build(csrf::COOKIE_CSRF, "").path("/").finish());
 }
 
 #[allow(dead_code)]
@@ -355,3 +1348,13 @@ fn clear(mut cookies: Cookies<'_>) {
 pub fn bad_request_catcher(_req: &Request<'_>) -> content::Html<String> {
     content::Html("<p>Sorry something unexpected happened!</p>".to_string())
 }
+
+#[allow(dead_code)]
+#[catch(429)]
+pub fn too_many_requests_catcher(req: &Request<'_>) -> Response<'static> {
+    let RetryAfter(secs) = *req.local_cache(RetryAfter::default);
+    Response::build()
+        .status(Status::TooManyRequests)
+        .raw_header("Retry-After", secs.to_string())
+        .finalize()
+}
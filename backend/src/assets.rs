@@ -0,0 +1,136 @@
+//! Optional serving of the compiled frontend bundle
+//!
+//! Enabled by setting `STATIC_FILES_DIR`. Precompressed `.br` and `.gz` variants are served when the client accepts them,
+//! and unknown paths fall back to `index.html` so that client side routing works.
+use rocket::{
+    handler::{Handler, Outcome},
+    http::{uri::Segments, ContentType, Method},
+    Data, Request, Response, Route,
+};
+use std::{
+    env,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+const INDEX: &str = "index.html";
+/// Paths handled by the backend itself, never served from `STATIC_FILES_DIR`
+const RESERVED_PREFIXES: &[&str] = &["api", "metrics"];
+
+#[derive(Clone, Debug)]
+pub struct Assets {
+    root: PathBuf,
+}
+
+impl Assets {
+    /// Lower priority than any other route
+    const RANK: isize = 20;
+
+    pub fn from_env() -> Option<Self> {
+        env::var("STATIC_FILES_DIR").ok().map(|root| Assets {
+            root: PathBuf::from(root),
+        })
+    }
+
+    /// Opens `path`, preferring a precompressed variant matching `accept_encoding`
+    fn open(&self, path: &Path, accept_encoding: &str) -> Option<(File, Option<&'static str>)> {
+        let full_path = self.root.join(path);
+        if !full_path.is_file() {
+            return None;
+        }
+        for (encoding, extension) in &[("br", "br"), ("gzip", "gz")] {
+            if accept_encoding
+                .split(',')
+                .any(|value| value.trim().split(';').next() == Some(*encoding))
+            {
+                let mut compressed = full_path.clone().into_os_string();
+                compressed.push(".");
+                compressed.push(extension);
+                if let Ok(file) = File::open(&compressed) {
+                    return Some((file, Some(*encoding)));
+                }
+            }
+        }
+        File::open(&full_path).ok().map(|file| (file, None))
+    }
+}
+
+/// Returns true if `path` contains a content hash, as generated by bundlers (e.g. `index.1a2b3c4d.js`)
+fn is_fingerprinted(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| {
+            name.split(|c| c == '.' || c == '-')
+                .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+        })
+}
+
+fn cache_control(path: &Path) -> &'static str {
+    if is_fingerprinted(path) {
+        "public, max-age=31536000, immutable"
+    } else if path == Path::new(INDEX) {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
+impl Handler for Assets {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let path = match request.get_segments::<Segments>(0) {
+            Some(Ok(segments)) => match segments.into_path_buf(false) {
+                Ok(path) => path,
+                Err(_) => return Outcome::forward(data),
+            },
+            _ => PathBuf::new(),
+        };
+        if path
+            .components()
+            .next()
+            .and_then(|component| component.as_os_str().to_str())
+            .map_or(false, |first| RESERVED_PREFIXES.contains(&first))
+        {
+            return Outcome::forward(data);
+        }
+
+        let accept_encoding = request.headers().get_one("Accept-Encoding").unwrap_or("");
+        // Unknown paths without extension are client side routes
+        let path = if path.as_os_str().is_empty()
+            || (path.extension().is_none() && !self.root.join(&path).is_file())
+        {
+            PathBuf::from(INDEX)
+        } else {
+            path
+        };
+        let (file, encoding) = match self.open(&path, accept_encoding) {
+            Some(file) => file,
+            None => return Outcome::forward(data),
+        };
+
+        let mut response = Response::build();
+        if let Some(content_type) = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(ContentType::from_extension)
+        {
+            response.header(content_type);
+        }
+        if let Some(encoding) = encoding {
+            response.raw_header("Content-Encoding", encoding);
+        }
+        response
+            .raw_header("Cache-Control", cache_control(&path))
+            .raw_header("Vary", "Accept-Encoding")
+            .sized_body(file);
+        Outcome::from(request, response.finalize())
+    }
+}
+
+impl From<Assets> for Vec<Route> {
+    fn from(assets: Assets) -> Self {
+        vec![
+            Route::ranked(Assets::RANK, Method::Get, "/", assets.clone()),
+            Route::ranked(Assets::RANK, Method::Get, "/<path..>", assets),
+        ]
+    }
+}
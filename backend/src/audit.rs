@@ -0,0 +1,135 @@
+//! Audit trail of sensitive operations
+//!
+//! Events are logged under the `audit` target and the most recent ones are kept in memory. They are also delivered to
+//! webhooks, if configured.
+use crate::{types::AuditEvent, webhooks::Webhooks};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct Audit {
+    events: Arc<Mutex<VecDeque<AuditEvent>>>,
+    webhooks: Option<Webhooks>,
+}
+
+impl Audit {
+    /// Maximum number of events kept in memory. Oldest events are dropped first.
+    const MAX_EVENTS: usize = 10_000;
+
+    pub fn new(webhooks: Option<Webhooks>) -> Self {
+        Audit {
+            events: Arc::default(),
+            webhooks,
+        }
+    }
+
+    pub fn webhooks(&self) -> Option<&Webhooks> {
+        self.webhooks.as_ref()
+    }
+
+    pub fn record(&self, actor: &str, action: &str, target: &str, details: Option<String>) {
+        log::info!(
+            target: "audit",
+            "{} {} {} {}",
+            actor,
+            action,
+            target,
+            details.as_deref().unwrap_or_default()
+        );
+        let event = AuditEvent {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            details,
+            time: SystemTime::now(),
+        };
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.enqueue(&event);
+        }
+        if let Ok(mut events) = self.events.lock() {
+            if events.len() >= Self::MAX_EVENTS {
+                events.pop_front();
+            }
+            events.push_back(event);
+        }
+    }
+
+    /// Replaces `identifiers` in recorded events with `replacement`. Only whole identifiers are replaced, e.g. scrubbing
+    /// `al` leaves `alice` untouched.
+    pub fn scrub(&self, identifiers: &[String], replacement: &str) {
+        if let Ok(mut events) = self.events.lock() {
+            for event in events.iter_mut() {
+                for identifier in identifiers {
+                    if &event.actor == identifier {
+                        event.actor = replacement.to_string();
+                    }
+                    if &event.target == identifier {
+                        event.target = replacement.to_string();
+                    }
+                    if let Some(details) = event.details.as_mut() {
+                        *details = replace_identifier(details, identifier, replacement);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns recorded events, most recent first
+    pub fn list(&self) -> Vec<AuditEvent> {
+        self.events
+            .lock()
+            .map(|events| events.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Replaces occurrences of `identifier` in `text` that aren't part of a longer identifier with `replacement`
+fn replace_identifier(text: &str, identifier: &str, replacement: &str) -> String {
+    if identifier.is_empty() {
+        return text.to_string();
+    }
+    let is_part = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, _) in text.match_indices(identifier) {
+        let end = start + identifier.len();
+        if text[..start].chars().next_back().map_or(false, is_part)
+            || text[end..].chars().next().map_or(false, is_part)
+        {
+            continue;
+        }
+        result.push_str(&text[last..start]);
+        result.push_str(replacement);
+        last = end;
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_whole_identifiers() {
+        assert_eq!(
+            replace_identifier("al alice {\"user\":\"al\"} al-2 al", "al", "<deleted>"),
+            "<deleted> alice {\"user\":\"<deleted>\"} al-2 <deleted>"
+        );
+        assert_eq!(replace_identifier("alice", "", "<deleted>"), "alice");
+    }
+
+    #[test]
+    fn scrubs_events() {
+        let audit = Audit::new(None);
+        audit.record("al", "delete_user", "alice", Some("by al".to_string()));
+        audit.scrub(&["al".to_string()], "<deleted>");
+        let event = &audit.list()[0];
+        assert_eq!(event.actor, "<deleted>");
+        assert_eq!(event.target, "alice");
+        assert_eq!(event.details.as_deref(), Some("by <deleted>"));
+    }
+}
@@ -0,0 +1,236 @@
+//! Server side store of authenticated sessions
+//!
+//! Cookies only reference an opaque key. Access tokens, issued by GitHub or an OIDC provider, are kept server side, alongside an expiry,
+//! so that sessions can be refreshed and revoked. Sessions are kept in a `Secret` each, named after a hash of their key, so
+//! that all replicas share them. Replicas cache sessions for `CACHE_TTL`: revocations by other replicas apply within that delay.
+use crate::{
+    error::{Error, Result},
+    kubernetes::Engine,
+};
+use log::warn;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::runtime::Runtime;
+
+const KEY_LENGTH: usize = 48;
+const RECORD_KIND: &str = "auth-session";
+/// Delay during which a session read by a replica isn't read again
+const CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Returns a random alphanumeric string of `length` characters
+pub fn random_token(length: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Returns the hex encoded SHA-256 of `key`, so that secrets such as session keys are never stored as is
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Runs `future` from synchronous code, e.g. Rocket handlers
+fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    Ok(Runtime::new()
+        .map_err(|err| Error::Failure(err.into()))?
+        .block_on(future))
+}
+
+/// Identity provider that issued a token
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    GitHub,
+    Oidc,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthSession {
+    /// The access token
+    pub token: String,
+    pub provider: Provider,
+    /// Set once the token has been resolved to a user
+    pub user_id: Option<String>,
+    pub expires_at: SystemTime,
+}
+
+#[derive(Clone)]
+pub struct AuthSessions {
+    ttl: Duration,
+    engine: Engine,
+    /// Sessions recently read, by key hash
+    cache: Arc<Mutex<HashMap<String, (AuthSession, Instant)>>>,
+}
+
+impl AuthSessions {
+    const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+    pub fn new(ttl: Duration, engine: Engine) -> Self {
+        AuthSessions {
+            ttl,
+            engine,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reads the session TTL, in minutes, from `AUTH_SESSION_TTL`
+    pub fn from_env(engine: Engine) -> Self {
+        let ttl = env::var("AUTH_SESSION_TTL")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map_or(Self::DEFAULT_TTL, |minutes| {
+                Duration::from_secs(minutes * 60)
+            });
+        Self::new(ttl, engine)
+    }
+
+    /// Stores a new session for `token`, issued by `provider`. Returns the key identifying it.
+    pub fn create(&self, token: String, provider: Provider) -> Result<String> {
+        let key = random_token(KEY_LENGTH);
+        self.save(
+            &key,
+            AuthSession {
+                token,
+                provider,
+                user_id: None,
+                expires_at: SystemTime::now() + self.ttl,
+            },
+        )?;
+        Ok(key)
+    }
+
+    fn save(&self, key: &str, session: AuthSession) -> Result<()> {
+        let id = hash_key(key);
+        let value = serde_json::to_string(&session).map_err(|err| Error::Failure(err.into()))?;
+        block_on(self.engine.save_record(RECORD_KIND, &id, &value))??;
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(id, (session, Instant::now()));
+        }
+        Ok(())
+    }
+
+    /// Returns the session associated to `key`, if not expired
+    pub async fn fetch(&self, key: &str) -> Option<AuthSession> {
+        let id = hash_key(key);
+        let cached = self.cache.lock().ok().and_then(|mut cache| {
+            cache.retain(|_, (_, read_at)| read_at.elapsed() < CACHE_TTL);
+            cache.get(&id).map(|(session, _)| session.clone())
+        });
+        let session = match cached {
+            Some(session) => session,
+            None => {
+                let session: AuthSession = match self.engine.get_record(RECORD_KIND, &id).await {
+                    Ok(value) => serde_json::from_str(&value?).ok()?,
+                    Err(err) => {
+                        warn!("Failed to read authenticated session: {}", err);
+                        return None;
+                    }
+                };
+                if let Ok(mut cache) = self.cache.lock() {
+                    cache.insert(id, (session.clone(), Instant::now()));
+                }
+                session
+            }
+        };
+        Some(session).filter(|session| session.expires_at > SystemTime::now())
+    }
+
+    /// Like `fetch`, from synchronous code
+    pub fn get(&self, key: &str) -> Option<AuthSession> {
+        block_on(self.fetch(key)).ok().flatten()
+    }
+
+    /// Associates the session `key` to `user_id`
+    pub fn bind(&self, key: &str, user_id: &str) {
+        if let Some(session) = self.get(key) {
+            if session.user_id.as_deref() == Some(user_id) {
+                return;
+            }
+            let session = AuthSession {
+                user_id: Some(user_id.to_string()),
+                ..session
+            };
+            if let Err(err) = self.save(key, session) {
+                warn!("Failed to bind authenticated session: {}", err);
+            }
+        }
+    }
+
+    /// Replaces session `key` with a new one with a fresh expiry. Returns the new key.
+    pub fn refresh(&self, key: &str) -> Option<String> {
+        let session = self.get(key)?;
+        let new_key = random_token(KEY_LENGTH);
+        self.save(
+            &new_key,
+            AuthSession {
+                expires_at: SystemTime::now() + self.ttl,
+                ..session
+            },
+        )
+        .map_err(|err| warn!("Failed to refresh authenticated session: {}", err))
+        .ok()?;
+        self.revoke(key);
+        Some(new_key)
+    }
+
+    pub fn revoke(&self, key: &str) {
+        let id = hash_key(key);
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.remove(&id);
+        }
+        if let Err(err) = block_on(self.engine.delete_record(RECORD_KIND, &id)).and_then(|r| r) {
+            warn!("Failed to revoke authenticated session: {}", err);
+        }
+    }
+
+    /// Revokes all sessions of `user_id`. Returns the number of revoked sessions.
+    pub fn revoke_user(&self, user_id: &str) -> usize {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.retain(|_, (session, _)| session.user_id.as_deref() != Some(user_id));
+        }
+        let result = block_on(async {
+            let mut revoked = 0;
+            for (id, value) in self.engine.list_records(RECORD_KIND).await? {
+                let session: Option<AuthSession> = serde_json::from_str(&value).ok();
+                if session.and_then(|session| session.user_id).as_deref() == Some(user_id) {
+                    self.engine.delete_record(RECORD_KIND, &id).await?;
+                    revoked += 1;
+                }
+            }
+            Ok::<_, Error>(revoked)
+        });
+        match result.and_then(|r| r) {
+            Ok(revoked) => revoked,
+            Err(err) => {
+                warn!("Failed to revoke sessions of {}: {}", user_id, err);
+                0
+            }
+        }
+    }
+
+    /// Deletes expired sessions
+    pub async fn purge(&self) -> Result<()> {
+        let now = SystemTime::now();
+        for (id, value) in self.engine.list_records(RECORD_KIND).await? {
+            let session: Option<AuthSession> = serde_json::from_str(&value).ok();
+            if session.map_or(true, |session| session.expires_at <= now) {
+                self.engine.delete_record(RECORD_KIND, &id).await?;
+            }
+        }
+        Ok(())
+    }
+}
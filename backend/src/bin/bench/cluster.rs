@@ -0,0 +1,772 @@
+//! In-memory stand-in for the Kubernetes API
+//!
+//! Objects are stored as JSON whatever their kind. Only what the playground relies on is emulated: CRUD, merge and
+//! JSON patches, label selectors, dry runs, watches and the controller of `StatefulSet`s, that creates their single pod.
+//! Pods start once `pod_startup` elapsed.
+use hyper::{body, header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+pub const NAMESPACE: &str = "playground";
+pub const TEMPLATE: &str = "bench";
+const NODE: &str = "bench-node";
+/// Default pool, see `SESSION_DEFAULT_POOL_AFFINITY`
+pub const POOL: &str = "default";
+/// Mirror those of `kubernetes.rs`
+const NODE_POOL_LABEL: &str = "cloud.google.com/gke-nodepool";
+const INSTANCE_TYPE_LABEL: &str = "node.kubernetes.io/instance-type";
+const HOSTNAME_LABEL: &str = "kubernetes.io/hostname";
+const INGRESS_NAME: &str = "ingress";
+const CONFIG_MAPS: &[&str] = &[
+    "playground-users",
+    "playground-orgs",
+    "playground-backend-state",
+];
+const TEMPLATES_CONFIG_MAP: &str = "playground-templates";
+/// Number of past events watches can resume from
+const HISTORY_SIZE: usize = 10_000;
+/// Used by watches not specifying `timeoutSeconds`
+const WATCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+struct Event {
+    collection: String,
+    version: u64,
+    kind: &'static str,
+    object: Value,
+}
+
+#[derive(Default)]
+struct State {
+    /// Objects by collection (e.g. `apps/v1/playground/statefulsets`) then name
+    objects: BTreeMap<String, BTreeMap<String, Value>>,
+    version: u64,
+    history: VecDeque<Event>,
+}
+
+impl State {
+    fn get(&self, collection: &str, name: &str) -> Option<&Value> {
+        self.objects
+            .get(collection)
+            .and_then(|objects| objects.get(name))
+    }
+
+    fn record(&mut self, events: &broadcast::Sender<Event>, event: Event) {
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(event.clone());
+        // Fails only if nothing is watching
+        let _ = events.send(event);
+    }
+
+    fn write(
+        &mut self,
+        events: &broadcast::Sender<Event>,
+        collection: &str,
+        kind: &'static str,
+        mut object: Value,
+    ) -> Value {
+        self.version += 1;
+        object["metadata"]["resourceVersion"] = json!(self.version.to_string());
+        let name = object["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        self.objects
+            .entry(collection.to_string())
+            .or_default()
+            .insert(name, object.clone());
+        self.record(
+            events,
+            Event {
+                collection: collection.to_string(),
+                version: self.version,
+                kind,
+                object: object.clone(),
+            },
+        );
+        object
+    }
+
+    fn remove(
+        &mut self,
+        events: &broadcast::Sender<Event>,
+        collection: &str,
+        name: &str,
+    ) -> Option<Value> {
+        let mut object = self.objects.get_mut(collection)?.remove(name)?;
+        self.version += 1;
+        object["metadata"]["resourceVersion"] = json!(self.version.to_string());
+        self.record(
+            events,
+            Event {
+                collection: collection.to_string(),
+                version: self.version,
+                kind: "DELETED",
+                object: object.clone(),
+            },
+        );
+        Some(object)
+    }
+}
+
+/// Target of a request, e.g. `/apis/apps/v1/namespaces/playground/statefulsets/session-1/status`
+struct Target {
+    /// e.g. `apps/v1/playground/statefulsets`
+    collection: String,
+    namespace: Option<String>,
+    resource: String,
+    name: Option<String>,
+    subresource: Option<String>,
+}
+
+impl Target {
+    fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let (group_version, rest) = match segments.as_slice() {
+            ["api", version, rest @ ..] => (version.to_string(), rest),
+            ["apis", group, version, rest @ ..] => (format!("{}/{}", group, version), rest),
+            _ => return None,
+        };
+        // `/api/v1/namespaces/<name>` targets a namespace itself
+        let (namespace, rest) = match rest {
+            ["namespaces", namespace, rest @ ..] if !rest.is_empty() => {
+                (Some(namespace.to_string()), rest)
+            }
+            _ => (None, rest),
+        };
+        let (resource, name, subresource) = match rest {
+            [resource] => (resource, None, None),
+            [resource, name] => (resource, Some(name), None),
+            [resource, name, subresource] => (resource, Some(name), Some(subresource)),
+            _ => return None,
+        };
+        Some(Target {
+            collection: format!(
+                "{}/{}/{}",
+                group_version,
+                namespace.as_deref().unwrap_or_default(),
+                resource
+            ),
+            namespace,
+            resource: resource.to_string(),
+            name: name.map(|name| name.to_string()),
+            subresource: subresource.map(|subresource| subresource.to_string()),
+        })
+    }
+}
+
+type Outcome = Result<Value, (StatusCode, String)>;
+
+#[derive(Clone)]
+pub struct Cluster {
+    state: Arc<Mutex<State>>,
+    events: broadcast::Sender<Event>,
+    pod_startup: Duration,
+}
+
+impl Cluster {
+    /// Creates a cluster holding a single node, the playground ingress and ConfigMaps with a single template
+    pub fn new(pod_startup: Duration) -> Self {
+        let (events, _) = broadcast::channel(HISTORY_SIZE);
+        let cluster = Cluster {
+            state: Arc::new(Mutex::new(State::default())),
+            events,
+            pod_startup,
+        };
+        let mut state = cluster.state.lock().expect("unpoisoned lock");
+        let now = timestamp(SystemTime::now());
+        let node = json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": {
+                "name": NODE,
+                "labels": {
+                    NODE_POOL_LABEL: POOL,
+                    INSTANCE_TYPE_LABEL: "bench",
+                    HOSTNAME_LABEL: NODE,
+                },
+            },
+            "status": {
+                "allocatable": { "cpu": "1000", "memory": "4000Gi", "pods": "10000" },
+                "capacity": { "cpu": "1000", "memory": "4000Gi", "pods": "10000" },
+                "conditions": [{ "type": "Ready", "status": "True", "lastTransitionTime": now }],
+            },
+        });
+        state.write(&cluster.events, "v1//nodes", "ADDED", node);
+        let ingress = json!({
+            "apiVersion": "networking.k8s.io/v1",
+            "kind": "Ingress",
+            "metadata": { "name": INGRESS_NAME, "namespace": NAMESPACE },
+            "spec": { "rules": [{ "host": "localhost" }] },
+        });
+        state.write(
+            &cluster.events,
+            &format!("networking.k8s.io/v1/{}/ingresses", NAMESPACE),
+            "ADDED",
+            ingress,
+        );
+        let template = format!(
+            "name: Bench\nimage: paritytech/substrate-playground-template-bench@sha256:{}\ndescription: Used by load tests\n",
+            "0".repeat(64)
+        );
+        let config_maps = CONFIG_MAPS
+            .iter()
+            .map(|name| (*name, json!({})))
+            .chain(std::iter::once((
+                TEMPLATES_CONFIG_MAP,
+                json!({ TEMPLATE: template }),
+            )));
+        for (name, data) in config_maps {
+            let config_map = json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": { "name": name, "namespace": NAMESPACE },
+                "data": data,
+            });
+            state.write(
+                &cluster.events,
+                &format!("v1/{}/configmaps", NAMESPACE),
+                "ADDED",
+                config_map,
+            );
+        }
+        drop(state);
+        cluster
+    }
+
+    pub async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let target = match Target::parse(request.uri().path()) {
+            Some(target) => target,
+            None => return status(StatusCode::NOT_FOUND, "Unknown path"),
+        };
+        let params = query(request.uri().query().unwrap_or_default());
+        let selector = params.get("labelSelector").cloned().unwrap_or_default();
+        let dry_run = params.contains_key("dryRun");
+        let method = request.method().clone();
+        let content_type = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = match body::to_bytes(request.into_body()).await {
+            Ok(body) => body,
+            Err(err) => return status(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+        let value = || {
+            serde_json::from_slice::<Value>(&body)
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid body".to_string()))
+        };
+        let object = || match value()? {
+            object if object.is_object() => Ok(object),
+            _ => Err((StatusCode::BAD_REQUEST, "Expected an object".to_string())),
+        };
+        let outcome = match (method, target.name.clone(), target.subresource.as_deref()) {
+            (Method::GET, None, _) if params.get("watch").map(String::as_str) == Some("true") => {
+                let since = params
+                    .get("resourceVersion")
+                    .and_then(|version| version.parse().ok())
+                    .unwrap_or_default();
+                let timeout = params
+                    .get("timeoutSeconds")
+                    .and_then(|timeout| timeout.parse().ok())
+                    .map_or(WATCH_TIMEOUT, Duration::from_secs);
+                return self.watch(target, selector, since, timeout);
+            }
+            (Method::GET, None, _) => Ok(self.list(&target, &selector)),
+            (_, Some(_), Some("log")) => return Response::new(Body::empty()),
+            (_, Some(_), Some(subresource)) if subresource != "status" => Err((
+                StatusCode::NOT_FOUND,
+                format!("Subresource {} is not supported", subresource),
+            )),
+            (Method::GET, Some(name), _) => self.get(&target, &name),
+            (Method::POST, None, _) => {
+                object().and_then(|object| self.create(&target, object, dry_run))
+            }
+            (Method::PUT, Some(name), _) => {
+                object().and_then(|object| self.replace(&target, &name, object))
+            }
+            (Method::PATCH, Some(name), _) => {
+                value().and_then(|patch| self.patch(&target, &name, &content_type, patch))
+            }
+            (Method::DELETE, Some(name), _) => self.delete(&target, &name),
+            (Method::DELETE, None, _) => Ok(self.delete_collection(&target, &selector)),
+            (method, _, _) => Err((
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("{} is not supported", method),
+            )),
+        };
+        match outcome {
+            Ok(object) => json_response(StatusCode::OK, &object),
+            Err((code, message)) => status(code, &message),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("unpoisoned lock")
+    }
+
+    fn list(&self, target: &Target, selector: &str) -> Value {
+        let state = self.lock();
+        let items: Vec<&Value> = state
+            .objects
+            .get(&target.collection)
+            .map(|objects| {
+                objects
+                    .values()
+                    .filter(|object| matches(selector, object))
+                    .collect()
+            })
+            .unwrap_or_default();
+        json!({
+            "apiVersion": "v1",
+            "kind": "List",
+            "metadata": { "resourceVersion": state.version.to_string() },
+            "items": items,
+        })
+    }
+
+    fn get(&self, target: &Target, name: &str) -> Outcome {
+        self.lock()
+            .get(&target.collection, name)
+            .cloned()
+            .ok_or_else(|| not_found(target, name))
+    }
+
+    fn create(&self, target: &Target, mut object: Value, dry_run: bool) -> Outcome {
+        let name = match (
+            object["metadata"]["name"].as_str(),
+            object["metadata"]["generateName"].as_str(),
+        ) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(prefix)) => format!("{}{}", prefix, suffix()),
+            (None, None) => return Err((StatusCode::BAD_REQUEST, "Missing name".to_string())),
+        };
+        object["metadata"]["name"] = json!(name);
+        if let Some(namespace) = &target.namespace {
+            object["metadata"]["namespace"] = json!(namespace);
+        }
+        object["metadata"]["uid"] = json!(uid());
+        object["metadata"]["creationTimestamp"] = json!(timestamp(SystemTime::now()));
+        let mut state = self.lock();
+        if state.get(&target.collection, &name).is_some() {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("{} \"{}\" already exists", target.resource, name),
+            ));
+        }
+        if dry_run {
+            return Ok(object);
+        }
+        if target.resource == "pods" {
+            object["status"] = json!({ "phase": "Pending" });
+        }
+        let object = state.write(&self.events, &target.collection, "ADDED", object);
+        match target.resource.as_str() {
+            "pods" => self.start_pod_later(&target.collection, &object),
+            "statefulsets" => self.create_owned_pod(&mut state, &object),
+            _ => {}
+        }
+        Ok(object)
+    }
+
+    fn replace(&self, target: &Target, name: &str, mut object: Value) -> Outcome {
+        let mut state = self.lock();
+        let existing = state
+            .get(&target.collection, name)
+            .ok_or_else(|| not_found(target, name))?;
+        // Server managed fields can't be changed
+        for field in &["name", "namespace", "uid", "creationTimestamp"] {
+            object["metadata"][*field] = existing["metadata"][*field].clone();
+        }
+        Ok(state.write(&self.events, &target.collection, "MODIFIED", object))
+    }
+
+    fn patch(&self, target: &Target, name: &str, content_type: &str, patch: Value) -> Outcome {
+        let mut state = self.lock();
+        let existing = state.get(&target.collection, name).cloned();
+        let (mut object, kind) = match existing {
+            Some(object) => (object, "MODIFIED"),
+            // Server side apply creates missing objects
+            None if content_type.starts_with("application/apply-patch") => {
+                let mut object = json!({ "metadata": { "name": name } });
+                if let Some(namespace) = &target.namespace {
+                    object["metadata"]["namespace"] = json!(namespace);
+                }
+                object["metadata"]["uid"] = json!(uid());
+                object["metadata"]["creationTimestamp"] = json!(timestamp(SystemTime::now()));
+                (object, "ADDED")
+            }
+            None => return Err(not_found(target, name)),
+        };
+        if content_type.starts_with("application/json-patch") {
+            let patch = json_patch::from_value(patch)
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+            json_patch::patch(&mut object, &patch)
+                .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+        } else {
+            // Strategic merges are handled like merges, lists are replaced
+            json_patch::merge(&mut object, &patch);
+        }
+        Ok(state.write(&self.events, &target.collection, kind, object))
+    }
+
+    fn delete(&self, target: &Target, name: &str) -> Outcome {
+        let mut state = self.lock();
+        let object = state
+            .remove(&self.events, &target.collection, name)
+            .ok_or_else(|| not_found(target, name))?;
+        match target.resource.as_str() {
+            "statefulsets" => {
+                state.remove(
+                    &self.events,
+                    &pods_collection(&object),
+                    &owned_pod_name(&object),
+                );
+            }
+            // Pods of a `StatefulSet` are recreated
+            "pods" => {
+                let owner = object["metadata"]["ownerReferences"]
+                    .as_array()
+                    .and_then(|owners| owners.iter().find(|owner| owner["kind"] == "StatefulSet"))
+                    .and_then(|owner| owner["name"].as_str())
+                    .and_then(|owner| {
+                        let namespace = object["metadata"]["namespace"].as_str()?;
+                        state
+                            .get(&format!("apps/v1/{}/statefulsets", namespace), owner)
+                            .cloned()
+                    });
+                if let Some(stateful_set) = owner {
+                    self.create_owned_pod(&mut state, &stateful_set);
+                }
+            }
+            _ => {}
+        }
+        Ok(object)
+    }
+
+    fn delete_collection(&self, target: &Target, selector: &str) -> Value {
+        let names: Vec<String> = match self.list(target, selector)["items"].as_array() {
+            Some(items) => items
+                .iter()
+                .filter_map(|item| item["metadata"]["name"].as_str().map(str::to_string))
+                .collect(),
+            None => Vec::new(),
+        };
+        let items: Vec<Value> = names
+            .iter()
+            .filter_map(|name| self.delete(target, name).ok())
+            .collect();
+        json!({ "apiVersion": "v1", "kind": "List", "metadata": {}, "items": items })
+    }
+
+    /// Creates the pod of `stateful_set`, like its controller would
+    fn create_owned_pod(&self, state: &mut State, stateful_set: &Value) {
+        let template = &stateful_set["spec"]["template"];
+        let mut metadata = template["metadata"].clone();
+        metadata["name"] = json!(owned_pod_name(stateful_set));
+        metadata["namespace"] = stateful_set["metadata"]["namespace"].clone();
+        metadata["uid"] = json!(uid());
+        metadata["creationTimestamp"] = json!(timestamp(SystemTime::now()));
+        metadata["ownerReferences"] = json!([{
+            "apiVersion": "apps/v1",
+            "kind": "StatefulSet",
+            "name": stateful_set["metadata"]["name"],
+            "uid": stateful_set["metadata"]["uid"],
+            "controller": true,
+        }]);
+        let pod = json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": metadata,
+            "spec": template["spec"],
+            "status": { "phase": "Pending" },
+        });
+        let collection = pods_collection(stateful_set);
+        let pod = state.write(&self.events, &collection, "ADDED", pod);
+        self.start_pod_later(&collection, &pod);
+    }
+
+    /// Schedules `pod` on the single node and marks it running once `pod_startup` elapsed
+    fn start_pod_later(&self, collection: &str, pod: &Value) {
+        let cluster = self.clone();
+        let collection = collection.to_string();
+        let name = pod["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let uid = pod["metadata"]["uid"].clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(cluster.pod_startup).await;
+            let mut state = cluster.lock();
+            // Unless deleted or recreated meanwhile
+            let mut pod = match state.get(&collection, &name) {
+                Some(pod) if pod["metadata"]["uid"] == uid => pod.clone(),
+                _ => return,
+            };
+            let now = timestamp(SystemTime::now());
+            pod["spec"]["nodeName"] = json!(NODE);
+            let containers: Vec<Value> = pod["spec"]["containers"]
+                .as_array()
+                .map(|containers| {
+                    containers
+                        .iter()
+                        .map(|container| {
+                            json!({
+                                "name": container["name"],
+                                "image": container["image"],
+                                "imageID": "",
+                                "ready": true,
+                                "started": true,
+                                "restartCount": 0,
+                                "state": { "running": { "startedAt": now } },
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            pod["status"] = json!({
+                "phase": "Running",
+                "hostIP": "10.0.0.1",
+                "podIP": "10.1.0.1",
+                "startTime": now,
+                "conditions": [{ "type": "Ready", "status": "True", "lastTransitionTime": now }],
+                "containerStatuses": containers,
+            });
+            state.write(&cluster.events, &collection, "MODIFIED", pod);
+        });
+    }
+
+    /// Streams events of `target` that happened after `since`, as line delimited JSON
+    fn watch(
+        &self,
+        target: Target,
+        selector: String,
+        since: u64,
+        timeout: Duration,
+    ) -> Response<Body> {
+        let (mut sender, body) = Body::channel();
+        // Subscribe while locked so that no event is missed between the history and live ones
+        let (history, mut events, expired) = {
+            let state = self.lock();
+            let expired = since > 0
+                && state
+                    .history
+                    .front()
+                    .map_or(false, |event| event.version > since + 1);
+            let history: Vec<Event> = state
+                .history
+                .iter()
+                .filter(|event| event.version > since)
+                .cloned()
+                .collect();
+            (history, self.events.subscribe(), expired)
+        };
+        tokio::spawn(async move {
+            if expired {
+                // Makes the client list again
+                let gone = json!({
+                    "type": "ERROR",
+                    "object": status_object(StatusCode::GONE, "Too old resource version"),
+                });
+                let _ = sender.send_data(format!("{}\n", gone).into()).await;
+                return;
+            }
+            let deadline = tokio::time::sleep(timeout);
+            tokio::pin!(deadline);
+            let relevant = |event: &Event| {
+                event.collection == target.collection && matches(&selector, &event.object)
+            };
+            for event in history.into_iter().filter(|event| relevant(event)) {
+                if sender.send_data(line(&event).into()).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => return,
+                    event = events.recv() => match event {
+                        Ok(event) if relevant(&event) => {
+                            if sender.send_data(line(&event).into()).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) => {}
+                        // Clients resume from the last event they received
+                        Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return,
+                    },
+                }
+            }
+        });
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .expect("valid response")
+    }
+}
+
+fn line(event: &Event) -> String {
+    format!(
+        "{}\n",
+        json!({ "type": event.kind, "object": event.object })
+    )
+}
+
+fn pods_collection(stateful_set: &Value) -> String {
+    format!(
+        "v1/{}/pods",
+        stateful_set["metadata"]["namespace"]
+            .as_str()
+            .unwrap_or_default()
+    )
+}
+
+/// Pods of `StatefulSet`s are named after their ordinal
+fn owned_pod_name(stateful_set: &Value) -> String {
+    format!(
+        "{}-0",
+        stateful_set["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+    )
+}
+
+/// Returns true if labels of `object` match `selector`, e.g. `app=playground,!migration`
+fn matches(selector: &str, object: &Value) -> bool {
+    let labels = &object["metadata"]["labels"];
+    let label = |key: &str| labels[key.trim()].as_str();
+    selector
+        .split(',')
+        .map(str::trim)
+        .filter(|requirement| !requirement.is_empty())
+        .all(|requirement| {
+            if let Some((key, value)) = requirement.split_once("!=") {
+                label(key) != Some(value.trim())
+            } else if let Some((key, value)) = requirement.split_once('=') {
+                label(key) == Some(value.trim_start_matches('=').trim())
+            } else if let Some(key) = requirement.strip_prefix('!') {
+                label(key).is_none()
+            } else {
+                label(requirement).is_some()
+            }
+        })
+}
+
+/// Parses a query string, e.g. `labelSelector=app%3Dplayground&watch=true`
+pub fn query(query: &str) -> BTreeMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+fn decode(value: &str) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap_or_default());
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn not_found(target: &Target, name: &str) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("{} \"{}\" not found", target.resource, name),
+    )
+}
+
+fn status_object(code: StatusCode, message: &str) -> Value {
+    let reason = match code {
+        StatusCode::NOT_FOUND => "NotFound",
+        StatusCode::CONFLICT => "AlreadyExists",
+        StatusCode::GONE => "Expired",
+        StatusCode::UNPROCESSABLE_ENTITY => "Invalid",
+        StatusCode::METHOD_NOT_ALLOWED => "MethodNotAllowed",
+        _ => "BadRequest",
+    };
+    json!({
+        "apiVersion": "v1",
+        "kind": "Status",
+        "metadata": {},
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": code.as_u16(),
+    })
+}
+
+/// Kubernetes flavored error
+fn status(code: StatusCode, message: &str) -> Response<Body> {
+    json_response(code, &status_object(code, message))
+}
+
+pub fn json_response(code: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .expect("valid response")
+}
+
+fn uid() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Random suffix of generated names
+fn suffix() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(5)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect()
+}
+
+/// Formats `time` as RFC 3339, e.g. `2021-09-01T12:00:00Z`
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let seconds_of_day = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        seconds_of_day % 3_600 / 60,
+        seconds_of_day % 60
+    )
+}
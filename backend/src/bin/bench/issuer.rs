@@ -0,0 +1,86 @@
+//! Stand-in for an OpenID Connect issuer, served under `PATH`
+//!
+//! Users are authenticated as soon as they reach the authorization endpoint, as the `login_hint` they provide. Access
+//! tokens are user ids, that the userinfo endpoint hands back as is. `ADMIN` is part of `ADMINS_GROUP`.
+use crate::cluster::json_response;
+use hyper::{body, header::AUTHORIZATION, Body, Method, Request, Response, StatusCode};
+use serde_json::json;
+
+pub const PATH: &str = "/oidc";
+pub const ADMIN: &str = "bench-admin";
+pub const ADMINS_GROUP: &str = "bench-admins";
+
+pub async fn handle(issuer_url: &str, request: Request<Body>) -> Response<Body> {
+    let path = request.uri().path().trim_start_matches(PATH).to_string();
+    match (request.method(), path.as_str()) {
+        (&Method::GET, "/.well-known/openid-configuration") => json_response(
+            StatusCode::OK,
+            &json!({
+                "issuer": issuer_url,
+                "authorization_endpoint": format!("{}/authorize", issuer_url),
+                "token_endpoint": format!("{}/token", issuer_url),
+                "userinfo_endpoint": format!("{}/userinfo", issuer_url),
+            }),
+        ),
+        (&Method::GET, "/authorize") => {
+            let params = crate::cluster::query(request.uri().query().unwrap_or_default());
+            match (params.get("redirect_uri"), params.get("login_hint")) {
+                (Some(redirect_uri), Some(user)) => {
+                    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+                    let location = format!(
+                        "{}{}code={}&state={}",
+                        redirect_uri,
+                        separator,
+                        user,
+                        params.get("state").cloned().unwrap_or_default()
+                    );
+                    Response::builder()
+                        .status(StatusCode::FOUND)
+                        .header("Location", location)
+                        .body(Body::empty())
+                        .expect("valid response")
+                }
+                _ => error(StatusCode::BAD_REQUEST, "invalid_request"),
+            }
+        }
+        (&Method::POST, "/token") => {
+            let form = match body::to_bytes(request.into_body()).await {
+                Ok(form) => String::from_utf8_lossy(&form).into_owned(),
+                Err(_) => return error(StatusCode::BAD_REQUEST, "invalid_request"),
+            };
+            match crate::cluster::query(&form).get("code") {
+                Some(code) => json_response(
+                    StatusCode::OK,
+                    &json!({ "access_token": code, "token_type": "Bearer", "expires_in": 3600 }),
+                ),
+                None => error(StatusCode::BAD_REQUEST, "invalid_grant"),
+            }
+        }
+        (&Method::GET, "/userinfo") => {
+            let user = request
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            match user {
+                Some(user) => {
+                    let groups = if user == ADMIN {
+                        vec![ADMINS_GROUP]
+                    } else {
+                        Vec::new()
+                    };
+                    json_response(
+                        StatusCode::OK,
+                        &json!({ "sub": user, "preferred_username": user, "groups": groups }),
+                    )
+                }
+                None => error(StatusCode::UNAUTHORIZED, "invalid_token"),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "not_found"),
+    }
+}
+
+fn error(code: StatusCode, error: &str) -> Response<Body> {
+    json_response(code, &json!({ "error": error }))
+}
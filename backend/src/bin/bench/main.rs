@@ -0,0 +1,387 @@
+//! Load testing harness
+//!
+//! Runs the playground against an in-memory Kubernetes API and OIDC issuer (see `cluster` and `issuer`), then simulates
+//! `BENCH_USERS` users (defaults to 10) concurrently creating a session, polling it until running then deleting it,
+//! `BENCH_ITERATIONS` times (defaults to 3). Meanwhile an admin keeps listing all sessions. Session pods start
+//! `BENCH_POD_STARTUP` milliseconds (defaults to 500) after being created.
+//!
+//! Latencies are reported per endpoint. `BENCH_BUDGETS` holds p99 budgets in milliseconds, e.g.
+//! `list_sessions=200,get_current_session=100`. The run fails if a budget is exceeded or if calls failed.
+//!
+//! The playground binary is expected next to this one, unless set via `BENCH_PLAYGROUND`:
+//! `cargo build --release && target/release/bench`.
+mod cluster;
+mod issuer;
+mod stats;
+mod user;
+
+use cluster::Cluster;
+use futures::future::join_all;
+use hyper::{
+    client::HttpConnector,
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Server,
+};
+use serde_json::json;
+use stats::Recorder;
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    env,
+    error::Error,
+    fs,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use user::User;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Polls after which a session is considered as failed to start
+const MAX_POLLS: usize = 120;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Settings {
+    users: usize,
+    iterations: usize,
+    pod_startup: Duration,
+    playground: PathBuf,
+    /// p99 budgets, per endpoint
+    budgets: BTreeMap<String, Duration>,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{}: invalid value '{}'", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+impl Settings {
+    fn from_env() -> Result<Self, Box<dyn Error>> {
+        let playground = match env::var("BENCH_PLAYGROUND") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => env::current_exe()?.with_file_name("playground"),
+        };
+        let budgets = env::var("BENCH_BUDGETS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|budget| !budget.trim().is_empty())
+            .map(|budget| {
+                let invalid = || format!("BENCH_BUDGETS: invalid budget '{}'", budget);
+                let (endpoint, millis) = budget.trim().split_once('=').ok_or_else(invalid)?;
+                let millis = millis.parse().map_err(|_| invalid())?;
+                Ok((endpoint.to_string(), Duration::from_millis(millis)))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Settings {
+            users: env_or("BENCH_USERS", 10)?,
+            iterations: env_or("BENCH_ITERATIONS", 3)?,
+            pod_startup: Duration::from_millis(env_or("BENCH_POD_STARTUP", 500)?),
+            playground,
+            budgets,
+        })
+    }
+}
+
+/// Serves the fake cluster and issuer. Returns their base url.
+fn serve(listener: TcpListener, cluster: Cluster) -> Result<String, Box<dyn Error>> {
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let issuer_url = format!("{}{}", base_url, issuer::PATH);
+    let service = make_service_fn(move |_| {
+        let cluster = cluster.clone();
+        let issuer_url = issuer_url.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let cluster = cluster.clone();
+                let issuer_url = issuer_url.clone();
+                async move {
+                    Ok::<_, Infallible>(if request.uri().path().starts_with(issuer::PATH) {
+                        issuer::handle(&issuer_url, request).await
+                    } else {
+                        cluster.handle(request).await
+                    })
+                }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)?.serve(service);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            eprintln!("Fake cluster failed: {}", err);
+        }
+    });
+    Ok(base_url)
+}
+
+fn free_port() -> std::io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn kubeconfig(cluster_url: &str) -> String {
+    format!(
+        "apiVersion: v1
+kind: Config
+clusters:
+- name: bench
+  cluster:
+    server: {}
+contexts:
+- name: bench
+  context:
+    cluster: bench
+    namespace: {}
+    user: bench
+current-context: bench
+users:
+- name: bench
+  user: {{}}
+",
+        cluster_url,
+        cluster::NAMESPACE
+    )
+}
+
+fn spawn_playground(
+    settings: &Settings,
+    fake_url: &str,
+    kubeconfig: &Path,
+    port: u16,
+) -> Result<Child, Box<dyn Error>> {
+    let unlimited = "1000000";
+    let child = Command::new(&settings.playground)
+        .env("KUBECONFIG", kubeconfig)
+        .env("ROCKET_ADDRESS", "127.0.0.1")
+        .env("ROCKET_PORT", port.to_string())
+        .env("ROCKET_LOG", "critical")
+        .env("WS_PORT", free_port()?.to_string())
+        .env("BASE_DOMAINS", "bench.localhost")
+        .env("GITHUB_CLIENT_ID", "bench")
+        .env("GITHUB_CLIENT_SECRET", "bench")
+        .env("OIDC_ISSUER_URL", format!("{}{}", fake_url, issuer::PATH))
+        .env("OIDC_CLIENT_ID", "bench")
+        .env("OIDC_CLIENT_SECRET", "bench")
+        .env("OIDC_ROLES", format!("{}=admin", issuer::ADMINS_GROUP))
+        .env("SESSION_DEFAULT_DURATION", "60")
+        .env("SESSION_MAX_DURATION", "1440")
+        .env("SESSION_DEFAULT_POOL_AFFINITY", cluster::POOL)
+        .env("SESSION_DEFAULT_MAX_PER_NODE", unlimited)
+        .env("RATE_LIMIT_READS", unlimited)
+        .env("RATE_LIMIT_MUTATIONS", unlimited)
+        .env("RATE_LIMIT_SESSION_CREATIONS", unlimited)
+        .env(
+            "RUST_LOG",
+            env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string()),
+        )
+        .spawn()
+        .map_err(|err| format!("Can't run {}: {}", settings.playground.display(), err))?;
+    Ok(child)
+}
+
+async fn wait_until_ready(
+    client: &Client<HttpConnector>,
+    base_url: &str,
+    playground: &mut Child,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = playground.try_wait()? {
+            return Err(format!("Playground exited with {}", status).into());
+        }
+        if client
+            .get(format!("{}/api/v1/", base_url).parse()?)
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        if start.elapsed() > STARTUP_TIMEOUT {
+            return Err("Playground did not start".into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn simulate(mut user: User, iterations: usize, recorder: &Recorder) {
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let conf = json!({ "template": cluster::TEMPLATE });
+        if let Err(err) = user
+            .call(
+                recorder,
+                "create_current_session",
+                Method::PUT,
+                "/session",
+                Some(conf),
+            )
+            .await
+        {
+            eprintln!("Failed to create session of {}: {}", user.id, err);
+            continue;
+        }
+        // Poll like the frontend does until the session is running
+        let mut running = false;
+        for _ in 0..MAX_POLLS {
+            match user
+                .call(
+                    recorder,
+                    "get_current_session",
+                    Method::GET,
+                    "/session",
+                    None,
+                )
+                .await
+            {
+                Ok(session) if session["pod"]["phase"] == "Running" => {
+                    running = true;
+                    break;
+                }
+                Ok(_) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(err) => {
+                    eprintln!("Failed to get session of {}: {}", user.id, err);
+                    break;
+                }
+            }
+        }
+        recorder.record("session_startup", start.elapsed(), running);
+        if let Err(err) = user
+            .call(
+                recorder,
+                "delete_current_session",
+                Method::DELETE,
+                "/session",
+                None,
+            )
+            .await
+        {
+            eprintln!("Failed to delete session of {}: {}", user.id, err);
+        }
+    }
+}
+
+/// Lists all sessions until `done`
+async fn observe(mut admin: User, recorder: &Recorder, done: &AtomicBool) {
+    while !done.load(Ordering::SeqCst) {
+        if let Err(err) = admin
+            .call(recorder, "list_sessions", Method::GET, "/sessions", None)
+            .await
+        {
+            eprintln!("Failed to list sessions: {}", err);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn run(
+    settings: &Settings,
+    client: Client<HttpConnector>,
+    base_url: &str,
+) -> Result<Arc<Recorder>, Box<dyn Error>> {
+    let recorder = Arc::new(Recorder::default());
+    let admin = User::login(client.clone(), base_url, issuer::ADMIN, &recorder).await?;
+    let done = Arc::new(AtomicBool::new(false));
+    let observer = {
+        let recorder = recorder.clone();
+        let done = done.clone();
+        tokio::spawn(async move { observe(admin, &recorder, &done).await })
+    };
+    let users = (0..settings.users).map(|i| {
+        let client = client.clone();
+        let base_url = base_url.to_string();
+        let recorder = recorder.clone();
+        let iterations = settings.iterations;
+        tokio::spawn(async move {
+            let id = format!("bench-user-{}", i);
+            match User::login(client, &base_url, &id, &recorder).await {
+                Ok(user) => simulate(user, iterations, &recorder).await,
+                Err(err) => eprintln!("Failed to log {} in: {}", id, err),
+            }
+        })
+    });
+    join_all(users).await;
+    done.store(true, Ordering::SeqCst);
+    observer.await?;
+    Ok(recorder)
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let settings = Settings::from_env()?;
+    let fake_url = serve(
+        TcpListener::bind("127.0.0.1:0")?,
+        Cluster::new(settings.pod_startup),
+    )?;
+    let kubeconfig_path = env::temp_dir().join(format!("playground-bench-{}", std::process::id()));
+    fs::write(&kubeconfig_path, kubeconfig(&fake_url))?;
+    let port = free_port()?;
+    let mut playground = spawn_playground(&settings, &fake_url, &kubeconfig_path, port)?;
+    let base_url = format!("http://127.0.0.1:{}", port);
+    let client = Client::new();
+    let result = match wait_until_ready(&client, &base_url, &mut playground).await {
+        Ok(()) => run(&settings, client, &base_url).await,
+        Err(err) => Err(err),
+    };
+    let _ = playground.kill();
+    let _ = fs::remove_file(&kubeconfig_path);
+    let reports = result?.reports();
+
+    println!(
+        "{:<24} {:>7} {:>7} {:>9} {:>9} {:>9}",
+        "endpoint", "calls", "errors", "p50", "p99", "max"
+    );
+    for report in &reports {
+        println!(
+            "{:<24} {:>7} {:>7} {:>9} {:>9} {:>9}",
+            report.endpoint,
+            report.calls,
+            report.errors,
+            millis(report.p50),
+            millis(report.p99),
+            millis(report.max)
+        );
+    }
+
+    let mut failures = Vec::new();
+    for report in &reports {
+        if report.errors > 0 {
+            failures.push(format!(
+                "{}: {} failed call(s)",
+                report.endpoint, report.errors
+            ));
+        }
+        if let Some(budget) = settings.budgets.get(report.endpoint) {
+            if report.p99 > *budget {
+                failures.push(format!(
+                    "{}: p99 of {} exceeds its {} budget",
+                    report.endpoint,
+                    millis(report.p99),
+                    millis(*budget)
+                ));
+            }
+        }
+    }
+    for endpoint in settings.budgets.keys() {
+        if !reports.iter().any(|report| report.endpoint == endpoint) {
+            failures.push(format!("{}: not called, budget can't be checked", endpoint));
+        }
+    }
+    if !failures.is_empty() {
+        for failure in &failures {
+            eprintln!("{}", failure);
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
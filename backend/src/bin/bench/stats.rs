@@ -0,0 +1,61 @@
+//! Latencies of API calls, per endpoint
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+#[derive(Default)]
+struct Samples {
+    durations: Vec<Duration>,
+    errors: usize,
+}
+
+#[derive(Default)]
+pub struct Recorder(Mutex<BTreeMap<&'static str, Samples>>);
+
+/// Summary of calls to an endpoint
+#[derive(Debug)]
+pub struct Report {
+    pub endpoint: &'static str,
+    pub calls: usize,
+    pub errors: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Recorder {
+    pub fn record(&self, endpoint: &'static str, duration: Duration, success: bool) {
+        let mut samples = self.0.lock().expect("unpoisoned lock");
+        let samples = samples.entry(endpoint).or_default();
+        samples.durations.push(duration);
+        if !success {
+            samples.errors += 1;
+        }
+    }
+
+    pub fn reports(&self) -> Vec<Report> {
+        let samples = self.0.lock().expect("unpoisoned lock");
+        samples
+            .iter()
+            .map(|(endpoint, samples)| {
+                let mut durations = samples.durations.clone();
+                durations.sort();
+                Report {
+                    endpoint,
+                    calls: durations.len(),
+                    errors: samples.errors,
+                    p50: percentile(&durations, 50),
+                    p99: percentile(&durations, 99),
+                    max: durations.last().copied().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Nearest-rank percentile of sorted `durations`
+fn percentile(durations: &[Duration], percentile: usize) -> Duration {
+    if durations.is_empty() {
+        return Duration::default();
+    }
+    let rank = (durations.len() * percentile + 99) / 100;
+    durations[rank.max(1) - 1]
+}
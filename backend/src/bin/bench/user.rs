@@ -0,0 +1,160 @@
+//! A simulated user, calling the playground API like the frontend does
+use crate::stats::Recorder;
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE},
+    Body, Client, Method, Request, Response, Uri,
+};
+use serde_json::Value;
+use std::{collections::BTreeMap, time::Instant};
+
+/// See `csrf::HEADER_CSRF`
+const HEADER_CSRF: &str = "X-CSRF-Token";
+const COOKIE_CSRF: &str = "csrf";
+
+pub struct User {
+    pub id: String,
+    base_url: String,
+    client: Client<HttpConnector>,
+    cookies: BTreeMap<String, String>,
+}
+
+impl User {
+    /// Logs `id` in via the OIDC flow, see `issuer`
+    pub async fn login(
+        client: Client<HttpConnector>,
+        base_url: &str,
+        id: &str,
+        recorder: &Recorder,
+    ) -> Result<Self, String> {
+        let mut user = User {
+            id: id.to_string(),
+            base_url: base_url.to_string(),
+            client,
+            cookies: BTreeMap::new(),
+        };
+        let start = Instant::now();
+        let result = user.follow_login().await;
+        recorder.record("login", start.elapsed(), result.is_ok());
+        result.map(|_| user)
+    }
+
+    async fn follow_login(&mut self) -> Result<(), String> {
+        let authorization = self
+            .redirect(&format!("{}/api/v1/login/oidc", self.base_url))
+            .await?;
+        let callback = self
+            .redirect(&format!("{}&login_hint={}", authorization, self.id))
+            .await?;
+        // The callback is advertised on the playground host, that is not the one it listens to
+        let callback: Uri = callback.parse().map_err(|_| "Invalid callback")?;
+        let path = callback
+            .path_and_query()
+            .ok_or("Invalid callback")?
+            .as_str()
+            .to_string();
+        self.redirect(&format!("{}{}", self.base_url, path)).await?;
+        if self.cookies.contains_key(COOKIE_CSRF) {
+            Ok(())
+        } else {
+            Err("No session cookie after login".to_string())
+        }
+    }
+
+    /// Returns where `uri` redirects to
+    async fn redirect(&mut self, uri: &str) -> Result<String, String> {
+        let request = self
+            .request(Method::GET, uri)
+            .body(Body::empty())
+            .map_err(|err| err.to_string())?;
+        let response = self.send(request).await?;
+        response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| format!("{} returned {} with no redirection", uri, response.status()))
+    }
+
+    fn request(&self, method: Method, uri: &str) -> hyper::http::request::Builder {
+        let cookies: Vec<String> = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(COOKIE, cookies.join("; "));
+        if let Some(csrf) = self.cookies.get(COOKIE_CSRF) {
+            builder = builder.header(HEADER_CSRF, csrf);
+        }
+        builder
+    }
+
+    async fn send(&mut self, request: Request<Body>) -> Result<Response<Body>, String> {
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+        for cookie in response.headers().get_all(SET_COOKIE) {
+            let cookie = cookie.to_str().unwrap_or_default();
+            let pair = cookie.split(';').next().unwrap_or_default();
+            if let Some((name, value)) = pair.split_once('=') {
+                if value.is_empty() {
+                    self.cookies.remove(name.trim());
+                } else {
+                    self.cookies
+                        .insert(name.trim().to_string(), value.trim().to_string());
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    /// Calls `path` of the API, recorded as `endpoint`. Returns the `result` of the call.
+    pub async fn call(
+        &mut self,
+        recorder: &Recorder,
+        endpoint: &'static str,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, String> {
+        let start = Instant::now();
+        let result = self.call_unrecorded(method, path, body).await;
+        recorder.record(endpoint, start.elapsed(), result.is_ok());
+        result
+    }
+
+    async fn call_unrecorded(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, String> {
+        let builder = self.request(method, &format!("{}/api/v1{}", self.base_url, path));
+        let request = match body {
+            Some(body) => builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        }
+        .map_err(|err| err.to_string())?;
+        let response = self.send(request).await?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body())
+            .await
+            .map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            return Err(format!("{} returned {}", path, status));
+        }
+        let mut value: Value = serde_json::from_slice(&bytes).map_err(|err| err.to_string())?;
+        match value.get("error") {
+            Some(error) => Err(error.to_string()),
+            None => Ok(value["result"].take()),
+        }
+    }
+}
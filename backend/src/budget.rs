@@ -0,0 +1,368 @@
+//! Monthly budgets of session minutes, per role or GitHub organization
+//!
+//! Configured via `BUDGETS`, e.g. `role:user=60000,org:substrate-developer-academy=20000`. Once a budget is exhausted new
+//! sessions are denied, or scheduled on `BUDGET_FALLBACK_POOL` if set. Crossing one of `BUDGET_THRESHOLDS` (percentages)
+//! is reported once per month. Organizations can also define their own budget, see `types::Org`.
+//! Consumption is stored as a `BudgetState`, shared by all replicas. It is accounted by the leader from running
+//! sessions, see `BudgetState::account`.
+use crate::{clock::civil_date, types::LoggedUser};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    Role(String),
+    Organization(String),
+}
+
+impl Scope {
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':')? {
+            ("role", role) => Some(Scope::Role(role.to_string())),
+            ("org", org) => Some(Scope::Organization(org.to_string())),
+            _ => None,
+        }
+    }
+
+    fn matches_user(&self, user: &LoggedUser) -> bool {
+        match self {
+            Scope::Role(role) => user.role() == role,
+            Scope::Organization(org) => user.organizations.contains(org),
+        }
+    }
+
+    fn matches_consumer(&self, consumer: &Consumer) -> bool {
+        match self {
+            Scope::Role(role) => consumer.role == role,
+            Scope::Organization(org) => consumer.organizations.contains(org),
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Scope::Role(role) => write!(f, "role:{}", role),
+            Scope::Organization(org) => write!(f, "org:{}", org),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Budget {
+    pub scope: Scope,
+    /// Session minutes available per calendar month (UTC)
+    pub minutes: u64,
+}
+
+/// Outcome of checking budgets before creating a session
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// Budget is exhausted, the session must be scheduled on this pool
+    Downgrade(String),
+    Deny(String),
+}
+
+/// A running session, as accounted against budgets
+pub struct Consumer<'a> {
+    pub role: &'a str,
+    pub organizations: &'a [String],
+    pub started_at: SystemTime,
+}
+
+/// Consumption of budgets for the current month
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BudgetState {
+    /// Start of the month consumption applies to, in seconds since epoch
+    pub month: u64,
+    /// Seconds since epoch of the last accounting
+    pub accounted_at: u64,
+    /// Session seconds consumed, per scope
+    pub consumed: BTreeMap<String, u64>,
+    /// Highest threshold notified, per scope
+    pub notified: BTreeMap<String, u8>,
+}
+
+impl BudgetState {
+    /// Adds the time `consumers` ran since the last accounting to matching `budgets`. Consumption is reset every month.
+    pub fn account(&mut self, budgets: &[Budget], consumers: &[Consumer], now: SystemTime) {
+        let now_secs = to_secs(now);
+        let month = to_secs(month_start(now));
+        if self.month != month {
+            let accounted_at = match self.accounted_at {
+                0 => 0,
+                accounted_at => accounted_at.max(month),
+            };
+            *self = BudgetState {
+                month,
+                accounted_at,
+                ..Default::default()
+            };
+        }
+        // The first accounting only sets the starting point
+        if self.accounted_at != 0 {
+            for budget in budgets {
+                let seconds: u64 = consumers
+                    .iter()
+                    .filter(|consumer| budget.scope.matches_consumer(consumer))
+                    .map(|consumer| {
+                        now_secs.saturating_sub(self.accounted_at.max(to_secs(consumer.started_at)))
+                    })
+                    .sum();
+                *self.consumed.entry(budget.scope.to_string()).or_default() += seconds;
+            }
+        }
+        self.accounted_at = now_secs;
+    }
+
+    /// Minutes consumed this month by sessions matching `scope`
+    fn minutes(&self, scope: &Scope) -> u64 {
+        self.consumed
+            .get(&scope.to_string())
+            .copied()
+            .unwrap_or_default()
+            / 60
+    }
+}
+
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the first instant of the month (UTC) `time` falls in
+fn month_start(time: SystemTime) -> SystemTime {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let (_, _, day) = civil_date(time);
+    UNIX_EPOCH + Duration::from_secs((days - u64::from(day - 1)) * 86400)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Budgets {
+    pub budgets: Vec<Budget>,
+    /// Percentages of a budget that trigger a notification
+    pub thresholds: Vec<u8>,
+    pub fallback_pool: Option<String>,
+}
+
+impl Budgets {
+    pub fn from_env() -> Self {
+        let budgets = env::var("BUDGETS")
+            .map(|value| {
+                value
+                    .split(',')
+                    .filter_map(|budget| {
+                        let (scope, minutes) = budget.trim().split_once('=')?;
+                        Some(Budget {
+                            scope: Scope::parse(scope)?,
+                            minutes: minutes.parse().ok()?,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut thresholds: Vec<u8> = env::var("BUDGET_THRESHOLDS")
+            .unwrap_or_else(|_| "80,100".to_string())
+            .split(',')
+            .filter_map(|threshold| threshold.trim().parse().ok())
+            .collect();
+        thresholds.sort_unstable();
+        Budgets {
+            budgets,
+            thresholds,
+            fallback_pool: env::var("BUDGET_FALLBACK_POOL").ok(),
+        }
+    }
+
+    /// Returns configured budgets followed by `extra` ones
+    pub fn all(&self, extra: &[Budget]) -> Vec<Budget> {
+        self.budgets.iter().chain(extra).cloned().collect()
+    }
+
+    /// Decides if `user` can create a new session given `state`, considering `extra` budgets on top of configured ones
+    pub fn check(&self, state: &BudgetState, user: &LoggedUser, extra: &[Budget]) -> Decision {
+        match self.budgets.iter().chain(extra).find(|budget| {
+            budget.scope.matches_user(user) && state.minutes(&budget.scope) >= budget.minutes
+        }) {
+            None => Decision::Allow,
+            Some(budget) => match &self.fallback_pool {
+                Some(pool) => Decision::Downgrade(pool.clone()),
+                None => Decision::Deny(format!(
+                    "monthly budget of {} minutes for {} is exhausted",
+                    budget.minutes, budget.scope
+                )),
+            },
+        }
+    }
+
+    /// Returns budgets (including `extra` ones) that crossed a new threshold since last recorded in `state`, with the
+    /// crossed threshold. `state` then records them as notified.
+    pub fn crossed_thresholds(
+        &self,
+        state: &mut BudgetState,
+        extra: &[Budget],
+    ) -> Vec<(Budget, u8)> {
+        let mut crossed = Vec::new();
+        for budget in self
+            .budgets
+            .iter()
+            .chain(extra)
+            .filter(|budget| budget.minutes > 0)
+        {
+            let percentage = state.minutes(&budget.scope) * 100 / budget.minutes;
+            let threshold = match self
+                .thresholds
+                .iter()
+                .rev()
+                .find(|threshold| u64::from(**threshold) <= percentage)
+            {
+                Some(threshold) => *threshold,
+                None => continue,
+            };
+            let key = budget.scope.to_string();
+            if state
+                .notified
+                .get(&key)
+                .map_or(true, |previous| *previous < threshold)
+            {
+                state.notified.insert(key, threshold);
+                crossed.push((budget.clone(), threshold));
+            }
+        }
+        crossed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86400;
+    /// 2021-03-15
+    const MID_MARCH: u64 = 18701 * DAY;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn budget(scope: &str, minutes: u64) -> Budget {
+        Budget {
+            scope: Scope::parse(scope).unwrap(),
+            minutes,
+        }
+    }
+
+    fn user(organizations: &[&str]) -> LoggedUser {
+        LoggedUser {
+            id: "jdoe".to_string(),
+            admin: false,
+            organizations: organizations.iter().map(|org| org.to_string()).collect(),
+            pool_affinity: None,
+            can_customize_duration: false,
+            can_customize_pool_affinity: false,
+            onboarding: Default::default(),
+            accepted_terms_version: None,
+            org_role: None,
+        }
+    }
+
+    #[test]
+    fn finds_month_start() {
+        assert_eq!(month_start(at(MID_MARCH)), at(MID_MARCH - 14 * DAY));
+        assert_eq!(
+            month_start(at(MID_MARCH - 14 * DAY)),
+            at(MID_MARCH - 14 * DAY)
+        );
+        // 2020-02-29
+        assert_eq!(month_start(at(18321 * DAY + 3600)), at(18293 * DAY));
+    }
+
+    #[test]
+    fn accounts_running_sessions() {
+        let budgets = vec![budget("org:a", 10), budget("org:b", 10)];
+        let organizations = vec!["a".to_string()];
+        let consumers = vec![
+            Consumer {
+                role: "user",
+                organizations: &organizations,
+                started_at: at(MID_MARCH - 3600),
+            },
+            // Started after the last accounting
+            Consumer {
+                role: "user",
+                organizations: &organizations,
+                started_at: at(MID_MARCH + 540),
+            },
+        ];
+        let mut state = BudgetState::default();
+        state.account(&budgets, &consumers, at(MID_MARCH));
+        assert!(state.consumed.is_empty());
+        state.account(&budgets, &consumers, at(MID_MARCH + 600));
+        assert_eq!(state.consumed.get("org:a"), Some(&660));
+        assert_eq!(state.consumed.get("org:b"), Some(&0));
+        assert_eq!(state.minutes(&Scope::parse("org:a").unwrap()), 11);
+
+        // Reset once the month changes
+        state.account(&budgets, &consumers, at(MID_MARCH + 20 * DAY));
+        assert_eq!(state.month, MID_MARCH + 17 * DAY);
+        assert_eq!(state.consumed.get("org:a"), Some(&(2 * 3 * DAY)));
+    }
+
+    #[test]
+    fn checks_exhausted_budgets() {
+        let mut budgets = Budgets {
+            budgets: vec![budget("org:a", 10)],
+            thresholds: vec![80, 100],
+            fallback_pool: None,
+        };
+        let mut state = BudgetState::default();
+        state.consumed.insert("org:a".to_string(), 10 * 60);
+        assert_eq!(budgets.check(&state, &user(&["b"]), &[]), Decision::Allow);
+        assert!(matches!(
+            budgets.check(&state, &user(&["a"]), &[]),
+            Decision::Deny(_)
+        ));
+        // Extra budgets count too
+        assert!(matches!(
+            budgets.check(&state, &user(&["b"]), &[budget("org:b", 0)]),
+            Decision::Deny(_)
+        ));
+        budgets.fallback_pool = Some("small".to_string());
+        assert_eq!(
+            budgets.check(&state, &user(&["a"]), &[]),
+            Decision::Downgrade("small".to_string())
+        );
+    }
+
+    #[test]
+    fn notifies_thresholds_once() {
+        let budgets = Budgets {
+            budgets: vec![budget("org:a", 10)],
+            thresholds: vec![80, 100],
+            fallback_pool: None,
+        };
+        let mut state = BudgetState::default();
+        state.consumed.insert("org:a".to_string(), 8 * 60);
+        let crossed = budgets.crossed_thresholds(&mut state, &[]);
+        assert_eq!(crossed.len(), 1);
+        assert_eq!(crossed[0].1, 80);
+        assert!(budgets.crossed_thresholds(&mut state, &[]).is_empty());
+
+        state.consumed.insert("org:a".to_string(), 12 * 60);
+        let crossed = budgets.crossed_thresholds(&mut state, &[]);
+        assert_eq!(crossed.len(), 1);
+        assert_eq!(crossed[0].1, 100);
+        assert_eq!(state.notified.get("org:a"), Some(&100));
+    }
+}
@@ -0,0 +1,66 @@
+//! Source of the current time
+//!
+//! Time dependent decisions, e.g. session expiry, read the time from a `Clock` rather than from the system, so that
+//! tests can simulate its passage with a `FakeClock`.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Returns the time elapsed since `time`, `None` if it is in the future
+    fn elapsed(&self, time: SystemTime) -> Option<Duration> {
+        self.now().duration_since(time).ok()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Returns the `(year, month, day)` of `time`, in UTC
+pub fn civil_date(time: SystemTime) -> (i64, u32, u32) {
+    let days = (time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400) as i64;
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug)]
+pub struct FakeClock(std::sync::Mutex<SystemTime>);
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(now: SystemTime) -> Self {
+        FakeClock(std::sync::Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,297 @@
+//! Configuration file
+//!
+//! The backend is configured via env variables. They can also be declared in a YAML file, passed via `--config <path>`
+//! or `CONFIG_FILE`, mapping variable names to values. Mappings and sequences are passed on as YAML, e.g.
+//!
+//! ```yaml
+//! SESSION_DEFAULT_DURATION: 60
+//! SESSION_ROLE_DEFAULTS:
+//!   admin:
+//!     duration: 240
+//! ```
+//!
+//! Env variables take precedence over the file. The resulting configuration is validated at startup, all errors being
+//! reported at once. `--check-config` only validates the configuration then exits.
+use crate::{
+    alerts::AlertThresholds,
+    dns::Dns,
+    scheduling::Scheduling,
+    types::{RoleDefaults, StaticPool},
+};
+use serde_yaml::Value;
+use std::{collections::BTreeMap, env, fs};
+
+pub const CHECK_FLAG: &str = "--check-config";
+const CONFIG_FLAG: &str = "--config";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Text,
+    Integer,
+    Float,
+    Boolean,
+    /// A mapping or a sequence
+    Yaml,
+}
+
+impl Kind {
+    fn describe(&self) -> &'static str {
+        match self {
+            Kind::Text => "a string",
+            Kind::Integer => "a positive integer",
+            Kind::Float => "a number",
+            Kind::Boolean => "a boolean",
+            Kind::Yaml => "a mapping or a sequence",
+        }
+    }
+}
+
+/// Known settings, with their kind and whether they are required
+const SETTINGS: &[(&str, Kind, bool)] = &[
+    ("API_LEGACY_SUNSET", Kind::Text, false),
+    ("ALERT_DEPLOY_DURATION_SECONDS", Kind::Float, false),
+    ("ALERT_DEPLOY_FAILURE_RATIO", Kind::Float, false),
+    ("ALERT_UNDEPLOY_FAILURES", Kind::Integer, false),
+    ("AUTH_SESSION_TTL", Kind::Integer, false),
+    ("AWS_ACCESS_KEY_ID", Kind::Text, false),
+    ("AWS_SECRET_ACCESS_KEY", Kind::Text, false),
+    ("BASE_DOMAINS", Kind::Text, true),
+    ("BUDGETS", Kind::Text, false),
+    ("BUDGET_FALLBACK_POOL", Kind::Text, false),
+    ("BUDGET_THRESHOLDS", Kind::Text, false),
+    ("CLOUDFLARE_API_TOKEN", Kind::Text, false),
+    ("CLOUDFLARE_ZONE_ID", Kind::Text, false),
+    ("CORS_ALLOWED_ORIGINS", Kind::Text, false),
+    ("DELETION_GRACE_PERIOD", Kind::Integer, false),
+    ("DNS_PROVIDER", Kind::Text, false),
+    ("DNS_TARGET", Kind::Text, false),
+    ("FAUCET_INTERVAL", Kind::Integer, false),
+    ("FAUCET_TOKEN", Kind::Text, false),
+    ("FAUCET_URL", Kind::Text, false),
+    ("GITHUB_APP_ID", Kind::Text, false),
+    ("GITHUB_APP_INSTALLATION_ID", Kind::Text, false),
+    ("GITHUB_APP_PRIVATE_KEY", Kind::Text, false),
+    ("GITHUB_CLIENT_ID", Kind::Text, true),
+    ("GITHUB_CLIENT_SECRET", Kind::Text, true),
+    ("GITHUB_WEBHOOK_SECRET", Kind::Text, false),
+    ("GRAPHQL_ENABLED", Kind::Boolean, false),
+    ("IDEMPOTENCY_TTL", Kind::Integer, false),
+    ("LEGAL_BANNER", Kind::Text, false),
+    ("LEGAL_TERMS_URL", Kind::Text, false),
+    ("LEGAL_TERMS_VERSION", Kind::Text, false),
+    ("OIDC_CLIENT_ID", Kind::Text, false),
+    ("OIDC_CLIENT_SECRET", Kind::Text, false),
+    ("OIDC_GROUPS_CLAIM", Kind::Text, false),
+    ("OIDC_ISSUER_URL", Kind::Text, false),
+    ("OIDC_ROLES", Kind::Text, false),
+    ("OIDC_SCOPES", Kind::Text, false),
+    ("ONBOARDING_REQUIRED", Kind::Boolean, false),
+    ("OTEL_EXPORTER_OTLP_ENDPOINT", Kind::Text, false),
+    ("POLICY_AUTO_SUSPEND", Kind::Boolean, false),
+    ("POLICY_CPU_SAMPLES", Kind::Integer, false),
+    ("POLICY_CPU_THRESHOLD", Kind::Float, false),
+    ("POLICY_PROCESS_DENYLIST", Kind::Text, false),
+    ("PREVIEW_BUILD_COMMAND", Kind::Text, false),
+    ("PREVIEW_DURATION", Kind::Integer, false),
+    ("PREVIEW_TEMPLATES", Kind::Text, false),
+    ("RATE_LIMIT_MUTATIONS", Kind::Integer, false),
+    ("RATE_LIMIT_READS", Kind::Integer, false),
+    ("RATE_LIMIT_SESSION_CREATIONS", Kind::Integer, false),
+    ("REPLICAS", Kind::Integer, false),
+    ("RESOURCE_PREFIX", Kind::Text, false),
+    ("RESTRICTED_MODE", Kind::Boolean, false),
+    ("ROUTE53_HOSTED_ZONE_ID", Kind::Text, false),
+    ("RUST_LOG", Kind::Text, false),
+    ("SCHEDULING_STRATEGIES", Kind::Yaml, false),
+    ("SECRETS_KUBERNETES_NAME", Kind::Text, false),
+    ("SECRETS_PROVIDER", Kind::Text, false),
+    ("SECRETS_REFRESH_INTERVAL", Kind::Integer, false),
+    ("SESSION_AUTH_ACCESS_TTL", Kind::Integer, false),
+    ("SESSION_AUTH_HANDOFF_TTL", Kind::Integer, false),
+    ("SESSION_AUTH_SECRET", Kind::Text, false),
+    ("SESSION_DEFAULT_DURATION", Kind::Integer, true),
+    ("SESSION_DEFAULT_MAX_PER_NODE", Kind::Integer, true),
+    ("SESSION_DEFAULT_POOL_AFFINITY", Kind::Text, true),
+    ("SESSION_DEFAULT_PRIORITY_CLASS", Kind::Text, false),
+    ("SESSION_DISRUPTION_POLICY", Kind::Text, false),
+    ("SESSION_HEARTBEAT_INTERVAL", Kind::Integer, false),
+    ("SESSION_HEARTBEAT_SECRET", Kind::Text, false),
+    ("SESSION_IDLE_TIMEOUT", Kind::Integer, false),
+    ("SESSION_MAX_DURATION", Kind::Integer, true),
+    ("SESSION_RETRY_DELAY", Kind::Integer, false),
+    ("SESSION_RETRY_FALLBACK_POOL", Kind::Text, false),
+    ("SESSION_RETRY_MAX_ATTEMPTS", Kind::Integer, false),
+    ("SESSION_ROLE_DEFAULTS", Kind::Yaml, false),
+    ("SESSION_SUBDOMAIN", Kind::Text, false),
+    ("SESSION_SUBDOMAIN_SECRET", Kind::Text, false),
+    ("SHUTDOWN_TIMEOUT", Kind::Integer, false),
+    ("STATIC_FILES_DIR", Kind::Text, false),
+    ("STATIC_POOLS", Kind::Yaml, false),
+    ("TELEMETRY_URL", Kind::Text, false),
+    ("TEMPLATE_ALLOWED_REGISTRIES", Kind::Text, false),
+    ("TEMPLATE_SNAPSHOT_REMOTE", Kind::Text, false),
+    ("TEMPLATE_SNAPSHOT_TOKEN", Kind::Text, false),
+    ("VAULT_ADDR", Kind::Text, false),
+    ("VAULT_ROLE", Kind::Text, false),
+    ("VAULT_SECRET_PATH", Kind::Text, false),
+    ("VAULT_TOKEN", Kind::Text, false),
+    ("WEBHOOK_EVENTS", Kind::Text, false),
+    ("WEBHOOK_MAX_ATTEMPTS", Kind::Integer, false),
+    ("WEBHOOK_SECRET", Kind::Text, false),
+    ("WEBHOOK_URL", Kind::Text, false),
+    ("WS_PORT", Kind::Integer, false),
+];
+
+/// Settings read by Rocket itself
+const ROCKET_PREFIX: &str = "ROCKET_";
+
+fn kind(name: &str) -> Option<Kind> {
+    SETTINGS
+        .iter()
+        .find(|(setting, _, _)| *setting == name)
+        .map(|(_, kind, _)| *kind)
+}
+
+/// Returns the path of the configuration file, if any
+pub fn path(args: &[String]) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == CONFIG_FLAG)
+        .and_then(|index| args.get(index + 1).cloned())
+        .or_else(|| env::var("CONFIG_FILE").ok())
+}
+
+/// Converts a YAML `value` of the file to the content of an env variable of `kind`
+fn to_env_value(kind: Kind, value: &Value) -> Result<String, String> {
+    match (kind, value) {
+        (Kind::Text, Value::String(value)) => Ok(value.clone()),
+        // Unquoted numbers are common for ids
+        (Kind::Text, Value::Number(value)) => Ok(value.to_string()),
+        (Kind::Integer, Value::Number(value)) if value.is_u64() => Ok(value.to_string()),
+        (Kind::Float, Value::Number(value)) => Ok(value.to_string()),
+        (Kind::Boolean, Value::Bool(value)) => Ok(value.to_string()),
+        (Kind::Yaml, Value::Mapping(_) | Value::Sequence(_)) => {
+            serde_yaml::to_string(value).map_err(|err| err.to_string())
+        }
+        (Kind::Yaml, Value::String(value)) => Ok(value.clone()),
+        (kind, _) => Err(format!("expected {}", kind.describe())),
+    }
+}
+
+/// Sets env variables declared in the file at `path`, unless already set. Returns errors found in the file.
+pub fn load(path: &str) -> Vec<String> {
+    let settings: BTreeMap<String, Value> = match fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|content| serde_yaml::from_str(&content).map_err(|err| err.to_string()))
+    {
+        Ok(settings) => settings,
+        Err(err) => return vec![format!("{}: {}", path, err)],
+    };
+    let mut errors = Vec::new();
+    for (name, value) in settings {
+        let kind = match kind(&name) {
+            Some(kind) => kind,
+            None if name.starts_with(ROCKET_PREFIX) => Kind::Text,
+            None => {
+                errors.push(format!("{}: unknown setting {}", path, name));
+                continue;
+            }
+        };
+        match to_env_value(kind, &value) {
+            Ok(value) => {
+                if env::var(&name).is_err() {
+                    env::set_var(&name, value);
+                }
+            }
+            Err(err) => errors.push(format!("{}: {}: {}", path, name, err)),
+        }
+    }
+    errors
+}
+
+fn parse_yaml<T: serde::de::DeserializeOwned>(name: &str, errors: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        if let Err(err) = serde_yaml::from_str::<T>(&value) {
+            errors.push(format!("{}: {}", name, err));
+        }
+    }
+}
+
+/// Validates the configuration, as set in env variables. Returns all errors found.
+pub fn validate() -> Vec<String> {
+    let mut errors = Vec::new();
+    for (name, kind, required) in SETTINGS {
+        let value = match env::var(name) {
+            Ok(value) => value,
+            Err(_) => {
+                if *required {
+                    errors.push(format!("{}: missing", name));
+                }
+                continue;
+            }
+        };
+        let valid = match kind {
+            Kind::Integer => value.parse::<u64>().is_ok(),
+            Kind::Float => value.parse::<f64>().is_ok(),
+            Kind::Boolean => value == "true" || value == "false",
+            Kind::Text | Kind::Yaml => true,
+        };
+        if !valid {
+            errors.push(format!(
+                "{}: expected {}, got '{}'",
+                name,
+                kind.describe(),
+                value
+            ));
+        }
+    }
+
+    parse_yaml::<BTreeMap<String, RoleDefaults>>("SESSION_ROLE_DEFAULTS", &mut errors);
+    parse_yaml::<BTreeMap<String, StaticPool>>("STATIC_POOLS", &mut errors);
+    if let Err(err) = Scheduling::from_env() {
+        errors.push(err);
+    }
+    if let Err(err) = Dns::from_env() {
+        errors.push(format!("DNS_PROVIDER: {}", err));
+    }
+    if let Err(err) = AlertThresholds::from_env() {
+        errors.push(err.to_string());
+    }
+    if env::var("OIDC_ISSUER_URL").is_ok() {
+        for name in &["OIDC_CLIENT_ID", "OIDC_CLIENT_SECRET"] {
+            if env::var(name).is_err() {
+                errors.push(format!("{}: required when OIDC_ISSUER_URL is set", name));
+            }
+        }
+    }
+    if let Ok(prefix) = env::var("RESOURCE_PREFIX") {
+        if !prefix
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            || prefix.starts_with('-')
+        {
+            errors.push(
+                "RESOURCE_PREFIX: must only hold lowercase letters, digits and dashes".to_string(),
+            );
+        }
+    }
+    if env::var("RESTRICTED_MODE").as_deref() == Ok("true") && env::var("STATIC_POOLS").is_err() {
+        errors.push("STATIC_POOLS: required in restricted mode".to_string());
+    }
+    // Nonces signed with a per replica random secret would be rejected by other replicas
+    let replicas = env::var("REPLICAS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(1);
+    if replicas > 1
+        && env::var("SESSION_HEARTBEAT_SECRET").map_or(true, |secret| secret.is_empty())
+        && env::var("SECRETS_PROVIDER").is_err()
+    {
+        errors.push("SESSION_HEARTBEAT_SECRET: required with more than one replica".to_string());
+    }
+    if let Ok(remote) = env::var("TEMPLATE_SNAPSHOT_REMOTE") {
+        if !remote.starts_with("https://") {
+            errors.push("TEMPLATE_SNAPSHOT_REMOTE: must use https".to_string());
+        }
+    }
+    errors
+}
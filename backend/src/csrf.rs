@@ -0,0 +1,164 @@
+//! CSRF protection for cookie authenticated calls
+//!
+//! Relies on double-submit tokens: a random token is stored in a cookie readable by the frontend
+//! when logging in, and must be sent back as a header on every mutating call.
+//! Additionally the `Origin` header, if provided, must be part of the allowed origins.
+use crate::auth::random_token;
+use rocket::{
+    http::{Cookie, Method, SameSite},
+    Request,
+};
+use rocket_cors::AllowedOrigins;
+use std::env;
+
+pub const COOKIE_CSRF: &str = "csrf";
+pub const HEADER_CSRF: &str = "X-CSRF-Token";
+const TOKEN_LENGTH: usize = 32;
+
+/// Creates the cookie holding a fresh CSRF token
+pub fn new_cookie() -> Cookie<'static> {
+    let token = random_token(TOKEN_LENGTH);
+    // Must be readable by the frontend so that it can be sent back as a header
+    Cookie::build(COOKIE_CSRF, token)
+        .path("/")
+        .same_site(SameSite::Lax)
+        .http_only(false)
+        .finish()
+}
+
+/// Origins allowed to call the API
+#[derive(Clone, Debug)]
+pub struct Origins(Option<Vec<String>>);
+
+impl Origins {
+    /// Reads comma separated origins from `CORS_ALLOWED_ORIGINS`, defaulting to `defaults`.
+    /// `*` allows all origins.
+    pub fn from_env(defaults: Vec<String>) -> Self {
+        match env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(value) if value.trim() == "*" => Origins(None),
+            Ok(value) => Origins(Some(
+                value
+                    .split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect(),
+            )),
+            Err(_) => Origins(Some(defaults)),
+        }
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        self.0
+            .as_ref()
+            .map_or(true, |origins| origins.iter().any(|o| o == origin))
+    }
+
+    pub fn to_cors(&self) -> AllowedOrigins {
+        match &self.0 {
+            Some(origins) => AllowedOrigins::some_exact(origins.as_slice()),
+            None => AllowedOrigins::all(),
+        }
+    }
+}
+
+fn is_mutation(method: Method) -> bool {
+    !matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+/// Ensures a mutating `request` carries a valid CSRF token and comes from an allowed origin.
+/// `token` is the value of the CSRF cookie, if any. It must be read by the caller: the cookie jar can't be borrowed
+/// twice, e.g. by a guard and by this function.
+pub fn verify(request: &Request, origins: &Origins, token: Option<&str>) -> Result<(), String> {
+    if !is_mutation(request.method()) {
+        return Ok(());
+    }
+    if let Some(origin) = request.headers().get_one("Origin") {
+        if !origins.allows(origin) {
+            return Err(format!("Origin {} is not allowed", origin));
+        }
+    }
+    let header = request.headers().get_one(HEADER_CSRF);
+    match (token, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() && cookie == header => Ok(()),
+        _ => Err("Missing or invalid CSRF token".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{
+        http::{Header, Status},
+        local::Client,
+        post,
+        request::{FromRequest, Outcome},
+        routes,
+    };
+
+    const ORIGIN: &str = "https://playground.substrate.dev";
+
+    /// Authenticates like `LoggedUser`, keeping the cookie jar borrowed while verifying
+    struct Verified;
+
+    impl<'a, 'r> FromRequest<'a, 'r> for Verified {
+        type Error = String;
+
+        fn from_request(request: &'a Request<'r>) -> Outcome<Verified, String> {
+            let cookies = request.cookies();
+            let token = cookies.get(COOKIE_CSRF).map(|c| c.value());
+            match verify(request, &Origins(Some(vec![ORIGIN.to_string()])), token) {
+                Ok(()) => Outcome::Success(Verified),
+                Err(err) => Outcome::Failure((Status::Forbidden, err)),
+            }
+        }
+    }
+
+    #[post("/")]
+    fn mutate(_verified: Verified) -> &'static str {
+        "done"
+    }
+
+    fn client() -> Client {
+        Client::new(rocket::ignite().mount("/", routes![mutate])).expect("valid rocket")
+    }
+
+    #[test]
+    fn accepts_cookie_authenticated_posts_with_matching_header() {
+        let client = client();
+        let response = client
+            .post("/")
+            .cookie(Cookie::new(COOKIE_CSRF, "token"))
+            .header(Header::new(HEADER_CSRF, "token"))
+            .header(Header::new("Origin", ORIGIN))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn rejects_posts_without_matching_header() {
+        let client = client();
+        let missing = client
+            .post("/")
+            .cookie(Cookie::new(COOKIE_CSRF, "token"))
+            .dispatch();
+        assert_eq!(missing.status(), Status::Forbidden);
+        let mismatch = client
+            .post("/")
+            .cookie(Cookie::new(COOKIE_CSRF, "token"))
+            .header(Header::new(HEADER_CSRF, "other"))
+            .dispatch();
+        assert_eq!(mismatch.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn rejects_posts_from_other_origins() {
+        let client = client();
+        let response = client
+            .post("/")
+            .cookie(Cookie::new(COOKIE_CSRF, "token"))
+            .header(Header::new(HEADER_CSRF, "token"))
+            .header(Header::new("Origin", "https://attacker.example"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
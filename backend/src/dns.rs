@@ -0,0 +1,428 @@
+//! Per-session DNS records, for deployments without wildcard DNS
+//!
+//! Enabled via `DNS_PROVIDER`, either `cloudflare` (requires `CLOUDFLARE_API_TOKEN` and `CLOUDFLARE_ZONE_ID`) or `route53`
+//! (requires `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `ROUTE53_HOSTED_ZONE_ID`). Session hosts point to
+//! `DNS_TARGET`, the ingress load balancer, via an `A` record if it is an IP address or a `CNAME` otherwise.
+//! Records are created and deleted alongside ingress rules.
+use crate::clock::civil_date;
+use futures::future::{FutureExt, LocalBoxFuture};
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{
+    body::{self, Bytes},
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE, HOST},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    error::Error as StdError,
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::lookup_host;
+
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+const ROUTE53_HOST: &str = "route53.amazonaws.com";
+/// Route53 is a global service, requests are signed for this region
+const ROUTE53_REGION: &str = "us-east-1";
+/// Keeps records of deleted sessions from lingering in caches
+const RECORD_TTL: u32 = 60;
+
+/// Content of session records
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// `A` or `CNAME`
+    pub kind: &'static str,
+    pub target: String,
+}
+
+impl Record {
+    pub fn new(target: &str) -> Self {
+        Record {
+            kind: if target.parse::<IpAddr>().is_ok() {
+                "A"
+            } else {
+                "CNAME"
+            },
+            target: target.to_string(),
+        }
+    }
+}
+
+pub trait DnsProvider: Send + Sync {
+    /// Creates the record of `host`, or updates it if it exists
+    fn upsert<'a>(
+        &'a self,
+        host: &'a str,
+        record: &'a Record,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn StdError>>>;
+
+    /// Deletes the record of `host`. Missing records are ignored.
+    fn delete<'a>(
+        &'a self,
+        host: &'a str,
+        record: &'a Record,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn StdError>>>;
+}
+
+#[derive(Clone)]
+pub struct Dns {
+    provider: Arc<dyn DnsProvider>,
+    record: Record,
+}
+
+impl Dns {
+    /// Returns `None` if `DNS_PROVIDER` is unset
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let provider: Arc<dyn DnsProvider> = match env::var("DNS_PROVIDER").ok().as_deref() {
+            None => return Ok(None),
+            Some("cloudflare") => Arc::new(Cloudflare {
+                token: required("CLOUDFLARE_API_TOKEN")?,
+                zone_id: required("CLOUDFLARE_ZONE_ID")?,
+            }),
+            Some("route53") => Arc::new(Route53 {
+                access_key_id: required("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: required("AWS_SECRET_ACCESS_KEY")?,
+                hosted_zone_id: required("ROUTE53_HOSTED_ZONE_ID")?,
+            }),
+            Some(provider) => return Err(format!("unknown DNS_PROVIDER {}", provider)),
+        };
+        Ok(Some(Dns {
+            provider,
+            record: Record::new(&required("DNS_TARGET")?),
+        }))
+    }
+
+    pub async fn create(&self, host: &str) -> Result<(), Box<dyn StdError>> {
+        self.provider.upsert(host, &self.record).await
+    }
+
+    pub async fn delete(&self, host: &str) -> Result<(), Box<dyn StdError>> {
+        self.provider.delete(host, &self.record).await
+    }
+}
+
+/// Returns true if `host` can be resolved from the backend, a good hint that its record propagated
+pub async fn resolves(host: &str) -> bool {
+    lookup_host((host, 443))
+        .await
+        .map_or(false, |mut addresses| addresses.next().is_some())
+}
+
+fn required(name: &str) -> Result<String, String> {
+    env::var(name).map_err(|_| format!("missing {}", name))
+}
+
+async fn send(request: Request<Body>) -> Result<(StatusCode, Bytes), Box<dyn StdError>> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+    let response = client.request(request).await?;
+    let status = response.status();
+    Ok((status, body::to_bytes(response.into_body()).await?))
+}
+
+/// See https://api.cloudflare.com/#dns-records-for-a-zone-properties
+struct Cloudflare {
+    token: String,
+    zone_id: String,
+}
+
+#[derive(Deserialize)]
+struct CloudflareResponse<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<serde_json::Value>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct CloudflareRecord {
+    id: String,
+}
+
+impl Cloudflare {
+    async fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Option<T>, Box<dyn StdError>> {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!(
+                "{}/zones/{}/dns_records{}",
+                CLOUDFLARE_API, self.zone_id, path
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", self.token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(match body {
+                Some(body) => Body::from(serde_json::to_vec(&body)?),
+                None => Body::empty(),
+            })?;
+        let (_, bytes) = send(request).await?;
+        let response: CloudflareResponse<T> = serde_json::from_slice(&bytes)?;
+        if !response.success {
+            return Err(format!("Cloudflare returned {:?}", response.errors).into());
+        }
+        Ok(response.result)
+    }
+
+    async fn find(&self, host: &str) -> Result<Option<String>, Box<dyn StdError>> {
+        let records: Option<Vec<CloudflareRecord>> = self
+            .call(Method::GET, &format!("?name={}", host), None)
+            .await?;
+        Ok(records
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .map(|record| record.id))
+    }
+
+    async fn upsert_record(&self, host: &str, record: &Record) -> Result<(), Box<dyn StdError>> {
+        let body = serde_json::json!({
+            "type": record.kind,
+            "name": host,
+            "content": record.target,
+            "ttl": RECORD_TTL,
+            "proxied": false,
+        });
+        match self.find(host).await? {
+            Some(id) => {
+                self.call::<serde_json::Value>(Method::PUT, &format!("/{}", id), Some(body))
+                    .await?
+            }
+            None => {
+                self.call::<serde_json::Value>(Method::POST, "", Some(body))
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn delete_record(&self, host: &str) -> Result<(), Box<dyn StdError>> {
+        if let Some(id) = self.find(host).await? {
+            self.call::<serde_json::Value>(Method::DELETE, &format!("/{}", id), None)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl DnsProvider for Cloudflare {
+    fn upsert<'a>(
+        &'a self,
+        host: &'a str,
+        record: &'a Record,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn StdError>>> {
+        self.upsert_record(host, record).boxed_local()
+    }
+
+    fn delete<'a>(
+        &'a self,
+        host: &'a str,
+        _record: &'a Record,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn StdError>>> {
+        self.delete_record(host).boxed_local()
+    }
+}
+
+/// See https://docs.aws.amazon.com/Route53/latest/APIReference/API_ChangeResourceRecordSets.html
+struct Route53 {
+    access_key_id: String,
+    secret_access_key: String,
+    hosted_zone_id: String,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    // HMAC accepts keys of any size, this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Formats `time` as `(YYYYMMDD, YYYYMMDDTHHMMSSZ)`
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_date(time);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time_of_day = secs % 86400;
+    let datetime = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    );
+    (date, datetime)
+}
+
+/// Returns the key signing requests to `service` in `region` on `date` (`YYYYMMDD`)
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    [region, service, "aws4_request"].iter().fold(
+        hmac(format!("AWS4{}", secret_access_key).as_bytes(), date),
+        |key, part| hmac(&key, part),
+    )
+}
+
+impl Route53 {
+    /// Returns the `x-amz-date` and `Authorization` headers of a change batch `payload` sent to `path` at `time`, see
+    /// https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+    fn sign(&self, path: &str, payload: &str, time: SystemTime) -> (String, String) {
+        let (date, datetime) = amz_date(time);
+        let signed_headers = "content-type;host;x-amz-date";
+        let canonical_request = format!(
+            "POST\n{}\n\ncontent-type:text/xml\nhost:{}\nx-amz-date:{}\n\n{}\n{}",
+            path,
+            ROUTE53_HOST,
+            datetime,
+            signed_headers,
+            sha256_hex(payload.as_bytes())
+        );
+        let scope = format!("{}/{}/route53/aws4_request", date, ROUTE53_REGION);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            datetime,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key = signing_key(&self.secret_access_key, &date, ROUTE53_REGION, "route53");
+        let signature = hex(&hmac(&key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+        (datetime, authorization)
+    }
+
+    /// Sends a signed change batch
+    async fn change(
+        &self,
+        action: &str,
+        host: &str,
+        record: &Record,
+    ) -> Result<(StatusCode, String), Box<dyn StdError>> {
+        let path = format!("/2013-04-01/hostedzone/{}/rrset/", self.hosted_zone_id);
+        let payload = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <ChangeResourceRecordSetsRequest xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\">\
+            <ChangeBatch><Changes><Change><Action>{}</Action><ResourceRecordSet>\
+            <Name>{}</Name><Type>{}</Type><TTL>{}</TTL>\
+            <ResourceRecords><ResourceRecord><Value>{}</Value></ResourceRecord></ResourceRecords>\
+            </ResourceRecordSet></Change></Changes></ChangeBatch></ChangeResourceRecordSetsRequest>",
+            action, host, record.kind, RECORD_TTL, record.target
+        );
+        let (datetime, authorization) = self.sign(&path, &payload, SystemTime::now());
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("https://{}{}", ROUTE53_HOST, path))
+            .header(HOST, ROUTE53_HOST)
+            .header(CONTENT_TYPE, "text/xml")
+            .header("x-amz-date", &datetime)
+            .header(AUTHORIZATION, authorization)
+            .body(Body::from(payload))?;
+        let (status, bytes) = send(request).await?;
+        Ok((status, String::from_utf8_lossy(&bytes).to_string()))
+    }
+
+    async fn upsert_record(&self, host: &str, record: &Record) -> Result<(), Box<dyn StdError>> {
+        match self.change("UPSERT", host, record).await? {
+            (status, _) if status.is_success() => Ok(()),
+            (status, body) => Err(format!("Route53 returned {}: {}", status, body).into()),
+        }
+    }
+
+    async fn delete_record(&self, host: &str, record: &Record) -> Result<(), Box<dyn StdError>> {
+        match self.change("DELETE", host, record).await? {
+            (status, _) if status.is_success() => Ok(()),
+            // Deleting a missing record is rejected as an invalid batch
+            (StatusCode::BAD_REQUEST, body) if body.contains("not found") => Ok(()),
+            (status, body) => Err(format!("Route53 returned {}: {}", status, body).into()),
+        }
+    }
+}
+
+impl DnsProvider for Route53 {
+    fn upsert<'a>(
+        &'a self,
+        host: &'a str,
+        record: &'a Record,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn StdError>>> {
+        self.upsert_record(host, record).boxed_local()
+    }
+
+    fn delete<'a>(
+        &'a self,
+        host: &'a str,
+        record: &'a Record,
+    ) -> LocalBoxFuture<'a, Result<(), Box<dyn StdError>>> {
+        self.delete_record(host, record).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn formats_amz_dates() {
+        assert_eq!(
+            amz_date(at(1440938160)),
+            ("20150830".to_string(), "20150830T123600Z".to_string())
+        );
+        assert_eq!(
+            amz_date(at(951782400 + 86399)),
+            ("20000229".to_string(), "20000229T235959Z".to_string())
+        );
+    }
+
+    #[test]
+    fn derives_signing_keys() {
+        // See https://docs.aws.amazon.com/general/latest/gr/signature-v4-examples.html
+        assert_eq!(
+            hex(&signing_key(
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                "20120215",
+                "us-east-1",
+                "iam"
+            )),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn signs_change_batches() {
+        let route53 = Route53 {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            hosted_zone_id: "Z1".to_string(),
+        };
+        let (datetime, authorization) =
+            route53.sign("/2013-04-01/hostedzone/Z1/rrset/", "<x/>", at(1440938160));
+        assert_eq!(datetime, "20150830T123600Z");
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/route53/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=3b896227b76b1198d87ac829a340adfa226bac85f404d8ee509ee60a9e6535bd"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Parsing of ConfigMap entries
+//!
+//! Entries are YAML documents. Errors are reported as `InvalidEntry`, pointing at the offending field and position when
+//! known.
+use crate::types::InvalidEntry;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Returns the path of the field a serde error relates to, if any
+fn error_field(message: &str) -> Option<String> {
+    // Nested errors are prefixed by their path, e.g. `runtime.ports[0].port: invalid type`
+    if let Some((path, _)) = message.split_once(": ") {
+        if !path.contains(' ') {
+            return Some(path.to_string());
+        }
+    }
+    if message.starts_with("missing field") || message.starts_with("unknown field") {
+        return message.split('`').nth(1).map(str::to_string);
+    }
+    None
+}
+
+/// Returns top level fields of the YAML `value` that are not part of `entry`
+fn unknown_fields<T: Serialize>(value: &str, entry: &T) -> Vec<String> {
+    let known = match serde_yaml::to_value(entry) {
+        Ok(serde_yaml::Value::Mapping(known)) => known,
+        _ => return Vec::new(),
+    };
+    match serde_yaml::from_str(value) {
+        Ok(serde_yaml::Value::Mapping(fields)) => fields
+            .iter()
+            .filter(|(field, _)| !known.contains_key(field))
+            .filter_map(|(field, _)| field.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parses `value`, stored under `key` in ConfigMap `config_map`. In `strict` mode, unknown fields are errors too.
+pub fn parse_entry<T: DeserializeOwned + Serialize>(
+    config_map: &str,
+    key: &str,
+    value: &str,
+    strict: bool,
+) -> std::result::Result<T, InvalidEntry> {
+    let entry: T = serde_yaml::from_str(value).map_err(|err| {
+        let message = err.to_string();
+        InvalidEntry {
+            config_map: config_map.to_string(),
+            key: key.to_string(),
+            field: error_field(&message),
+            line: err.location().map(|location| location.line()),
+            column: err.location().map(|location| location.column()),
+            error: message,
+        }
+    })?;
+    if strict {
+        if let Some(field) = unknown_fields(value, &entry).into_iter().next() {
+            let prefix = format!("{}:", field);
+            return Err(InvalidEntry {
+                config_map: config_map.to_string(),
+                key: key.to_string(),
+                error: format!("unknown field `{}`", field),
+                line: value
+                    .lines()
+                    .position(|line| line.starts_with(&prefix))
+                    .map(|index| index + 1),
+                column: None,
+                field: Some(field),
+            });
+        }
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Entry {
+        name: String,
+        #[serde(default)]
+        ports: Vec<Port>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Port {
+        port: u16,
+    }
+
+    #[test]
+    fn finds_error_fields() {
+        assert_eq!(
+            error_field("ports[0].port: invalid type: string \"a\", expected u16"),
+            Some("ports[0].port".to_string())
+        );
+        assert_eq!(
+            error_field("missing field `name` at line 1 column 1"),
+            Some("name".to_string())
+        );
+        assert_eq!(
+            error_field("unknown field `other`, expected `name`"),
+            Some("other".to_string())
+        );
+        assert_eq!(error_field("invalid type: expected a map"), None);
+    }
+
+    #[test]
+    fn lists_unknown_fields() {
+        let entry = Entry {
+            name: "a".to_string(),
+            ports: Vec::new(),
+        };
+        assert_eq!(
+            unknown_fields("name: a\nother: b\nports: []", &entry),
+            vec!["other".to_string()]
+        );
+        assert!(unknown_fields("name: a", &entry).is_empty());
+        assert!(unknown_fields("- a", &entry).is_empty());
+    }
+
+    #[test]
+    fn parses_entries() {
+        let entry: Entry =
+            parse_entry("map", "key", "name: a\nports:\n  - port: 80", true).unwrap();
+        assert_eq!(entry.ports[0].port, 80);
+
+        let error =
+            parse_entry::<Entry>("map", "key", "name: a\nports:\n  - port: a", false).unwrap_err();
+        assert_eq!(error.config_map, "map");
+        assert_eq!(error.key, "key");
+        assert_eq!(error.field.as_deref(), Some("ports[0].port"));
+        assert!(error.line.is_some());
+
+        // Unknown fields are only rejected in strict mode
+        let value = "name: a\nother: b";
+        assert!(parse_entry::<Entry>("map", "key", value, false).is_ok());
+        let error = parse_entry::<Entry>("map", "key", value, true).unwrap_err();
+        assert_eq!(error.field.as_deref(), Some("other"));
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.column, None);
+    }
+}
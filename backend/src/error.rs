@@ -10,8 +10,16 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum Error {
     #[error("Unauthorized")]
     Unauthorized(/*Permission*/),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     #[error("Missing data {0}")]
     MissingData(&'static str),
+    #[error("Invalid parameter {0}")]
+    InvalidParameter(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Shutting down")]
+    ShuttingDown(),
     #[error("Failure: {0}")]
     Failure(#[from] Box<dyn std::error::Error>),
 }
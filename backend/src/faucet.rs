@@ -0,0 +1,150 @@
+//! Testnet funds for tutorial chains
+//!
+//! Requests are forwarded to a `Faucet`, by default an HTTP endpoint configured via `FAUCET_URL`.
+//! Each user can only be funded once per `FAUCET_INTERVAL` (in seconds). Requests are tracked in the backend state, so
+//! that the limit holds across replicas.
+use crate::types::FaucetRequest;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request,
+};
+use hyper_tls::HttpsConnector;
+use std::{collections::BTreeMap, env, error::Error as StdError, time::Duration};
+
+type FaucetError = Box<dyn StdError + Send + Sync>;
+
+pub trait Faucet: Send + Sync {
+    /// Sends funds as described by `request`. Returns the faucet response, e.g. a transaction hash.
+    fn fund<'a>(
+        &'a self,
+        request: &'a FaucetRequest,
+    ) -> BoxFuture<'a, Result<serde_json::Value, FaucetError>>;
+}
+
+/// Forwards requests as JSON to `url`
+pub struct HttpFaucet {
+    url: String,
+    /// Sent as a bearer token, if set
+    token: Option<String>,
+}
+
+impl HttpFaucet {
+    pub fn from_env() -> Option<Self> {
+        env::var("FAUCET_URL").ok().map(|url| HttpFaucet {
+            url,
+            token: env::var("FAUCET_TOKEN").ok(),
+        })
+    }
+
+    async fn post(&self, request: &FaucetRequest) -> Result<serde_json::Value, FaucetError> {
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+        let response = client
+            .request(builder.body(Body::from(serde_json::to_vec(request)?))?)
+            .await?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(format!(
+                "Faucet returned {}: {}",
+                status,
+                String::from_utf8_lossy(&bytes)
+            )
+            .into());
+        }
+        Ok(serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null))
+    }
+}
+
+impl Faucet for HttpFaucet {
+    fn fund<'a>(
+        &'a self,
+        request: &'a FaucetRequest,
+    ) -> BoxFuture<'a, Result<serde_json::Value, FaucetError>> {
+        self.post(request).boxed()
+    }
+}
+
+/// Wraps a `Faucet`, limiting how often each user can be funded
+pub struct RateLimitedFaucet {
+    faucet: Box<dyn Faucet>,
+    interval: Duration,
+}
+
+impl RateLimitedFaucet {
+    pub fn new(faucet: Box<dyn Faucet>, interval: Duration) -> Self {
+        RateLimitedFaucet { faucet, interval }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let interval = env::var("FAUCET_INTERVAL")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(3600);
+        HttpFaucet::from_env()
+            .map(|faucet| RateLimitedFaucet::new(Box::new(faucet), Duration::from_secs(interval)))
+    }
+
+    /// Records a request by `user_id` at `now` in `last_requests`, the time of the last request of each user (in seconds
+    /// since epoch). Older requests are forgotten. Returns how long to wait before retrying if the previous one is too
+    /// recent.
+    pub fn check(
+        &self,
+        last_requests: &mut BTreeMap<String, u64>,
+        user_id: &str,
+        now: u64,
+    ) -> Result<(), Duration> {
+        let interval = self.interval.as_secs();
+        last_requests.retain(|_, at| now.saturating_sub(*at) < interval);
+        if let Some(at) = last_requests.get(user_id) {
+            return Err(Duration::from_secs(interval - now.saturating_sub(*at)));
+        }
+        last_requests.insert(user_id.to_string(), now);
+        Ok(())
+    }
+
+    pub async fn fund(&self, request: &FaucetRequest) -> Result<serde_json::Value, FaucetError> {
+        self.faucet.fund(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoFaucet;
+
+    impl Faucet for NoFaucet {
+        fn fund<'a>(
+            &'a self,
+            _request: &'a FaucetRequest,
+        ) -> BoxFuture<'a, Result<serde_json::Value, FaucetError>> {
+            async { Ok(serde_json::Value::Null) }.boxed()
+        }
+    }
+
+    #[test]
+    fn limits_requests_per_user() {
+        let faucet = RateLimitedFaucet::new(Box::new(NoFaucet), Duration::from_secs(60));
+        let mut last_requests = BTreeMap::new();
+        assert_eq!(faucet.check(&mut last_requests, "a", 1000), Ok(()));
+        assert_eq!(faucet.check(&mut last_requests, "b", 1010), Ok(()));
+        assert_eq!(
+            faucet.check(&mut last_requests, "a", 1030),
+            Err(Duration::from_secs(30))
+        );
+        // Expired requests are dropped
+        assert_eq!(faucet.check(&mut last_requests, "a", 1060), Ok(()));
+        assert_eq!(faucet.check(&mut last_requests, "c", 1075), Ok(()));
+        assert_eq!(last_requests.len(), 2);
+    }
+}
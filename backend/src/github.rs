@@ -2,6 +2,7 @@
 
 use body::aggregate;
 use core::fmt;
+use hmac::{Hmac, Mac, NewMac};
 use hyper::{
     body::{self, Buf},
     client::HttpConnector,
@@ -10,9 +11,14 @@ use hyper::{
     Body, Client, Request,
 };
 use hyper_tls::HttpsConnector;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use serde::de::DeserializeOwned;
 use serde_json::from_reader;
-use std::error::Error as StdError;
+use sha2::Sha256;
+use std::{
+    error::Error as StdError,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 // Custom Error type
 #[derive(Debug)]
@@ -58,6 +64,29 @@ pub struct GitHubClientError {
     pub code: String,
 }
 
+/// Credentials of a GitHub App, used instead of a personal token to access installation resources
+#[derive(Clone, Debug)]
+pub struct GitHubApp {
+    pub app_id: String,
+    pub installation_id: String,
+    /// PEM encoded RSA private key
+    pub private_key: String,
+}
+
+/// A short lived token granting access to the repositories of an installation
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct InstallationToken {
+    pub token: String,
+    pub expires_at: String,
+}
+
+#[derive(serde::Serialize)]
+struct AppClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
 /// Create a new `Client`
 fn create_client() -> Client<HttpsConnector<HttpConnector>> {
     Client::builder().build(HttpsConnector::new())
@@ -73,11 +102,18 @@ fn create_request_builder(token: &str) -> Builder {
 
 // Send a fresh `Request` created from a `Builder`, sends it and return the object `T` parsed from JSON.
 async fn send<T>(builder: Builder) -> Result<T, Box<dyn StdError>>
+where
+    T: DeserializeOwned,
+{
+    send_with_body(builder, Body::default()).await
+}
+
+async fn send_with_body<T>(builder: Builder, body: Body) -> Result<T, Box<dyn StdError>>
 where
     T: DeserializeOwned,
 {
     let client = create_client();
-    let req = builder.body(Body::default())?;
+    let req = builder.body(body)?;
     let res = client.request(req).await?;
     let status = res.status();
     let whole_body = aggregate(res).await?;
@@ -101,6 +137,106 @@ pub async fn current_user(token: &str) -> Result<GitHubUser, Box<dyn StdError>>
     send(builder).await
 }
 
+///
+/// Checks that the GitHub API can be reached. Doesn't count against the rate limit.
+///
+pub async fn ping() -> Result<(), Box<dyn StdError>> {
+    let builder = Request::builder()
+        .header(USER_AGENT, "Substrate Playground")
+        .uri("https://api.github.com/rate_limit");
+    send::<serde_json::Value>(builder).await.map(|_| ())
+}
+
+impl GitHubApp {
+    /// Creates a JWT authenticating as the app itself, valid for 10 minutes
+    fn jwt(&self) -> Result<String, Box<dyn StdError>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = AppClaims {
+            // Allow for some clock drift
+            iat: now - 60,
+            exp: now + 9 * 60,
+            iss: self.app_id.clone(),
+        };
+        let key = EncodingKey::from_rsa_pem(self.private_key.as_bytes())?;
+        Ok(encode(&Header::new(Algorithm::RS256), &claims, &key)?)
+    }
+
+    ///
+    /// Exchanges the app credentials for an installation token.
+    ///
+    pub async fn installation_token(&self) -> Result<InstallationToken, Box<dyn StdError>> {
+        let builder = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "Substrate Playground")
+            .header(AUTHORIZATION, format!("Bearer {}", self.jwt()?))
+            .uri(format!(
+                "https://api.github.com/app/installations/{}/access_tokens",
+                self.installation_id
+            ));
+        send(builder).await
+    }
+}
+
+///
+/// Comments on issue or pull request `number` of `repository`.
+///
+/// # Arguments
+///
+/// * `token` - a github token allowed to write issues, e.g. an installation token
+/// * `repository` - the repository full name
+/// * `number` - the issue or pull request number
+/// * `body` - the comment, as markdown
+///
+pub async fn create_issue_comment(
+    token: &str,
+    repository: &str,
+    number: u64,
+    body: &str,
+) -> Result<(), Box<dyn StdError>> {
+    let builder = create_request_builder(token).method("POST").uri(format!(
+        "https://api.github.com/repos/{}/issues/{}/comments",
+        repository, number
+    ));
+    let body = serde_json::to_vec(&serde_json::json!({ "body": body }))?;
+    send_with_body::<serde_json::Value>(builder, Body::from(body))
+        .await
+        .map(|_| ())
+}
+
+///
+/// Checks that a webhook `payload` was signed with `secret`.
+///
+/// # Arguments
+///
+/// * `secret` - the webhook secret
+/// * `payload` - the raw request body
+/// * `signature` - the `X-Hub-Signature-256` header value
+///
+pub fn verify_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let signature = match signature.strip_prefix("sha256=").and_then(decode_hex) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(payload);
+    // Constant time comparison
+    mac.verify(&signature).is_ok()
+}
+
+pub fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 ///
 /// Returns a Vec<GitHubOrg> associated to a GitHubUser.
 ///
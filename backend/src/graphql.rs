@@ -0,0 +1,616 @@
+//! GraphQL API
+//!
+//! An optional read-only view of sessions, users, templates and pools at `/api/v1/graphql`, enabled with
+//! `GRAPHQL_ENABLED`. Objects can be traversed (e.g. session → pod → node → pool) so that a page fetches exactly what it
+//! renders in one round trip. Resolvers go through the `Manager`, so the usual permissions apply. Queries nested deeper
+//! than `MAX_DEPTH` or batches larger than `MAX_BATCH_SIZE` are rejected before being executed.
+use crate::{
+    error::{Error, Result},
+    manager::Manager,
+    types::{DeploymentStep, LoggedUser, Pod, Pool, Session, Template, TemplateQuery, User},
+};
+use juniper::{
+    graphql_object, http::GraphQLBatchRequest, EmptyMutation, EmptySubscription, FieldResult,
+    RootNode,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub type Schema = RootNode<'static, Query, EmptyMutation<Context>, EmptySubscription<Context>>;
+
+/// Maximum nesting of selection sets, e.g. `{ sessions { pod { node { pool { name } } } } }` has a depth of 5
+pub const MAX_DEPTH: usize = 8;
+/// Maximum number of queries in a batch
+pub const MAX_BATCH_SIZE: usize = 10;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, EmptyMutation::new(), EmptySubscription::new())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Queries {
+    Single { query: String },
+    Batch(Vec<Queries>),
+}
+
+impl Queries {
+    fn all(&self) -> Vec<&str> {
+        match self {
+            Queries::Single { query } => vec![query.as_str()],
+            Queries::Batch(queries) => queries.iter().flat_map(Queries::all).collect(),
+        }
+    }
+}
+
+/// Returns an upper bound of the nesting of selection sets in `query`. As fragments can be spread anywhere, their depth
+/// is added to the one of the deepest operation.
+fn depth(query: &str) -> usize {
+    let mut chars = query.chars().peekable();
+    let (mut depth, mut parentheses, mut current) = (0, 0, 0);
+    let (mut operations, mut fragments) = (0, 0);
+    let mut fragment = false;
+    let mut word = String::new();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        if depth == 0 && word == "fragment" {
+            fragment = true;
+        }
+        word.clear();
+        match c {
+            '#' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '"' if chars.peek() == Some(&'"') => {
+                chars.next();
+                if chars.peek() == Some(&'"') {
+                    // Block string
+                    chars.next();
+                    let mut quotes = 0;
+                    for c in chars.by_ref() {
+                        quotes = if c == '"' { quotes + 1 } else { 0 };
+                        if quotes == 3 {
+                            break;
+                        }
+                    }
+                }
+            }
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' => parentheses += 1,
+            ')' => parentheses -= 1,
+            '{' => {
+                depth += 1;
+                current = current.max(depth);
+            }
+            '}' => {
+                depth -= 1;
+                if depth == 0 && parentheses == 0 {
+                    if fragment {
+                        fragments += current;
+                    } else {
+                        operations = operations.max(current);
+                    }
+                    current = 0;
+                    fragment = false;
+                }
+            }
+            _ => {}
+        }
+        if depth < 0 || parentheses < 0 {
+            // Unbalanced, left to the parser to report
+            break;
+        }
+    }
+    (operations + fragments) as usize
+}
+
+/// Executes the JSON encoded GraphQL `request`, returning whether it succeeded along with the JSON encoded response
+pub fn execute(schema: &Schema, context: &Context, request: &str) -> Result<(bool, String)> {
+    let queries: Queries = serde_json::from_str(request)
+        .map_err(|err| Error::InvalidParameter(format!("request: {}", err)))?;
+    let queries = queries.all();
+    if queries.len() > MAX_BATCH_SIZE {
+        return Err(Error::InvalidParameter(format!(
+            "batch of {} queries, at most {} are allowed",
+            queries.len(),
+            MAX_BATCH_SIZE
+        )));
+    }
+    if let Some(query) = queries.iter().find(|query| depth(query) > MAX_DEPTH) {
+        return Err(Error::InvalidParameter(format!(
+            "query nested deeper than {}: {}",
+            MAX_DEPTH, query
+        )));
+    }
+
+    let request: GraphQLBatchRequest = serde_json::from_str(request)
+        .map_err(|err| Error::InvalidParameter(format!("request: {}", err)))?;
+    let response = request.execute_sync(schema, context);
+    let body = serde_json::to_string(&response).map_err(|err| Error::Failure(err.into()))?;
+    Ok((response.is_ok(), body))
+}
+
+/// Returns the cached value of `cache`, loading it first if needed
+fn cached<T: Clone>(cache: &Mutex<Option<T>>, load: impl FnOnce() -> Result<T>) -> Result<T> {
+    let mut value = cache
+        .lock()
+        .map_err(|_| Error::Failure("Failed to acquire cache lock".into()))?;
+    if let Some(value) = value.as_ref() {
+        return Ok(value.clone());
+    }
+    let loaded = load()?;
+    value.replace(loaded.clone());
+    Ok(loaded)
+}
+
+/// Returns the cached lookup of `id` in `cache`, looking it up first if needed
+fn looked_up<T: Clone>(
+    cache: &Mutex<BTreeMap<String, Option<T>>>,
+    id: &str,
+    lookup: impl FnOnce() -> Result<Option<T>>,
+) -> Result<Option<T>> {
+    let mut values = cache
+        .lock()
+        .map_err(|_| Error::Failure("Failed to acquire cache lock".into()))?;
+    if let Some(value) = values.get(id) {
+        return Ok(value.clone());
+    }
+    let value = lookup()?;
+    values.insert(id.to_string(), value.clone());
+    Ok(value)
+}
+
+/// State of a single query. Pools, sessions and users are only listed once per query, so that traversing objects
+/// doesn't hit the cluster for each of them. Users without admin read rights can't list, their lookups are cached
+/// instead.
+pub struct Context {
+    manager: Manager,
+    user: LoggedUser,
+    pools: Mutex<Option<BTreeMap<String, Pool>>>,
+    sessions: Mutex<Option<BTreeMap<String, Session>>>,
+    users: Mutex<Option<BTreeMap<String, User>>>,
+    session_lookups: Mutex<BTreeMap<String, Option<Session>>>,
+    user_lookups: Mutex<BTreeMap<String, Option<User>>>,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    pub fn new(manager: Manager, user: LoggedUser) -> Self {
+        Context {
+            manager,
+            user,
+            pools: Mutex::new(None),
+            sessions: Mutex::new(None),
+            users: Mutex::new(None),
+            session_lookups: Mutex::new(BTreeMap::new()),
+            user_lookups: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn pools(&self) -> Result<BTreeMap<String, Pool>> {
+        cached(&self.pools, || self.manager.list_pools(&self.user))
+    }
+
+    fn sessions(&self) -> Result<BTreeMap<String, Session>> {
+        cached(&self.sessions, || {
+            Ok(self.manager.list_sessions(&self.user)?.0)
+        })
+    }
+
+    fn users(&self) -> Result<BTreeMap<String, User>> {
+        cached(&self.users, || self.manager.list_users(&self.user, false))
+    }
+
+    fn session(&self, id: &str) -> Result<Option<Session>> {
+        if self.user.has_admin_read_rights() {
+            return Ok(self.sessions()?.remove(id));
+        }
+        looked_up(&self.session_lookups, id, || {
+            self.manager.get_session(&self.user, id)
+        })
+    }
+
+    fn user(&self, id: &str) -> Result<Option<User>> {
+        if self.user.has_admin_read_rights() {
+            return Ok(self.users()?.remove(id));
+        }
+        looked_up(&self.user_lookups, id, || {
+            self.manager.get_user(&self.user, id)
+        })
+    }
+}
+
+/// Seconds since epoch
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default()
+}
+
+pub struct Query;
+
+#[graphql_object(context = Context)]
+impl Query {
+    fn session(context: &Context, id: String) -> FieldResult<Option<SessionObject>> {
+        let id = id.to_lowercase();
+        Ok(context
+            .session(&id)?
+            .map(|session| SessionObject { id, session }))
+    }
+
+    fn sessions(context: &Context) -> FieldResult<Vec<SessionObject>> {
+        Ok(context
+            .sessions()?
+            .into_iter()
+            .map(|(id, session)| SessionObject { id, session })
+            .collect())
+    }
+
+    fn user(context: &Context, id: String) -> FieldResult<Option<UserObject>> {
+        Ok(context.user(&id)?.map(|user| UserObject { id, user }))
+    }
+
+    fn users(context: &Context) -> FieldResult<Vec<UserObject>> {
+        Ok(context
+            .users()?
+            .into_iter()
+            .map(|(id, user)| UserObject { id, user })
+            .collect())
+    }
+
+    /// Templates sessions are created from, see `TemplateQuery`
+    fn templates(
+        context: &Context,
+        q: Option<String>,
+        tag: Option<String>,
+        page: Option<i32>,
+        per_page: Option<i32>,
+    ) -> FieldResult<Vec<TemplateObject>> {
+        let query = TemplateQuery {
+            q,
+            tag,
+            page: page.map(|page| page.max(1) as usize),
+            per_page: per_page.map(|per_page| per_page.max(1) as usize),
+            ..Default::default()
+        };
+        Ok(context
+            .manager
+            .list_templates(Some(&context.user), query, false)?
+            .items
+            .into_iter()
+            .map(|entry| TemplateObject {
+                id: Some(entry.id),
+                template: entry.value,
+            })
+            .collect())
+    }
+
+    fn pool(context: &Context, id: String) -> FieldResult<Option<PoolObject>> {
+        Ok(context.pools()?.remove(&id).map(|pool| PoolObject { pool }))
+    }
+
+    fn pools(context: &Context) -> FieldResult<Vec<PoolObject>> {
+        Ok(context
+            .pools()?
+            .into_iter()
+            .map(|(_, pool)| PoolObject { pool })
+            .collect())
+    }
+}
+
+pub struct SessionObject {
+    id: String,
+    session: Session,
+}
+
+#[graphql_object(context = Context, name = "Session")]
+impl SessionObject {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn user(&self, context: &Context) -> FieldResult<Option<UserObject>> {
+        let id = self.session.user_id.clone();
+        Ok(context.user(&id)?.map(|user| UserObject { id, user }))
+    }
+
+    fn template(&self) -> TemplateObject {
+        TemplateObject {
+            id: None,
+            template: self.session.template.clone(),
+        }
+    }
+
+    fn url(&self) -> &str {
+        &self.session.url
+    }
+
+    /// In minutes
+    fn duration(&self) -> i32 {
+        (self.session.duration.as_secs() / 60) as i32
+    }
+
+    fn pod(&self) -> PodObject {
+        PodObject {
+            pod: self.session.pod.clone(),
+            node: self.session.node.clone(),
+        }
+    }
+
+    fn flags(&self) -> Vec<String> {
+        self.session.flags.clone()
+    }
+
+    fn workshop(&self) -> Option<&str> {
+        self.session.workshop.as_deref()
+    }
+
+    fn retries(&self) -> i32 {
+        self.session.retries as i32
+    }
+
+    /// Seconds since epoch of the last heartbeat
+    fn last_activity(&self) -> Option<f64> {
+        self.session.last_activity.map(seconds)
+    }
+
+    /// Seconds since epoch, set while the session is pending deletion
+    fn deleted_at(&self) -> Option<f64> {
+        self.session.deleted_at.map(seconds)
+    }
+}
+
+pub struct PodObject {
+    pod: Pod,
+    /// Hostname of the node the pod is scheduled on, if any
+    node: String,
+}
+
+#[graphql_object(context = Context, name = "Pod")]
+impl PodObject {
+    fn phase(&self) -> String {
+        format!("{:?}", self.pod.phase)
+    }
+
+    fn reason(&self) -> &str {
+        &self.pod.reason
+    }
+
+    fn message(&self) -> &str {
+        &self.pod.message
+    }
+
+    /// Seconds since epoch
+    fn start_time(&self) -> Option<f64> {
+        self.pod.start_time.map(seconds)
+    }
+
+    /// Deployment progress, in order
+    fn steps(&self) -> Vec<StepObject> {
+        self.pod
+            .steps
+            .iter()
+            .cloned()
+            .map(|step| StepObject { step })
+            .collect()
+    }
+
+    /// Set when the session can't start
+    fn failure_reason(&self) -> Option<String> {
+        self.pod
+            .failure
+            .as_ref()
+            .map(|failure| format!("{:?}", failure.reason))
+    }
+
+    fn node(&self) -> Option<NodeObject> {
+        if self.node.is_empty() {
+            return None;
+        }
+        Some(NodeObject {
+            hostname: self.node.clone(),
+        })
+    }
+}
+
+pub struct StepObject {
+    step: DeploymentStep,
+}
+
+#[graphql_object(context = Context, name = "DeploymentStep")]
+impl StepObject {
+    fn name(&self) -> &str {
+        &self.step.name
+    }
+
+    fn completed(&self) -> bool {
+        self.step.completed
+    }
+
+    /// Seconds since epoch
+    fn completed_at(&self) -> Option<f64> {
+        self.step.completed_at.map(seconds)
+    }
+}
+
+pub struct NodeObject {
+    hostname: String,
+}
+
+#[graphql_object(context = Context, name = "Node")]
+impl NodeObject {
+    fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    fn pool(&self, context: &Context) -> FieldResult<Option<PoolObject>> {
+        Ok(context
+            .pools()?
+            .into_iter()
+            .map(|(_, pool)| pool)
+            .find(|pool| pool.nodes.iter().any(|node| node.hostname == self.hostname))
+            .map(|pool| PoolObject { pool }))
+    }
+
+    fn sessions(&self, context: &Context) -> FieldResult<Vec<SessionObject>> {
+        Ok(context
+            .sessions()?
+            .into_iter()
+            .filter(|(_, session)| session.node == self.hostname)
+            .map(|(id, session)| SessionObject { id, session })
+            .collect())
+    }
+}
+
+pub struct PoolObject {
+    pool: Pool,
+}
+
+#[graphql_object(context = Context, name = "Pool")]
+impl PoolObject {
+    fn name(&self) -> &str {
+        &self.pool.name
+    }
+
+    fn instance_type(&self) -> Option<&str> {
+        self.pool.instance_type.as_deref()
+    }
+
+    /// Maximum number of concurrent sessions, if declared
+    fn capacity(&self) -> Option<i32> {
+        self.pool.capacity.map(|capacity| capacity as i32)
+    }
+
+    fn nodes(&self) -> Vec<NodeObject> {
+        self.pool
+            .nodes
+            .iter()
+            .map(|node| NodeObject {
+                hostname: node.hostname.clone(),
+            })
+            .collect()
+    }
+}
+
+pub struct UserObject {
+    id: String,
+    user: User,
+}
+
+#[graphql_object(context = Context, name = "User")]
+impl UserObject {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn admin(&self) -> bool {
+        self.user.admin
+    }
+
+    fn can_customize_duration(&self) -> bool {
+        self.user.can_customize_duration
+    }
+
+    fn can_customize_pool_affinity(&self) -> bool {
+        self.user.can_customize_pool_affinity
+    }
+
+    fn pool_affinity(&self) -> Option<&str> {
+        self.user.pool_affinity.as_deref()
+    }
+
+    fn onboarding(&self) -> String {
+        format!("{:?}", self.user.onboarding)
+    }
+
+    /// Seconds since epoch, set while the user can still be restored
+    fn deleted_at(&self) -> Option<f64> {
+        self.user.deleted_at.map(|seconds| seconds as f64)
+    }
+
+    fn session(&self, context: &Context) -> FieldResult<Option<SessionObject>> {
+        let id = self.id.to_lowercase();
+        Ok(context
+            .session(&id)?
+            .map(|session| SessionObject { id, session }))
+    }
+}
+
+pub struct TemplateObject {
+    /// Unknown for templates of sessions
+    id: Option<String>,
+    template: Template,
+}
+
+#[graphql_object(context = Context, name = "Template")]
+impl TemplateObject {
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn name(&self) -> &str {
+        &self.template.name
+    }
+
+    fn image(&self) -> &str {
+        &self.template.image
+    }
+
+    fn description(&self) -> &str {
+        &self.template.description
+    }
+
+    /// Pools sessions can be scheduled on, all if unset
+    fn allowed_pools(&self) -> Option<Vec<String>> {
+        self.template.allowed_pools.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_query_depth() {
+        assert_eq!(depth("{ sessions { id } }"), 2);
+        assert_eq!(
+            depth("query Q($id: String!) { session(id: $id) { pod { node { hostname } } } }"),
+            4
+        );
+        // Braces of strings and comments don't count
+        assert_eq!(depth(r#"{ session(id: "{{{") { id } } # {{{"#), 2);
+        assert_eq!(depth(r#"{ session(id: """ {{{ """) { id } }"#), 2);
+        // Fragments can be spread at any depth
+        assert_eq!(
+            depth("{ sessions { ...S } } fragment S on Session { pod { phase } }"),
+            4
+        );
+        assert_eq!(depth("{ a { b } } { c }"), 2);
+    }
+
+    #[test]
+    fn lists_batched_queries() {
+        let queries: Queries =
+            serde_json::from_str(r#"[{"query": "{ a }"}, {"query": "{ b }", "variables": {}}]"#)
+                .unwrap();
+        assert_eq!(queries.all(), vec!["{ a }", "{ b }"]);
+    }
+}
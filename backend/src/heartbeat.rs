@@ -0,0 +1,152 @@
+//! Session keep-alive
+//!
+//! While a session is in use the frontend periodically sends heartbeats, recorded as the session last activity. Each
+//! accepted heartbeat returns a nonce to send with the next one. Nonces are signed and bound to the last activity they
+//! were issued for, so that each can be used only once. Only the first heartbeat of a session is accepted without nonce.
+//! A frontend that lost its nonce re-syncs it via `Manager::resync_session_heartbeat`, as the session owner. Heartbeats
+//! closer than `SESSION_HEARTBEAT_INTERVAL` seconds (defaults to 60) are rejected.
+//! If `SESSION_IDLE_TIMEOUT` (in minutes) is set, sessions without activity for longer are deleted, unless unattended
+//! (e.g. previews). Heartbeats never extend a session past its duration.
+//! Nonces are signed with `SESSION_HEARTBEAT_SECRET`, that must be shared by all replicas. A random one is used if unset,
+//! which `config::validate` only allows with a single replica.
+//! Nonces signed with the secret a rotation replaced are still accepted.
+use crate::{auth::random_token, github::decode_hex, secrets};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::{env, time::Duration};
+
+#[derive(Clone)]
+pub struct Heartbeats {
+    secret: String,
+    /// Minimal delay between two heartbeats of a session
+    pub interval: Duration,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Heartbeats {
+    pub fn from_env() -> Self {
+        Heartbeats {
+            secret: env::var("SESSION_HEARTBEAT_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .unwrap_or_else(|| random_token(32)),
+            interval: Duration::from_secs(
+                env::var("SESSION_HEARTBEAT_INTERVAL")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(60),
+            ),
+            idle_timeout: env::var("SESSION_IDLE_TIMEOUT")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .and_then(|minutes| minutes.checked_mul(60))
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Returns the secrets nonces are verified with, the one they are signed with first
+    fn secrets(&self) -> Vec<String> {
+        // Picks up rotated secrets
+        let secrets = secrets::versions("SESSION_HEARTBEAT_SECRET");
+        if secrets.is_empty() {
+            vec![self.secret.clone()]
+        } else {
+            secrets
+        }
+    }
+
+    fn mac(secret: &str, session_id: &str, activity: u64) -> Option<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+        mac.update(format!("{}:{}", session_id, activity).as_bytes());
+        Some(mac)
+    }
+
+    /// Returns the nonce expected by the heartbeat following one recorded at `activity` (in seconds since epoch)
+    pub fn nonce(&self, session_id: &str, activity: u64) -> String {
+        self.secrets()
+            .first()
+            .and_then(|secret| Self::mac(secret, session_id, activity))
+            .map(|mac| {
+                mac.finalize()
+                    .into_bytes()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Checks a heartbeat of `session_id` sent with `nonce` at `now`, given the activity recorded by the previous one
+    pub fn check(
+        &self,
+        session_id: &str,
+        last_activity: Option<u64>,
+        nonce: Option<&str>,
+        now: u64,
+    ) -> Result<(), String> {
+        // The first heartbeat of a session doesn't have a nonce yet
+        let last_activity = match last_activity {
+            Some(last_activity) => last_activity,
+            None => return Ok(()),
+        };
+        let next = last_activity.saturating_add(self.interval.as_secs());
+        if now < next {
+            return Err(format!("next heartbeat accepted in {}s", next - now));
+        }
+        let nonce = nonce.ok_or_else(|| "missing heartbeat nonce".to_string())?;
+        let signature = decode_hex(nonce).ok_or_else(|| "invalid heartbeat nonce".to_string())?;
+        // Constant time comparison
+        self.secrets()
+            .iter()
+            .filter_map(|secret| Self::mac(secret, session_id, last_activity))
+            .find_map(|mac| mac.verify(&signature).ok())
+            .ok_or_else(|| "invalid heartbeat nonce".to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeats() -> Heartbeats {
+        Heartbeats {
+            secret: "secret".to_string(),
+            interval: Duration::from_secs(60),
+            idle_timeout: None,
+        }
+    }
+
+    #[test]
+    fn accepts_chained_heartbeats() {
+        let heartbeats = heartbeats();
+        assert_eq!(heartbeats.check("id", None, None, 100), Ok(()));
+        let nonce = heartbeats.nonce("id", 100);
+        assert_eq!(heartbeats.check("id", Some(100), Some(&nonce), 160), Ok(()));
+        // Too early
+        assert!(heartbeats
+            .check("id", Some(100), Some(&nonce), 159)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_nonces() {
+        let heartbeats = heartbeats();
+        let nonce = heartbeats.nonce("id", 100);
+        // Nonces are bound to an activity and a session
+        assert!(heartbeats
+            .check("id", Some(160), Some(&nonce), 220)
+            .is_err());
+        assert!(heartbeats
+            .check("other", Some(100), Some(&nonce), 160)
+            .is_err());
+        assert!(heartbeats.check("id", Some(100), Some("zz"), 160).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_nonces_once_active() {
+        let heartbeats = heartbeats();
+        assert!(heartbeats.check("id", Some(100), None, 160).is_err());
+        assert!(heartbeats.check("id", Some(100), None, 1000).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Idempotent mutations
+//!
+//! Mutating requests (`PUT`, `PATCH` and `DELETE`) can carry an `Idempotency-Key` header. The first successful response
+//! for a key is kept for `IDEMPOTENCY_TTL` minutes (defaults to 60) and replayed to retries, so that retrying a request
+//! doesn't create or delete things twice. Failed requests are not kept and can be retried with the same key.
+//! Keys are scoped per user and bound to the fingerprint of the request they were first used with (method, url and
+//! body). Reusing a key for another request fails, and so does reusing it while the first request is still running,
+//! with a conflict.
+//! Keys are kept in a `ConfigMap` each, so that they are shared by all replicas: creating it claims the key. Requests
+//! still running after `RUNNING_TIMEOUT`, e.g. on a replica that stopped, are considered abandoned.
+use crate::{
+    error::{Error, Result},
+    kubernetes::Engine,
+};
+use log::warn;
+use rocket::{
+    http::Status,
+    request::{self, FromRequest, Request},
+    Outcome,
+};
+use rocket_contrib::json::JsonValue;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::runtime::Runtime;
+
+pub const HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LENGTH: usize = 255;
+/// Requests still running after this long are considered abandoned
+const RUNNING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The `Idempotency-Key` of a request, if any
+pub struct Idempotency {
+    key: Option<String>,
+    /// Method and url of the request
+    request: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Idempotency {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Idempotency, String> {
+        let key = request.headers().get_one(HEADER);
+        if let Some(key) = key {
+            if key.is_empty()
+                || key.len() > MAX_KEY_LENGTH
+                || !key.chars().all(|c| c.is_ascii_graphic())
+            {
+                return Outcome::Failure((Status::BadRequest, format!("Invalid {}", HEADER)));
+            }
+        }
+        Outcome::Success(Idempotency {
+            key: key.map(str::to_string),
+            request: format!("{} {}", request.method(), request.uri()),
+        })
+    }
+}
+
+/// What is kept for a key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    fingerprint: String,
+    /// Seconds since epoch
+    claimed_at: u64,
+    /// Unset while the request is running
+    response: Option<serde_json::Value>,
+}
+
+/// How to handle a request whose key is already kept
+#[derive(Debug, PartialEq)]
+enum Check {
+    Replay(serde_json::Value),
+    Running,
+    Mismatch,
+    Expired,
+}
+
+/// Checks a request of `fingerprint` against `record`, kept for its key, at `now` (in seconds since epoch)
+fn check(record: &Record, fingerprint: &str, ttl: Duration, now: u64) -> Check {
+    let timeout = match record.response {
+        Some(_) => ttl,
+        None => RUNNING_TIMEOUT.min(ttl),
+    };
+    if now.saturating_sub(record.claimed_at) >= timeout.as_secs() {
+        return Check::Expired;
+    }
+    if record.fingerprint != fingerprint {
+        return Check::Mismatch;
+    }
+    match &record.response {
+        Some(response) => Check::Replay(response.clone()),
+        None => Check::Running,
+    }
+}
+
+fn hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn fingerprint<B: Serialize>(request: &str, body: &B) -> String {
+    let body = serde_json::to_string(body).unwrap_or_default();
+    hash(&format!("{}\n{}", request, body))
+}
+
+/// Returns how long responses are kept, `IDEMPOTENCY_TTL` minutes (defaults to 60)
+pub fn ttl() -> Duration {
+    Duration::from_secs(
+        env::var("IDEMPOTENCY_TTL")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .and_then(|minutes| minutes.checked_mul(60))
+            .unwrap_or(60 * 60),
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Forgets about a running request unless its response was kept, so that it can be retried
+struct Pending<'a> {
+    engine: &'a Engine,
+    runtime: Runtime,
+    id: String,
+    done: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Err(err) = self
+            .runtime
+            .block_on(self.engine.delete_idempotency_key(&self.id, None))
+        {
+            warn!("Failed to release {} {}: {}", HEADER, self.id, err);
+        }
+    }
+}
+
+/// Responses of requests with an `Idempotency-Key`, indexed by user and key
+#[derive(Clone)]
+pub struct Responses {
+    ttl: Duration,
+    engine: Engine,
+}
+
+impl Responses {
+    pub fn new(engine: Engine) -> Self {
+        Responses { ttl: ttl(), engine }
+    }
+
+    /// Calls `f` with `body` unless a response is already kept for the key of `idempotency`, in which case that response
+    /// is returned
+    pub fn run<B: Serialize, F: FnOnce(B) -> JsonValue>(
+        &self,
+        user: &str,
+        idempotency: &Idempotency,
+        body: B,
+        f: F,
+    ) -> Result<JsonValue> {
+        let key = match &idempotency.key {
+            Some(key) => key,
+            None => return Ok(f(body)),
+        };
+        // Users and keys are free form, the id must be a valid name
+        let id = hash(&format!("{}\n{}", user, key));
+        let mut record = Record {
+            fingerprint: fingerprint(&idempotency.request, &body),
+            claimed_at: now(),
+            response: None,
+        };
+        let value = serde_json::to_string(&record).map_err(|err| Error::Failure(err.into()))?;
+        let runtime = Runtime::new().map_err(|err| Error::Failure(err.into()))?;
+        let mut claimed = false;
+        // An expired key is replaced once
+        for _ in 0..2 {
+            let (kept, version) =
+                match runtime.block_on(self.engine.claim_idempotency_key(&id, &value))? {
+                    Some(kept) => kept,
+                    None => {
+                        claimed = true;
+                        break;
+                    }
+                };
+            let check = serde_json::from_str(&kept).map_or(Check::Expired, |kept| {
+                check(&kept, &record.fingerprint, self.ttl, record.claimed_at)
+            });
+            match check {
+                Check::Replay(response) => return Ok(JsonValue(response)),
+                Check::Running => {
+                    return Err(Error::Conflict(format!(
+                        "a request with {} {} is still running",
+                        HEADER, key
+                    )))
+                }
+                Check::Mismatch => {
+                    return Err(Error::InvalidParameter(format!(
+                        "{} {} was used for another request",
+                        HEADER, key
+                    )))
+                }
+                Check::Expired => runtime.block_on(
+                    self.engine
+                        .delete_idempotency_key(&id, Some(version.as_str())),
+                )?,
+            }
+        }
+        if !claimed {
+            return Err(Error::Conflict(format!(
+                "{} {} is used by another request",
+                HEADER, key
+            )));
+        }
+
+        let mut pending = Pending {
+            engine: &self.engine,
+            runtime,
+            id,
+            done: false,
+        };
+        let response = f(body);
+        if response.get("error").is_none() {
+            record.response = Some(response.0.clone());
+            let result = serde_json::to_string(&record)
+                .map_err(|err| Error::Failure(err.into()))
+                .and_then(|value| {
+                    pending
+                        .runtime
+                        .block_on(self.engine.update_idempotency_key(&pending.id, &value))
+                });
+            match result {
+                Ok(()) => pending.done = true,
+                Err(err) => warn!("Failed to keep response for {} {}: {}", HEADER, key, err),
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    fn record(response: Option<serde_json::Value>) -> Record {
+        Record {
+            fingerprint: "a".to_string(),
+            claimed_at: 1000,
+            response,
+        }
+    }
+
+    #[test]
+    fn replays_kept_responses() {
+        let response = serde_json::json!({ "result": 1 });
+        assert_eq!(
+            check(&record(Some(response.clone())), "a", TTL, 1000 + 30 * 60),
+            Check::Replay(response.clone())
+        );
+        assert_eq!(
+            check(&record(Some(response)), "b", TTL, 1000),
+            Check::Mismatch
+        );
+    }
+
+    #[test]
+    fn expires_records() {
+        assert_eq!(
+            check(
+                &record(Some(serde_json::json!({}))),
+                "a",
+                TTL,
+                1000 + 60 * 60
+            ),
+            Check::Expired
+        );
+        assert_eq!(check(&record(None), "a", TTL, 1000 + 60), Check::Running);
+        // Requests of a lost replica are abandoned
+        assert_eq!(
+            check(&record(None), "a", TTL, 1000 + RUNNING_TIMEOUT.as_secs()),
+            Check::Expired
+        );
+    }
+}
@@ -1,39 +1,92 @@
 //! Helper methods ton interact with k8s
 use crate::{
+    auth::random_token,
+    clock::{Clock, SystemClock},
+    dns::{self, Dns},
+    entries::parse_entry,
     error::{Error, Result},
+    git,
+    github::GitHubApp,
+    oidc::OidcConfiguration,
+    plugins::{self, Plugins},
+    reaper, registry,
+    scheduling::{node_loads, Scheduling},
+    storage::{self, Migration},
+    telemetry::traced,
     types::{
-        self, ContainerPhase, LoggedUser, Phase, Pool, Session, SessionConfiguration,
-        SessionDefaults, SessionUpdateConfiguration, Template, User, UserConfiguration,
-        UserUpdateConfiguration,
+        self, ArchivedConfigMap, Artifact, Check, ContainerPhase, DeploymentStep, DisruptionPolicy,
+        DnsStatus, Entry, GitState, Ide, Identity, InvalidEntry, Legal, LoggedUser,
+        OnboardingState, Org, Phase, Pool, Port, PrepullStatus, Reservation, ResourceProfile,
+        RetryPolicy, RoleDefaults, Session, SessionBackup, SessionConfiguration, SessionDefaults,
+        SessionDuration, SessionEnvUpdate, SessionEvent, SessionEviction, SessionFailure,
+        SessionFailureReason, SessionPlan, SessionUpdateConfiguration, StartLatency, StateArchive,
+        StaticPool, StorageVersion, SubdomainStrategy, Template, TemplateStats, UsablePool, User,
+        UserConfiguration, UserPreferencesUpdate, UserUpdateConfiguration, WorkspaceSnapshot,
     },
 };
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
 use json_patch::{AddOperation, PatchOperation, RemoveOperation};
-use k8s_openapi::apimachinery::pkg::{apis::meta::v1::ObjectMeta, util::intstr::IntOrString};
+use k8s_openapi::apimachinery::pkg::{
+    apis::meta::v1::{LabelSelector, MicroTime, ObjectMeta, OwnerReference},
+    util::intstr::IntOrString,
+};
 use k8s_openapi::{
     api::{
+        apps::v1::{StatefulSet, StatefulSetSpec, StatefulSetUpdateStrategy},
+        batch::v1::{Job, JobSpec},
+        coordination::v1::{Lease, LeaseSpec},
         core::v1::{
-            Affinity, ConfigMap, Container, ContainerStatus, EnvVar, Node, NodeAffinity,
-            NodeSelector, NodeSelectorRequirement, NodeSelectorTerm, Pod, PodSpec,
-            ResourceRequirements, Service, ServicePort, ServiceSpec,
+            Affinity, ConfigMap, ConfigMapEnvSource, ConfigMapVolumeSource, Container,
+            ContainerState, ContainerStatus, EmptyDirVolumeSource, EnvFromSource, EnvVar, Event,
+            ExecAction, HTTPGetAction, Handler, Lifecycle, Node, NodeAffinity, NodeSelector,
+            NodeSelectorRequirement, NodeSelectorTerm, Pod, PodSpec, PodStatus, PodTemplateSpec,
+            PreferredSchedulingTerm, Probe, ResourceRequirements, Secret, Service, ServicePort,
+            ServiceSpec, Volume, VolumeMount,
         },
         networking::v1::{
             HTTPIngressPath, HTTPIngressRuleValue, Ingress, IngressBackend, IngressRule,
             IngressServiceBackend, ServiceBackendPort,
         },
+        policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec},
     },
     apimachinery::pkg::api::resource::Quantity,
+    ByteString,
 };
 use kube::{
-    api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams},
+    api::{
+        Api, ApiResource, AttachParams, DeleteParams, DynamicObject, GroupVersionKind, ListParams,
+        Patch, PatchParams, PostParams, Preconditions,
+    },
     config::KubeConfigOptions,
     Client, Config,
 };
-use log::error;
+use kube_runtime::{
+    reflector::{reflector, store::Writer, Store},
+    watcher::{self, watcher},
+};
+use log::{error, info, warn};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::BTreeMap, convert::TryFrom, env, fmt::Debug, num::ParseIntError, str::FromStr,
-    time::Duration,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    env,
+    fmt::Debug,
+    lazy::SyncLazy,
+    num::ParseIntError,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::broadcast,
 };
 
 const NODE_POOL_LABEL: &str = "cloud.google.com/gke-nodepool";
@@ -44,12 +97,128 @@ const APP_VALUE: &str = "playground";
 const COMPONENT_LABEL: &str = "app.kubernetes.io/component";
 const COMPONENT_VALUE: &str = "session";
 const OWNER_LABEL: &str = "app.kubernetes.io/owner";
+const PREPULL_COMPONENT_VALUE: &str = "prepull";
+const PREPULL_POOL_LABEL: &str = "playground.substrate.io/pool";
+const PREPULL_NODE_LABEL: &str = "playground.substrate.io/node";
+const PREPULL_TEMPLATE_LABEL: &str = "playground.substrate.io/template";
+/// Finished pre-pull `Job`s, and their pods, are deleted after this long. Their status is reported until then.
+const PREPULL_JOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 const INGRESS_NAME: &str = "ingress";
+/// Covers all session pods, see `DisruptionPolicy`
+const DISRUPTION_BUDGET_NAME: &str = "playground-sessions";
 const TEMPLATE_ANNOTATION: &str = "playground.substrate.io/template";
 const SESSION_DURATION_ANNOTATION: &str = "playground.substrate.io/session_duration";
+const SESSION_FLAGS_ANNOTATION: &str = "playground.substrate.io/flags";
+const SESSION_DOMAIN_ANNOTATION: &str = "playground.substrate.io/domain";
+/// DNS label the session is served under, see `SubdomainStrategy`
+const SESSION_SUBDOMAIN_ANNOTATION: &str = "playground.substrate.io/subdomain";
+const SESSION_MIGRATION_ANNOTATION: &str = "playground.substrate.io/migration";
+const SESSION_BACKUP_ANNOTATION: &str = "playground.substrate.io/backup";
+/// Role of the session owner at creation
+const SESSION_ROLE_ANNOTATION: &str = "playground.substrate.io/role";
+/// Organizations of the session owner at creation, as a JSON list
+const SESSION_ORGANIZATIONS_ANNOTATION: &str = "playground.substrate.io/organizations";
+/// Pool requested for the session, that it might not be scheduled on yet
+const SESSION_POOL_ANNOTATION: &str = "playground.substrate.io/pool";
+const SESSION_RETRIES_ANNOTATION: &str = "playground.substrate.io/retries";
+/// Set to `true` for sessions not subject to the idle timeout
+const SESSION_UNATTENDED_ANNOTATION: &str = "playground.substrate.io/unattended";
+/// Seconds since epoch of the last heartbeat
+const SESSION_ACTIVITY_ANNOTATION: &str = "playground.substrate.io/last_activity";
+/// Seconds since epoch of the soft deletion of a session
+const SESSION_DELETED_ANNOTATION: &str = "playground.substrate.io/deleted_at";
+/// Seconds since epoch the session duration counts from. Kept by pods recreated by their `StatefulSet`.
+const SESSION_STARTED_ANNOTATION: &str = "playground.substrate.io/started_at";
+/// Comma separated hostnames a session failed to start on
+const SESSION_FAILED_NODES_ANNOTATION: &str = "playground.substrate.io/failed_nodes";
+/// Node of sessions not scheduled yet
+pub const UNSCHEDULED_NODE: &str = "<Unknown>";
+/// Delay after which pods still terminating past their grace period are considered lost with their node
+const LOST_POD_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Key of the backup Secret holding the git remote token
+const BACKUP_TOKEN_KEY: &str = "token";
+/// Set on pods that must not be considered as the live pod of their session, during a migration
+const MIGRATION_LABEL: &str = "playground.substrate.io/migration";
+/// Set on `Secret`s holding records shared by replicas, with their kind as value
+const RECORD_LABEL: &str = "playground.substrate.io/record";
+const RECORD_KEY: &str = "value";
+/// Set on `ConfigMap`s holding idempotency keys, see `idempotency`
+const IDEMPOTENCY_LABEL: &str = "playground.substrate.io/idempotency";
+const IDEMPOTENCY_KEY: &str = "record";
+/// Set on `StatefulSet`s of sessions scaled to zero by `Engine::set_session_suspended`, so that they are still listed
+const SUSPENDED_LABEL: &str = "playground.substrate.io/suspended";
+/// Identifies the pod a session service routes to
+const POD_LABEL: &str = "playground.substrate.io/pod";
+/// Set on session pods and peer services of sessions that joined a workshop
+const WORKSHOP_LABEL: &str = "playground.substrate.io/workshop";
+/// Maximum size of all artifacts of a workshop. They share a ConfigMap, limited to 1MiB including its metadata.
+const MAX_ARTIFACTS_SIZE: usize = 1000 * 1024;
+const WORKSHOP_ENV: &str = "SUBSTRATE_PLAYGROUND_WORKSHOP";
+const WORKSHOP_PEERS_ENV: &str = "SUBSTRATE_PLAYGROUND_WORKSHOP_PEERS";
+/// Central telemetry server in-session nodes can forward to, e.g. via `--telemetry-url`
+const TELEMETRY_URL_ENV: &str = "SUBSTRATE_PLAYGROUND_TELEMETRY_URL";
+/// Path the in-session telemetry output is exposed under
+const TELEMETRY_PATH: &str = "/telemetry";
+const WORKSPACE_PATH: &str = "/home/playground/workspace";
+/// Holds the workspace of sessions restored from a snapshot, see `restore_workspace_snapshot`
+const WORKSPACE_VOLUME: &str = "workspace";
+/// Files likely to hold credentials, left out of published workspaces
+const SNAPSHOT_EXCLUDES: &[&str] = &[
+    "**/.env",
+    "**/.env.*",
+    "**/.git-credentials",
+    "**/.netrc",
+    "**/.npmrc",
+    "**/*.pem",
+    "**/*.key",
+    "**/id_rsa*",
+    "**/id_ed25519*",
+];
+/// Where variables set at runtime via `Engine::update_session_env` are exposed, one file per variable
+const SESSION_ENV_PATH: &str = "/etc/playground/env";
+const SESSION_ENV_VOLUME: &str = "session-env";
 const USERS_CONFIG_MAP: &str = "playground-users";
 const TEMPLATES_CONFIG_MAP: &str = "playground-templates";
-const THEIA_WEB_PORT: i32 = 3000;
+const STATE_CONFIG_MAP: &str = "playground-backend-state";
+/// Number of times a state update is attempted when conflicting with concurrent ones
+const STATE_UPDATE_ATTEMPTS: usize = 5;
+const ORGS_CONFIG_MAP: &str = "playground-orgs";
+/// Organizations are read on each authenticated request, and cached that long. Replicas other than the one updating an
+/// organization pick up changes within this delay.
+const ORGS_CACHE_TTL: Duration = Duration::from_secs(30);
+/// Session pod changes not yet received by all subscribers, before the slowest ones lag
+const POD_CHANGES_CAPACITY: usize = 256;
+/// ConfigMaps holding resources, with migrations of their format
+const STORED_RESOURCES: &[(&str, &[Migration])] = &[
+    (USERS_CONFIG_MAP, storage::USER_MIGRATIONS),
+    (TEMPLATES_CONFIG_MAP, storage::TEMPLATE_MIGRATIONS),
+    (ORGS_CONFIG_MAP, storage::ORG_MIGRATIONS),
+];
+/// State key holding the node last used by each session id
+const LAST_NODES_STATE: &str = "lastNodes";
+/// How long the node a session last ran on is remembered for
+const LAST_NODE_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Maximum number of last nodes remembered, the oldest are forgotten first
+const MAX_LAST_NODES: usize = 1000;
+/// State key holding the last eviction of each session id
+const EVICTIONS_STATE: &str = "evictions";
+/// How long evictions are kept for
+const EVICTION_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// State key holding capacity reservations, by id
+const RESERVATIONS_STATE: &str = "reservations";
+/// State key holding daily session creations per template
+const TEMPLATE_ANALYTICS_STATE: &str = "templateAnalytics";
+/// Number of days template analytics are kept for
+pub const TEMPLATE_ANALYTICS_RETENTION_DAYS: u64 = 90;
+/// State key set once running sessions are handed off to another cluster
+const HANDOFF_STATE: &str = "handoff";
+/// Format version of `StateArchive`s
+const ARCHIVE_VERSION: u32 = 1;
+/// State keys included in archives. Others refer to resources of the current cluster (nodes, pods).
+const ARCHIVED_STATES: &[&str] = &[RESERVATIONS_STATE, TEMPLATE_ANALYTICS_STATE];
+const THEIA_SETTINGS_ENV: &str = "SUBSTRATE_PLAYGROUND_THEIA_SETTINGS";
+/// Maximum time spent resolving an image digest before falling back to its tag
+const IMAGE_PIN_TIMEOUT: Duration = Duration::from_secs(3);
 
 fn running_or_pending_sessions(sessions: Vec<&Session>) -> Vec<&Session> {
     sessions
@@ -74,12 +243,356 @@ async fn list_by_selector<K: Clone + DeserializeOwned + Debug>(
         .map_err(|err| Error::Failure(err.into()))
 }
 
+/// Parses a CPU quantity (e.g. `1`, `250m`, `123456789n`) into millicores
+fn cpu_to_millicores(quantity: &str) -> Option<u64> {
+    if let Some(nanocores) = quantity.strip_suffix('n') {
+        nanocores.parse::<u64>().ok().map(|n| n / 1_000_000)
+    } else if let Some(microcores) = quantity.strip_suffix('u') {
+        microcores.parse::<u64>().ok().map(|n| n / 1_000)
+    } else if let Some(millicores) = quantity.strip_suffix('m') {
+        millicores.parse().ok()
+    } else {
+        quantity.parse::<f64>().ok().map(|n| (n * 1000.0) as u64)
+    }
+}
+
+/// Prepended to the names of resources created for sessions, so that they don't clash with others sharing the namespace
+static RESOURCE_PREFIX: SyncLazy<String> =
+    SyncLazy::new(|| env::var("RESOURCE_PREFIX").unwrap_or_default());
+
 pub fn pod_name(user: &str) -> String {
-    format!("{}-{}", COMPONENT_VALUE, user)
+    format!("{}{}-{}", *RESOURCE_PREFIX, COMPONENT_VALUE, user)
+}
+
+/// Selects the live pod of session `id`, ignoring pods involved in a migration
+fn session_pod_selector(id: &str) -> String {
+    format!(
+        "{}={},{}={},!{}",
+        COMPONENT_LABEL, COMPONENT_VALUE, OWNER_LABEL, id, MIGRATION_LABEL
+    )
+}
+
+/// Returns the live pod of session `id`. Its name changes once migrated.
+async fn get_session_pod(pod_api: &Api<Pod>, id: &str) -> Result<Option<Pod>> {
+    Ok(list_by_selector(pod_api, session_pod_selector(id))
+        .await?
+        .into_iter()
+        .next())
+}
+
+async fn get_session_pod_name(pod_api: &Api<Pod>, id: &str) -> Result<String> {
+    get_session_pod(pod_api, id)
+        .await?
+        .and_then(|pod| pod.metadata.name)
+        .ok_or(Error::MissingData("no matching session"))
 }
 
 pub fn service_name(session_id: &str) -> String {
-    format!("{}-service-{}", COMPONENT_VALUE, session_id)
+    format!(
+        "{}{}-service-{}",
+        *RESOURCE_PREFIX, COMPONENT_VALUE, session_id
+    )
+}
+
+fn env_config_map_name(session_id: &str) -> String {
+    format!("{}{}-env-{}", *RESOURCE_PREFIX, COMPONENT_VALUE, session_id)
+}
+
+fn backup_secret_name(session_id: &str) -> String {
+    format!(
+        "{}{}-backup-{}",
+        *RESOURCE_PREFIX, COMPONENT_VALUE, session_id
+    )
+}
+
+fn create_backup_secret(session_id: &str, token: &str) -> Secret {
+    let mut labels = BTreeMap::new();
+    labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
+    labels.insert(COMPONENT_LABEL.to_string(), COMPONENT_VALUE.to_string());
+    labels.insert(OWNER_LABEL.to_string(), session_id.to_string());
+
+    Secret {
+        metadata: ObjectMeta {
+            name: Some(backup_secret_name(session_id)),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        string_data: Some(BTreeMap::from([(
+            BACKUP_TOKEN_KEY.to_string(),
+            token.to_string(),
+        )])),
+        ..Default::default()
+    }
+}
+
+/// Escapes `token` to be used as a JSON pointer segment, see RFC 6901. `~` must be escaped first, so that escaped `/`
+/// aren't escaped again.
+fn json_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+/// Name under which session `session_id` can be reached by other members of `workshop`. Names too long for a DNS
+/// label are truncated and suffixed with a hash of the full name, so that they stay unique.
+fn peer_service_name(workshop: &str, session_id: &str) -> String {
+    const MAX_LENGTH: usize = 63;
+    const HASH_LENGTH: usize = 8;
+    let name = format!("{}workshop-{}-{}", *RESOURCE_PREFIX, workshop, session_id);
+    if name.len() <= MAX_LENGTH {
+        return name;
+    }
+    let hash: String = Sha256::digest(name.as_bytes())
+        .iter()
+        .take(HASH_LENGTH / 2)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let prefix: String = name.chars().take(MAX_LENGTH - HASH_LENGTH - 1).collect();
+    format!("{}-{}", prefix.trim_end_matches('-'), hash)
+}
+
+/// An `ExternalName` service aliasing the service of session `session_id`
+fn create_peer_service(workshop: &str, session_id: &str, namespace: &str) -> Service {
+    let mut labels = BTreeMap::new();
+    labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
+    labels.insert(COMPONENT_LABEL.to_string(), COMPONENT_VALUE.to_string());
+    labels.insert(OWNER_LABEL.to_string(), session_id.to_string());
+    labels.insert(WORKSHOP_LABEL.to_string(), workshop.to_string());
+
+    Service {
+        metadata: ObjectMeta {
+            name: Some(peer_service_name(workshop, session_id)),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        spec: Some(ServiceSpec {
+            type_: Some("ExternalName".to_string()),
+            external_name: Some(format!(
+                "{}.{}.svc.cluster.local",
+                service_name(session_id),
+                namespace
+            )),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Slots of `pool` reserved at `now` for workshops other than `workshop`, and not yet used by their members
+fn reserved_slots(
+    reservations: &BTreeMap<String, Reservation>,
+    pool: &Pool,
+    workshop: Option<&str>,
+    sessions: &BTreeMap<String, Session>,
+    now: SystemTime,
+) -> usize {
+    reservations
+        .values()
+        .filter(|reservation| {
+            reservation.pool == pool.name
+                && reservation.is_active(now)
+                && Some(reservation.workshop.as_str()) != workshop
+        })
+        .map(|reservation| {
+            let used = pool_sessions(pool, sessions)
+                .iter()
+                .filter(|session| session.workshop.as_ref() == Some(&reservation.workshop))
+                .count();
+            reservation.slots.saturating_sub(used)
+        })
+        .sum()
+}
+
+/// Returns the number of sessions that can still be created on `pool`, not counting slots reserved at `now` for
+/// workshops other than `workshop`
+fn free_slots(
+    pool: &Pool,
+    max_sessions_per_pod: usize,
+    reservations: &BTreeMap<String, Reservation>,
+    workshop: Option<&str>,
+    sessions: &BTreeMap<String, Session>,
+    now: SystemTime,
+) -> usize {
+    // TODO Should trigger pool dynamic scalability. Right now this will only consider the pool lower bound.
+    let capacity = pool
+        .capacity
+        .unwrap_or(pool.nodes.len() * max_sessions_per_pod);
+    capacity.saturating_sub(
+        pool_sessions(pool, sessions).len()
+            + reserved_slots(reservations, pool, workshop, sessions, now),
+    )
+}
+
+/// Returns true if `session` runs on `pool`, or was requested on it if not scheduled yet
+fn in_pool(session: &Session, pool: &Pool) -> bool {
+    if session.node == UNSCHEDULED_NODE {
+        session.pool.as_deref() == Some(pool.name.as_str())
+    } else {
+        pool.nodes.iter().any(|node| node.hostname == session.node)
+    }
+}
+
+/// Returns the running or pending sessions of `pool`
+fn pool_sessions<'a>(pool: &Pool, sessions: &'a BTreeMap<String, Session>) -> Vec<&'a Session> {
+    running_or_pending_sessions(
+        sessions
+            .values()
+            .filter(|session| in_pool(session, pool))
+            .collect(),
+    )
+}
+
+/// Holds record `id` of `kind`, see `Engine::save_record`
+fn record_secret_name(kind: &str, id: &str) -> String {
+    format!("{}{}-{}", *RESOURCE_PREFIX, kind, id)
+}
+
+/// Returns the value of the record held by `secret`
+fn record_value(secret: &Secret) -> Option<String> {
+    secret
+        .data
+        .as_ref()?
+        .get(RECORD_KEY)
+        .and_then(|value| String::from_utf8(value.0.clone()).ok())
+}
+
+/// Holds the response kept for idempotency key `id`, see `idempotency`
+fn idempotency_config_map_name(id: &str) -> String {
+    format!("{}idempotency-{}", *RESOURCE_PREFIX, id)
+}
+
+/// Holds files shared by members of `workshop`, e.g. chain specs
+fn artifacts_config_map_name(workshop: &str) -> String {
+    format!("{}workshop-artifacts-{}", *RESOURCE_PREFIX, workshop)
+}
+
+/// Labels `pod` as part of `workshop` and lists `peers` (other members at creation time) in its env
+fn join_workshop(pod: &mut Pod, workshop: &str, peers: &[String]) {
+    if let Some(labels) = pod.metadata.labels.as_mut() {
+        labels.insert(WORKSHOP_LABEL.to_string(), workshop.to_string());
+    }
+    let peers = peers
+        .iter()
+        .map(|peer| peer_service_name(workshop, peer))
+        .collect::<Vec<_>>()
+        .join(",");
+    add_env_var(pod, create_env_var(WORKSHOP_ENV, workshop));
+    add_env_var(pod, create_env_var(WORKSHOP_PEERS_ENV, &peers));
+}
+
+fn add_env_var(pod: &mut Pod, env: EnvVar) {
+    if let Some(container) = pod
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.containers.first_mut())
+    {
+        container.env.get_or_insert_with(Vec::new).push(env);
+    }
+}
+
+/// Overrides resources requested by the session container with `profile`
+fn apply_resource_profile(pod: &mut Pod, profile: &ResourceProfile) {
+    if let Some(requests) = pod
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.containers.first_mut())
+        .and_then(|container| container.resources.as_mut())
+        .map(|resources| resources.requests.get_or_insert_with(BTreeMap::new))
+    {
+        if let Some(memory) = &profile.memory {
+            requests.insert("memory".to_string(), Quantity(memory.clone()));
+        }
+        if let Some(cpu) = &profile.cpu {
+            requests.insert("cpu".to_string(), Quantity(cpu.clone()));
+        }
+    }
+}
+
+/// Prefers scheduling `pod` on nodes in `weights`, keyed by hostname
+fn prefer_nodes(pod: &mut Pod, weights: BTreeMap<String, i32>) {
+    if let Some(affinity) = pod
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.affinity.as_mut())
+        .and_then(|affinity| affinity.node_affinity.as_mut())
+    {
+        let terms = affinity
+            .preferred_during_scheduling_ignored_during_execution
+            .get_or_insert_with(Vec::new);
+        terms.extend(
+            weights
+                .into_iter()
+                .map(|(hostname, weight)| PreferredSchedulingTerm {
+                    weight,
+                    preference: NodeSelectorTerm {
+                        match_expressions: Some(vec![NodeSelectorRequirement {
+                            key: HOSTNAME_LABEL.to_string(),
+                            operator: "In".to_string(),
+                            values: Some(vec![hostname]),
+                        }]),
+                        ..Default::default()
+                    },
+                }),
+        );
+    }
+}
+
+/// Workshop names end up in service names, they must be valid DNS labels
+pub fn validate_workshop(workshop: &str) -> Result<()> {
+    if workshop.is_empty()
+        || workshop.len() > 20
+        || workshop.starts_with('-')
+        || workshop.ends_with('-')
+        || !workshop
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(Error::InvalidParameter(format!("workshop {}", workshop)));
+    }
+    Ok(())
+}
+
+/// Ids of the sessions that joined `workshop`, excluding `session_id`
+fn workshop_peers(
+    sessions: &BTreeMap<String, Session>,
+    workshop: &str,
+    session_id: &str,
+) -> Vec<String> {
+    sessions
+        .iter()
+        .filter(|(id, session)| {
+            id.as_str() != session_id && session.workshop.as_deref() == Some(workshop)
+        })
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// Checks that `backup` can't be used to inject arguments in git commands
+fn validate_backup(backup: &SessionBackup) -> Result<()> {
+    let remote = backup
+        .remote
+        .strip_prefix("https://")
+        .ok_or_else(|| Error::InvalidParameter("backup remote must use https".to_string()))?;
+    if remote.contains('@') || remote.chars().any(char::is_whitespace) {
+        return Err(Error::InvalidParameter(format!(
+            "backup remote {}",
+            backup.remote
+        )));
+    }
+    if backup.branch.is_empty()
+        || backup.branch.starts_with('-')
+        || backup
+            .branch
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "~^:?*[\\".contains(c))
+    {
+        return Err(Error::InvalidParameter(format!(
+            "backup branch {}",
+            backup.branch
+        )));
+    }
+    if backup.interval == 0 || backup.interval.checked_mul(60).is_none() {
+        return Err(Error::InvalidParameter("backup interval".to_string()));
+    }
+    Ok(())
 }
 
 fn create_env_var(name: &str, value: &str) -> EnvVar {
@@ -94,32 +607,76 @@ fn patch_value(value: String, host: &str) -> String {
     value.replace("%HOST%", host)
 }
 
-fn pod_env_variables(template: &Template, host: &str, session_id: &str) -> Vec<EnvVar> {
+/// `url` is the host the session is served under, that `%HOST%` expands to in template env variables
+fn pod_env_variables(template: &Template, host: &str, url: &str, session_id: &str) -> Vec<EnvVar> {
     let mut envs = vec![
         create_env_var("SUBSTRATE_PLAYGROUND", ""),
         create_env_var("SUBSTRATE_PLAYGROUND_SESSION", session_id),
         create_env_var("SUBSTRATE_PLAYGROUND_HOSTNAME", host),
     ];
     if let Some(mut template_envs) = template.runtime.as_ref().and_then(|r| {
-        let user_host = format!("{}.{}", &session_id, &host);
         r.env.clone().map(|envs| {
             envs.iter()
-                .map(|env| create_env_var(&env.name, &patch_value(env.value.clone(), &user_host)))
+                .map(|env| create_env_var(&env.name, &patch_value(env.value.clone(), url)))
                 .collect::<Vec<EnvVar>>()
         })
     }) {
         envs.append(&mut template_envs);
     };
+    if let Some(theia) = &template.theia {
+        if !theia.plugins.is_empty() {
+            envs.push(create_env_var("THEIA_PLUGINS", &theia.plugins.join(",")));
+        }
+        if let Some(settings) = &theia.settings {
+            envs.push(create_env_var(THEIA_SETTINGS_ENV, &settings.to_string()));
+        }
+    }
     envs
 }
 
-// TODO detect when ingress is restarted, then re-sync theia sessions
+/// Marks the session ready once its IDE answers
+fn ide_readiness_probe(ide: &Ide) -> Option<Probe> {
+    ide.health_check().map(|path| Probe {
+        http_get: Some(HTTPGetAction {
+            path: Some(path.to_string()),
+            port: IntOrString::Int(ide.port()),
+            ..Default::default()
+        }),
+        period_seconds: Some(5),
+        ..Default::default()
+    })
+}
+
+/// Writes Theia settings provided by the template, unless the user already has some
+fn theia_settings_hook(template: &Template) -> Option<Lifecycle> {
+    template.theia.as_ref()?.settings.as_ref()?;
+    let script = format!(
+        "mkdir -p \"$HOME/.theia\" && [ -f \"$HOME/.theia/settings.json\" ] || printf '%s' \"${}\" > \"$HOME/.theia/settings.json\"",
+        THEIA_SETTINGS_ENV
+    );
+    Some(Lifecycle {
+        post_start: Some(Handler {
+            exec: Some(ExecAction {
+                command: Some(vec!["sh".to_string(), "-c".to_string(), script]),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
 
 fn session_duration_annotation(duration: Duration) -> String {
     let duration_min = duration.as_secs() / 60;
     duration_min.to_string()
 }
 
+fn session_started_annotation(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string()
+}
+
 fn str_to_session_duration_minutes(str: &str) -> Result<Duration> {
     Ok(Duration::from_secs(
         str.parse::<u64>()
@@ -131,6 +688,7 @@ fn str_to_session_duration_minutes(str: &str) -> Result<Duration> {
 fn create_pod_annotations(
     template: &Template,
     duration: &Duration,
+    domain: &str,
 ) -> Result<BTreeMap<String, String>> {
     let mut annotations = BTreeMap::new();
     let s = serde_yaml::to_string(template).map_err(|err| Error::Failure(err.into()))?;
@@ -139,26 +697,38 @@ fn create_pod_annotations(
         SESSION_DURATION_ANNOTATION.to_string(),
         session_duration_annotation(*duration),
     );
+    annotations.insert(SESSION_DOMAIN_ANNOTATION.to_string(), domain.to_string());
+    annotations.insert(
+        SESSION_STARTED_ANNOTATION.to_string(),
+        session_started_annotation(SystemTime::now()),
+    );
     Ok(annotations)
 }
 
 fn create_pod(
-    env: &Environment,
+    domain: &str,
+    url: &str,
     session_id: &str,
+    name: &str,
     template: &Template,
     duration: &Duration,
     pool_id: &str,
+    pool_requirements: Vec<NodeSelectorRequirement>,
+    preferred_node: Option<&str>,
 ) -> Result<Pod> {
+    let mut annotations = create_pod_annotations(template, duration, domain)?;
+    annotations.insert(SESSION_POOL_ANNOTATION.to_string(), pool_id.to_string());
     let mut labels = BTreeMap::new();
     labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
     labels.insert(COMPONENT_LABEL.to_string(), COMPONENT_VALUE.to_string());
     labels.insert(OWNER_LABEL.to_string(), session_id.to_string());
+    labels.insert(POD_LABEL.to_string(), name.to_string());
 
-    Ok(Pod {
+    let mut pod = Pod {
         metadata: ObjectMeta {
-            name: Some(pod_name(session_id)),
+            name: Some(name.to_string()),
             labels: Some(labels),
-            annotations: Some(create_pod_annotations(template, duration)?),
+            annotations: Some(annotations),
             ..Default::default()
         },
         spec: Some(PodSpec {
@@ -166,22 +736,49 @@ fn create_pod(
                 node_affinity: Some(NodeAffinity {
                     required_during_scheduling_ignored_during_execution: Some(NodeSelector {
                         node_selector_terms: vec![NodeSelectorTerm {
-                            match_expressions: Some(vec![NodeSelectorRequirement {
-                                key: NODE_POOL_LABEL.to_string(),
-                                operator: "In".to_string(),
-                                values: Some(vec![pool_id.to_string()]),
-                            }]),
+                            match_expressions: Some(pool_requirements),
                             ..Default::default()
                         }],
                     }),
-                    ..Default::default()
+                    // Image layers are more likely to be cached there
+                    preferred_during_scheduling_ignored_during_execution: preferred_node.map(
+                        |node| {
+                            vec![PreferredSchedulingTerm {
+                                weight: 100,
+                                preference: NodeSelectorTerm {
+                                    match_expressions: Some(vec![NodeSelectorRequirement {
+                                        key: HOSTNAME_LABEL.to_string(),
+                                        operator: "In".to_string(),
+                                        values: Some(vec![node.to_string()]),
+                                    }]),
+                                    ..Default::default()
+                                },
+                            }]
+                        },
+                    ),
                 }),
                 ..Default::default()
             }),
             containers: vec![Container {
                 name: format!("{}-container", COMPONENT_VALUE),
                 image: Some(template.image.to_string()),
-                env: Some(pod_env_variables(template, &env.host, session_id)),
+                env: Some(pod_env_variables(template, domain, url, session_id)),
+                // Only picked up on container restart, files under `SESSION_ENV_PATH` are kept up to date
+                env_from: Some(vec![EnvFromSource {
+                    config_map_ref: Some(ConfigMapEnvSource {
+                        name: Some(env_config_map_name(session_id)),
+                        optional: Some(true),
+                    }),
+                    ..Default::default()
+                }]),
+                volume_mounts: Some(vec![VolumeMount {
+                    name: SESSION_ENV_VOLUME.to_string(),
+                    mount_path: SESSION_ENV_PATH.to_string(),
+                    read_only: Some(true),
+                    ..Default::default()
+                }]),
+                lifecycle: theia_settings_hook(template),
+                readiness_probe: ide_readiness_probe(&template.ide()),
                 resources: Some(ResourceRequirements {
                     requests: Some(BTreeMap::from([
                         ("memory".to_string(), Quantity("10Gi".to_string())),
@@ -197,12 +794,130 @@ fn create_pod(
                 }),
                 ..Default::default()
             }],
+            volumes: Some(vec![Volume {
+                name: SESSION_ENV_VOLUME.to_string(),
+                config_map: Some(ConfigMapVolumeSource {
+                    name: Some(env_config_map_name(session_id)),
+                    optional: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }]),
             termination_grace_period_seconds: Some(1),
             automount_service_account_token: Some(false),
             ..Default::default()
         }),
         ..Default::default()
-    })
+    };
+    if let Some(snapshot) = &template.snapshot {
+        restore_workspace_snapshot(&mut pod, &template.image, snapshot);
+    }
+    Ok(pod)
+}
+
+/// Checks out `snapshot` in the workspace of `pod` before it starts, via an init container running `image`.
+/// The workspace is moved to a volume shared with the init container.
+fn restore_workspace_snapshot(pod: &mut Pod, image: &str, snapshot: &WorkspaceSnapshot) {
+    let mount = VolumeMount {
+        name: WORKSPACE_VOLUME.to_string(),
+        mount_path: WORKSPACE_PATH.to_string(),
+        ..Default::default()
+    };
+    if let Some(spec) = pod.spec.as_mut() {
+        spec.volumes.get_or_insert_with(Vec::new).push(Volume {
+            name: WORKSPACE_VOLUME.to_string(),
+            empty_dir: Some(EmptyDirVolumeSource::default()),
+            ..Default::default()
+        });
+        if let Some(container) = spec.containers.first_mut() {
+            container
+                .volume_mounts
+                .get_or_insert_with(Vec::new)
+                .push(mount.clone());
+        }
+        // Remote and commit are passed as positional parameters, never interpolated
+        let script = "cd \"$0\" \
+            && git init -q \
+            && git fetch -q \"$1\" \"$2\" \
+            && git checkout -q --force FETCH_HEAD";
+        spec.init_containers
+            .get_or_insert_with(Vec::new)
+            .push(Container {
+                name: "restore-workspace".to_string(),
+                image: Some(image.to_string()),
+                command: Some(vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    script.to_string(),
+                    WORKSPACE_PATH.to_string(),
+                    snapshot.remote.clone(),
+                    snapshot.commit.clone(),
+                ]),
+                volume_mounts: Some(vec![mount]),
+                ..Default::default()
+            });
+    }
+}
+
+/// A `Job` pulling `template` image on `hostname`, so that sessions scheduled there start faster
+fn create_prepull_job(
+    pool_id: &str,
+    hostname: &str,
+    template_id: &str,
+    template: &Template,
+) -> Job {
+    let mut labels = BTreeMap::new();
+    labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
+    labels.insert(
+        COMPONENT_LABEL.to_string(),
+        PREPULL_COMPONENT_VALUE.to_string(),
+    );
+    labels.insert(PREPULL_POOL_LABEL.to_string(), pool_id.to_string());
+    labels.insert(PREPULL_NODE_LABEL.to_string(), hostname.to_string());
+    labels.insert(PREPULL_TEMPLATE_LABEL.to_string(), template_id.to_string());
+
+    Job {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}{}-", *RESOURCE_PREFIX, PREPULL_COMPONENT_VALUE)),
+            labels: Some(labels.clone()),
+            ..Default::default()
+        },
+        spec: Some(JobSpec {
+            backoff_limit: Some(2),
+            ttl_seconds_after_finished: Some(PREPULL_JOB_TTL.as_secs() as i32),
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    node_selector: Some(BTreeMap::from([(
+                        HOSTNAME_LABEL.to_string(),
+                        hostname.to_string(),
+                    )])),
+                    containers: vec![Container {
+                        name: format!("{}-container", PREPULL_COMPONENT_VALUE),
+                        image: Some(template.image.to_string()),
+                        // Pulling the image is all that matters
+                        command: Some(vec!["sh".to_string(), "-c".to_string(), "true".to_string()]),
+                        resources: Some(ResourceRequirements {
+                            requests: Some(BTreeMap::from([
+                                ("cpu".to_string(), Quantity("10m".to_string())),
+                                ("memory".to_string(), Quantity("16Mi".to_string())),
+                            ])),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }],
+                    restart_policy: Some("Never".to_string()),
+                    automount_service_account_token: Some(false),
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
 }
 
 fn create_service(session_id: &str, template: &Template) -> Service {
@@ -212,30 +927,33 @@ fn create_service(session_id: &str, template: &Template) -> Service {
     labels.insert(OWNER_LABEL.to_string(), session_id.to_string());
     let mut selectors = BTreeMap::new();
     selectors.insert(OWNER_LABEL.to_string(), session_id.to_string());
+    selectors.insert(POD_LABEL.to_string(), pod_name(session_id));
 
-    // The theia port itself is mandatory
+    // The IDE port itself is mandatory
     let mut ports = vec![ServicePort {
         name: Some("web".to_string()),
         protocol: Some("TCP".to_string()),
-        port: THEIA_WEB_PORT,
+        port: template.ide().port(),
         ..Default::default()
     }];
     if let Some(mut template_ports) = template.runtime.as_ref().and_then(|r| {
         r.ports.clone().map(|ports| {
             ports
                 .iter()
-                .map(|port| ServicePort {
-                    name: Some(port.clone().name),
-                    protocol: port.clone().protocol,
-                    port: port.port,
-                    target_port: port.clone().target.map(IntOrString::Int),
-                    ..Default::default()
-                })
+                .map(create_service_port)
                 .collect::<Vec<ServicePort>>()
         })
     }) {
         ports.append(&mut template_ports);
     };
+    if let Some(telemetry) = &template.telemetry {
+        ports.push(ServicePort {
+            name: Some("telemetry".to_string()),
+            protocol: Some("TCP".to_string()),
+            port: telemetry.port,
+            ..Default::default()
+        });
+    }
 
     Service {
         metadata: ObjectMeta {
@@ -253,6 +971,16 @@ fn create_service(session_id: &str, template: &Template) -> Service {
     }
 }
 
+fn create_service_port(port: &Port) -> ServicePort {
+    ServicePort {
+        name: Some(port.name.clone()),
+        protocol: port.protocol.clone(),
+        port: port.port,
+        target_port: port.target.map(IntOrString::Int),
+        ..Default::default()
+    }
+}
+
 fn create_ingress_path(path: &str, service_name: &str, service_port: i32) -> HTTPIngressPath {
     HTTPIngressPath {
         path: Some(path.to_string()),
@@ -271,7 +999,8 @@ fn create_ingress_path(path: &str, service_name: &str, service_port: i32) -> HTT
 }
 
 fn create_ingress_paths(service_name: String, template: &Template) -> Vec<HTTPIngressPath> {
-    let mut paths = vec![create_ingress_path("/", &service_name, THEIA_WEB_PORT)];
+    let ide = template.ide();
+    let mut paths = vec![create_ingress_path(ide.path(), &service_name, ide.port())];
     if let Some(mut template_paths) = template.runtime.as_ref().and_then(|r| {
         r.ports.clone().map(|ports| {
             ports
@@ -284,77 +1013,885 @@ fn create_ingress_paths(service_name: String, template: &Template) -> Vec<HTTPIn
     }) {
         paths.append(&mut template_paths);
     };
+    if let Some(telemetry) = &template.telemetry {
+        paths.push(create_ingress_path(
+            TELEMETRY_PATH,
+            &service_name,
+            telemetry.port,
+        ));
+    }
     paths
 }
 
-fn subdomain(host: &str, session_id: &str) -> String {
-    format!("{}.{}", session_id, host)
+/// Resolves the pool a session will be scheduled on, honoring `Template::allowed_pools`.
+/// An explicitly requested pool must be allowed, otherwise the first allowed pool is used as fallback.
+fn session_pool(
+    user: &LoggedUser,
+    conf: &SessionConfiguration,
+    template: &Template,
+    defaults: &SessionDefaults,
+) -> Result<String> {
+    let pool_id = conf.clone().pool_affinity.unwrap_or_else(|| {
+        user.clone()
+            .pool_affinity
+            .unwrap_or_else(|| defaults.pool_affinity.clone())
+    });
+    match &template.allowed_pools {
+        Some(allowed_pools) if !allowed_pools.contains(&pool_id) => {
+            if conf.pool_affinity.is_some() {
+                Err(Error::Forbidden(format!(
+                    "template {} can't run on pool {}",
+                    template.name, pool_id
+                )))
+            } else {
+                allowed_pools
+                    .first()
+                    .cloned()
+                    .ok_or(Error::MissingData("template#allowed_pools"))
+            }
+        }
+        _ => Ok(pool_id),
+    }
 }
 
-async fn config() -> Result<Config> {
-    Config::from_kubeconfig(&KubeConfigOptions::default())
-        .await
-        .or_else(|_| Config::from_cluster_env())
-        .map_err(|err| Error::Failure(err.into()))
+/// Waits up to 5 minutes for pod `name` to be running
+async fn wait_for_running(pod_api: &Api<Pod>, name: &str) -> Result<()> {
+    for _ in 0..150 {
+        let pod = match pod_api.get(name).await {
+            Ok(pod) => pod,
+            // Pods of a `StatefulSet` are created asynchronously
+            Err(kube::Error::Api(err)) if err.code == 404 => {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                continue;
+            }
+            Err(err) => return Err(Error::Failure(err.into())),
+        };
+        match pod.status.and_then(|status| status.phase).as_deref() {
+            Some("Running") => return Ok(()),
+            Some("Failed") => return Err(Error::Forbidden(format!("pod {} failed", name))),
+            _ => tokio::time::sleep(Duration::from_secs(2)).await,
+        }
+    }
+    Err(Error::Forbidden(format!("pod {} did not start", name)))
 }
 
-async fn new_client() -> Result<Client> {
-    let config = config().await?;
-    Client::try_from(config).map_err(|err| Error::Failure(err.into()))
+fn template_error(key: &str, field: &str, error: String) -> InvalidEntry {
+    InvalidEntry {
+        config_map: TEMPLATES_CONFIG_MAP.to_string(),
+        key: key.to_string(),
+        error,
+        field: Some(field.to_string()),
+        line: None,
+        column: None,
+    }
 }
 
-// ConfigMap utilities
+/// Checks parsed template `key` can be used to create sessions
+fn check_template(key: &str, template: &Template) -> Option<InvalidEntry> {
+    let (field, error) = if template.image.trim().is_empty() {
+        ("image", "empty image".to_string())
+    } else if registry::ImageReference::parse(&template.image).is_none() {
+        ("image", format!("invalid image {}", template.image))
+    } else if template.allowed_pools.as_ref().map_or(false, Vec::is_empty) {
+        ("allowed_pools", "empty allowed_pools".to_string())
+    } else if let Err(err) = template.validate() {
+        (err.field(), err.to_string())
+    } else {
+        return None;
+    };
+    Some(template_error(key, field, error))
+}
 
-async fn get_config_map(
-    client: Client,
-    namespace: &str,
-    name: &str,
-) -> Result<BTreeMap<String, String>> {
-    let config_map_api: Api<ConfigMap> = Api::namespaced(client, namespace);
-    config_map_api
-        .get(name)
-        .await
-        .map_err(|err| Error::Failure(err.into()))
-        .and_then(|o| o.data.ok_or(Error::MissingData("config map")))
+/// Parses all `entries` of ConfigMap `config_map`, separating invalid ones
+fn parse_entries<T: DeserializeOwned + Serialize>(
+    config_map: &str,
+    entries: BTreeMap<String, String>,
+    strict: bool,
+) -> (BTreeMap<String, T>, Vec<InvalidEntry>) {
+    let mut valid = BTreeMap::new();
+    let mut invalid = Vec::new();
+    for (key, value) in entries {
+        match parse_entry(config_map, &key, &value, strict) {
+            Ok(entry) => {
+                valid.insert(key, entry);
+            }
+            Err(entry) => invalid.push(entry),
+        }
+    }
+    (valid, invalid)
 }
 
-//
-// Adds a value to a ConfigMap, specified by a `key`.
-// Err if provided `key` doesn't exist
-//
-// Equivalent to `kubectl patch configmap $name --type=json -p='[{"op": "add", "path": "/data/$key", "value": "$value"}]'`
-async fn add_config_map_value(
-    client: Client,
-    namespace: &str,
-    name: &str,
-    key: &str,
-    value: &str,
-) -> Result<()> {
-    let config_map_api: Api<ConfigMap> = Api::namespaced(client, namespace);
-    let params = PatchParams {
-        ..PatchParams::default()
-    };
-    let patch: Patch<json_patch::Patch> =
-        Patch::Json(json_patch::Patch(vec![PatchOperation::Add(AddOperation {
-            path: format!("/data/{}", key),
-            value: json!(value),
-        })]));
-    config_map_api
-        .patch(name, &params, &patch)
-        .await
-        .map_err(|err| Error::Failure(err.into()))?;
-    Ok(())
+/// Migrates then strictly parses all entries of `archive`. Returns the ConfigMaps to write, at the latest format
+/// version, unless any entry is invalid.
+fn prepare_archive(archive: StateArchive) -> Result<BTreeMap<String, ArchivedConfigMap>> {
+    if archive.version != ARCHIVE_VERSION {
+        return Err(Error::InvalidParameter(format!(
+            "unsupported archive version {}",
+            archive.version
+        )));
+    }
+    let mut config_maps = BTreeMap::new();
+    let mut invalid_entries = Vec::new();
+    for (name, mut archived) in archive.config_maps {
+        if name == STATE_CONFIG_MAP {
+            for (key, value) in &archived.data {
+                let valid = match key.as_str() {
+                    RESERVATIONS_STATE => {
+                        serde_json::from_str::<BTreeMap<String, Reservation>>(value).is_ok()
+                    }
+                    TEMPLATE_ANALYTICS_STATE => serde_json::from_str::<
+                        BTreeMap<u64, BTreeMap<String, TemplateStats>>,
+                    >(value)
+                    .is_ok(),
+                    _ => return Err(Error::InvalidParameter(format!("state key {}", key))),
+                };
+                if !valid {
+                    return Err(Error::InvalidParameter(format!("state {}", key)));
+                }
+            }
+            config_maps.insert(name, archived);
+            continue;
+        }
+        let migrations = STORED_RESOURCES
+            .iter()
+            .find(|(stored, _)| *stored == name)
+            .map(|(_, migrations)| *migrations)
+            .ok_or_else(|| Error::InvalidParameter(format!("ConfigMap {}", name)))?;
+        let version = archived.storage_version.unwrap_or_default();
+        for (key, value) in archived.data.iter_mut() {
+            // Entries that fail to migrate are reported by parsing
+            if let Ok(Some(migrated)) = storage::migrate_entry(migrations, version, value) {
+                *value = migrated;
+            }
+            let invalid = match name.as_str() {
+                USERS_CONFIG_MAP => parse_entry::<UserConfiguration>(&name, key, value, true).err(),
+                TEMPLATES_CONFIG_MAP => parse_entry::<Template>(&name, key, value, true).err(),
+                _ => parse_entry::<Org>(&name, key, value, true).err(),
+            };
+            invalid_entries.extend(invalid);
+        }
+        archived.storage_version = Some(storage::latest_version(migrations));
+        config_maps.insert(name, archived);
+    }
+    if !invalid_entries.is_empty() {
+        return Err(Error::InvalidParameter(
+            invalid_entries
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ));
+    }
+    Ok(config_maps)
 }
 
-//
-// Deletes a value from a ConfigMap, specified by a `key`.
-// Err if provided `key` doesn't exist
-//
-// Equivalent to `kubectl patch configmap $name --type=json -p='[{"op": "remove", "path": "/data/$key"}]'`
-async fn delete_config_map_value(
-    client: Client,
-    namespace: &str,
-    name: &str,
+/// The node a session last ran on
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LastNode {
+    hostname: String,
+    /// Seconds since epoch
+    recorded_at: u64,
+}
+
+/// Parses the `value` of `LAST_NODES_STATE`
+fn parse_last_nodes(value: Option<&str>) -> BTreeMap<String, LastNode> {
+    // Last nodes are only hints: older entries, without time, are dropped
+    value
+        .and_then(|value| serde_json::from_str(value).ok())
+        .unwrap_or_default()
+}
+
+/// Forgets last nodes older than `LAST_NODE_RETENTION` at `now`, then the oldest ones beyond `MAX_LAST_NODES`
+fn prune_last_nodes(nodes: &mut BTreeMap<String, LastNode>, now: u64) {
+    nodes.retain(|_, node| now.saturating_sub(node.recorded_at) < LAST_NODE_RETENTION.as_secs());
+    let excess = nodes.len().saturating_sub(MAX_LAST_NODES);
+    if excess > 0 {
+        let mut oldest: Vec<(u64, String)> = nodes
+            .iter()
+            .map(|(id, node)| (node.recorded_at, id.clone()))
+            .collect();
+        oldest.sort_unstable();
+        for (_, id) in oldest.into_iter().take(excess) {
+            nodes.remove(&id);
+        }
+    }
+}
+
+/// Returns the format version `config_map` entries are stored in
+fn storage_version(config_map: &ConfigMap) -> u32 {
+    config_map
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(storage::VERSION_ANNOTATION))
+        .and_then(|version| version.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Name of the only pod of `StatefulSet` `name`
+fn stateful_set_pod_name(name: &str) -> String {
+    format!("{}-0", name)
+}
+
+/// Returns the id of the session pod `name` belongs to, for pods that might not exist anymore. Pods named after a
+/// migration target can't be told apart and are mapped to an unknown id.
+fn pod_name_to_session_id(name: &str) -> Option<&str> {
+    let id = name.strip_prefix(&pod_name(""))?;
+    // Sessions created before `StatefulSet`s have their pod named after them
+    Some(id.strip_suffix("-0").unwrap_or(id))
+}
+
+/// Returns true if `pod` is stuck terminating, e.g. on a node that stopped responding. Such pods are only removed once
+/// their node confirms it, which a lost node never does.
+fn is_stuck_terminating(pod: &Pod, now: SystemTime) -> bool {
+    let deleted_at: SystemTime = match &pod.metadata.deletion_timestamp {
+        Some(time) => time.0.into(),
+        None => return false,
+    };
+    let grace_period = Duration::from_secs(
+        pod.metadata
+            .deletion_grace_period_seconds
+            .and_then(|seconds| u64::try_from(seconds).ok())
+            .unwrap_or_default(),
+    );
+    now.duration_since(deleted_at)
+        .map_or(false, |elapsed| elapsed > grace_period + LOST_POD_DELAY)
+}
+
+/// Returns the pod standing for the session of `stateful_set` while it is suspended, built from its template
+fn suspended_pod(stateful_set: StatefulSet) -> Option<Pod> {
+    let name = stateful_set.metadata.name?;
+    let template = stateful_set.spec?.template;
+    let metadata = template.metadata.unwrap_or_default();
+    Some(Pod {
+        metadata: ObjectMeta {
+            name: Some(stateful_set_pod_name(&name)),
+            labels: metadata.labels,
+            annotations: metadata.annotations,
+            owner_references: Some(vec![OwnerReference {
+                api_version: "apps/v1".to_string(),
+                kind: "StatefulSet".to_string(),
+                name,
+                uid: stateful_set.metadata.uid.unwrap_or_default(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        },
+        spec: template.spec,
+        status: Some(PodStatus {
+            phase: Some("Suspended".to_string()),
+            ..Default::default()
+        }),
+    })
+}
+
+/// Returns true if `pod` stands for a suspended session, see `suspended_pod`
+fn is_suspended(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref())
+        == Some("Suspended")
+}
+
+/// Returns the name of the `StatefulSet` managing `pod`, if any
+fn owning_stateful_set(pod: &Pod) -> Option<String> {
+    pod.metadata
+        .owner_references
+        .as_ref()?
+        .iter()
+        .find(|reference| reference.kind == "StatefulSet")
+        .map(|reference| reference.name.clone())
+}
+
+/// Wraps `pod` in a single replica `StatefulSet` of the same name, so that it is recreated if lost. On node failure the
+/// pod is only recreated once force deleted, see `Engine::release_lost_pods`.
+///
+/// Pods are only replaced once deleted (`OnDelete`): updating the template doesn't restart the session, but is picked
+/// up by the next pod. The template is then the durable copy of the session annotations.
+fn create_stateful_set(session_id: &str, pod: Pod) -> Result<StatefulSet> {
+    let name = pod
+        .metadata
+        .name
+        .clone()
+        .ok_or(Error::MissingData("pod#metadata#name"))?;
+    let labels = pod.metadata.labels.clone().unwrap_or_default();
+    // Pods created by a migration have their own `POD_LABEL` and must not be adopted
+    let selector = labels
+        .iter()
+        .filter(|(key, _)| [COMPONENT_LABEL, OWNER_LABEL, POD_LABEL].contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    Ok(StatefulSet {
+        metadata: ObjectMeta {
+            name: Some(name),
+            labels: Some(labels.clone()),
+            ..Default::default()
+        },
+        spec: Some(StatefulSetSpec {
+            replicas: Some(1),
+            selector: LabelSelector {
+                match_labels: Some(selector),
+                ..Default::default()
+            },
+            service_name: service_name(session_id),
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    annotations: pod.metadata.annotations,
+                    ..Default::default()
+                }),
+                spec: pod.spec,
+            },
+            update_strategy: Some(StatefulSetUpdateStrategy {
+                type_: Some("OnDelete".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// Merges `metadata` into `pod`. Pods managed by a `StatefulSet` get it merged into its template first, so that it
+/// survives them being recreated. Suspended sessions only have their template.
+async fn patch_pod_metadata(
+    client: Client,
+    namespace: &str,
+    pod: &Pod,
+    metadata: serde_json::Value,
+) -> Result<()> {
+    let name = pod
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(Error::MissingData("pod#metadata#name"))?;
+    if let Some(stateful_set_name) = owning_stateful_set(pod) {
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+        stateful_set_api
+            .patch(
+                &stateful_set_name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "spec": { "template": { "metadata": metadata.clone() } } })),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        if is_suspended(pod) {
+            return Ok(());
+        }
+    }
+    let pod_api: Api<Pod> = Api::namespaced(client, namespace);
+    match pod_api
+        .patch(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "metadata": metadata })),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        // Pods removed since, e.g. by a suspension, are recreated from the patched template
+        Err(kube::Error::Api(err)) if err.code == 404 && owning_stateful_set(pod).is_some() => {
+            Ok(())
+        }
+        Err(err) => Err(Error::Failure(err.into())),
+    }
+}
+
+/// Recreates pod `name` from `source` labels, `annotations` and `spec`.
+///
+/// Pods managed by a `StatefulSet` get its template updated then are deleted, the `StatefulSet` recreating them.
+/// Others are deleted then created again.
+async fn replace_pod(
+    pod_api: &Api<Pod>,
+    stateful_set_api: &Api<StatefulSet>,
+    name: &str,
+    source: &Pod,
+    annotations: BTreeMap<String, String>,
+    spec: PodSpec,
+) -> Result<()> {
+    if let Some(stateful_set_name) = owning_stateful_set(source) {
+        let mut stateful_set = stateful_set_api
+            .get(&stateful_set_name)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        if let Some(template) = stateful_set.spec.as_mut().map(|spec| &mut spec.template) {
+            // Labels are kept, pods get additional ones from the `StatefulSet` controller
+            template
+                .metadata
+                .get_or_insert_with(ObjectMeta::default)
+                .annotations = Some(annotations);
+            template.spec = Some(spec);
+        }
+        stateful_set_api
+            .replace(&stateful_set_name, &PostParams::default(), &stateful_set)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        return match pod_api.delete(name, &DeleteParams::default()).await {
+            Ok(_) => Ok(()),
+            // Failed pods might already have been replaced
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+            Err(err) => Err(Error::Failure(err.into())),
+        };
+    }
+
+    let pod = Pod {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            labels: source.metadata.labels.clone(),
+            annotations: Some(annotations),
+            ..Default::default()
+        },
+        spec: Some(spec),
+        ..Default::default()
+    };
+
+    pod_api
+        .delete(name, &DeleteParams::default())
+        .await
+        .map_err(|err| Error::Failure(err.into()))?;
+    wait_for_deletion(pod_api, name).await?;
+    traced(
+        "kubernetes.create_pod",
+        pod_api.create(&PostParams::default(), &pod),
+    )
+    .await
+    .map_err(|err| Error::Failure(err.into()))?;
+
+    Ok(())
+}
+
+async fn wait_for_deletion(pod_api: &Api<Pod>, name: &str) -> Result<()> {
+    for _ in 0..60 {
+        match pod_api.get(name).await {
+            Err(kube::Error::Api(err)) if err.code == 404 => return Ok(()),
+            Err(err) => return Err(Error::Failure(err.into())),
+            Ok(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+    Err(Error::Forbidden(format!("pod {} was not deleted", name)))
+}
+
+/// Returns true if `domain` is a valid DNS name
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.split('.').count() > 1
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Returns true if one of the ingress TLS `hosts` is a wildcard covering sessions subdomains of `domain`
+fn has_wildcard_certificate(tls_hosts: &[String], domain: &str) -> bool {
+    tls_hosts
+        .iter()
+        .any(|host| host == &format!("*.{}", domain))
+}
+
+fn check_ingress(ingress: Option<&Ingress>) -> Check {
+    let name = "ingress";
+    let ingress = match ingress {
+        Some(ingress) => ingress,
+        None => return Check::fail(name, format!("Ingress {} not found", INGRESS_NAME)),
+    };
+    let addresses: Vec<String> = ingress
+        .status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .and_then(|load_balancer| load_balancer.ingress.as_ref())
+        .map(|ingresses| {
+            ingresses
+                .iter()
+                .filter_map(|ingress| ingress.ip.clone().or_else(|| ingress.hostname.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+    if addresses.is_empty() {
+        Check::warn(name, "No load balancer address assigned".to_string())
+    } else {
+        Check::pass(name)
+    }
+}
+
+async fn check_certificates(
+    client: Client,
+    namespace: &str,
+    ingress: Option<&Ingress>,
+    base_domains: &[String],
+) -> Check {
+    let name = "certificates";
+    let tls = match ingress
+        .and_then(|ingress| ingress.spec.as_ref())
+        .and_then(|spec| spec.tls.clone())
+    {
+        Some(tls) => tls,
+        None => return Check::warn(name, "TLS is not configured".to_string()),
+    };
+    let tls_hosts: Vec<String> = tls
+        .iter()
+        .flat_map(|tls| tls.hosts.clone().unwrap_or_default())
+        .collect();
+    let uncovered: Vec<&String> = base_domains
+        .iter()
+        .filter(|domain| !has_wildcard_certificate(&tls_hosts, domain))
+        .collect();
+    if !uncovered.is_empty() {
+        return Check::fail(name, format!("No wildcard certificate for {:?}", uncovered));
+    }
+    let secret_api: Api<Secret> = Api::namespaced(client, namespace);
+    for secret_name in tls.iter().filter_map(|tls| tls.secret_name.as_ref()) {
+        match secret_api.get(secret_name).await {
+            Ok(secret)
+                if secret
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.get("tls.crt"))
+                    .map_or(false, |certificate| !certificate.0.is_empty()) => {}
+            Ok(_) => {
+                return Check::fail(name, format!("Secret {} has no certificate", secret_name))
+            }
+            Err(err) => {
+                return Check::fail(
+                    name,
+                    format!("Failed to read secret {}: {}", secret_name, err),
+                )
+            }
+        }
+    }
+    Check::pass(name)
+}
+
+/// Derives deployment milestones from `pod` status
+fn deployment_steps(pod: &Pod) -> Vec<DeploymentStep> {
+    let step = |name: &str, completed: bool, completed_at: Option<SystemTime>| DeploymentStep {
+        name: name.to_string(),
+        completed,
+        completed_at,
+    };
+    let status = pod.status.as_ref();
+    let condition = |type_: &str| -> Option<Option<SystemTime>> {
+        status
+            .and_then(|status| status.conditions.as_ref())
+            .and_then(|conditions| {
+                conditions
+                    .iter()
+                    .find(|condition| condition.type_ == type_ && condition.status == "True")
+            })
+            .map(|condition| {
+                condition
+                    .last_transition_time
+                    .as_ref()
+                    .map(|time| time.0.into())
+            })
+    };
+    let container_status = status
+        .and_then(|status| status.container_statuses.as_ref())
+        .and_then(|statuses| statuses.first());
+    let started_at: Option<Option<SystemTime>> = container_status
+        .and_then(|status| status.state.as_ref())
+        .and_then(|state| state.running.as_ref())
+        .map(|running| running.started_at.as_ref().map(|time| time.0.into()));
+    let scheduled = condition("PodScheduled");
+    let ready = condition("Ready");
+
+    vec![
+        // The ingress is patched before the pod is created
+        step(
+            "created",
+            true,
+            pod.metadata
+                .creation_timestamp
+                .as_ref()
+                .map(|time| time.0.into()),
+        ),
+        step("scheduled", scheduled.is_some(), scheduled.flatten()),
+        // Pull time is only known through events, the container starts right after
+        step(
+            "image-pulled",
+            container_status.map_or(false, |status| !status.image_id.is_empty()),
+            None,
+        ),
+        step("started", started_at.is_some(), started_at.flatten()),
+        step("ready", ready.is_some(), ready.flatten()),
+    ]
+}
+
+/// Maps common reasons for `pod` not starting to a `SessionFailure`
+fn classify_failure(pod: &Pod) -> Option<SessionFailure> {
+    let status = pod.status.as_ref()?;
+    let detail = |reason: &str, message: &str| format!("{}: {}", reason, message);
+
+    // Pods preempted by the scheduler are deleted shortly after
+    let preempted = status.conditions.as_ref().and_then(|conditions| {
+        conditions.iter().find(|condition| {
+            condition.type_ == "DisruptionTarget"
+                && condition.status == "True"
+                && matches!(
+                    condition.reason.as_deref(),
+                    Some("PreemptionByKubeScheduler" | "PreemptionByScheduler")
+                )
+        })
+    });
+    if let Some(condition) = preempted {
+        return Some(SessionFailure::new(
+            SessionFailureReason::Evicted,
+            detail(
+                condition.reason.as_deref().unwrap_or_default(),
+                condition.message.as_deref().unwrap_or_default(),
+            ),
+        ));
+    }
+    if status.reason.as_deref() == Some("Evicted") {
+        return Some(SessionFailure::new(
+            SessionFailureReason::Evicted,
+            detail("Evicted", status.message.as_deref().unwrap_or_default()),
+        ));
+    }
+
+    // Pods rejected by the kubelet at admission
+    if let Some(reason @ ("OutOfcpu" | "OutOfmemory" | "OutOfpods" | "UnexpectedAdmissionError")) =
+        status.reason.as_deref()
+    {
+        return Some(SessionFailure::new(
+            SessionFailureReason::InsufficientResources,
+            detail(reason, status.message.as_deref().unwrap_or_default()),
+        ));
+    }
+
+    // Init containers run first, any of them can hold the pod back
+    let states: Vec<(&str, &ContainerState)> = status
+        .init_container_statuses
+        .iter()
+        .chain(status.container_statuses.iter())
+        .flatten()
+        .filter_map(|status| Some((status.name.as_str(), status.state.as_ref()?)))
+        .collect();
+    for (name, waiting) in states
+        .iter()
+        .filter_map(|(name, state)| Some((name, state.waiting.as_ref()?)))
+    {
+        let reason = waiting.reason.as_deref().unwrap_or_default();
+        let failure_reason = match reason {
+            "ErrImagePull" | "ImagePullBackOff" | "InvalidImageName" | "ErrImageNeverPull" => {
+                Some(SessionFailureReason::ImagePull)
+            }
+            "CrashLoopBackOff" => Some(SessionFailureReason::Crash),
+            "CreateContainerConfigError" | "CreateContainerError" => {
+                Some(SessionFailureReason::Unknown)
+            }
+            _ => None,
+        };
+        if let Some(failure_reason) = failure_reason {
+            return Some(SessionFailure::new(
+                failure_reason,
+                detail(
+                    &format!("{} {}", name, reason),
+                    waiting.message.as_deref().unwrap_or_default(),
+                ),
+            ));
+        }
+    }
+    // Containers that exited successfully, e.g. completed init containers, didn't fail
+    let terminated = states.iter().find_map(|(name, state)| {
+        Some((
+            name,
+            state
+                .terminated
+                .as_ref()
+                .filter(|terminated| terminated.exit_code != 0)?,
+        ))
+    });
+    if let Some((name, terminated)) = terminated {
+        let reason = terminated.reason.as_deref().unwrap_or_default();
+        return Some(SessionFailure::new(
+            if reason == "OOMKilled" {
+                SessionFailureReason::InsufficientResources
+            } else {
+                SessionFailureReason::Crash
+            },
+            detail(
+                &format!("{} {}", name, reason),
+                terminated.message.as_deref().unwrap_or_default(),
+            ),
+        ));
+    }
+
+    let unschedulable = status.conditions.as_ref().and_then(|conditions| {
+        conditions.iter().find(|condition| {
+            condition.type_ == "PodScheduled"
+                && condition.status == "False"
+                && condition.reason.as_deref() == Some("Unschedulable")
+        })
+    })?;
+    let message = unschedulable.message.as_deref().unwrap_or_default();
+    let reason = if message.contains("PersistentVolumeClaim") {
+        SessionFailureReason::VolumePending
+    } else if message.contains("Insufficient") {
+        SessionFailureReason::InsufficientResources
+    } else {
+        SessionFailureReason::Unknown
+    };
+    Some(SessionFailure::new(
+        reason,
+        detail("Unschedulable", message),
+    ))
+}
+
+/// Maps pod creation errors caused by a `ResourceQuota` to a user-actionable error
+fn pod_creation_error(err: kube::Error) -> Error {
+    match err {
+        kube::Error::Api(err) if err.code == 403 && err.message.contains("exceeded quota") => {
+            Error::Forbidden(SessionFailureReason::QuotaExceeded.message().to_string())
+        }
+        err => Error::Failure(err.into()),
+    }
+}
+
+/// Returns true if `session_id` falls in the `percentage` of sessions using a canary image. Stable for a given session
+/// id, across replicas and releases.
+fn is_canary(session_id: &str, percentage: u8) -> bool {
+    let digest = Sha256::digest(session_id.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes) % 100 < u64::from(percentage)
+}
+
+fn subdomain(host: &str, label: &str) -> String {
+    format!("{}.{}", label, host)
+}
+
+/// Returns the DNS label a new session `session_id` is served under. `secret` keys hashed labels.
+fn subdomain_label(strategy: SubdomainStrategy, secret: &str, session_id: &str) -> Result<String> {
+    Ok(match strategy {
+        SubdomainStrategy::Id => session_id.to_string(),
+        // Keyed, so that labels can't be matched against hashed guesses. Fits in the 63 characters of a DNS label.
+        SubdomainStrategy::Hashed => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .map_err(|_| Error::Failure("invalid SESSION_SUBDOMAIN_SECRET".into()))?;
+            mac.update(session_id.as_bytes());
+            mac.finalize()
+                .into_bytes()
+                .iter()
+                .take(16)
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        }
+        SubdomainStrategy::Random => random_token(16).to_lowercase(),
+    })
+}
+
+/// Returns the id of the session `rule` routes to, whatever its host
+fn rule_session(rule: &IngressRule) -> Option<&str> {
+    let prefix = service_name("");
+    rule.http
+        .as_ref()?
+        .paths
+        .iter()
+        .filter_map(|path| path.backend.service.as_ref())
+        .find_map(|service| service.name.strip_prefix(&prefix))
+}
+
+async fn config() -> Result<Config> {
+    Config::from_kubeconfig(&KubeConfigOptions::default())
+        .await
+        .or_else(|_| Config::from_cluster_env())
+        .map_err(|err| Error::Failure(err.into()))
+}
+
+async fn new_client() -> Result<Client> {
+    let config = config().await?;
+    Client::try_from(config).map_err(|err| Error::Failure(err.into()))
+}
+
+// ConfigMap utilities
+
+async fn get_config_map(
+    client: Client,
+    namespace: &str,
+    name: &str,
+) -> Result<BTreeMap<String, String>> {
+    let config_map_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    config_map_api
+        .get(name)
+        .await
+        .map_err(|err| Error::Failure(err.into()))
+        .and_then(|o| o.data.ok_or(Error::MissingData("config map")))
+}
+
+//
+// Adds a value to a ConfigMap, specified by a `key`.
+// Err if provided `key` doesn't exist
+//
+// Equivalent to `kubectl patch configmap $name --type=json -p='[{"op": "add", "path": "/data/$key", "value": "$value"}]'`
+async fn add_config_map_value(
+    client: Client,
+    namespace: &str,
+    name: &str,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    let config_map_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    let params = PatchParams {
+        ..PatchParams::default()
+    };
+    let patch: Patch<json_patch::Patch> =
+        Patch::Json(json_patch::Patch(vec![PatchOperation::Add(AddOperation {
+            path: format!("/data/{}", json_pointer_token(key)),
+            value: json!(value),
+        })]));
+    config_map_api
+        .patch(name, &params, &patch)
+        .await
+        .map_err(|err| Error::Failure(err.into()))?;
+    Ok(())
+}
+
+//
+// Adds a number of values to a ConfigMap in a single patch.
+//
+// Equivalent to `kubectl patch configmap $name --type=json -p='[{"op": "add", "path": "/data/$key1", "value": "$value1"}, ...]'`
+async fn add_config_map_values(
+    client: Client,
+    namespace: &str,
+    name: &str,
+    values: &BTreeMap<String, String>,
+) -> Result<()> {
+    let config_map_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    let params = PatchParams {
+        ..PatchParams::default()
+    };
+    let patch: Patch<json_patch::Patch> = Patch::Json(json_patch::Patch(
+        values
+            .iter()
+            .map(|(key, value)| {
+                PatchOperation::Add(AddOperation {
+                    path: format!("/data/{}", json_pointer_token(key)),
+                    value: json!(value),
+                })
+            })
+            .collect(),
+    ));
+    config_map_api
+        .patch(name, &params, &patch)
+        .await
+        .map_err(|err| Error::Failure(err.into()))?;
+    Ok(())
+}
+
+//
+// Deletes a value from a ConfigMap, specified by a `key`.
+// Err if provided `key` doesn't exist
+//
+// Equivalent to `kubectl patch configmap $name --type=json -p='[{"op": "remove", "path": "/data/$key"}]'`
+async fn delete_config_map_value(
+    client: Client,
+    namespace: &str,
+    name: &str,
     key: &str,
 ) -> Result<()> {
     let config_map_api: Api<ConfigMap> = Api::namespaced(client, namespace);
@@ -364,7 +1901,7 @@ async fn delete_config_map_value(
     let patch: Patch<json_patch::Patch> =
         Patch::Json(json_patch::Patch(vec![PatchOperation::Remove(
             RemoveOperation {
-                path: format!("/data/{}", key),
+                path: format!("/data/{}", json_pointer_token(key)),
             },
         )]));
     config_map_api
@@ -374,31 +1911,246 @@ async fn delete_config_map_value(
     Ok(())
 }
 
-async fn get_templates(client: Client, namespace: &str) -> Result<BTreeMap<String, String>> {
-    get_config_map(client, namespace, TEMPLATES_CONFIG_MAP).await
-}
-
-async fn list_users(client: Client, namespace: &str) -> Result<BTreeMap<String, String>> {
-    get_config_map(client, namespace, USERS_CONFIG_MAP).await
+/// Returns false if nodes can't be listed, e.g. when the backend is only granted a namespaced role
+async fn can_list_nodes(client: Client) -> bool {
+    let node_api: Api<Node> = Api::all(client);
+    match node_api.list(&ListParams::default().limit(1)).await {
+        Err(kube::Error::Api(err)) if err.code == 403 => {
+            warn!("Not allowed to list nodes, falling back to STATIC_POOLS");
+            false
+        }
+        _ => true,
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Environment {
-    pub secured: bool,
-    pub host: String,
-    pub namespace: String,
+/// Returns node requirements matching all labels of `selector`
+fn selector_requirements(selector: &BTreeMap<String, String>) -> Vec<NodeSelectorRequirement> {
+    selector
+        .iter()
+        .map(|(key, value)| NodeSelectorRequirement {
+            key: key.clone(),
+            operator: "In".to_string(),
+            values: Some(vec![value.clone()]),
+        })
+        .collect()
+}
+
+/// Returns true if `node` matches all `requirements`, that only use the `In` operator
+fn matches_requirements(node: &Node, requirements: &[NodeSelectorRequirement]) -> bool {
+    let labels = node.metadata.labels.as_ref();
+    requirements.iter().all(|requirement| {
+        labels
+            .and_then(|labels| labels.get(&requirement.key))
+            .map_or(false, |value| {
+                requirement
+                    .values
+                    .as_ref()
+                    .map_or(false, |values| values.contains(value))
+            })
+    })
+}
+
+/// Returns the label selector matching `requirements`, that only use the `In` operator
+fn requirements_label_selector(requirements: &[NodeSelectorRequirement]) -> String {
+    requirements
+        .iter()
+        .map(|requirement| {
+            format!(
+                "{} in ({})",
+                requirement.key,
+                requirement.values.clone().unwrap_or_default().join(",")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+async fn get_templates(client: Client, namespace: &str) -> Result<BTreeMap<String, String>> {
+    get_config_map(client, namespace, TEMPLATES_CONFIG_MAP).await
+}
+
+async fn list_users(client: Client, namespace: &str) -> Result<BTreeMap<String, String>> {
+    get_config_map(client, namespace, USERS_CONFIG_MAP).await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Environment {
+    pub secured: bool,
+    pub host: String,
+    pub namespace: String,
 }
 
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Configuration {
     pub github_client_id: String,
+    /// Domains sessions can be served under. The first one is used by default.
+    pub base_domains: Vec<String>,
     pub session: SessionDefaults,
+    /// If true, users must complete onboarding before creating sessions
+    pub onboarding_required: bool,
+    pub legal: Legal,
+    /// Central telemetry server session nodes can forward to
+    pub telemetry_url: Option<String>,
+    /// If set, sessions failing to start for transient reasons are recreated
+    pub retry_policy: Option<RetryPolicy>,
+    /// If set, users can also log in via this OpenID Connect issuer
+    pub oidc: Option<OidcConfiguration>,
+    /// If set, templates can be published from sessions. Their workspace is pushed there.
+    pub template_snapshot_remote: Option<String>,
+    pub disruption_policy: DisruptionPolicy,
+    /// How new sessions are named in DNS
+    pub subdomain_strategy: SubdomainStrategy,
+}
+
+/// Differences between the ingress, session services and live sessions
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Drift {
+    /// Ids of sessions without ingress rule
+    pub missing_rules: BTreeSet<String>,
+    /// Hosts of ingress rules without session
+    pub stale_rules: BTreeSet<String>,
+    /// Names of services, including workshop peer services, without session
+    pub orphaned_services: BTreeSet<String>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.missing_rules.is_empty()
+            && self.stale_rules.is_empty()
+            && self.orphaned_services.is_empty()
+    }
+
+    /// Returns drift present in both `self` and `other`
+    pub fn intersection(&self, other: &Drift) -> Drift {
+        Drift {
+            missing_rules: self
+                .missing_rules
+                .intersection(&other.missing_rules)
+                .cloned()
+                .collect(),
+            stale_rules: self
+                .stale_rules
+                .intersection(&other.stale_rules)
+                .cloned()
+                .collect(),
+            orphaned_services: self
+                .orphaned_services
+                .intersection(&other.orphaned_services)
+                .cloned()
+                .collect(),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Secrets {
     pub github_client_secret: String,
+    /// Set when authenticating as a GitHub App
+    pub github_app: Option<GitHubApp>,
+    pub oidc_client_secret: Option<String>,
+    /// Used to push workspace snapshots
+    pub template_snapshot_token: Option<String>,
+    /// Keys hashed session subdomains
+    pub subdomain_secret: Option<String>,
+}
+
+/// In-memory view of session pods, kept up to date by watch events
+#[derive(Clone)]
+pub struct PodCache {
+    store: Store<Pod>,
+    synced: Arc<AtomicBool>,
+    /// Ids of sessions whose pods changed
+    changes: broadcast::Sender<String>,
+}
+
+impl PodCache {
+    /// Starts watching session pods. Must be called from within a long lived tokio runtime.
+    fn watch(client: Client, namespace: &str) -> Self {
+        let api: Api<Pod> = Api::namespaced(client, namespace);
+        let params =
+            ListParams::default().labels(&format!("{}={}", COMPONENT_LABEL, COMPONENT_VALUE));
+        let writer = Writer::default();
+        let store = writer.as_reader();
+        let synced = Arc::new(AtomicBool::new(false));
+        let (changes, _) = broadcast::channel(POD_CHANGES_CAPACITY);
+        let (cache_synced, cache_changes) = (synced.clone(), changes.clone());
+        tokio::spawn(
+            reflector(writer, watcher(api, params)).for_each(move |event| {
+                let (synced, changes) = (cache_synced.clone(), cache_changes.clone());
+                async move {
+                    track_sync(&synced, &event);
+                    match event {
+                        Ok(event) => {
+                            for id in changed_sessions(&event) {
+                                // Fails if no one subscribed
+                                let _ = changes.send(id);
+                            }
+                        }
+                        Err(err) => {
+                            warn!("Session pods watch failed: {}", err);
+                            tokio::time::sleep(Duration::from_secs(5)).await;
+                        }
+                    }
+                }
+            }),
+        );
+        PodCache {
+            store,
+            synced,
+            changes,
+        }
+    }
+
+    /// Returns live session pods, or `None` until a full list has been received since the last watch failure
+    fn pods(&self) -> Option<Vec<Pod>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(
+            self.store
+                .state()
+                .into_iter()
+                .filter(|pod| {
+                    pod.metadata
+                        .labels
+                        .as_ref()
+                        .map_or(false, |labels| !labels.contains_key(MIGRATION_LABEL))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Updates whether a watch cache is `synced` given its latest `event`. Caches might miss events once the watch failed,
+/// and are only trusted again once a full list has been received.
+fn track_sync<K, E>(synced: &AtomicBool, event: &std::result::Result<watcher::Event<K>, E>) {
+    match event {
+        Ok(watcher::Event::Restarted(_)) => synced.store(true, Ordering::SeqCst),
+        Ok(_) => {}
+        Err(_) => synced.store(false, Ordering::SeqCst),
+    }
+}
+
+/// Returns ids of the sessions whose pods `event` is about
+fn changed_sessions(event: &watcher::Event<Pod>) -> Vec<String> {
+    let pods = match event {
+        watcher::Event::Applied(pod) | watcher::Event::Deleted(pod) => vec![pod],
+        watcher::Event::Restarted(pods) => pods.iter().collect(),
+    };
+    pods.into_iter()
+        .filter_map(|pod| pod.metadata.labels.as_ref()?.get(OWNER_LABEL).cloned())
+        .collect()
+}
+
+/// Resources created so far by a session creation, rolled back if it fails
+#[derive(Default)]
+struct CreatedResources {
+    secret: bool,
+    stateful_set: bool,
+    ingress_rules: bool,
+    service: bool,
+    peer_service: bool,
 }
 
 #[derive(Clone)]
@@ -406,515 +2158,3969 @@ pub struct Engine {
     pub env: Environment,
     pub configuration: Configuration,
     pub secrets: Secrets,
+    pods: PodCache,
+    /// Set if session DNS records are managed by the playground
+    dns: Option<Dns>,
+    /// If true, cluster scoped resources (i.e. nodes) are not accessed and pools are the static ones
+    restricted: bool,
+    /// Pools declared via `STATIC_POOLS`
+    static_pools: BTreeMap<String, StaticPool>,
+    /// Registries images of templates validated by non admins can be checked against, see `validate_template`
+    allowed_registries: Vec<String>,
+    scheduling: Scheduling,
+    pub plugins: Plugins,
+    pub clock: Arc<dyn Clock>,
+    /// Organizations, along with when they were read
+    orgs: Arc<Mutex<Option<(Instant, BTreeMap<String, Org>)>>>,
 }
 
 impl Engine {
     pub async fn new() -> Result<Self> {
+        Engine::with_clock(Arc::new(SystemClock)).await
+    }
+
+    /// Creates an `Engine` reading the current time from `clock`
+    pub async fn with_clock(clock: Arc<dyn Clock>) -> Result<Self> {
         let config = config().await?;
         let namespace = config.clone().default_namespace.to_string();
         let client = Client::try_from(config).map_err(|err| Error::Failure(err.into()))?;
+        let pods = PodCache::watch(client.clone(), &namespace);
         let ingress_api: Api<Ingress> = Api::namespaced(client.clone(), &namespace);
-        let secured = if let Ok(ingress) = ingress_api.get(INGRESS_NAME).await {
-            ingress
+        let ingress_spec = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .ok()
+            .map(|ingress| ingress.spec.ok_or(Error::MissingData("spec")))
+            .transpose()?;
+        let tls_hosts: Option<Vec<String>> = ingress_spec.as_ref().and_then(|spec| {
+            spec.tls.as_ref().map(|tls| {
+                tls.iter()
+                    .flat_map(|tls| tls.hosts.clone().unwrap_or_default())
+                    .collect()
+            })
+        });
+        let secured = tls_hosts.is_some();
+
+        let ingress_host = if let Some(spec) = ingress_spec {
+            spec.rules
+                .ok_or(Error::MissingData("spec#rules"))?
+                .first()
+                .ok_or(Error::MissingData("spec#rules[0]"))?
+                .host
+                .as_ref()
+                .ok_or(Error::MissingData("spec#rules[0]#host"))?
+                .clone()
+        } else {
+            "localhost".to_string()
+        };
+
+        // Domains are explicitly configured or default to the ingress host
+        let base_domains = match env::var("BASE_DOMAINS") {
+            Ok(value) => {
+                let domains: Vec<String> = value
+                    .split(',')
+                    .map(|domain| domain.trim().to_lowercase())
+                    .filter(|domain| !domain.is_empty())
+                    .collect();
+                if let Some(domain) = domains.iter().find(|domain| !is_valid_domain(domain)) {
+                    return Err(Error::InvalidParameter(format!("BASE_DOMAINS: {}", domain)));
+                }
+                // Without a wildcard certificate, sessions subdomains can't be served over TLS
+                let (domains, uncovered): (Vec<String>, Vec<String>) =
+                    domains.into_iter().partition(|domain| {
+                        tls_hosts
+                            .as_ref()
+                            .map_or(true, |hosts| has_wildcard_certificate(hosts, domain))
+                    });
+                if !uncovered.is_empty() {
+                    error!("No wildcard certificate for {:?}, ignoring", uncovered);
+                }
+                domains
+            }
+            Err(_) => vec![ingress_host.clone()],
+        };
+        let host = base_domains
+            .first()
+            .cloned()
+            .ok_or(Error::MissingData("BASE_DOMAINS"))?;
+
+        // Retrieve 'static' configuration from Env variables
+        let github_client_id =
+            env::var("GITHUB_CLIENT_ID").map_err(|_| Error::MissingData("GITHUB_CLIENT_ID"))?;
+        let github_client_secret =
+            env::var("GITHUB_CLIENT_SECRET").map_err(|_| Error::MissingData("GITHUB_CLIENT_ID"))?;
+        let github_app = match (
+            env::var("GITHUB_APP_ID"),
+            env::var("GITHUB_APP_INSTALLATION_ID"),
+            env::var("GITHUB_APP_PRIVATE_KEY"),
+        ) {
+            (Ok(app_id), Ok(installation_id), Ok(private_key)) => Some(GitHubApp {
+                app_id,
+                installation_id,
+                private_key,
+            }),
+            _ => None,
+        };
+        let session_default_duration = env::var("SESSION_DEFAULT_DURATION")
+            .map_err(|_| Error::MissingData("SESSION_DEFAULT_DURATION"))?;
+        let session_max_duration = env::var("SESSION_MAX_DURATION")
+            .map_err(|_| Error::MissingData("SESSION_MAX_DURATION"))?;
+        let session_default_duration = SessionDuration::from_str(&session_default_duration)
+            .map_err(|err| Error::InvalidParameter(format!("SESSION_DEFAULT_DURATION: {}", err)))?;
+        let session_max_duration = SessionDuration::from_str(&session_max_duration)
+            .map_err(|err| Error::InvalidParameter(format!("SESSION_MAX_DURATION: {}", err)))?;
+        session_default_duration
+            .check_max(session_max_duration.as_duration())
+            .map_err(|err| Error::InvalidParameter(format!("SESSION_DEFAULT_DURATION: {}", err)))?;
+        let session_default_pool_affinity = env::var("SESSION_DEFAULT_POOL_AFFINITY")
+            .map_err(|_| Error::MissingData("SESSION_DEFAULT_POOL_AFFINITY"))?;
+        let session_default_max_per_node = env::var("SESSION_DEFAULT_MAX_PER_NODE")
+            .map_err(|_| Error::MissingData("SESSION_DEFAULT_MAX_PER_NODE"))?;
+        // YAML mapping of roles to `RoleDefaults`
+        let session_role_defaults: BTreeMap<String, RoleDefaults> =
+            match env::var("SESSION_ROLE_DEFAULTS") {
+                Ok(value) => serde_yaml::from_str(&value).map_err(|err| {
+                    Error::InvalidParameter(format!("SESSION_ROLE_DEFAULTS: {}", err))
+                })?,
+                Err(_) => BTreeMap::new(),
+            };
+        for (role, defaults) in &session_role_defaults {
+            if let Some(duration) = defaults.duration {
+                duration
+                    .check_max(session_max_duration.as_duration())
+                    .map_err(|err| {
+                        Error::InvalidParameter(format!("SESSION_ROLE_DEFAULTS {}: {}", role, err))
+                    })?;
+            }
+        }
+        let onboarding_required = env::var("ONBOARDING_REQUIRED")
+            .map(|value| value == "true")
+            .unwrap_or(false);
+        let telemetry_url = env::var("TELEMETRY_URL").ok();
+        let retry_policy = match env::var("SESSION_RETRY_MAX_ATTEMPTS") {
+            Ok(max_attempts) => Some(RetryPolicy {
+                max_attempts: max_attempts
+                    .parse()
+                    .map_err(|err: ParseIntError| Error::Failure(err.into()))?,
+                delay: Duration::from_secs(
+                    env::var("SESSION_RETRY_DELAY")
+                        .ok()
+                        .and_then(|delay| delay.parse().ok())
+                        .unwrap_or(120),
+                ),
+                fallback_pool: env::var("SESSION_RETRY_FALLBACK_POOL").ok(),
+            }),
+            Err(_) => None,
+        };
+        let oidc = OidcConfiguration::from_env()
+            .await
+            .map_err(Error::Failure)?;
+        let oidc_client_secret = env::var("OIDC_CLIENT_SECRET").ok();
+        if oidc.is_some() && oidc_client_secret.is_none() {
+            return Err(Error::MissingData("OIDC_CLIENT_SECRET"));
+        }
+        let template_snapshot_remote = env::var("TEMPLATE_SNAPSHOT_REMOTE").ok();
+        if let Some(remote) = &template_snapshot_remote {
+            if !remote.starts_with("https://") {
+                return Err(Error::InvalidParameter(
+                    "TEMPLATE_SNAPSHOT_REMOTE must use https".to_string(),
+                ));
+            }
+        }
+        let template_snapshot_token = env::var("TEMPLATE_SNAPSHOT_TOKEN").ok();
+        let dns = Dns::from_env().map_err(Error::InvalidParameter)?;
+        let restricted_mode = env::var("RESTRICTED_MODE")
+            .map(|value| value == "true")
+            .unwrap_or(false);
+        let restricted = restricted_mode || !can_list_nodes(new_client().await?).await;
+        // YAML mapping of pool names to `StaticPool`
+        let static_pools: BTreeMap<String, StaticPool> = match env::var("STATIC_POOLS") {
+            Ok(value) => serde_yaml::from_str(&value)
+                .map_err(|err| Error::InvalidParameter(format!("STATIC_POOLS: {}", err)))?,
+            Err(_) => BTreeMap::new(),
+        };
+        if restricted && static_pools.is_empty() {
+            return Err(Error::MissingData("STATIC_POOLS"));
+        }
+        let allowed_registries = match env::var("TEMPLATE_ALLOWED_REGISTRIES") {
+            Ok(value) => value
+                .split(',')
+                .map(|registry| registry.trim().to_lowercase())
+                .filter(|registry| !registry.is_empty())
+                .collect(),
+            Err(_) => registry::PUBLIC_REGISTRIES
+                .iter()
+                .map(|registry| registry.to_string())
+                .collect(),
+        };
+        let mut disruption_policy = match env::var("SESSION_DISRUPTION_POLICY") {
+            Ok(value) => value.parse().map_err(|err| {
+                Error::InvalidParameter(format!("SESSION_DISRUPTION_POLICY: {}", err))
+            })?,
+            Err(_) => DisruptionPolicy::Evict,
+        };
+        // Drained nodes are found by listing nodes
+        if restricted && disruption_policy == DisruptionPolicy::Migrate {
+            if restricted_mode {
+                return Err(Error::InvalidParameter(
+                    "SESSION_DISRUPTION_POLICY: migrate isn't supported in restricted mode"
+                        .to_string(),
+                ));
+            }
+            warn!("Not allowed to list nodes, drains are blocked but sessions aren't migrated");
+            disruption_policy = DisruptionPolicy::Block;
+        }
+        let subdomain_strategy = match env::var("SESSION_SUBDOMAIN") {
+            Ok(value) => value
+                .parse()
+                .map_err(|err| Error::InvalidParameter(format!("SESSION_SUBDOMAIN: {}", err)))?,
+            Err(_) => SubdomainStrategy::Id,
+        };
+        let subdomain_secret = env::var("SESSION_SUBDOMAIN_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+        if subdomain_strategy == SubdomainStrategy::Hashed && subdomain_secret.is_none() {
+            return Err(Error::InvalidParameter(
+                "SESSION_SUBDOMAIN: hashed requires SESSION_SUBDOMAIN_SECRET".to_string(),
+            ));
+        }
+        let scheduling = Scheduling::from_env().map_err(Error::InvalidParameter)?;
+        let legal = Legal {
+            terms_url: env::var("LEGAL_TERMS_URL").ok(),
+            terms_version: env::var("LEGAL_TERMS_VERSION").ok(),
+            banner: env::var("LEGAL_BANNER").ok(),
+        };
+
+        Ok(Engine {
+            env: Environment {
+                secured,
+                host,
+                namespace: namespace.clone(),
+            },
+            configuration: Configuration {
+                github_client_id,
+                base_domains,
+                session: SessionDefaults {
+                    duration: session_default_duration.as_duration(),
+                    max_duration: session_max_duration.as_duration(),
+                    pool_affinity: session_default_pool_affinity,
+                    max_sessions_per_pod: session_default_max_per_node
+                        .parse()
+                        .map_err(|err: ParseIntError| Error::Failure(err.into()))?,
+                    resource_profile: None,
+                    priority_class: env::var("SESSION_DEFAULT_PRIORITY_CLASS").ok(),
+                    roles: session_role_defaults,
+                },
+                onboarding_required,
+                legal,
+                telemetry_url,
+                retry_policy,
+                oidc,
+                template_snapshot_remote,
+                disruption_policy,
+                subdomain_strategy,
+            },
+            secrets: Secrets {
+                github_client_secret,
+                github_app,
+                oidc_client_secret,
+                template_snapshot_token,
+                subdomain_secret,
+            },
+            pods,
+            dns,
+            restricted,
+            static_pools,
+            allowed_registries,
+            scheduling,
+            plugins: plugins::registered(),
+            clock,
+            orgs: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    // Creates a Session from a Pod annotations
+    fn pod_to_session(self, env: &Environment, pod: &Pod) -> Result<Session> {
+        let labels = pod
+            .metadata
+            .labels
+            .clone()
+            .ok_or(Error::MissingData("pod#metadata#labels"))?;
+        let unknown = "UNKNOWN OWNER".to_string();
+        let username = labels.get(OWNER_LABEL).unwrap_or(&unknown);
+        let annotations = &pod
+            .metadata
+            .annotations
+            .clone()
+            .ok_or(Error::MissingData("pod#metadata#annotations"))?;
+        let template = serde_yaml::from_str(
+            annotations
+                .get(TEMPLATE_ANNOTATION)
+                .ok_or(Error::MissingData("template"))?,
+        )
+        .map_err(|err| Error::Failure(err.into()))?;
+        let duration = str_to_session_duration_minutes(
+            annotations
+                .get(SESSION_DURATION_ANNOTATION)
+                .ok_or(Error::MissingData("template#session_duration"))?,
+        )?;
+
+        let migration = annotations.get(SESSION_MIGRATION_ANNOTATION).cloned();
+        let retries = annotations
+            .get(SESSION_RETRIES_ANNOTATION)
+            .and_then(|retries| retries.parse().ok())
+            .unwrap_or_default();
+        let workshop = labels.get(WORKSHOP_LABEL).cloned();
+        let last_activity = annotations
+            .get(SESSION_ACTIVITY_ANNOTATION)
+            .and_then(|activity| activity.parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let deleted_at = annotations
+            .get(SESSION_DELETED_ANNOTATION)
+            .and_then(|deleted_at| deleted_at.parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let backup = annotations
+            .get(SESSION_BACKUP_ANNOTATION)
+            .and_then(|backup| serde_json::from_str(backup).ok());
+        // Sessions created before domains were configurable use the default one
+        let domain = annotations
+            .get(SESSION_DOMAIN_ANNOTATION)
+            .cloned()
+            .unwrap_or_else(|| env.host.clone());
+        // Sessions created before subdomains were configurable are served under their id
+        let label = annotations
+            .get(SESSION_SUBDOMAIN_ANNOTATION)
+            .map_or(username.as_str(), String::as_str);
+        let flags = annotations
+            .get(SESSION_FLAGS_ANNOTATION)
+            .map(|flags| {
+                flags
+                    .split(',')
+                    .filter(|flag| !flag.is_empty())
+                    .map(|flag| flag.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut details = Self::pod_to_details(self, pod)?;
+        // Pods recreated by their `StatefulSet`, e.g. after a node failure, don't extend the session
+        if owning_stateful_set(pod).is_some() {
+            if let Some(started_at) = annotations
+                .get(SESSION_STARTED_ANNOTATION)
+                .and_then(|started_at| started_at.parse().ok())
+            {
+                details.start_time = Some(UNIX_EPOCH + Duration::from_secs(started_at));
+            }
+        }
+
+        Ok(Session {
+            user_id: username.clone(),
+            template,
+            url: subdomain(&domain, label),
+            domain,
+            pod: details,
+            duration,
+            node: pod
+                .clone()
                 .spec
-                .ok_or(Error::MissingData("spec"))?
-                .tls
-                .is_some()
+                .ok_or(Error::MissingData("pod#spec"))?
+                .node_name
+                .unwrap_or_else(|| UNSCHEDULED_NODE.to_string()),
+            pool: annotations.get(SESSION_POOL_ANNOTATION).cloned(),
+            role: annotations.get(SESSION_ROLE_ANNOTATION).cloned(),
+            organizations: annotations
+                .get(SESSION_ORGANIZATIONS_ANNOTATION)
+                .and_then(|organizations| serde_json::from_str(organizations).ok())
+                .unwrap_or_default(),
+            flags,
+            migration,
+            backup,
+            workshop,
+            retries,
+            last_activity,
+            unattended: annotations
+                .get(SESSION_UNATTENDED_ANNOTATION)
+                .map_or(false, |unattended| unattended == "true"),
+            deleted_at,
+            dns: None,
+        })
+    }
+
+    /// Returns the propagation status of the DNS record of `session`, if managed by the playground
+    pub async fn dns_status(&self, session: &Session) -> Option<DnsStatus> {
+        self.dns.as_ref()?;
+        Some(if dns::resolves(&session.url).await {
+            DnsStatus::Propagated
         } else {
-            false
+            DnsStatus::Pending
+        })
+    }
+
+    /// Creates DNS records of `hosts`, if managed by the playground. Failures are only logged, affected sessions are
+    /// reported with a `Pending` DNS status.
+    async fn create_dns_records(&self, hosts: &[String]) {
+        if let Some(dns) = self.managed_dns().await {
+            for host in hosts {
+                if let Err(err) = dns.create(host).await {
+                    warn!("Failed to create DNS record of {}: {}", host, err);
+                }
+            }
+        }
+    }
+
+    /// Deletes DNS records of `hosts`, if managed by the playground
+    async fn delete_dns_records(&self, hosts: &[String]) {
+        if let Some(dns) = self.managed_dns().await {
+            for host in hosts {
+                if let Err(err) = dns.delete(host).await {
+                    warn!("Failed to delete DNS record of {}: {}", host, err);
+                }
+            }
+        }
+    }
+
+    /// Returns the DNS provider if records are managed by this playground. Once sessions are handed off, records are
+    /// managed by the new cluster.
+    async fn managed_dns(&self) -> Option<&Dns> {
+        let dns = self.dns.as_ref()?;
+        if self.handed_off().await {
+            return None;
+        }
+        Some(dns)
+    }
+
+    fn nodes_to_pool(self, id: String, nodes: Vec<Node>) -> Result<Pool> {
+        let node = nodes
+            .first()
+            .ok_or(Error::MissingData("empty vec of nodes"))?;
+        let labels = node
+            .metadata
+            .labels
+            .clone()
+            .ok_or(Error::MissingData("metadata#labels"))?;
+        let local = "local".to_string();
+        let unknown = "unknown".to_string();
+        let instance_type = labels.get(INSTANCE_TYPE_LABEL).unwrap_or(&local);
+
+        Ok(Pool {
+            name: id,
+            instance_type: Some(instance_type.clone()),
+            nodes: nodes
+                .iter()
+                .map(|node| crate::types::Node {
+                    hostname: node
+                        .metadata
+                        .labels
+                        .clone()
+                        .unwrap_or_default()
+                        .get(HOSTNAME_LABEL)
+                        .unwrap_or(&unknown)
+                        .clone(),
+                })
+                .collect(),
+            capacity: None,
+        })
+    }
+
+    /// Prefers nodes of pool `pool_id` for `pod` according to the pool scheduling strategy, if any
+    async fn apply_scheduling_strategy(
+        &self,
+        pod: &mut Pod,
+        pool_id: &str,
+        max_sessions_per_node: usize,
+    ) -> Result<()> {
+        let strategy = match self.scheduling.strategy(pool_id) {
+            Some(strategy) => strategy,
+            None => return Ok(()),
+        };
+        let pool = self
+            .get_pool(pool_id)
+            .await?
+            .ok_or(Error::MissingData("no matching pool"))?;
+        let sessions = self.list_sessions().await?;
+        let running = running_or_pending_sessions(sessions.values().collect());
+        let session_nodes: Vec<&str> = running
+            .iter()
+            .map(|session| session.node.as_str())
+            .collect();
+        let nodes = node_loads(&pool, &session_nodes, max_sessions_per_node);
+        prefer_nodes(pod, strategy.weights(&nodes));
+        Ok(())
+    }
+
+    /// Returns the requirements nodes part of pool `id` match: the selector of its static definition if any, else its
+    /// hostnames if listed, else the node pool label
+    fn pool_requirements(&self, id: &str) -> Vec<NodeSelectorRequirement> {
+        match self.static_pools.get(id) {
+            Some(StaticPool {
+                selector: Some(selector),
+                ..
+            }) => selector_requirements(selector),
+            Some(StaticPool { nodes, .. }) if !nodes.is_empty() => {
+                vec![NodeSelectorRequirement {
+                    key: HOSTNAME_LABEL.to_string(),
+                    operator: "In".to_string(),
+                    values: Some(nodes.clone()),
+                }]
+            }
+            _ => selector_requirements(&BTreeMap::from([(
+                NODE_POOL_LABEL.to_string(),
+                id.to_string(),
+            )])),
+        }
+    }
+
+    /// Creates pool `id` from its static `definition`, completed with matching `nodes` if they could be listed
+    fn static_pool(&self, id: &str, definition: &StaticPool, nodes: Option<&[Node]>) -> Pool {
+        let requirements = self.pool_requirements(id);
+        let nodes = nodes
+            .unwrap_or_default()
+            .iter()
+            .filter(|node| matches_requirements(node, &requirements))
+            .cloned()
+            .collect();
+        let mut pool = match self.clone().nodes_to_pool(id.to_string(), nodes) {
+            Ok(pool) => pool,
+            Err(_) => Pool {
+                name: id.to_string(),
+                instance_type: None,
+                nodes: definition
+                    .nodes
+                    .iter()
+                    .map(|hostname| types::Node {
+                        hostname: hostname.clone(),
+                    })
+                    .collect(),
+                capacity: None,
+            },
+        };
+        if definition.instance_type.is_some() {
+            pool.instance_type = definition.instance_type.clone();
+        }
+        pool.capacity = definition.capacity;
+        pool
+    }
+
+    fn container_status_to_container_status(
+        self,
+        status: &ContainerStatus,
+    ) -> types::ContainerStatus {
+        let state = status.state.as_ref();
+        types::ContainerStatus {
+            phase: state
+                .map(|s| {
+                    if s.running.is_some() {
+                        ContainerPhase::Running
+                    } else if s.waiting.is_some() {
+                        ContainerPhase::Waiting
+                    } else {
+                        ContainerPhase::Terminated
+                    }
+                })
+                .unwrap_or(ContainerPhase::Unknown),
+            reason: state.and_then(|s| {
+                s.waiting
+                    .as_ref()
+                    .and_then(|s| s.reason.clone())
+                    .or_else(|| s.terminated.as_ref().and_then(|s| s.reason.clone()))
+            }),
+            message: state.and_then(|s| {
+                s.waiting
+                    .as_ref()
+                    .and_then(|s| s.message.clone())
+                    .or_else(|| s.terminated.as_ref().and_then(|s| s.message.clone()))
+            }),
+        }
+    }
+
+    fn pod_to_details(self, pod: &Pod) -> Result<types::Pod> {
+        let status = pod.status.as_ref().ok_or(Error::MissingData("status"))?;
+        let container_statuses = status.clone().container_statuses;
+        let container_status = container_statuses.as_ref().and_then(|v| v.first());
+        Ok(types::Pod {
+            phase: Phase::from_str(
+                &status
+                    .clone()
+                    .phase
+                    .unwrap_or_else(|| "Unknown".to_string()),
+            )
+            .map_err(|err| Error::Failure(err.into()))?,
+            reason: status.clone().reason.unwrap_or_else(|| "".to_string()),
+            message: status.clone().message.unwrap_or_else(|| "".to_string()),
+            start_time: status.clone().start_time.map(|dt| dt.0.into()),
+            container: container_status.map(|c| self.container_status_to_container_status(c)),
+            steps: deployment_steps(pod),
+            failure: classify_failure(pod),
+        })
+    }
+
+    fn yaml_to_user(self, s: &str) -> Result<User> {
+        let user_configuration: UserConfiguration =
+            serde_yaml::from_str(s).map_err(|err| Error::Failure(err.into()))?;
+        Ok(user_configuration.into())
+    }
+
+    /// Lists valid templates. Templates that can't be parsed are logged and ignored.
+    pub async fn list_templates(self) -> Result<BTreeMap<String, Template>> {
+        let (templates, invalid_entries) = self.list_templates_with_invalid_entries(false).await?;
+        for entry in invalid_entries {
+            error!("Error while parsing template {}", entry);
+        }
+        Ok(templates)
+    }
+
+    /// Lists templates, alongside entries that can't be parsed. In `strict` mode, unknown fields make an entry invalid.
+    pub async fn list_templates_with_invalid_entries(
+        &self,
+        strict: bool,
+    ) -> Result<(BTreeMap<String, Template>, Vec<InvalidEntry>)> {
+        let client = new_client().await?;
+        Ok(parse_entries(
+            TEMPLATES_CONFIG_MAP,
+            get_templates(client, &self.env.namespace).await?,
+            strict,
+        ))
+    }
+
+    /// Lists users, alongside entries that can't be parsed. In `strict` mode, unknown fields make an entry invalid.
+    pub async fn list_users_with_invalid_entries(
+        &self,
+        strict: bool,
+    ) -> Result<(BTreeMap<String, User>, Vec<InvalidEntry>)> {
+        let client = new_client().await?;
+        let (users, invalid_entries) = parse_entries::<UserConfiguration>(
+            USERS_CONFIG_MAP,
+            list_users(client, &self.env.namespace).await?,
+            strict,
+        );
+        Ok((
+            users
+                .into_iter()
+                .map(|(id, conf)| (id, conf.into()))
+                .collect(),
+            invalid_entries,
+        ))
+    }
+
+    /// Checks cluster resources the backend relies on
+    pub async fn diagnose(&self) -> Result<Vec<Check>> {
+        let client = new_client().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client.clone(), &self.env.namespace);
+        let ingress = ingress_api.get(INGRESS_NAME).await.ok();
+        Ok(vec![
+            check_ingress(ingress.as_ref()),
+            check_certificates(
+                client.clone(),
+                &self.env.namespace,
+                ingress.as_ref(),
+                &self.configuration.base_domains,
+            )
+            .await,
+            self.check_orphaned_resources(client, ingress.as_ref())
+                .await,
+        ])
+    }
+
+    /// Counts services and ingress rules left behind by sessions that don't exist anymore
+    async fn check_orphaned_resources(&self, client: Client, ingress: Option<&Ingress>) -> Check {
+        let name = "orphaned-resources";
+        let params = ListParams::default().labels(OWNER_LABEL);
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let pods = match pod_api.list(&params).await {
+            Ok(pods) => pods,
+            Err(err) => return Check::fail(name, format!("Failed to list pods: {}", err)),
+        };
+        let service_api: Api<Service> = Api::namespaced(client, &self.env.namespace);
+        let services = match service_api.list(&params).await {
+            Ok(services) => services,
+            Err(err) => return Check::fail(name, format!("Failed to list services: {}", err)),
+        };
+        let owner = |metadata: &ObjectMeta| {
+            metadata
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(OWNER_LABEL))
+                .cloned()
+        };
+        let session_ids: BTreeSet<String> =
+            pods.iter().filter_map(|pod| owner(&pod.metadata)).collect();
+        let orphaned_services = services
+            .iter()
+            .filter_map(|service| owner(&service.metadata))
+            .filter(|id| !session_ids.contains(id))
+            .count();
+        let orphaned_rules = ingress
+            .and_then(|ingress| ingress.spec.as_ref())
+            .and_then(|spec| spec.rules.as_ref())
+            .map_or(0, |rules| {
+                rules
+                    .iter()
+                    .filter_map(rule_session)
+                    .filter(|id| !session_ids.contains(*id))
+                    .count()
+            });
+        if orphaned_services + orphaned_rules > 0 {
+            Check::warn(
+                name,
+                format!(
+                    "{} services and {} ingress rules without session",
+                    orphaned_services, orphaned_rules
+                ),
+            )
+        } else {
+            Check::pass(name)
+        }
+    }
+
+    /// Strictly parses then checks `value`, the YAML definition of template `key`, as done for stored templates.
+    /// With `check_image`, the image must also exist in its registry.
+    pub async fn validate_template(
+        &self,
+        key: &str,
+        value: &str,
+        user: Option<&LoggedUser>,
+    ) -> Vec<InvalidEntry> {
+        let template: Template = match parse_entry(TEMPLATES_CONFIG_MAP, key, value, true) {
+            Ok(template) => template,
+            Err(entry) => return vec![entry],
+        };
+        if let Some(entry) = check_template(key, &template) {
+            return vec![entry];
+        }
+        // Checking an image contacts its registry: only admins can have arbitrary hosts contacted
+        let check_image = user.map_or(false, |user| {
+            user.has_admin_edit_rights()
+                || registry::ImageReference::parse(&template.image).map_or(false, |image| {
+                    self.allowed_registries.contains(&image.registry)
+                })
+        });
+        if check_image {
+            match registry::exists(&template.image).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return vec![template_error(key, "image", "image not found".to_string())]
+                }
+                // Registries can be unreachable from the cluster, sessions would then fail anyway
+                Err(err) => {
+                    return vec![template_error(
+                        key,
+                        "image",
+                        format!("failed to check image: {}", err),
+                    )]
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    /// Strictly parses all templates, users and organizations, returning entries that are invalid
+    pub async fn validate_config_maps(&self) -> Result<Vec<InvalidEntry>> {
+        let client = new_client().await?;
+        let (templates, mut invalid_entries) = parse_entries::<Template>(
+            TEMPLATES_CONFIG_MAP,
+            get_templates(client.clone(), &self.env.namespace).await?,
+            true,
+        );
+        invalid_entries.extend(
+            templates
+                .iter()
+                .filter_map(|(key, template)| check_template(key, template)),
+        );
+        invalid_entries.extend(
+            parse_entries::<UserConfiguration>(
+                USERS_CONFIG_MAP,
+                list_users(client.clone(), &self.env.namespace).await?,
+                true,
+            )
+            .1,
+        );
+        // Organizations are optional
+        if let Ok(orgs) = get_config_map(client, &self.env.namespace, ORGS_CONFIG_MAP).await {
+            invalid_entries.extend(parse_entries::<Org>(ORGS_CONFIG_MAP, orgs, true).1);
+        }
+        Ok(invalid_entries)
+    }
+
+    pub async fn get_user(&self, id: &str) -> Result<Option<User>> {
+        let client = new_client().await?;
+
+        let users = list_users(client, &self.env.namespace).await?;
+        let user = users.get(id);
+
+        match user.map(|user| self.clone().yaml_to_user(user)) {
+            Some(user) => user.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_users(&self) -> Result<BTreeMap<String, User>> {
+        let client = new_client().await?;
+
+        Ok(list_users(client, &self.env.namespace)
+            .await?
+            .into_iter()
+            .map(|(k, v)| Ok((k, self.clone().yaml_to_user(&v)?)))
+            .collect::<Result<BTreeMap<String, User>>>()?)
+    }
+
+    pub async fn create_user(&self, id: String, conf: UserConfiguration) -> Result<()> {
+        let client = new_client().await?;
+
+        add_config_map_value(
+            client,
+            &self.env.namespace,
+            USERS_CONFIG_MAP,
+            id.as_str(),
+            serde_yaml::to_string(&conf)
+                .map_err(|err| Error::Failure(err.into()))?
+                .as_str(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Creates all `users` at once
+    pub async fn create_users(&self, users: &[Entry<UserConfiguration>]) -> Result<()> {
+        let client = new_client().await?;
+
+        let values = users
+            .iter()
+            .map(|entry| {
+                Ok((
+                    entry.id.clone(),
+                    serde_yaml::to_string(&entry.value)
+                        .map_err(|err| Error::Failure(err.into()))?,
+                ))
+            })
+            .collect::<Result<BTreeMap<String, String>>>()?;
+        add_config_map_values(client, &self.env.namespace, USERS_CONFIG_MAP, &values).await
+    }
+
+    pub async fn update_user(&self, id: String, conf: UserUpdateConfiguration) -> Result<()> {
+        // Preferences and onboarding state are managed independently and must be preserved
+        let existing = self.get_user(&id).await?;
+        self.store_user(
+            &id,
+            &UserConfiguration {
+                admin: conf.admin,
+                can_customize_duration: conf.can_customize_duration,
+                can_customize_pool_affinity: conf.can_customize_pool_affinity,
+                pool_affinity: conf.pool_affinity,
+                preferences: existing
+                    .as_ref()
+                    .map(|user| user.preferences.clone())
+                    .unwrap_or_default(),
+                onboarding: existing
+                    .as_ref()
+                    .map(|user| user.onboarding)
+                    .unwrap_or_default(),
+                accepted_terms_version: existing
+                    .as_ref()
+                    .and_then(|user| user.accepted_terms_version.clone()),
+                deleted_at: existing.as_ref().and_then(|user| user.deleted_at),
+                identities: existing.map(|user| user.identities).unwrap_or_default(),
+                // Explicitly configured from now on
+                implicit: false,
+            },
+        )
+        .await
+    }
+
+    /// Applies `update` to the preferences of user `id`, following merge-patch semantics
+    pub async fn update_user_preferences(
+        &self,
+        id: &str,
+        update: UserPreferencesUpdate,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut user = self
+            .get_user(id)
+            .await?
+            .ok_or(Error::MissingData("no matching user"))?;
+        for (key, value) in update {
+            match value {
+                Some(value) => user.preferences.insert(key, value),
+                None => user.preferences.remove(&key),
+            };
+        }
+        let preferences = user.preferences.clone();
+        self.store_user(id, &user.into()).await?;
+        Ok(preferences)
+    }
+
+    /// Adds `identity` to the ones user `id` can log in with
+    pub async fn link_user_identity(&self, id: &str, identity: Identity) -> Result<()> {
+        let mut user = self
+            .get_user(id)
+            .await?
+            .ok_or(Error::MissingData("no matching user"))?;
+        if !user.identities.contains(&identity) {
+            user.identities.push(identity);
+        }
+        self.store_user(id, &user.into()).await
+    }
+
+    pub async fn update_user_onboarding(
+        &self,
+        id: &str,
+        state: OnboardingState,
+        accepted_terms_version: Option<String>,
+    ) -> Result<()> {
+        let mut user = self.get_user(id).await?.unwrap_or_else(User::implicit);
+        user.onboarding = state;
+        if accepted_terms_version.is_some() {
+            user.accepted_terms_version = accepted_terms_version;
+        }
+        self.store_user(id, &user.into()).await
+    }
+
+    /// Sets or clears the deletion time of user `id`, in seconds since epoch
+    pub async fn update_user_deletion(&self, id: &str, deleted_at: Option<u64>) -> Result<()> {
+        let mut user = self
+            .get_user(id)
+            .await?
+            .ok_or(Error::MissingData("no matching user"))?;
+        user.deleted_at = deleted_at;
+        self.store_user(id, &user.into()).await
+    }
+
+    pub async fn store_template(&self, id: &str, template: &Template) -> Result<()> {
+        template
+            .validate()
+            .map_err(|err| Error::InvalidParameter(format!("template {}: {}", id, err)))?;
+        let client = new_client().await?;
+
+        add_config_map_value(
+            client,
+            &self.env.namespace,
+            TEMPLATES_CONFIG_MAP,
+            id,
+            serde_yaml::to_string(template)
+                .map_err(|err| Error::Failure(err.into()))?
+                .as_str(),
+        )
+        .await
+    }
+
+    async fn store_user(&self, id: &str, conf: &UserConfiguration) -> Result<()> {
+        let client = new_client().await?;
+
+        add_config_map_value(
+            client,
+            &self.env.namespace,
+            USERS_CONFIG_MAP,
+            id,
+            serde_yaml::to_string(conf)
+                .map_err(|err| Error::Failure(err.into()))?
+                .as_str(),
+        )
+        .await
+    }
+
+    pub async fn delete_user(&self, id: String) -> Result<()> {
+        let client = new_client().await?;
+        delete_config_map_value(client, &self.env.namespace, USERS_CONFIG_MAP, id.as_str()).await
+    }
+
+    /// Returns the format version of ConfigMaps holding resources, keyed by ConfigMap
+    pub async fn storage_versions(&self) -> Result<BTreeMap<String, StorageVersion>> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let mut versions = BTreeMap::new();
+        for (name, migrations) in STORED_RESOURCES {
+            let version = match config_map_api.get(name).await {
+                Ok(config_map) => storage_version(&config_map),
+                Err(kube::Error::Api(err)) if err.code == 404 => continue,
+                Err(err) => return Err(Error::Failure(err.into())),
+            };
+            versions.insert(
+                name.to_string(),
+                StorageVersion {
+                    version,
+                    latest: storage::latest_version(migrations),
+                },
+            );
+        }
+        Ok(versions)
+    }
+
+    /// Upgrades, in place, entries of ConfigMaps holding resources stored in an older format
+    pub async fn migrate_storage(&self) -> Result<()> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        for (name, migrations) in STORED_RESOURCES {
+            let mut config_map = match config_map_api.get(name).await {
+                Ok(config_map) => config_map,
+                Err(kube::Error::Api(err)) if err.code == 404 => continue,
+                Err(err) => return Err(Error::Failure(err.into())),
+            };
+            let version = storage_version(&config_map);
+            let latest = storage::latest_version(migrations);
+            if version >= latest {
+                continue;
+            }
+
+            if let Some(data) = config_map.data.as_mut() {
+                for (key, value) in data.iter_mut() {
+                    match storage::migrate_entry(migrations, version, value) {
+                        Ok(Some(migrated)) => *value = migrated,
+                        Ok(None) => {}
+                        // Left as is, reported by `validate_config_maps`
+                        Err(err) => warn!("Failed to migrate {} in {}: {}", key, name, err),
+                    }
+                }
+            }
+            config_map
+                .metadata
+                .annotations
+                .get_or_insert_with(BTreeMap::new)
+                .insert(storage::VERSION_ANNOTATION.to_string(), latest.to_string());
+            // Relies on `resourceVersion` to fail if the ConfigMap was concurrently updated
+            config_map_api
+                .replace(name, &PostParams::default(), &config_map)
+                .await
+                .map_err(|err| Error::Failure(err.into()))?;
+            info!("Migrated {} from version {} to {}", name, version, latest);
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of stored resources and of the portable part of the backend state
+    pub async fn export_state(&self) -> Result<StateArchive> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let mut config_maps = BTreeMap::new();
+        for (name, _) in STORED_RESOURCES {
+            let config_map = match config_map_api.get(name).await {
+                Ok(config_map) => config_map,
+                Err(kube::Error::Api(err)) if err.code == 404 => continue,
+                Err(err) => return Err(Error::Failure(err.into())),
+            };
+            config_maps.insert(
+                name.to_string(),
+                ArchivedConfigMap {
+                    storage_version: Some(storage_version(&config_map)),
+                    data: config_map.data.unwrap_or_default(),
+                },
+            );
+        }
+        let mut state = BTreeMap::new();
+        for key in ARCHIVED_STATES {
+            if let Some(value) = self.load_state(key).await? {
+                state.insert(key.to_string(), value);
+            }
+        }
+        config_maps.insert(
+            STATE_CONFIG_MAP.to_string(),
+            ArchivedConfigMap {
+                storage_version: None,
+                data: state,
+            },
+        );
+        Ok(StateArchive {
+            version: ARCHIVE_VERSION,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            config_maps,
+            sessions: Vec::new(),
+        })
+    }
+
+    /// Returns true once sessions are handed off to another cluster, see `start_handoff`
+    pub async fn handed_off(&self) -> bool {
+        matches!(self.load_state(HANDOFF_STATE).await, Ok(Some(_)))
+    }
+
+    /// Switches to handoff mode: sessions are being moved to another cluster, that now manages their DNS records
+    pub async fn start_handoff(&self) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        self.save_state(HANDOFF_STATE, now.to_string()).await
+    }
+
+    /// Pushes the workspace of session `id` to `branch` of `remote` then stops the session, so that no later change
+    /// is lost. Its resources are kept. Returns the pushed commit.
+    pub async fn handoff_session(
+        &self,
+        id: &str,
+        remote: &str,
+        token: Option<&str>,
+        branch: &str,
+    ) -> Result<String> {
+        let commit = self.snapshot_session(id, remote, token, branch).await?;
+        self.scale_session(id, 0).await?;
+        Ok(commit)
+    }
+
+    /// Scales the `StatefulSet` of session `id` to `replicas`. At 0, the session pod is removed but other resources
+    /// are kept, so that it can be scaled back.
+    pub async fn scale_session(&self, id: &str, replicas: i32) -> Result<()> {
+        self.patch_session_stateful_set(id, json!({ "spec": { "replicas": replicas } }))
+            .await
+    }
+
+    /// Suspends session `id` by scaling it to zero, which stops all its processes. Suspended sessions are still
+    /// listed, with a `Suspended` phase, until resumed. Their pod is then recreated from the template: changes made
+    /// to the workspace meanwhile are lost.
+    pub async fn set_session_suspended(&self, id: &str, suspended: bool) -> Result<()> {
+        // A null label is removed
+        let (label, replicas) = if suspended {
+            (Some("true"), 0)
+        } else {
+            (None, 1)
+        };
+        self.patch_session_stateful_set(
+            id,
+            json!({
+                "metadata": { "labels": { SUSPENDED_LABEL: label } },
+                "spec": { "replicas": replicas },
+            }),
+        )
+        .await
+    }
+
+    /// Merges `patch` into the `StatefulSet` of session `id`. Sessions created before `StatefulSet`s don't have one.
+    async fn patch_session_stateful_set(&self, id: &str, patch: serde_json::Value) -> Result<()> {
+        let client = new_client().await?;
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client, &self.env.namespace);
+        let name = list_by_selector(&stateful_set_api, session_pod_selector(id))
+            .await?
+            .into_iter()
+            .find_map(|stateful_set| stateful_set.metadata.name)
+            .ok_or(Error::MissingData("no matching session"))?;
+        stateful_set_api
+            .patch(&name, &PatchParams::default(), &Patch::Merge(patch))
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(())
+    }
+
+    /// Returns the pod standing for session `id` if it is suspended, see `suspended_pod`
+    async fn get_suspended_pod(&self, id: &str) -> Result<Option<Pod>> {
+        let client = new_client().await?;
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client, &self.env.namespace);
+        Ok(list_by_selector(
+            &stateful_set_api,
+            format!("{},{}", session_pod_selector(id), SUSPENDED_LABEL),
+        )
+        .await?
+        .into_iter()
+        .find_map(suspended_pod))
+    }
+
+    /// Replaces stored resources and backend state with those of `archive`. Nothing is written unless all entries of
+    /// the archive are valid, once migrated. ConfigMaps are then written one by one: if one fails, those already
+    /// written are reverted.
+    pub async fn import_state(&self, archive: StateArchive) -> Result<()> {
+        let config_maps = prepare_archive(archive)?;
+
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        // ConfigMaps as they were before being written, if they existed
+        let mut written: Vec<(String, Option<ConfigMap>)> = Vec::new();
+        let mut result = Ok(());
+        for (name, archived) in config_maps {
+            let previous = match config_map_api.get(&name).await {
+                Ok(config_map) => Some(config_map),
+                Err(kube::Error::Api(err)) if err.code == 404 => None,
+                Err(err) => {
+                    result = Err(Error::Failure(err.into()));
+                    break;
+                }
+            };
+            let mut config_map = previous.clone().unwrap_or_else(|| ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            if name == STATE_CONFIG_MAP {
+                // Other state keys are left as is
+                config_map
+                    .data
+                    .get_or_insert_with(BTreeMap::new)
+                    .extend(archived.data);
+            } else {
+                let annotations = config_map
+                    .metadata
+                    .annotations
+                    .get_or_insert_with(BTreeMap::new);
+                if let Some(version) = archived.storage_version {
+                    annotations
+                        .insert(storage::VERSION_ANNOTATION.to_string(), version.to_string());
+                }
+                config_map.data = Some(archived.data);
+            }
+            let write = match &previous {
+                // Carries the read `resourceVersion`, rejected if the ConfigMap changed since
+                Some(_) => {
+                    config_map_api
+                        .replace(&name, &PostParams::default(), &config_map)
+                        .await
+                }
+                None => {
+                    config_map_api
+                        .create(&PostParams::default(), &config_map)
+                        .await
+                }
+            };
+            if let Err(err) = write {
+                result = Err(Error::Failure(err.into()));
+                break;
+            }
+            written.push((name, previous));
+        }
+        if let Err(err) = result {
+            for (name, previous) in written.into_iter().rev() {
+                if let Err(err) = self
+                    .revert_config_map(&config_map_api, &name, previous)
+                    .await
+                {
+                    error!("Failed to revert {} after a failed restore: {}", name, err);
+                }
+            }
+            return Err(err);
+        }
+        for name in &written {
+            info!("Restored {}", name.0);
+        }
+        Ok(())
+    }
+
+    /// Puts back `previous`, the content of ConfigMap `name` before it was written. Deletes it if it didn't exist.
+    async fn revert_config_map(
+        &self,
+        config_map_api: &Api<ConfigMap>,
+        name: &str,
+        previous: Option<ConfigMap>,
+    ) -> Result<()> {
+        match previous {
+            Some(mut previous) => {
+                let current = config_map_api
+                    .get(name)
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+                previous.metadata.resource_version = current.metadata.resource_version;
+                config_map_api
+                    .replace(name, &PostParams::default(), &previous)
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+            }
+            None => {
+                config_map_api
+                    .delete(name, &DeleteParams::default())
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns organizations, possibly cached for `ORGS_CACHE_TTL`. The cache is per replica: only the one updating an
+    /// organization sees the change right away.
+    pub async fn list_orgs(&self) -> Result<BTreeMap<String, Org>> {
+        if let Some((read_at, orgs)) = self.orgs.lock().ok().and_then(|orgs| orgs.clone()) {
+            if read_at.elapsed() < ORGS_CACHE_TTL {
+                return Ok(orgs);
+            }
+        }
+
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let orgs: BTreeMap<String, Org> = match config_map_api.get(ORGS_CONFIG_MAP).await {
+            Ok(config_map) => config_map
+                .data
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(id, value)| {
+                    Ok((
+                        id,
+                        serde_yaml::from_str(&value).map_err(|err| Error::Failure(err.into()))?,
+                    ))
+                })
+                .collect::<Result<_>>()?,
+            Err(kube::Error::Api(err)) if err.code == 404 => BTreeMap::new(),
+            Err(err) => return Err(Error::Failure(err.into())),
+        };
+        if let Ok(mut cache) = self.orgs.lock() {
+            cache.replace((Instant::now(), orgs.clone()));
+        }
+        Ok(orgs)
+    }
+
+    fn invalidate_orgs(&self) {
+        if let Ok(mut cache) = self.orgs.lock() {
+            cache.take();
+        }
+    }
+
+    /// Stores `org` settings, creating the organizations ConfigMap if needed
+    pub async fn store_org(&self, id: &str, org: &Org) -> Result<()> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(ORGS_CONFIG_MAP.to_string()),
+                ..Default::default()
+            },
+            data: Some(BTreeMap::from([(
+                id.to_string(),
+                serde_yaml::to_string(org).map_err(|err| Error::Failure(err.into()))?,
+            )])),
+            ..Default::default()
+        };
+        config_map_api
+            .patch(
+                ORGS_CONFIG_MAP,
+                &PatchParams::apply(&format!("{}-org-{}", APP_VALUE, id)).force(),
+                &Patch::Apply(&config_map),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        self.invalidate_orgs();
+        Ok(())
+    }
+
+    pub async fn delete_org(&self, id: &str) -> Result<()> {
+        let client = new_client().await?;
+        let result =
+            delete_config_map_value(client, &self.env.namespace, ORGS_CONFIG_MAP, id).await;
+        self.invalidate_orgs();
+        result
+    }
+
+    /// Returns a receiver of the ids of sessions whose pods change, as seen by this replica watch
+    pub fn subscribe_session_changes(&self) -> broadcast::Receiver<String> {
+        self.pods.changes.subscribe()
+    }
+
+    pub async fn get_session(&self, id: &str) -> Result<Option<Session>> {
+        let pod = match self.pods.pods() {
+            Some(pods) => pods.into_iter().find(|pod| {
+                pod.metadata
+                    .labels
+                    .as_ref()
+                    .and_then(|labels| labels.get(OWNER_LABEL))
+                    .map_or(false, |owner| owner == id)
+            }),
+            // Cache not ready yet
+            None => {
+                let client = new_client().await?;
+                let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
+                get_session_pod(&pod_api, id).await.ok().flatten()
+            }
+        };
+        let pod = match pod {
+            Some(pod) => Some(pod),
+            None => self.get_suspended_pod(id).await?,
+        };
+
+        match pod.map(|pod| self.clone().pod_to_session(&self.env, &pod)) {
+            Some(session) => session.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists all currently running sessions. Pods that can't be converted are skipped and logged.
+    pub async fn list_sessions(&self) -> Result<BTreeMap<String, Session>> {
+        let (sessions, warnings) = self.list_sessions_with_warnings().await?;
+        for warning in warnings {
+            warn!("{}", warning);
+        }
+        Ok(sessions)
+    }
+
+    /// Lists all currently running sessions, alongside a description of pods that can't be converted
+    pub async fn list_sessions_with_warnings(
+        &self,
+    ) -> Result<(BTreeMap<String, Session>, Vec<String>)> {
+        let pods = match self.pods.pods() {
+            Some(pods) => pods,
+            // Cache not ready yet
+            None => {
+                let client = new_client().await?;
+                let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
+                list_by_selector(
+                    &pod_api,
+                    format!(
+                        "{}={},!{}",
+                        COMPONENT_LABEL, COMPONENT_VALUE, MIGRATION_LABEL
+                    ),
+                )
+                .await?
+            }
+        };
+
+        let client = new_client().await?;
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client, &self.env.namespace);
+        let suspended_pods = list_by_selector(&stateful_set_api, SUSPENDED_LABEL.to_string())
+            .await?
+            .into_iter()
+            .filter_map(suspended_pod);
+
+        let mut sessions = BTreeMap::new();
+        let mut warnings = Vec::new();
+        // Pods of suspended sessions might still be terminating
+        for pod in pods.into_iter().chain(suspended_pods) {
+            match self.clone().pod_to_session(&self.env, &pod) {
+                Ok(session) => {
+                    sessions.entry(session.user_id.clone()).or_insert(session);
+                }
+                Err(err) => warnings.push(format!(
+                    "Invalid session pod {}: {}",
+                    pod.metadata.name.as_deref().unwrap_or("<unknown>"),
+                    err
+                )),
+            }
+        }
+        Ok((sessions, warnings))
+    }
+
+    /// Adds ingress rules for `sessions`, mapping session ids to their template and host
+    pub async fn patch_ingress(
+        &self,
+        sessions: &BTreeMap<String, (&Template, &str)>,
+    ) -> Result<()> {
+        let client = new_client().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
+        let mut ingress: Ingress = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .clone();
+        let mut spec = ingress
+            .clone()
+            .spec
+            .ok_or(Error::MissingData("ingress#spec"))?
+            .clone();
+        let mut rules: Vec<IngressRule> = spec
+            .clone()
+            .rules
+            .ok_or(Error::MissingData("ingress#spec#rules"))?;
+        let mut hosts = Vec::new();
+        for (session_id, (template, host)) in sessions {
+            rules.push(IngressRule {
+                host: Some(host.to_string()),
+                http: Some(HTTPIngressRuleValue {
+                    paths: create_ingress_paths(service_name(session_id), template),
+                }),
+            });
+            hosts.push(host.to_string());
+        }
+        spec.rules.replace(rules);
+        ingress.spec.replace(spec);
+
+        ingress_api
+            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        self.create_dns_records(&hosts).await;
+
+        Ok(())
+    }
+
+    /// Validates the creation of session `session_id` and returns what would be created, without touching the cluster
+    pub async fn plan_session(
+        &self,
+        user: &LoggedUser,
+        session_id: &str,
+        conf: &SessionConfiguration,
+    ) -> Result<SessionPlan> {
+        // Make sure some node on the right pools still have rooms
+        // Find pool affinity, lookup corresponding pool and capacity based on nodes, figure out if there is room left
+        // TODO: replace with custom scheduler
+        // * https://kubernetes.io/docs/tasks/extend-kubernetes/configure-multiple-schedulers/
+        // * https://kubernetes.io/blog/2017/03/advanced-scheduling-in-kubernetes/
+        // Access the right image id
+        let mut template = match &conf.handoff {
+            Some(template) => template.clone(),
+            None => traced("kubernetes.list_templates", self.clone().list_templates())
+                .await?
+                .get(&conf.template.to_string())
+                .ok_or(Error::MissingData("no matching template"))?
+                .clone(),
+        };
+        // Templates edited directly in the ConfigMap bypass validation at store time
+        template.validate().map_err(|err| {
+            Error::InvalidParameter(format!("template {}: {}", conf.template, err))
+        })?;
+        if let Some(canary) = template.canary.take() {
+            if is_canary(session_id, canary.percentage) {
+                template.image = canary.image;
+            }
+        }
+        let defaults = self.configuration.session.for_role(user.role());
+        let pool_id = session_pool(user, conf, &template, &defaults)?;
+        let pool = traced("kubernetes.get_pool", self.get_pool(&pool_id))
+            .await?
+            .ok_or(Error::MissingData("no matching pool"))?;
+        let sessions = traced("kubernetes.list_sessions", self.list_sessions()).await?;
+        if free_slots(
+            &pool,
+            defaults.max_sessions_per_pod,
+            &self.list_reservations().await?,
+            conf.workshop.as_deref(),
+            &sessions,
+            self.clock.now(),
+        ) == 0
+        {
+            // "Reached maximum number of concurrent sessions allowed: {}"
+            return Err(Error::Unauthorized());
+        }
+
+        if let Some(backup) = &conf.backup {
+            validate_backup(backup)?;
+        }
+        if let Some(workshop) = &conf.workshop {
+            validate_workshop(workshop)?;
+        }
+
+        let domain = match conf.domain.clone() {
+            Some(domain) if self.configuration.base_domains.contains(&domain) => domain,
+            Some(domain) => return Err(Error::InvalidParameter(format!("domain {}", domain))),
+            None => self.env.host.clone(),
+        };
+
+        let label = subdomain_label(
+            self.configuration.subdomain_strategy,
+            self.secrets.subdomain_secret.as_deref().unwrap_or_default(),
+            session_id,
+        )?;
+        Ok(SessionPlan {
+            id: session_id.to_string(),
+            template,
+            pool: pool_id,
+            url: subdomain(&domain, &label),
+            domain,
+            subdomain: label,
+            pod_name: stateful_set_pod_name(&pod_name(session_id)),
+            duration: self.session_duration(conf.duration, &defaults)?,
+            resource_profile: defaults.resource_profile,
+            priority_class: defaults.priority_class,
+        })
+    }
+
+    pub async fn create_session(
+        &self,
+        user: &LoggedUser,
+        session_id: &str,
+        conf: SessionConfiguration,
+    ) -> Result<()> {
+        let SessionPlan {
+            mut template,
+            pool: pool_id,
+            domain,
+            subdomain,
+            url,
+            duration,
+            resource_profile,
+            priority_class,
+            ..
+        } = self.plan_session(user, session_id, &conf).await?;
+        // Pin the image so that the session is reproducible even if its tag is later updated
+        match tokio::time::timeout(IMAGE_PIN_TIMEOUT, registry::pin(&template.image)).await {
+            Ok(Ok(image)) => template.image = image,
+            Ok(Err(err)) => warn!("Failed to resolve digest of {}: {}", template.image, err),
+            Err(_) => warn!("Timed out resolving digest of {}", template.image),
+        }
+        let template = &template;
+        let client = new_client().await?;
+        let namespace = &self.env.namespace;
+
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), namespace);
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+
+        //TODO deploy a new ingress matching the route
+        // With the proper mapping
+        // Define the correct route
+        // Also deploy proper tcp mapping configmap https://kubernetes.github.io/ingress-nginx/user-guide/exposing-tcp-udp-services/
+
+        let last_node = self.last_node(session_id).await;
+        let mut pod = create_pod(
+            &domain,
+            &url,
+            session_id,
+            &pod_name(session_id),
+            template,
+            &duration,
+            &pool_id,
+            self.pool_requirements(&pool_id),
+            last_node.as_deref(),
+        )?;
+        if let Some(url) = &self.configuration.telemetry_url {
+            add_env_var(&mut pod, create_env_var(TELEMETRY_URL_ENV, url));
+        }
+        if let Some(profile) = &resource_profile {
+            apply_resource_profile(&mut pod, profile);
+        }
+        if let Some(spec) = pod.spec.as_mut() {
+            spec.priority_class_name = priority_class;
+        }
+        // Budgets are accounted from running sessions
+        let annotations = pod.metadata.annotations.get_or_insert_with(BTreeMap::new);
+        annotations.insert(SESSION_ROLE_ANNOTATION.to_string(), user.role().to_string());
+        annotations.insert(
+            SESSION_ORGANIZATIONS_ANNOTATION.to_string(),
+            serde_json::to_string(&user.organizations).map_err(|err| Error::Failure(err.into()))?,
+        );
+        let defaults = self.configuration.session.for_role(user.role());
+        self.apply_scheduling_strategy(&mut pod, &pool_id, defaults.max_sessions_per_pod)
+            .await?;
+        if let Some(workshop) = &conf.workshop {
+            let sessions = self.list_sessions().await?;
+            join_workshop(
+                &mut pod,
+                workshop,
+                &workshop_peers(&sessions, workshop, session_id),
+            );
+        }
+        if let Some(annotations) = pod.metadata.annotations.as_mut() {
+            annotations.insert(SESSION_SUBDOMAIN_ANNOTATION.to_string(), subdomain);
+            if conf.unattended {
+                annotations.insert(SESSION_UNATTENDED_ANNOTATION.to_string(), true.to_string());
+            }
+        }
+        if let Some(backup) = &conf.backup {
+            if let Some(annotations) = pod.metadata.annotations.as_mut() {
+                annotations.insert(
+                    SESSION_BACKUP_ANNOTATION.to_string(),
+                    serde_json::to_string(backup).map_err(|err| Error::Failure(err.into()))?,
+                );
+            }
+        }
+        self.plugins.customize_pod(session_id, &mut pod);
+        let stateful_set = create_stateful_set(session_id, pod.clone())?;
+        let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
+        let service = create_service(session_id, template);
+        let secret_api: Api<Secret> = Api::namespaced(client.clone(), namespace);
+        // Only what this call created is rolled back: a concurrent creation of the same session must be left alone
+        let mut created = CreatedResources::default();
+        let result = async {
+            if let Some(token) = conf
+                .backup
+                .as_ref()
+                .and_then(|backup| backup.token.as_ref())
+            {
+                secret_api
+                    .create(
+                        &PostParams::default(),
+                        &create_backup_secret(session_id, token),
+                    )
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+                created.secret = true;
+            }
+
+            // The pod is created asynchronously by its `StatefulSet`: report admission failures (e.g. quotas) right away
+            pod_api
+                .create(
+                    &PostParams {
+                        dry_run: true,
+                        ..PostParams::default()
+                    },
+                    &pod,
+                )
+                .await
+                .map_err(pod_creation_error)?;
+
+            // Deploy a new pod for this image, recreated if lost. Fails if the session already exists, so that
+            // concurrent creations are rejected before touching shared resources.
+            traced(
+                "kubernetes.create_stateful_set",
+                stateful_set_api.create(&PostParams::default(), &stateful_set),
+            )
+            .await
+            .map_err(pod_creation_error)?;
+            created.stateful_set = true;
+
+            let mut sessions = BTreeMap::new();
+            sessions.insert(session_id.to_string(), (template, url.as_str()));
+            traced("kubernetes.patch_ingress", self.patch_ingress(&sessions)).await?;
+            created.ingress_rules = true;
+
+            // Deploy the associated service
+            traced(
+                "kubernetes.create_service",
+                service_api.create(&PostParams::default(), &service),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+            created.service = true;
+
+            // Make this session reachable by other workshop members
+            if let Some(workshop) = &conf.workshop {
+                service_api
+                    .create(
+                        &PostParams::default(),
+                        &create_peer_service(workshop, session_id, namespace),
+                    )
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+                created.peer_service = true;
+            }
+
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            // Roll back so that no dangling pod or ingress rule is left behind
+            if created.stateful_set {
+                if let Err(err) = stateful_set_api
+                    .delete(&pod_name(session_id), &DeleteParams::default())
+                    .await
+                {
+                    error!("Failed to roll back pod {}: {}", session_id, err);
+                }
+            }
+            if created.ingress_rules {
+                if let Err(err) = self.remove_ingress_rules(session_id).await {
+                    error!("Failed to roll back ingress rules {}: {}", session_id, err);
+                }
+            }
+            if created.service {
+                if let Err(err) = service_api
+                    .delete(&service_name(session_id), &DeleteParams::default())
+                    .await
+                {
+                    error!("Failed to roll back service {}: {}", session_id, err);
+                }
+            }
+            if let (true, Some(workshop)) = (created.peer_service, &conf.workshop) {
+                if let Err(err) = service_api
+                    .delete(
+                        &peer_service_name(workshop, session_id),
+                        &DeleteParams::default(),
+                    )
+                    .await
+                {
+                    error!("Failed to roll back peer service {}: {}", session_id, err);
+                }
+            }
+            if created.secret {
+                if let Err(err) = secret_api
+                    .delete(&backup_secret_name(session_id), &DeleteParams::default())
+                    .await
+                {
+                    error!("Failed to roll back backup secret {}: {}", session_id, err);
+                }
+            }
+        }
+        result
+    }
+
+    /// Resolves a requested session `duration`, which can't exceed the configured maximum
+    fn session_duration(
+        &self,
+        duration: Option<SessionDuration>,
+        defaults: &SessionDefaults,
+    ) -> Result<Duration> {
+        match duration {
+            Some(duration) => {
+                duration
+                    .check_max(defaults.max_duration)
+                    .map_err(Error::InvalidParameter)?;
+                Ok(duration.as_duration())
+            }
+            None => Ok(defaults.duration),
+        }
+    }
+
+    /// Updates session `session_id`. Omitted values are reset to defaults of `user`.
+    pub async fn update_session(
+        &self,
+        user: &LoggedUser,
+        session_id: &str,
+        conf: SessionUpdateConfiguration,
+    ) -> Result<()> {
+        let session = self
+            .clone()
+            .get_session(session_id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+
+        let defaults = self.configuration.session.for_role(user.role());
+        let duration = self.session_duration(conf.duration, &defaults)?;
+        if duration != session.duration {
+            self.patch_session_metadata(
+                &session.user_id,
+                json!({
+                    "annotations": {
+                        SESSION_DURATION_ANNOTATION: session_duration_annotation(duration)
+                    }
+                }),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Recreates the pod of session `id` with the same name, so that its service and ingress rule keep working.
+    /// What is left of the session duration is preserved. Workspaces live in the container filesystem rather than a
+    /// volume, so the workspace is lost: persisting it would require sessions backed by a `PersistentVolumeClaim`.
+    pub async fn restart_session(&self, id: &str) -> Result<()> {
+        let client = new_client().await?;
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client, &self.env.namespace);
+        let source = get_session_pod(&pod_api, id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let name = source
+            .metadata
+            .name
+            .clone()
+            .ok_or(Error::MissingData("pod#metadata#name"))?;
+        let session = self.clone().pod_to_session(&self.env, &source)?;
+        if session.migration.is_some() {
+            return Err(Error::Forbidden("a migration is in progress".to_string()));
+        }
+
+        let duration = reaper::remaining(&*self.clock, session.pod.start_time, session.duration);
+        let mut annotations = source.metadata.annotations.clone().unwrap_or_default();
+        annotations.insert(
+            SESSION_DURATION_ANNOTATION.to_string(),
+            session_duration_annotation(duration),
+        );
+        annotations.insert(
+            SESSION_STARTED_ANNOTATION.to_string(),
+            session_started_annotation(self.clock.now()),
+        );
+        let mut spec = source.spec.clone().ok_or(Error::MissingData("pod#spec"))?;
+        // Let the scheduler pick a node again, the previous one might be the culprit
+        spec.node_name = None;
+        // Resolved again from `priorityClassName`, whose value might have changed
+        spec.priority = None;
+
+        replace_pod(
+            &pod_api,
+            &stateful_set_api,
+            &name,
+            &source,
+            annotations,
+            spec,
+        )
+        .await
+    }
+
+    /// Recreates the pod of session `id` after a transient failure, away from nodes it already failed on.
+    /// The session is moved to `pool` if set. Fails if the pod would be recreated as is, i.e. it was never scheduled and
+    /// stays in the same pool. Returns the number of retries so far.
+    pub async fn retry_session(&self, id: &str, pool: Option<&str>) -> Result<u32> {
+        let client = new_client().await?;
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let stateful_set_api: Api<StatefulSet> = Api::namespaced(client, &self.env.namespace);
+        let source = get_session_pod(&pod_api, id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let name = source
+            .metadata
+            .name
+            .clone()
+            .ok_or(Error::MissingData("pod#metadata#name"))?;
+        let session = self.clone().pod_to_session(&self.env, &source)?;
+        if session.migration.is_some() {
+            return Err(Error::Forbidden("a migration is in progress".to_string()));
+        }
+        let pool = pool.filter(|pool| session.pool.as_deref() != Some(*pool));
+        // Pods never scheduled have no node to avoid: the same pod would be recreated
+        if source
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.node_name.as_ref())
+            .is_none()
+            && pool.is_none()
+        {
+            return Err(Error::Conflict(format!(
+                "session {} can't be placed elsewhere",
+                id
+            )));
+        }
+
+        let mut annotations = source.metadata.annotations.clone().unwrap_or_default();
+        let retries = session.retries + 1;
+        annotations.insert(SESSION_RETRIES_ANNOTATION.to_string(), retries.to_string());
+        annotations.insert(
+            SESSION_STARTED_ANNOTATION.to_string(),
+            session_started_annotation(self.clock.now()),
+        );
+        let mut spec = source.spec.clone().ok_or(Error::MissingData("pod#spec"))?;
+        // Resolved again from `priorityClassName`, whose value might have changed
+        spec.priority = None;
+        let mut failed_nodes: Vec<String> = annotations
+            .get(SESSION_FAILED_NODES_ANNOTATION)
+            .map(|nodes| nodes.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        if let Some(node) = spec.node_name.take() {
+            failed_nodes.push(node);
+            annotations.insert(
+                SESSION_FAILED_NODES_ANNOTATION.to_string(),
+                failed_nodes.join(","),
+            );
+        }
+        if let Some(term) = spec
+            .affinity
+            .as_mut()
+            .and_then(|affinity| affinity.node_affinity.as_mut())
+            .and_then(|affinity| {
+                affinity
+                    .required_during_scheduling_ignored_during_execution
+                    .as_mut()
+            })
+            .and_then(|selector| selector.node_selector_terms.first_mut())
+        {
+            let requirements = term.match_expressions.get_or_insert_with(Vec::new);
+            if let Some(pool) = pool {
+                annotations.insert(SESSION_POOL_ANNOTATION.to_string(), pool.to_string());
+                *requirements = self.pool_requirements(pool);
+            } else {
+                requirements.retain(|requirement| {
+                    requirement.key != HOSTNAME_LABEL || requirement.operator != "NotIn"
+                });
+            }
+            if !failed_nodes.is_empty() {
+                requirements.push(NodeSelectorRequirement {
+                    key: HOSTNAME_LABEL.to_string(),
+                    operator: "NotIn".to_string(),
+                    values: Some(failed_nodes),
+                });
+            }
+        }
+
+        replace_pod(
+            &pod_api,
+            &stateful_set_api,
+            &name,
+            &source,
+            annotations,
+            spec,
+        )
+        .await?;
+        Ok(retries)
+    }
+
+    /// Sets or removes runtime env variables of session `id`. Returns all variables set.
+    pub async fn update_session_env(
+        &self,
+        id: &str,
+        update: SessionEnvUpdate,
+    ) -> Result<BTreeMap<String, String>> {
+        self.get_session(id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let name = env_config_map_name(id);
+        let mut env = config_map_api
+            .get(&name)
+            .await
+            .ok()
+            .and_then(|config_map| config_map.data)
+            .unwrap_or_default();
+        for (key, value) in update {
+            match value {
+                Some(value) => env.insert(key, value),
+                None => env.remove(&key),
+            };
+        }
+
+        let mut labels = BTreeMap::new();
+        labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
+        labels.insert(COMPONENT_LABEL.to_string(), COMPONENT_VALUE.to_string());
+        labels.insert(OWNER_LABEL.to_string(), id.to_string());
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            data: Some(env.clone()),
+            ..Default::default()
+        };
+        config_map_api
+            .patch(
+                &name,
+                &PatchParams::apply(APP_VALUE).force(),
+                &Patch::Apply(&config_map),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(env)
+    }
+
+    pub async fn list_artifacts(&self, workshop: &str) -> Result<Vec<Artifact>> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        match config_map_api
+            .get(&artifacts_config_map_name(workshop))
+            .await
+        {
+            Ok(config_map) => Ok(config_map
+                .binary_data
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, content)| Artifact {
+                    name,
+                    size: content.0.len(),
+                })
+                .collect()),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(Vec::new()),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
+    }
+
+    pub async fn get_artifact(&self, workshop: &str, name: &str) -> Result<Option<Vec<u8>>> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        match config_map_api
+            .get(&artifacts_config_map_name(workshop))
+            .await
+        {
+            Ok(config_map) => Ok(config_map
+                .binary_data
+                .and_then(|mut data| data.remove(name))
+                .map(|content| content.0)),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(None),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
+    }
+
+    /// Stores `content` as artifact `name` of `workshop`, replacing any previous version. Fails if artifacts of
+    /// `workshop` would then exceed `MAX_ARTIFACTS_SIZE`.
+    pub async fn publish_artifact(
+        &self,
+        workshop: &str,
+        name: &str,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        let others: usize = self
+            .list_artifacts(workshop)
+            .await?
+            .iter()
+            .filter(|artifact| artifact.name != name)
+            .map(|artifact| artifact.size)
+            .sum();
+        if others + content.len() > MAX_ARTIFACTS_SIZE {
+            return Err(Error::InvalidParameter(format!(
+                "artifacts of {} would take {} bytes, more than the {} allowed",
+                workshop,
+                others + content.len(),
+                MAX_ARTIFACTS_SIZE
+            )));
+        }
+
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let mut labels = BTreeMap::new();
+        labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
+        labels.insert(COMPONENT_LABEL.to_string(), COMPONENT_VALUE.to_string());
+        labels.insert(WORKSHOP_LABEL.to_string(), workshop.to_string());
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(artifacts_config_map_name(workshop)),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            binary_data: Some(BTreeMap::from([(name.to_string(), ByteString(content))])),
+            ..Default::default()
+        };
+        config_map_api
+            .patch(
+                &artifacts_config_map_name(workshop),
+                // One manager per artifact, so that publishing an artifact doesn't remove others
+                &PatchParams::apply(&format!("{}-{}", APP_VALUE, name)).force(),
+                &Patch::Apply(&config_map),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(())
+    }
+
+    /// Exposes an additional `port` on running session `id`, without recreating its pod. The port is served under `host`
+    /// if set, otherwise under the session host.
+    pub async fn add_session_port(&self, id: &str, port: &Port, host: Option<&str>) -> Result<()> {
+        let session = self
+            .get_session(id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let client = new_client().await?;
+        let service_api: Api<Service> = Api::namespaced(client.clone(), &self.env.namespace);
+        let mut service = service_api
+            .get(&service_name(id))
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let ports = service
+            .spec
+            .as_mut()
+            .ok_or(Error::MissingData("service#spec"))?
+            .ports
+            .get_or_insert_with(Vec::new);
+        if ports
+            .iter()
+            .any(|p| p.name.as_deref() == Some(port.name.as_str()) || p.port == port.port)
+        {
+            return Err(Error::InvalidParameter(format!(
+                "Port {} ({}) is already exposed",
+                port.name, port.port
+            )));
+        }
+        ports.push(create_service_port(port));
+        // The port must be served before being routed to
+        service_api
+            .replace(&service_name(id), &PostParams::default(), &service)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        let path = create_ingress_path(&port.path, &service_name(id), port.port);
+        let result = match host {
+            Some(host) => self.add_ingress_rule(host, path).await,
+            None => {
+                self.update_ingress_paths(&session.url, |paths| {
+                    if paths.iter().any(|p| p.path == path.path) {
+                        return Err(Error::InvalidParameter(format!(
+                            "Path {} is already exposed",
+                            port.path
+                        )));
+                    }
+                    paths.push(path);
+                    Ok(())
+                })
+                .await
+            }
+        };
+        if let Err(err) = result {
+            if let Err(err) = self.remove_service_port(&service_api, id, port.port).await {
+                warn!(
+                    "Failed to remove port {} of {} after a failed update: {}",
+                    port.port, id, err
+                );
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Removes port `number` from the service of session `id`
+    async fn remove_service_port(
+        &self,
+        service_api: &Api<Service>,
+        id: &str,
+        number: i32,
+    ) -> Result<()> {
+        let mut service = service_api
+            .get(&service_name(id))
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        if let Some(ports) = service.spec.as_mut().and_then(|spec| spec.ports.as_mut()) {
+            ports.retain(|p| p.port != number);
+        }
+        service_api
+            .replace(&service_name(id), &PostParams::default(), &service)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(())
+    }
+
+    /// Removes port `name` from running session `id`, along with hosts only serving it. The IDE port can't be removed.
+    pub async fn remove_session_port(&self, id: &str, name: &str) -> Result<()> {
+        if name == "web" {
+            return Err(Error::InvalidParameter(
+                "The IDE port can't be removed".into(),
+            ));
+        }
+        let session = self
+            .get_session(id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let client = new_client().await?;
+        let service_api: Api<Service> = Api::namespaced(client.clone(), &self.env.namespace);
+        let mut service = service_api
+            .get(&service_name(id))
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let ports = service
+            .spec
+            .as_mut()
+            .and_then(|spec| spec.ports.as_mut())
+            .ok_or(Error::MissingData("service#spec#ports"))?;
+        let number = ports
+            .iter()
+            .find(|p| p.name.as_deref() == Some(name))
+            .map(|p| p.port)
+            .ok_or_else(|| Error::InvalidParameter(format!("Unknown port {}", name)))?;
+        ports.retain(|p| p.port != number);
+
+        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
+        let mut ingress = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let rules = ingress
+            .spec
+            .as_mut()
+            .and_then(|spec| spec.rules.as_mut())
+            .ok_or(Error::MissingData("ingress#spec#rules"))?;
+        for rule in rules
+            .iter_mut()
+            .filter(|rule| rule_session(rule) == Some(id))
+        {
+            if let Some(http) = rule.http.as_mut() {
+                http.paths.retain(|path| {
+                    path.backend
+                        .service
+                        .as_ref()
+                        .and_then(|service| service.port.as_ref())
+                        .and_then(|port| port.number)
+                        != Some(number)
+                });
+            }
+        }
+        // Hosts other than the session one only serve a single port, e.g. the read-only viewer
+        let mut removed = Vec::new();
+        rules.retain(|rule| {
+            let empty = rule
+                .http
+                .as_ref()
+                .map_or(true, |http| http.paths.is_empty());
+            match &rule.host {
+                Some(host) if empty && *host != session.url => {
+                    removed.push(host.clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+        ingress_api
+            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        self.delete_dns_records(&removed).await;
+
+        service_api
+            .replace(&service_name(id), &PostParams::default(), &service)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        Ok(())
+    }
+
+    /// Adds an ingress rule serving `path` under `host`, that must not be served yet
+    async fn add_ingress_rule(&self, host: &str, path: HTTPIngressPath) -> Result<()> {
+        let client = new_client().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
+        let mut ingress = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let rules = ingress
+            .spec
+            .as_mut()
+            .and_then(|spec| spec.rules.as_mut())
+            .ok_or(Error::MissingData("ingress#spec#rules"))?;
+        if rules.iter().any(|rule| rule.host.as_deref() == Some(host)) {
+            return Err(Error::InvalidParameter(format!(
+                "Host {} is already exposed",
+                host
+            )));
+        }
+        rules.push(IngressRule {
+            host: Some(host.to_string()),
+            http: Some(HTTPIngressRuleValue { paths: vec![path] }),
+        });
+
+        ingress_api
+            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        self.create_dns_records(&[host.to_string()]).await;
+
+        Ok(())
+    }
+
+    /// Applies `f` to the paths of the ingress rule matching `host`
+    async fn update_ingress_paths<F>(&self, host: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<HTTPIngressPath>) -> Result<()>,
+    {
+        let client = new_client().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
+        let mut ingress = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let rule = ingress
+            .spec
+            .as_mut()
+            .and_then(|spec| spec.rules.as_mut())
+            .and_then(|rules| {
+                rules
+                    .iter_mut()
+                    .find(|rule| rule.host.as_deref() == Some(host))
+            })
+            .ok_or(Error::MissingData("ingress#spec#rules"))?;
+        f(&mut rule
+            .http
+            .get_or_insert_with(|| HTTPIngressRuleValue { paths: Vec::new() })
+            .paths)?;
+
+        ingress_api
+            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        Ok(())
+    }
+
+    pub async fn delete_session(&self, id: &str) -> Result<()> {
+        let session = self.get_session(id).await.ok().flatten();
+        if let Some(session) = &session {
+            if let Err(err) = self.record_last_node(id, &session.node).await {
+                warn!("Failed to record last node of {}: {}", id, err);
+            }
+        }
+
+        // Undeploy the service by its id
+        let client = new_client().await?;
+        let service_api: Api<Service> = Api::namespaced(client.clone(), &self.env.namespace);
+        service_api
+            .delete(&service_name(id), &DeleteParams::default())
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        // Leave the workshop, if any
+        service_api
+            .delete_collection(
+                &DeleteParams::default(),
+                &ListParams::default()
+                    .labels(&format!("{},{}={}", WORKSHOP_LABEL, OWNER_LABEL, id)),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        if let Some(workshop) = session.and_then(|session| session.workshop) {
+            let sessions = self.list_sessions().await?;
+            if workshop_peers(&sessions, &workshop, id).is_empty() {
+                // Last member left
+                let config_map_api: Api<ConfigMap> =
+                    Api::namespaced(client.clone(), &self.env.namespace);
+                if let Err(err) = config_map_api
+                    .delete(
+                        &artifacts_config_map_name(&workshop),
+                        &DeleteParams::default(),
+                    )
+                    .await
+                {
+                    warn!("Failed to delete artifacts of {}: {}", workshop, err);
+                }
+            }
+        }
+
+        let secret_api: Api<Secret> = Api::namespaced(client.clone(), &self.env.namespace);
+        secret_api
+            .delete_collection(
+                &DeleteParams::default(),
+                &ListParams::default().labels(&format!("{}={}", OWNER_LABEL, id)),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client.clone(), &self.env.namespace);
+        config_map_api
+            .delete_collection(
+                &DeleteParams::default(),
+                &ListParams::default().labels(&format!("{}={}", OWNER_LABEL, id)),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        // Also removes pods left by an interrupted migration, and those of sessions created before `StatefulSet`s
+        let selector = format!(
+            "{}={},{}={}",
+            COMPONENT_LABEL, COMPONENT_VALUE, OWNER_LABEL, id
+        );
+        let stateful_set_api: Api<StatefulSet> =
+            Api::namespaced(client.clone(), &self.env.namespace);
+        stateful_set_api
+            .delete_collection(
+                &DeleteParams::default(),
+                &ListParams::default().labels(&selector),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        pod_api
+            .delete_collection(
+                &DeleteParams::default(),
+                &ListParams::default().labels(&selector),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        self.remove_ingress_rules(id).await
+    }
+
+    pub async fn list_reservations(&self) -> Result<BTreeMap<String, Reservation>> {
+        match self.load_state(RESERVATIONS_STATE).await? {
+            Some(value) => serde_json::from_str(&value).map_err(|err| Error::Failure(err.into())),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Applies `update` to the current reservations, see `update_state`
+    pub async fn update_reservations<F>(&self, update: F) -> Result<()>
+    where
+        F: Fn(&mut BTreeMap<String, Reservation>) -> Result<()>,
+    {
+        self.update_state(RESERVATIONS_STATE, |value| {
+            let mut reservations = match value {
+                Some(value) => {
+                    serde_json::from_str(value).map_err(|err| Error::Failure(err.into()))?
+                }
+                None => BTreeMap::new(),
+            };
+            update(&mut reservations)?;
+            serde_json::to_string(&reservations).map_err(|err| Error::Failure(err.into()))
+        })
+        .await
+    }
+
+    /// Returns session creations per template, keyed by day since epoch
+    pub async fn template_analytics(
+        &self,
+    ) -> Result<BTreeMap<u64, BTreeMap<String, TemplateStats>>> {
+        match self.load_state(TEMPLATE_ANALYTICS_STATE).await? {
+            Some(value) => serde_json::from_str(&value).map_err(|err| Error::Failure(err.into())),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Records a session creation from `template` in today's aggregate
+    pub async fn record_template_usage(&self, template: &str, failed: bool) -> Result<()> {
+        let today = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() / 86400)
+            .unwrap_or_default();
+        // Creations from all replicas are counted, concurrent updates are retried
+        self.update_state(TEMPLATE_ANALYTICS_STATE, |value| {
+            let mut analytics: BTreeMap<u64, BTreeMap<String, TemplateStats>> = match value {
+                Some(value) => {
+                    serde_json::from_str(value).map_err(|err| Error::Failure(err.into()))?
+                }
+                None => BTreeMap::new(),
+            };
+            analytics.retain(|day, _| *day + TEMPLATE_ANALYTICS_RETENTION_DAYS > today);
+            let stats = analytics
+                .entry(today)
+                .or_default()
+                .entry(template.to_string())
+                .or_default();
+            stats.created += 1;
+            if failed {
+                stats.failed += 1;
+            }
+            serde_json::to_string(&analytics).map_err(|err| Error::Failure(err.into()))
+        })
+        .await
+    }
+
+    /// Returns the hostname of the node session `id` last ran on, if known
+    async fn last_node(&self, id: &str) -> Option<String> {
+        self.load_state(LAST_NODES_STATE)
+            .await
+            .map_err(|err| warn!("Failed to load last nodes: {}", err))
+            .ok()
+            .map(|value| parse_last_nodes(value.as_deref()))
+            .and_then(|mut nodes| nodes.remove(id))
+            .map(|node| node.hostname)
+    }
+
+    async fn evictions(&self) -> Result<BTreeMap<String, SessionEviction>> {
+        match self.load_state(EVICTIONS_STATE).await? {
+            Some(value) => serde_json::from_str(&value).map_err(|err| Error::Failure(err.into())),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the last eviction of session `id`, if it happened recently
+    pub async fn get_eviction(&self, id: &str) -> Result<Option<SessionEviction>> {
+        Ok(self.evictions().await?.remove(id))
+    }
+
+    /// Records evictions and preemptions of session pods, as reported by kubernetes events.
+    /// Returns newly recorded evictions, by session id.
+    pub async fn record_evictions(&self) -> Result<BTreeMap<String, SessionEviction>> {
+        let client = new_client().await?;
+        // Pods recreated by their `StatefulSet` keep their name, and their owner can be looked up
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let owners: BTreeMap<String, String> = pod_api
+            .list(
+                &ListParams::default().labels(&format!("{}={}", COMPONENT_LABEL, COMPONENT_VALUE)),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .items
+            .into_iter()
+            .filter_map(|pod| {
+                let owner = pod.metadata.labels?.remove(OWNER_LABEL)?;
+                Some((pod.metadata.name?, owner))
+            })
+            .collect();
+        let event_api: Api<Event> = Api::namespaced(client, &self.env.namespace);
+        let params = ListParams {
+            field_selector: Some("involvedObject.kind=Pod".to_string()),
+            ..ListParams::default()
+        };
+        let events = event_api
+            .list(&params)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .items;
+        let mut candidates: BTreeMap<String, SessionEviction> = BTreeMap::new();
+        for event in events {
+            let reason = match event.reason.as_deref() {
+                Some(reason @ ("Preempted" | "Evicted")) => reason.to_string(),
+                _ => continue,
+            };
+            let id = match event.involved_object.name.as_deref().and_then(|name| {
+                owners
+                    .get(name)
+                    .map(String::as_str)
+                    .or_else(|| pod_name_to_session_id(name))
+            }) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let time = event
+                .last_timestamp
+                .map(|time| SystemTime::from(time.0))
+                .or_else(|| event.event_time.map(|time| time.0.into()))
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(UNIX_EPOCH)
+                .map_err(|err| Error::Failure(err.into()))?
+                .as_secs();
+            if candidates
+                .get(&id)
+                .map_or(false, |eviction| eviction.time >= time)
+            {
+                continue;
+            }
+            candidates.insert(
+                id,
+                SessionEviction {
+                    reason,
+                    message: event.message,
+                    time,
+                },
+            );
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| Error::Failure(err.into()))?
+            .as_secs();
+        // Returns evictions once merged with `candidates` and expired ones dropped, and those newly recorded
+        let merge = |mut evictions: BTreeMap<String, SessionEviction>| {
+            let mut recorded = BTreeMap::new();
+            for (id, eviction) in &candidates {
+                if evictions
+                    .get(id)
+                    .map_or(true, |recorded| recorded.time < eviction.time)
+                {
+                    evictions.insert(id.clone(), eviction.clone());
+                    recorded.insert(id.clone(), eviction.clone());
+                }
+            }
+            evictions.retain(|_, eviction| {
+                now.saturating_sub(eviction.time) < EVICTION_RETENTION.as_secs()
+            });
+            (evictions, recorded)
+        };
+        // Most of the time there is nothing to write
+        let current = self.evictions().await?;
+        let (merged, _) = merge(current.clone());
+        if merged == current {
+            return Ok(BTreeMap::new());
+        }
+        let recorded = RefCell::new(BTreeMap::new());
+        self.update_state(EVICTIONS_STATE, |value| {
+            let evictions = match value {
+                Some(value) => {
+                    serde_json::from_str(value).map_err(|err| Error::Failure(err.into()))?
+                }
+                None => BTreeMap::new(),
+            };
+            let (evictions, newly_recorded) = merge(evictions);
+            recorded.replace(newly_recorded);
+            serde_json::to_string(&evictions).map_err(|err| Error::Failure(err.into()))
+        })
+        .await?;
+        Ok(recorded.into_inner())
+    }
+
+    /// Force deletes session pods stuck terminating on a lost node, so that their `StatefulSet` recreates them elsewhere.
+    /// Pods whose node is still ready are left alone; without access to nodes (restricted mode) pods stuck long enough
+    /// are assumed lost. Returns the affected sessions.
+    pub async fn release_lost_pods(&self) -> Result<Vec<String>> {
+        let client = new_client().await?;
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let now = self.clock.now();
+        let pods: Vec<Pod> = pod_api
+            .list(
+                &ListParams::default().labels(&format!("{}={}", COMPONENT_LABEL, COMPONENT_VALUE)),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .items
+            .into_iter()
+            .filter(|pod| owning_stateful_set(pod).is_some() && is_stuck_terminating(pod, now))
+            .collect();
+        if pods.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ready_nodes: Option<BTreeSet<String>> = if self.restricted {
+            None
+        } else {
+            let node_api: Api<Node> = Api::all(client);
+            Some(
+                node_api
+                    .list(&ListParams::default())
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?
+                    .items
+                    .into_iter()
+                    .filter(|node| {
+                        node.status
+                            .as_ref()
+                            .and_then(|status| status.conditions.as_ref())
+                            .map_or(false, |conditions| {
+                                conditions.iter().any(|condition| {
+                                    condition.type_ == "Ready" && condition.status == "True"
+                                })
+                            })
+                    })
+                    .filter_map(|node| node.metadata.name)
+                    .collect(),
+            )
+        };
+
+        let mut released = Vec::new();
+        for pod in pods {
+            let node = pod.spec.as_ref().and_then(|spec| spec.node_name.as_ref());
+            if let (Some(ready_nodes), Some(node)) = (&ready_nodes, node) {
+                if ready_nodes.contains(node) {
+                    continue;
+                }
+            }
+            let (name, owner) = match (
+                pod.metadata.name.as_ref(),
+                pod.metadata
+                    .labels
+                    .as_ref()
+                    .and_then(|labels| labels.get(OWNER_LABEL)),
+            ) {
+                (Some(name), Some(owner)) => (name, owner),
+                _ => continue,
+            };
+            pod_api
+                .delete(
+                    name,
+                    &DeleteParams {
+                        grace_period_seconds: Some(0),
+                        ..DeleteParams::default()
+                    },
+                )
+                .await
+                .map_err(|err| Error::Failure(err.into()))?;
+            released.push(owner.clone());
+        }
+        Ok(released)
+    }
+
+    /// Removes any trace of the node session `id` ran on
+    pub async fn forget_last_node(&self, id: &str) -> Result<()> {
+        self.update_state(LAST_NODES_STATE, |value| {
+            let mut nodes = parse_last_nodes(value);
+            nodes.remove(id);
+            serde_json::to_string(&nodes).map_err(|err| Error::Failure(err.into()))
+        })
+        .await
+    }
+
+    async fn record_last_node(&self, id: &str, node_name: &str) -> Result<()> {
+        let hostname = if self.restricted {
+            // Node names usually match their hostname. Pods that were never scheduled are ignored.
+            match self
+                .static_pools
+                .values()
+                .flat_map(|pool| &pool.nodes)
+                .find(|hostname| *hostname == node_name)
+            {
+                Some(hostname) => hostname.clone(),
+                None => return Ok(()),
+            }
+        } else {
+            let node_api: Api<Node> = Api::all(new_client().await?);
+            // Ignore pods that were never scheduled
+            match node_api.get(node_name).await.ok().and_then(|node| {
+                node.metadata
+                    .labels
+                    .and_then(|mut labels| labels.remove(HOSTNAME_LABEL))
+            }) {
+                Some(hostname) => hostname,
+                None => return Ok(()),
+            }
+        };
+        let now = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        self.update_state(LAST_NODES_STATE, |value| {
+            let mut nodes = parse_last_nodes(value);
+            nodes.insert(
+                id.to_string(),
+                LastNode {
+                    hostname: hostname.clone(),
+                    recorded_at: now,
+                },
+            );
+            prune_last_nodes(&mut nodes, now);
+            serde_json::to_string(&nodes).map_err(|err| Error::Failure(err.into()))
+        })
+        .await
+    }
+
+    /// Removes ingress rules routing to session `id`
+    async fn remove_ingress_rules(&self, id: &str) -> Result<()> {
+        let client = new_client().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
+        let mut ingress: Ingress = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .clone();
+        let mut spec = ingress
+            .clone()
+            .spec
+            .ok_or(Error::MissingData("spec"))?
+            .clone();
+        // The session host is not known anymore, match rules by the service they route to
+        let (removed, rules): (Vec<IngressRule>, Vec<IngressRule>) = spec
+            .clone()
+            .rules
+            .unwrap()
+            .into_iter()
+            .partition(|rule| rule_session(rule) == Some(id));
+        spec.rules.replace(rules);
+        ingress.spec.replace(spec);
+
+        ingress_api
+            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        let hosts: Vec<String> = removed.into_iter().filter_map(|rule| rule.host).collect();
+        self.delete_dns_records(&hosts).await;
+
+        Ok(())
+    }
+
+    /// Compares the ingress and session services with live sessions, e.g. after the ingress was restored or edited
+    pub async fn detect_drift(&self) -> Result<Drift> {
+        let client = new_client().await?;
+        let params = ListParams::default().labels(OWNER_LABEL);
+        let owner = |metadata: &ObjectMeta| {
+            metadata
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(OWNER_LABEL))
+                .cloned()
+        };
+        // Includes pods being migrated, or that can't be converted to a `Session`
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let owners: BTreeSet<String> = pod_api
+            .list(&params)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .iter()
+            .filter_map(|pod| owner(&pod.metadata))
+            .collect();
+        let sessions = self.list_sessions().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client.clone(), &self.env.namespace);
+        let rules = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?
+            .spec
+            .and_then(|spec| spec.rules)
+            .unwrap_or_default();
+        let hosts: BTreeSet<&str> = rules
+            .iter()
+            .filter_map(|rule| rule.host.as_deref())
+            .collect();
+        let service_api: Api<Service> = Api::namespaced(client, &self.env.namespace);
+        let services = service_api
+            .list(&params)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        Ok(Drift {
+            // Sessions pending deletion are not routed
+            missing_rules: sessions
+                .values()
+                .filter(|session| session.deleted_at.is_none())
+                .filter(|session| !hosts.contains(session.url.as_str()))
+                .map(|session| session.user_id.clone())
+                .collect(),
+            stale_rules: rules
+                .iter()
+                .filter(|rule| rule_session(rule).map_or(false, |id| !owners.contains(id)))
+                .filter_map(|rule| rule.host.clone())
+                .collect(),
+            orphaned_services: services
+                .iter()
+                .filter(|service| {
+                    owner(&service.metadata).map_or(false, |id| !owners.contains(&id))
+                })
+                .filter_map(|service| service.metadata.name.clone())
+                .collect(),
+        })
+    }
+
+    /// Adds missing ingress rules and removes stale rules and services listed in `drift`.
+    /// Ports added at runtime to sessions missing a rule are not restored.
+    pub async fn repair_drift(&self, drift: &Drift) -> Result<()> {
+        if !drift.missing_rules.is_empty() {
+            let sessions = self.list_sessions().await?;
+            let missing = sessions
+                .iter()
+                .filter(|(id, _)| drift.missing_rules.contains(*id))
+                .map(|(id, session)| (id.clone(), (&session.template, session.url.as_str())))
+                .collect();
+            self.patch_ingress(&missing).await?;
+        }
+        if !drift.stale_rules.is_empty() {
+            self.update_ingress_rules(|rules| {
+                rules.retain(|rule| {
+                    rule.host
+                        .as_ref()
+                        .map_or(true, |host| !drift.stale_rules.contains(host))
+                })
+            })
+            .await?;
+            let hosts: Vec<String> = drift.stale_rules.iter().cloned().collect();
+            self.delete_dns_records(&hosts).await;
+        }
+        if !drift.orphaned_services.is_empty() {
+            let client = new_client().await?;
+            let service_api: Api<Service> = Api::namespaced(client, &self.env.namespace);
+            for name in &drift.orphaned_services {
+                match service_api.delete(name, &DeleteParams::default()).await {
+                    Ok(_) => {}
+                    Err(kube::Error::Api(err)) if err.code == 404 => {}
+                    Err(err) => return Err(Error::Failure(err.into())),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `f` to all ingress rules
+    async fn update_ingress_rules<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<IngressRule>),
+    {
+        let client = new_client().await?;
+        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
+        let mut ingress = ingress_api
+            .get(INGRESS_NAME)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        if let Some(rules) = ingress.spec.as_mut().and_then(|spec| spec.rules.as_mut()) {
+            f(rules);
+        }
+
+        ingress_api
+            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        Ok(())
+    }
+
+    /// Moves session `id` to pool `pool_id` without losing its workspace.
+    ///
+    /// A new pod is created on the target pool, the workspace is streamed from the current pod and the service is then switched to the new pod.
+    /// Progress is reported via `Session::migration`.
+    pub async fn migrate_session(&self, id: &str, pool_id: &str) -> Result<()> {
+        let client = new_client().await?;
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let stateful_set_api: Api<StatefulSet> =
+            Api::namespaced(client.clone(), &self.env.namespace);
+        let source = get_session_pod(&pod_api, id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let source_name = source
+            .metadata
+            .name
+            .clone()
+            .ok_or(Error::MissingData("pod#metadata#name"))?;
+        let session = self.clone().pod_to_session(&self.env, &source)?;
+        if session.pod.phase != Phase::Running {
+            return Err(Error::Forbidden("session is not running".to_string()));
+        }
+        traced("kubernetes.get_pool", self.get_pool(pool_id))
+            .await?
+            .ok_or(Error::MissingData("no matching pool"))?;
+
+        // The new pod inherits what is left of the session duration
+        let duration = reaper::remaining(&*self.clock, session.pod.start_time, session.duration);
+        let target_name = format!("{}-{}", pod_name(id), random_token(5).to_lowercase());
+        let mut target = create_pod(
+            &session.domain,
+            &session.url,
+            id,
+            &target_name,
+            &session.template,
+            &duration,
+            pool_id,
+            self.pool_requirements(pool_id),
+            None,
+        )?;
+        // Weighed as for the owner of the session when it was created
+        let defaults = self
+            .configuration
+            .session
+            .for_role(session.role.as_deref().unwrap_or_default());
+        self.apply_scheduling_strategy(&mut target, pool_id, defaults.max_sessions_per_pod)
+            .await?;
+        if let Some(labels) = target.metadata.labels.as_mut() {
+            labels.insert(MIGRATION_LABEL.to_string(), "target".to_string());
+        }
+        // Keeps preempting or being preempted as decided for its owner's role
+        if let (Some(spec), Some(source_spec)) = (target.spec.as_mut(), source.spec.as_ref()) {
+            spec.priority_class_name = source_spec.priority_class_name.clone();
+        }
+        // Carried over from the source pod, e.g. so that the session keeps its url
+        for name in &[
+            SESSION_BACKUP_ANNOTATION,
+            SESSION_SUBDOMAIN_ANNOTATION,
+            SESSION_UNATTENDED_ANNOTATION,
+        ] {
+            if let (Some(value), Some(annotations)) = (
+                source
+                    .metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|annotations| annotations.get(*name)),
+                target.metadata.annotations.as_mut(),
+            ) {
+                annotations.insert(name.to_string(), value.clone());
+            }
+        }
+        if let Some(url) = &self.configuration.telemetry_url {
+            add_env_var(&mut target, create_env_var(TELEMETRY_URL_ENV, url));
+        }
+        if let Some(workshop) = &session.workshop {
+            let sessions = self.list_sessions().await?;
+            join_workshop(
+                &mut target,
+                workshop,
+                &workshop_peers(&sessions, workshop, id),
+            );
+        }
+
+        let target = create_stateful_set(id, target)?;
+        let target_pod_name = stateful_set_pod_name(&target_name);
+
+        self.update_migration_progress(&pod_api, &source_name, "scheduling")
+            .await?;
+        let result = self
+            .clone()
+            .migrate_to(
+                &pod_api,
+                &stateful_set_api,
+                &source_name,
+                &target_pod_name,
+                &target,
+            )
+            .await;
+        if let Err(err) = &result {
+            error!("Failed to migrate {}: {}", id, err);
+            // Leave the session untouched and clean up the new pod
+            if let Err(err) = stateful_set_api
+                .delete(&target_name, &DeleteParams::default())
+                .await
+            {
+                error!("Failed to delete {}: {}", target_name, err);
+            }
+            self.update_migration_progress(&pod_api, &source_name, &format!("failed: {}", err))
+                .await?;
+            return result;
+        }
+
+        // Switch traffic to the new pod, then get rid of the old one
+        let service_api: Api<Service> = Api::namespaced(client.clone(), &self.env.namespace);
+        pod_api
+            .patch(
+                &source_name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "metadata": { "labels": { MIGRATION_LABEL: "source" } } })),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let target_pod = pod_api
+            .get(&target_pod_name)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        patch_pod_metadata(
+            client,
+            &self.env.namespace,
+            &target_pod,
+            json!({ "labels": { MIGRATION_LABEL: null } }),
+        )
+        .await?;
+        service_api
+            .patch(
+                &service_name(id),
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "spec": { "selector": { POD_LABEL: target_name } } })),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        match owning_stateful_set(&source) {
+            Some(name) => stateful_set_api
+                .delete(&name, &DeleteParams::default())
+                .await
+                .map(|_| ()),
+            None => pod_api
+                .delete(&source_name, &DeleteParams::default())
+                .await
+                .map(|_| ()),
+        }
+        .map_err(|err| Error::Failure(err.into()))?;
+
+        Ok(())
+    }
+
+    async fn migrate_to(
+        self,
+        pod_api: &Api<Pod>,
+        stateful_set_api: &Api<StatefulSet>,
+        source_name: &str,
+        target_name: &str,
+        target: &StatefulSet,
+    ) -> Result<()> {
+        traced(
+            "kubernetes.create_stateful_set",
+            stateful_set_api.create(&PostParams::default(), target),
+        )
+        .await
+        .map_err(|err| Error::Failure(err.into()))?;
+        wait_for_running(pod_api, target_name).await?;
+
+        self.update_migration_progress(pod_api, source_name, "copying")
+            .await?;
+        let mut archive = pod_api
+            .exec(
+                source_name,
+                vec!["tar", "cf", "-", "-C", WORKSPACE_PATH, "."],
+                &AttachParams::default().stderr(false),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let mut extract = pod_api
+            .exec(
+                target_name,
+                vec!["tar", "xf", "-", "-C", WORKSPACE_PATH],
+                &AttachParams::default()
+                    .stdin(true)
+                    .stdout(false)
+                    .stderr(false),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let mut stdout = archive.stdout().ok_or(Error::MissingData("exec#stdout"))?;
+        let mut stdin = extract.stdin().ok_or(Error::MissingData("exec#stdin"))?;
+        tokio::io::copy(&mut stdout, &mut stdin)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        stdin
+            .shutdown()
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        drop(stdin);
+        extract
+            .join()
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        self.update_migration_progress(pod_api, source_name, "switching")
+            .await
+    }
+
+    async fn update_migration_progress(
+        &self,
+        pod_api: &Api<Pod>,
+        name: &str,
+        progress: &str,
+    ) -> Result<()> {
+        pod_api
+            .patch(
+                name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({
+                    "metadata": { "annotations": { SESSION_MIGRATION_ANNOTATION: progress } }
+                })),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(())
+    }
+
+    /// Acquires or renews the `Lease` named `name` on behalf of `identity`. Returns true if `identity` holds the lease.
+    ///
+    /// A lease not renewed within `duration` can be taken over. Concurrent updates are rejected thanks to `resourceVersion`.
+    pub async fn acquire_lease(
+        &self,
+        name: &str,
+        identity: &str,
+        duration: Duration,
+    ) -> Result<bool> {
+        let client = new_client().await?;
+        let lease_api: Api<Lease> = Api::namespaced(client, &self.env.namespace);
+        let now = k8s_openapi::chrono::Utc::now();
+        let spec = LeaseSpec {
+            holder_identity: Some(identity.to_string()),
+            lease_duration_seconds: Some(duration.as_secs() as i32),
+            renew_time: Some(MicroTime(now)),
+            ..Default::default()
         };
+        match lease_api.get(name).await {
+            Ok(lease) => {
+                let current = lease.spec.clone().unwrap_or_default();
+                let held_by_other = current.holder_identity.as_deref() != Some(identity);
+                let expired = match (current.renew_time, current.lease_duration_seconds) {
+                    (Some(MicroTime(renew_time)), Some(seconds)) => {
+                        renew_time + k8s_openapi::chrono::Duration::seconds(seconds.into()) < now
+                    }
+                    _ => true,
+                };
+                if held_by_other && !expired {
+                    return Ok(false);
+                }
+                let spec = LeaseSpec {
+                    acquire_time: if held_by_other {
+                        Some(MicroTime(now))
+                    } else {
+                        current.acquire_time
+                    },
+                    lease_transitions: if held_by_other {
+                        Some(current.lease_transitions.unwrap_or(0) + 1)
+                    } else {
+                        current.lease_transitions
+                    },
+                    ..spec
+                };
+                // Fails if the lease was updated in the meantime
+                match lease_api
+                    .replace(
+                        name,
+                        &PostParams::default(),
+                        &Lease {
+                            metadata: lease.metadata,
+                            spec: Some(spec),
+                        },
+                    )
+                    .await
+                {
+                    Ok(_) => Ok(true),
+                    Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+                    Err(err) => Err(Error::Failure(err.into())),
+                }
+            }
+            Err(kube::Error::Api(err)) if err.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(name.to_string()),
+                        ..Default::default()
+                    },
+                    spec: Some(LeaseSpec {
+                        acquire_time: Some(MicroTime(now)),
+                        ..spec
+                    }),
+                };
+                match lease_api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+                    Err(err) => Err(Error::Failure(err.into())),
+                }
+            }
+            Err(err) => Err(Error::Failure(err.into())),
+        }
+    }
 
-        let host = if let Ok(ingress) = ingress_api.get(INGRESS_NAME).await {
-            ingress
-                .spec
-                .ok_or(Error::MissingData("spec"))?
-                .rules
-                .ok_or(Error::MissingData("spec#rules"))?
-                .first()
-                .ok_or(Error::MissingData("spec#rules[0]"))?
-                .host
-                .as_ref()
-                .ok_or(Error::MissingData("spec#rules[0]#host"))?
-                .clone()
-        } else {
-            "localhost".to_string()
+    /// Deletes the `Lease` named `name` if still held by `identity`, see `acquire_lease`
+    pub async fn release_lease(&self, name: &str, identity: &str) -> Result<()> {
+        let client = new_client().await?;
+        let lease_api: Api<Lease> = Api::namespaced(client, &self.env.namespace);
+        let lease = match lease_api.get(name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(err)) if err.code == 404 => return Ok(()),
+            Err(err) => return Err(Error::Failure(err.into())),
         };
+        if lease.spec.and_then(|spec| spec.holder_identity).as_deref() != Some(identity) {
+            return Ok(());
+        }
+        let params = DeleteParams {
+            // Fails if the lease was taken over in the meantime
+            preconditions: Some(Preconditions {
+                resource_version: lease.metadata.resource_version,
+                uid: None,
+            }),
+            ..DeleteParams::default()
+        };
+        match lease_api.delete(name, &params).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(err)) if err.code == 404 || err.code == 409 => Ok(()),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
+    }
 
-        // Retrieve 'static' configuration from Env variables
-        let github_client_id =
-            env::var("GITHUB_CLIENT_ID").map_err(|_| Error::MissingData("GITHUB_CLIENT_ID"))?;
-        let github_client_secret =
-            env::var("GITHUB_CLIENT_SECRET").map_err(|_| Error::MissingData("GITHUB_CLIENT_ID"))?;
-        let session_default_duration = env::var("SESSION_DEFAULT_DURATION")
-            .map_err(|_| Error::MissingData("SESSION_DEFAULT_DURATION"))?;
-        let session_max_duration = env::var("SESSION_MAX_DURATION")
-            .map_err(|_| Error::MissingData("SESSION_MAX_DURATION"))?;
-        let session_default_pool_affinity = env::var("SESSION_DEFAULT_POOL_AFFINITY")
-            .map_err(|_| Error::MissingData("SESSION_DEFAULT_POOL_AFFINITY"))?;
-        let session_default_max_per_node = env::var("SESSION_DEFAULT_MAX_PER_NODE")
-            .map_err(|_| Error::MissingData("SESSION_DEFAULT_MAX_PER_NODE"))?;
-
-        Ok(Engine {
-            env: Environment {
-                secured,
-                host,
-                namespace: namespace.clone(),
-            },
-            configuration: Configuration {
-                github_client_id,
-                session: SessionDefaults {
-                    duration: str_to_session_duration_minutes(&session_default_duration)?,
-                    max_duration: str_to_session_duration_minutes(&session_max_duration)?,
-                    pool_affinity: session_default_pool_affinity,
-                    max_sessions_per_pod: session_default_max_per_node
-                        .parse()
-                        .map_err(|err: ParseIntError| Error::Failure(err.into()))?,
-                },
-            },
-            secrets: Secrets {
-                github_client_secret,
+    /// Stores `value` as record `id` of `kind`, replacing it if it exists. Records are kept in a `Secret` each, shared by
+    /// all replicas. `kind` and `id` must be valid in names.
+    pub async fn save_record(&self, kind: &str, id: &str, value: &str) -> Result<()> {
+        let client = new_client().await?;
+        let secret_api: Api<Secret> = Api::namespaced(client, &self.env.namespace);
+        let name = record_secret_name(kind, id);
+        let secret = Secret {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                labels: Some(BTreeMap::from([(
+                    RECORD_LABEL.to_string(),
+                    kind.to_string(),
+                )])),
+                ..Default::default()
             },
-        })
+            string_data: Some(BTreeMap::from([(
+                RECORD_KEY.to_string(),
+                value.to_string(),
+            )])),
+            ..Default::default()
+        };
+        match secret_api.create(&PostParams::default(), &secret).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(err)) if err.code == 409 => {
+                secret_api
+                    .patch(
+                        &name,
+                        &PatchParams::default(),
+                        &Patch::Merge(json!({ "stringData": { RECORD_KEY: value } })),
+                    )
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+                Ok(())
+            }
+            Err(err) => Err(Error::Failure(err.into())),
+        }
     }
 
-    // Creates a Session from a Pod annotations
-    fn pod_to_session(self, env: &Environment, pod: &Pod) -> Result<Session> {
-        let labels = pod
-            .metadata
-            .labels
-            .clone()
-            .ok_or(Error::MissingData("pod#metadata#labels"))?;
-        let unknown = "UNKNOWN OWNER".to_string();
-        let username = labels.get(OWNER_LABEL).unwrap_or(&unknown);
-        let annotations = &pod
-            .metadata
-            .annotations
-            .clone()
-            .ok_or(Error::MissingData("pod#metadata#annotations"))?;
-        let template = serde_yaml::from_str(
-            annotations
-                .get(TEMPLATE_ANNOTATION)
-                .ok_or(Error::MissingData("template"))?,
-        )
-        .map_err(|err| Error::Failure(err.into()))?;
-        let duration = str_to_session_duration_minutes(
-            annotations
-                .get(SESSION_DURATION_ANNOTATION)
-                .ok_or(Error::MissingData("template#session_duration"))?,
-        )?;
+    /// Returns record `id` of `kind`, if any
+    pub async fn get_record(&self, kind: &str, id: &str) -> Result<Option<String>> {
+        let client = new_client().await?;
+        let secret_api: Api<Secret> = Api::namespaced(client, &self.env.namespace);
+        match secret_api.get(&record_secret_name(kind, id)).await {
+            Ok(secret) => Ok(record_value(&secret)),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(None),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
+    }
 
-        Ok(Session {
-            user_id: username.clone(),
-            template,
-            url: subdomain(&env.host, username),
-            pod: Self::pod_to_details(self, &pod.clone())?,
-            duration,
-            node: pod
-                .clone()
-                .spec
-                .ok_or(Error::MissingData("pod#spec"))?
-                .node_name
-                .unwrap_or_else(|| "<Unknown>".to_string()),
-        })
+    /// Deletes record `id` of `kind`, returning it. A record can only be taken once, even by concurrent replicas.
+    pub async fn take_record(&self, kind: &str, id: &str) -> Result<Option<String>> {
+        let client = new_client().await?;
+        let secret_api: Api<Secret> = Api::namespaced(client, &self.env.namespace);
+        let name = record_secret_name(kind, id);
+        let secret = match secret_api.get(&name).await {
+            Ok(secret) => secret,
+            Err(kube::Error::Api(err)) if err.code == 404 => return Ok(None),
+            Err(err) => return Err(Error::Failure(err.into())),
+        };
+        let params = DeleteParams {
+            preconditions: Some(Preconditions {
+                resource_version: secret.metadata.resource_version.clone(),
+                uid: secret.metadata.uid.clone(),
+            }),
+            ..DeleteParams::default()
+        };
+        match secret_api.delete(&name, &params).await {
+            Ok(_) => Ok(record_value(&secret)),
+            // Taken or updated in the meantime
+            Err(kube::Error::Api(err)) if err.code == 404 || err.code == 409 => Ok(None),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
     }
 
-    fn nodes_to_pool(self, id: String, nodes: Vec<Node>) -> Result<Pool> {
-        let node = nodes
-            .first()
-            .ok_or(Error::MissingData("empty vec of nodes"))?;
-        let labels = node
-            .metadata
-            .labels
-            .clone()
-            .ok_or(Error::MissingData("metadata#labels"))?;
-        let local = "local".to_string();
-        let unknown = "unknown".to_string();
-        let instance_type = labels.get(INSTANCE_TYPE_LABEL).unwrap_or(&local);
+    pub async fn delete_record(&self, kind: &str, id: &str) -> Result<()> {
+        let client = new_client().await?;
+        let secret_api: Api<Secret> = Api::namespaced(client, &self.env.namespace);
+        match secret_api
+            .delete(&record_secret_name(kind, id), &DeleteParams::default())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
+    }
 
-        Ok(Pool {
-            name: id,
-            instance_type: Some(instance_type.clone()),
-            nodes: nodes
-                .iter()
-                .map(|node| crate::types::Node {
-                    hostname: node
+    /// Lists records of `kind`, by id
+    pub async fn list_records(&self, kind: &str) -> Result<BTreeMap<String, String>> {
+        let client = new_client().await?;
+        let secret_api: Api<Secret> = Api::namespaced(client, &self.env.namespace);
+        let prefix = record_secret_name(kind, "");
+        Ok(
+            list_by_selector(&secret_api, format!("{}={}", RECORD_LABEL, kind))
+                .await?
+                .into_iter()
+                .filter_map(|secret| {
+                    let id = secret
                         .metadata
-                        .labels
-                        .clone()
-                        .unwrap_or_default()
-                        .get(HOSTNAME_LABEL)
-                        .unwrap_or(&unknown)
-                        .clone(),
+                        .name
+                        .as_ref()?
+                        .strip_prefix(&prefix)?
+                        .to_string();
+                    Some((id, record_value(&secret)?))
                 })
                 .collect(),
-        })
+        )
     }
 
-    fn container_status_to_container_status(
-        self,
-        status: &ContainerStatus,
-    ) -> types::ContainerStatus {
-        let state = status.state.as_ref();
-        types::ContainerStatus {
-            phase: state
-                .map(|s| {
-                    if s.running.is_some() {
-                        ContainerPhase::Running
-                    } else if s.waiting.is_some() {
-                        ContainerPhase::Waiting
-                    } else {
-                        ContainerPhase::Terminated
-                    }
-                })
-                .unwrap_or(ContainerPhase::Unknown),
-            reason: state.and_then(|s| {
-                s.waiting
-                    .as_ref()
-                    .and_then(|s| s.reason.clone())
-                    .or_else(|| s.terminated.as_ref().and_then(|s| s.reason.clone()))
-            }),
-            message: state.and_then(|s| {
-                s.waiting
-                    .as_ref()
-                    .and_then(|s| s.message.clone())
-                    .or_else(|| s.terminated.as_ref().and_then(|s| s.message.clone()))
-            }),
+    /// Keeps `value` for idempotency key `id` unless a value is kept already, see `idempotency`. Returns the kept
+    /// value and its version otherwise.
+    pub async fn claim_idempotency_key(
+        &self,
+        id: &str,
+        value: &str,
+    ) -> Result<Option<(String, String)>> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let name = idempotency_config_map_name(id);
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                labels: Some(
+                    vec![(IDEMPOTENCY_LABEL.to_string(), "true".to_string())]
+                        .into_iter()
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            data: Some(
+                vec![(IDEMPOTENCY_KEY.to_string(), value.to_string())]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        // Creation is atomic: a single request can claim a key
+        match config_map_api
+            .create(&PostParams::default(), &config_map)
+            .await
+        {
+            Ok(_) => return Ok(None),
+            Err(kube::Error::Api(err)) if err.code == 409 => {}
+            Err(err) => return Err(Error::Failure(err.into())),
         }
+        let config_map = config_map_api
+            .get(&name)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(Some((
+            config_map
+                .data
+                .and_then(|mut data| data.remove(IDEMPOTENCY_KEY))
+                .unwrap_or_default(),
+            config_map.metadata.resource_version.unwrap_or_default(),
+        )))
     }
 
-    fn pod_to_details(self, pod: &Pod) -> Result<types::Pod> {
-        let status = pod.status.as_ref().ok_or(Error::MissingData("status"))?;
-        let container_statuses = status.clone().container_statuses;
-        let container_status = container_statuses.as_ref().and_then(|v| v.first());
-        Ok(types::Pod {
-            phase: Phase::from_str(
-                &status
-                    .clone()
-                    .phase
-                    .unwrap_or_else(|| "Unknown".to_string()),
-            )
-            .map_err(|err| Error::Failure(err.into()))?,
-            reason: status.clone().reason.unwrap_or_else(|| "".to_string()),
-            message: status.clone().message.unwrap_or_else(|| "".to_string()),
-            start_time: status.clone().start_time.map(|dt| dt.0.into()),
-            container: container_status.map(|c| self.container_status_to_container_status(c)),
-        })
+    /// Replaces the value kept for idempotency key `id`
+    pub async fn update_idempotency_key(&self, id: &str, value: &str) -> Result<()> {
+        let client = new_client().await?;
+        add_config_map_value(
+            client,
+            &self.env.namespace,
+            &idempotency_config_map_name(id),
+            IDEMPOTENCY_KEY,
+            value,
+        )
+        .await
     }
 
-    fn yaml_to_user(self, s: &str) -> Result<User> {
-        let user_configuration: UserConfiguration =
-            serde_yaml::from_str(s).map_err(|err| Error::Failure(err.into()))?;
-        Ok(User {
-            admin: user_configuration.admin,
-            pool_affinity: user_configuration.pool_affinity,
-            can_customize_duration: user_configuration.can_customize_duration,
-            can_customize_pool_affinity: user_configuration.can_customize_pool_affinity,
-        })
+    /// Forgets idempotency key `id`. If `version` is set, only if it wasn't updated since.
+    pub async fn delete_idempotency_key(&self, id: &str, version: Option<&str>) -> Result<()> {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let params = DeleteParams {
+            preconditions: version.map(|version| Preconditions {
+                resource_version: Some(version.to_string()),
+                uid: None,
+            }),
+            ..DeleteParams::default()
+        };
+        match config_map_api
+            .delete(&idempotency_config_map_name(id), &params)
+            .await
+        {
+            Ok(_) => Ok(()),
+            // Already claimed again by another request
+            Err(kube::Error::Api(err)) if err.code == 404 || err.code == 409 => Ok(()),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
     }
 
-    pub async fn list_templates(self) -> Result<BTreeMap<String, Template>> {
+    /// Forgets idempotency keys claimed before `before`. Returns how many were forgotten.
+    pub async fn purge_idempotency_keys(&self, before: SystemTime) -> Result<usize> {
         let client = new_client().await?;
-
-        Ok(get_templates(client, &self.env.namespace)
-            .await?
-            .into_iter()
-            .filter_map(|(k, v)| {
-                if let Ok(template) = serde_yaml::from_str(&v) {
-                    Some((k, template))
-                } else {
-                    error!("Error while parsing template {}", k);
-                    None
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        let mut purged = 0;
+        for config_map in list_by_selector(&config_map_api, IDEMPOTENCY_LABEL.to_string()).await? {
+            let expired = config_map
+                .metadata
+                .creation_timestamp
+                .map_or(false, |time| SystemTime::from(time.0) < before);
+            if let (true, Some(name)) = (expired, config_map.metadata.name) {
+                match config_map_api.delete(&name, &DeleteParams::default()).await {
+                    Ok(_) => purged += 1,
+                    Err(kube::Error::Api(err)) if err.code == 404 => {}
+                    Err(err) => return Err(Error::Failure(err.into())),
                 }
-            })
-            .collect::<BTreeMap<String, Template>>())
+            }
+        }
+        Ok(purged)
     }
 
-    pub async fn get_user(&self, id: &str) -> Result<Option<User>> {
-        let client = new_client().await?;
-
-        let users = list_users(client, &self.env.namespace).await?;
-        let user = users.get(id);
+    /// Stores `value` under `key` in the backend state ConfigMap, creating it if needed
+    pub async fn save_state(&self, key: &str, value: String) -> Result<()> {
+        self.update_state(key, |_| Ok(value.clone())).await
+    }
 
-        match user.map(|user| self.clone().yaml_to_user(user)) {
-            Some(user) => user.map(Some),
-            None => Ok(None),
+    /// Replaces the value stored under `key` in the backend state ConfigMap with the one returned by `update`, given
+    /// the current value. Concurrent updates are detected via `resourceVersion`, `update` is then called again with
+    /// the new current value.
+    pub async fn update_state<F>(&self, key: &str, update: F) -> Result<()>
+    where
+        F: Fn(Option<&str>) -> Result<String>,
+    {
+        let client = new_client().await?;
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        for _ in 0..STATE_UPDATE_ATTEMPTS {
+            let result = match config_map_api.get(STATE_CONFIG_MAP).await {
+                Ok(mut config_map) => {
+                    let data = config_map.data.get_or_insert_with(BTreeMap::new);
+                    let value = update(data.get(key).map(String::as_str))?;
+                    data.insert(key.to_string(), value);
+                    // Carries the read `resourceVersion`, rejected if the ConfigMap changed since
+                    config_map_api
+                        .replace(STATE_CONFIG_MAP, &PostParams::default(), &config_map)
+                        .await
+                }
+                Err(kube::Error::Api(err)) if err.code == 404 => {
+                    let config_map = ConfigMap {
+                        metadata: ObjectMeta {
+                            name: Some(STATE_CONFIG_MAP.to_string()),
+                            ..Default::default()
+                        },
+                        data: Some(BTreeMap::from([(key.to_string(), update(None)?)])),
+                        ..Default::default()
+                    };
+                    config_map_api
+                        .create(&PostParams::default(), &config_map)
+                        .await
+                }
+                Err(err) => return Err(Error::Failure(err.into())),
+            };
+            match result {
+                Ok(_) => return Ok(()),
+                Err(kube::Error::Api(err)) if err.code == 409 => continue,
+                Err(err) => return Err(Error::Failure(err.into())),
+            }
         }
+        Err(Error::Failure(
+            format!("Too many concurrent updates of {}", key).into(),
+        ))
     }
 
-    pub async fn list_users(&self) -> Result<BTreeMap<String, User>> {
+    /// Returns the value stored under `key` in the backend state ConfigMap, if any
+    pub async fn load_state(&self, key: &str) -> Result<Option<String>> {
         let client = new_client().await?;
-
-        Ok(list_users(client, &self.env.namespace)
-            .await?
-            .into_iter()
-            .map(|(k, v)| Ok((k, self.clone().yaml_to_user(&v)?)))
-            .collect::<Result<BTreeMap<String, User>>>()?)
+        let config_map_api: Api<ConfigMap> = Api::namespaced(client, &self.env.namespace);
+        match config_map_api.get(STATE_CONFIG_MAP).await {
+            Ok(config_map) => Ok(config_map.data.and_then(|mut data| data.remove(key))),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(None),
+            Err(err) => Err(Error::Failure(err.into())),
+        }
     }
 
-    pub async fn create_user(&self, id: String, conf: UserConfiguration) -> Result<()> {
+    /// Merges `metadata` into the pod of session `id`, see `patch_pod_metadata`
+    async fn patch_session_metadata(&self, id: &str, metadata: serde_json::Value) -> Result<()> {
         let client = new_client().await?;
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        let pod = match get_session_pod(&pod_api, id).await? {
+            Some(pod) => pod,
+            None => self
+                .get_suspended_pod(id)
+                .await?
+                .ok_or(Error::MissingData("no matching session"))?,
+        };
+        patch_pod_metadata(client, &self.env.namespace, &pod, metadata).await
+    }
 
-        add_config_map_value(
-            client,
-            &self.env.namespace,
-            USERS_CONFIG_MAP,
-            id.as_str(),
-            serde_yaml::to_string(&conf)
-                .map_err(|err| Error::Failure(err.into()))?
-                .as_str(),
+    /// Replaces the policy flags of session `id`
+    pub async fn update_session_flags(&self, id: &str, flags: &[String]) -> Result<()> {
+        self.patch_session_metadata(
+            id,
+            json!({ "annotations": { SESSION_FLAGS_ANNOTATION: flags.join(",") } }),
         )
-        .await?;
+        .await
+    }
 
+    /// Records `activity` (in seconds since epoch) as the last activity of session `id`.
+    /// Only kept by the current pod, recreated pods are considered active since they started.
+    pub async fn update_session_activity(&self, id: &str, activity: u64) -> Result<()> {
+        let client = new_client().await?;
+        let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
+        let patch: Patch<json_patch::Patch> =
+            Patch::Json(json_patch::Patch(vec![PatchOperation::Add(AddOperation {
+                path: format!(
+                    "/metadata/annotations/{}",
+                    json_pointer_token(SESSION_ACTIVITY_ANNOTATION)
+                ),
+                value: json!(activity.to_string()),
+            })]));
+        pod_api
+            .patch(
+                &get_session_pod_name(&pod_api, id).await?,
+                &PatchParams::default(),
+                &patch,
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
         Ok(())
     }
 
-    pub async fn update_user(&self, id: String, conf: UserUpdateConfiguration) -> Result<()> {
-        let client = new_client().await?;
-
-        add_config_map_value(
-            client,
-            &self.env.namespace,
-            USERS_CONFIG_MAP,
-            id.as_str(),
-            serde_yaml::to_string(&conf)
-                .map_err(|err| Error::Failure(err.into()))?
-                .as_str(),
+    /// Marks session `id` as deleted at `deleted_at` (in seconds since epoch) and stops routing traffic to it.
+    /// The session is kept until restored via `restore_session` or deleted.
+    pub async fn soft_delete_session(&self, id: &str, deleted_at: u64) -> Result<()> {
+        self.patch_session_metadata(
+            id,
+            json!({ "annotations": { SESSION_DELETED_ANNOTATION: deleted_at.to_string() } }),
         )
         .await?;
 
-        Ok(())
+        self.remove_ingress_rules(id).await
     }
 
-    pub async fn delete_user(&self, id: String) -> Result<()> {
-        let client = new_client().await?;
-        delete_config_map_value(client, &self.env.namespace, USERS_CONFIG_MAP, id.as_str()).await
+    /// Routes traffic to soft deleted session `id` again
+    pub async fn restore_session(&self, id: &str) -> Result<()> {
+        let session = self
+            .get_session(id)
+            .await?
+            .ok_or(Error::MissingData("no matching session"))?;
+        self.patch_session_metadata(
+            id,
+            json!({ "annotations": { SESSION_DELETED_ANNOTATION: null } }),
+        )
+        .await?;
+
+        let mut sessions = BTreeMap::new();
+        sessions.insert(id.to_string(), (&session.template, session.url.as_str()));
+        self.patch_ingress(&sessions).await
     }
 
-    pub async fn get_session(&self, id: &str) -> Result<Option<Session>> {
+    /// Lists events about the pods and service of session `id`, oldest first
+    pub async fn list_session_events(&self, id: &str) -> Result<Vec<SessionEvent>> {
         let client = new_client().await?;
-        let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
-        let pod = pod_api.get(&pod_name(id)).await.ok();
+        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
+        // Includes pods being migrated. Events of pods deleted since then are still kept for a while.
+        // Those of the `StatefulSet`, named after the session, report pods failing to be created.
+        let mut names: BTreeSet<String> = list_by_selector(
+            &pod_api,
+            format!(
+                "{}={},{}={}",
+                COMPONENT_LABEL, COMPONENT_VALUE, OWNER_LABEL, id
+            ),
+        )
+        .await?
+        .into_iter()
+        .filter_map(|pod| pod.metadata.name)
+        .collect();
+        names.insert(pod_name(id));
+        names.insert(service_name(id));
 
-        match pod.map(|pod| self.clone().pod_to_session(&self.env, &pod)) {
-            Some(session) => session.map(Some),
-            None => Ok(None),
+        let event_api: Api<Event> = Api::namespaced(client, &self.env.namespace);
+        let mut events = Vec::new();
+        for name in names {
+            let params = ListParams {
+                field_selector: Some(format!("involvedObject.name={}", name)),
+                ..ListParams::default()
+            };
+            let items = event_api
+                .list(&params)
+                .await
+                .map_err(|err| Error::Failure(err.into()))?
+                .items;
+            events.extend(items.into_iter().map(|event| {
+                let first_seen: Option<SystemTime> = event
+                    .first_timestamp
+                    .map(|time| time.0.into())
+                    .or_else(|| event.event_time.map(|time| time.0.into()));
+                SessionEvent {
+                    type_: event.type_.unwrap_or_else(|| "Normal".to_string()),
+                    reason: event.reason.unwrap_or_default(),
+                    message: event.message,
+                    object: format!(
+                        "{}/{}",
+                        event.involved_object.kind.unwrap_or_default(),
+                        name
+                    ),
+                    count: event.count.unwrap_or(1).max(0) as u32,
+                    first_seen,
+                    last_seen: event
+                        .last_timestamp
+                        .map(|time| time.0.into())
+                        .or(first_seen),
+                }
+            }));
         }
+        events.sort_by_key(|event| event.last_seen);
+        Ok(events)
     }
 
-    /// Lists all currently running sessions
-    pub async fn list_sessions(&self) -> Result<BTreeMap<String, Session>> {
+    /// Executes `command` in session `id` and returns its standard output
+    pub async fn exec_session(&self, id: &str, command: Vec<&str>) -> Result<String> {
         let client = new_client().await?;
         let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
-        let pods = list_by_selector(
-            &pod_api,
-            format!("{}={}", COMPONENT_LABEL, COMPONENT_VALUE).to_string(),
-        )
-        .await?;
+        let mut process = pod_api
+            .exec(
+                &get_session_pod_name(&pod_api, id).await?,
+                command,
+                &AttachParams::default().stderr(false),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        let mut output = String::new();
+        process
+            .stdout()
+            .ok_or(Error::MissingData("exec#stdout"))?
+            .read_to_string(&mut output)
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+        Ok(output)
+    }
 
-        Ok(pods
-            .iter()
-            .flat_map(|pod| self.clone().pod_to_session(&self.env, pod).ok())
-            .map(|session| (session.clone().user_id, session))
-            .collect::<BTreeMap<String, Session>>())
+    /// Returns the state of the git repository in session `id` workspace, if any
+    pub async fn session_git_state(&self, id: &str) -> Result<Option<GitState>> {
+        let status = self
+            .exec_session(
+                id,
+                vec![
+                    "git",
+                    "-C",
+                    WORKSPACE_PATH,
+                    "status",
+                    "--porcelain=v1",
+                    "--branch",
+                ],
+            )
+            .await?;
+        let mut state = match git::parse_status(&status) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let shortstat = self
+            .exec_session(
+                id,
+                vec![
+                    "git",
+                    "-C",
+                    WORKSPACE_PATH,
+                    "diff",
+                    "--shortstat",
+                    git::diff_base(&status),
+                ],
+            )
+            .await?;
+        git::parse_shortstat(&shortstat, &mut state);
+        Ok(Some(state))
     }
 
-    pub async fn patch_ingress(&self, templates: &BTreeMap<String, &Template>) -> Result<()> {
+    /// Commits all changes of session `id` workspace and pushes them as configured by `backup`
+    pub async fn backup_session(&self, id: &str, backup: &SessionBackup) -> Result<()> {
         let client = new_client().await?;
-        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
-        let mut ingress: Ingress = ingress_api
-            .get(INGRESS_NAME)
+        let secret_api: Api<Secret> = Api::namespaced(client, &self.env.namespace);
+        let token = secret_api
+            .get(&backup_secret_name(id))
             .await
-            .map_err(|err| Error::Failure(err.into()))?
-            .clone();
-        let mut spec = ingress
-            .clone()
-            .spec
-            .ok_or(Error::MissingData("ingress#spec"))?
-            .clone();
-        let mut rules: Vec<IngressRule> = spec
-            .clone()
-            .rules
-            .ok_or(Error::MissingData("ingress#spec#rules"))?;
-        for (session_id, template) in templates {
-            let subdomain = subdomain(&self.env.host, session_id);
-            rules.push(IngressRule {
-                host: Some(subdomain.clone()),
-                http: Some(HTTPIngressRuleValue {
-                    paths: create_ingress_paths(service_name(session_id), template),
-                }),
-            });
+            .ok()
+            .and_then(|secret| secret.data)
+            .and_then(|mut data| data.remove(BACKUP_TOKEN_KEY))
+            .and_then(|token| String::from_utf8(token.0).ok());
+        let remote = match token {
+            Some(token) => git::authenticated_remote(&backup.remote, &token),
+            None => backup.remote.clone(),
+        };
+        // Remote and branch are passed as positional parameters, never interpolated
+        let script = "cd \"$0\" \
+            && git add -A \
+            && (git diff --cached --quiet || git -c user.name=playground -c user.email=playground@substrate.io commit -q -m 'Playground backup') \
+            && git push -q \"$1\" \"HEAD:refs/heads/$2\" \
+            && echo done";
+        let output = self
+            .exec_session(
+                id,
+                vec!["sh", "-c", script, WORKSPACE_PATH, &remote, &backup.branch],
+            )
+            .await?;
+        if output.trim() == "done" {
+            Ok(())
+        } else {
+            Err(Error::Failure(
+                format!("Failed to push to {}", backup.remote).into(),
+            ))
         }
-        spec.rules.replace(rules);
-        ingress.spec.replace(spec);
-
-        ingress_api
-            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
-            .await
-            .map_err(|err| Error::Failure(err.into()))?;
-
-        Ok(())
     }
 
-    pub async fn create_session(
+    /// Commits all files of session `id` workspace and pushes them to `branch` of `remote`, using `token` if set.
+    /// Returns the pushed commit.
+    ///
+    /// The session only bundles its workspace, in a fresh repository ignoring its git configuration and hooks. `token`
+    /// never reaches the session: the bundle is pushed by the backend.
+    pub async fn snapshot_session(
         &self,
-        user: &LoggedUser,
-        session_id: &str,
-        conf: SessionConfiguration,
-    ) -> Result<()> {
-        // Make sure some node on the right pools still have rooms
-        // Find pool affinity, lookup corresponding pool and capacity based on nodes, figure out if there is room left
-        // TODO: replace with custom scheduler
-        // * https://kubernetes.io/docs/tasks/extend-kubernetes/configure-multiple-schedulers/
-        // * https://kubernetes.io/blog/2017/03/advanced-scheduling-in-kubernetes/
-        let pool_id = conf.clone().pool_affinity.unwrap_or_else(|| {
-            user.clone()
-                .pool_affinity
-                .unwrap_or(self.clone().configuration.session.pool_affinity)
-        });
-        let pool = self
-            .get_pool(&pool_id)
-            .await?
-            .ok_or(Error::MissingData("no matching pool"))?;
-        let max_sessions_allowed =
-            pool.nodes.len() * self.configuration.session.max_sessions_per_pod;
-        let sessions = self.list_sessions().await?;
-
-        if running_or_pending_sessions(sessions.values().collect()).len() >= max_sessions_allowed {
-            // TODO Should trigger pool dynamic scalability. Right now this will only consider the pool lower bound.
-            // "Reached maximum number of concurrent sessions allowed: {}"
-            return Err(Error::Unauthorized());
-        }
+        id: &str,
+        remote: &str,
+        token: Option<&str>,
+        branch: &str,
+    ) -> Result<String> {
+        let script = format!(
+            "cd \"$0\" \
+            && export GIT_DIR=\"$(mktemp -d)\" GIT_WORK_TREE=\"$0\" GIT_CONFIG_NOSYSTEM=1 HOME=/nonexistent \
+            && git init -q \
+            && git -c core.hooksPath=/dev/null -c core.fsmonitor=false add -A -- . {} \
+            && git -c core.hooksPath=/dev/null -c user.name=playground -c user.email=playground@substrate.io commit -q --allow-empty -m 'Playground snapshot' \
+            && git bundle create - HEAD; \
+            rm -rf \"$GIT_DIR\"",
+            SNAPSHOT_EXCLUDES
+                .iter()
+                .map(|pattern| format!("':(exclude,glob){}'", pattern))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
         let client = new_client().await?;
-        // Access the right image id
-        let templates = self.clone().list_templates().await?;
-        let template = templates
-            .get(&conf.template.to_string())
-            .ok_or(Error::MissingData("no matching template"))?;
-
-        let namespace = &self.env.namespace;
-
-        let pod_api: Api<Pod> = Api::namespaced(client.clone(), namespace);
-
-        //TODO deploy a new ingress matching the route
-        // With the proper mapping
-        // Define the correct route
-        // Also deploy proper tcp mapping configmap https://kubernetes.github.io/ingress-nginx/user-guide/exposing-tcp-udp-services/
-
-        let mut sessions = BTreeMap::new();
-        sessions.insert(session_id.to_string(), template);
-        self.patch_ingress(&sessions).await?;
-
-        let duration = conf.duration.unwrap_or(self.configuration.session.duration);
-
-        // Deploy a new pod for this image
-        pod_api
-            .create(
-                &PostParams::default(),
-                &create_pod(&self.env, session_id, template, &duration, &pool_id)?,
+        let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
+        let mut process = pod_api
+            .exec(
+                &get_session_pod_name(&pod_api, id).await?,
+                vec!["sh", "-c", &script, WORKSPACE_PATH],
+                &AttachParams::default().stderr(false),
             )
             .await
             .map_err(|err| Error::Failure(err.into()))?;
-
-        // Deploy the associated service
-        let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
-        let service = create_service(session_id, template);
-        service_api
-            .create(&PostParams::default(), &service)
+        let bundle = process.stdout().ok_or(Error::MissingData("exec#stdout"))?;
+        let commit = git::push_bundle(remote, token, branch, bundle)
             .await
-            .map_err(|err| Error::Failure(err.into()))?;
-
-        Ok(())
+            .map_err(|err| {
+                Error::Failure(format!("Failed to push to {}: {}", remote, err).into())
+            })?;
+        drop(process);
+        Ok(commit)
     }
 
-    pub async fn update_session(
+    /// Checks out `git_ref` of `remote` in session `id` workspace, then starts `build` in the background. Returns the
+    /// checked out commit.
+    pub async fn checkout_session(
         &self,
-        session_id: &str,
-        conf: SessionUpdateConfiguration,
-    ) -> Result<()> {
-        let session = self
-            .clone()
-            .get_session(session_id)
-            .await?
-            .ok_or(Error::MissingData("no matching session"))?;
-
-        let duration = conf.duration.unwrap_or(self.configuration.session.duration);
-        let max_duration = self.configuration.session.max_duration;
-        if duration >= max_duration {
-            return Err(Error::Unauthorized());
-        }
-        if duration != session.duration {
-            let client = new_client().await?;
-            let pod_api: Api<Pod> = Api::namespaced(client, &self.env.namespace);
-            let params = PatchParams {
-                ..PatchParams::default()
-            };
-            let patch: Patch<json_patch::Patch> =
-                Patch::Json(json_patch::Patch(vec![PatchOperation::Add(AddOperation {
-                    path: format!(
-                        "/metadata/annotations/{}",
-                        SESSION_DURATION_ANNOTATION.replace("/", "~1")
-                    ),
-                    value: json!(session_duration_annotation(duration)),
-                })]));
-            pod_api
-                .patch(&pod_name(&session.user_id), &params, &patch)
-                .await
-                .map_err(|err| Error::Failure(err.into()))?;
+        id: &str,
+        remote: &str,
+        git_ref: &str,
+        build: Option<&str>,
+    ) -> Result<String> {
+        // Parameters are passed as positional parameters, never interpolated. The build outlives the exec call.
+        let script = "cd \"$0\" \
+            && git init -q \
+            && git fetch -q \"$1\" \"$2\" \
+            && git checkout -q --force FETCH_HEAD \
+            && (test -z \"$3\" || nohup sh -c \"$3\" > /tmp/playground-build.log 2>&1 &) \
+            && git rev-parse HEAD";
+        let output = self
+            .exec_session(
+                id,
+                vec![
+                    "sh",
+                    "-c",
+                    script,
+                    WORKSPACE_PATH,
+                    remote,
+                    git_ref,
+                    build.unwrap_or_default(),
+                ],
+            )
+            .await?;
+        let commit = output.trim();
+        if commit.len() == 40 && commit.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(commit.to_string())
+        } else {
+            Err(Error::Failure(
+                format!("Failed to check out {} of {}", git_ref, remote).into(),
+            ))
         }
+    }
+
+    /// Returns the current CPU usage in millicores of all sessions. Requires metrics-server.
+    pub async fn sessions_cpu_usage(&self) -> Result<BTreeMap<String, u64>> {
+        let client = new_client().await?;
+        let resource = ApiResource::from_gvk_with_plural(
+            &GroupVersionKind::gvk("metrics.k8s.io", "v1beta1", "PodMetrics"),
+            "pods",
+        );
+        let api: Api<DynamicObject> = Api::namespaced_with(client, &self.env.namespace, &resource);
+        let metrics =
+            list_by_selector(&api, format!("{}={}", COMPONENT_LABEL, COMPONENT_VALUE)).await?;
+
+        Ok(metrics
+            .iter()
+            .filter_map(|metric| {
+                let owner = metric.metadata.labels.as_ref()?.get(OWNER_LABEL)?.clone();
+                let millicores = metric.data["containers"]
+                    .as_array()?
+                    .iter()
+                    .filter_map(|container| container["usage"]["cpu"].as_str())
+                    .filter_map(cpu_to_millicores)
+                    .sum();
+                Some((owner, millicores))
+            })
+            .collect())
+    }
 
+    /// Creates or updates a `PrometheusRule` named `name`. Requires the prometheus-operator CRDs.
+    pub async fn apply_prometheus_rule(&self, name: &str, spec: serde_json::Value) -> Result<()> {
+        let client = new_client().await?;
+        let resource = ApiResource::from_gvk(&GroupVersionKind::gvk(
+            "monitoring.coreos.com",
+            "v1",
+            "PrometheusRule",
+        ));
+        let api: Api<DynamicObject> = Api::namespaced_with(client, &self.env.namespace, &resource);
+        let rule = DynamicObject::new(name, &resource).data(json!({ "spec": spec }));
+        api.patch(
+            name,
+            &PatchParams::apply(APP_VALUE).force(),
+            &Patch::Apply(&rule),
+        )
+        .await
+        .map_err(|err| Error::Failure(err.into()))?;
         Ok(())
     }
 
-    pub async fn delete_session(&self, id: &str) -> Result<()> {
-        // Undeploy the service by its id
+    /// Creates or removes the `PodDisruptionBudget` covering session pods, as configured by `DisruptionPolicy`
+    pub async fn apply_disruption_budget(&self) -> Result<()> {
         let client = new_client().await?;
-        let service_api: Api<Service> = Api::namespaced(client.clone(), &self.env.namespace);
-        service_api
-            .delete(&service_name(id), &DeleteParams::default())
-            .await
-            .map_err(|err| Error::Failure(err.into()))?;
+        let budget_api: Api<PodDisruptionBudget> = Api::namespaced(client, &self.env.namespace);
+        if self.configuration.disruption_policy == DisruptionPolicy::Evict {
+            return match budget_api
+                .delete(DISRUPTION_BUDGET_NAME, &DeleteParams::default())
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+                Err(err) => Err(Error::Failure(err.into())),
+            };
+        }
 
-        let pod_api: Api<Pod> = Api::namespaced(client.clone(), &self.env.namespace);
-        pod_api
-            .delete(&pod_name(id), &DeleteParams::default())
+        let mut labels = BTreeMap::new();
+        labels.insert(APP_LABEL.to_string(), APP_VALUE.to_string());
+        let budget = PodDisruptionBudget {
+            metadata: ObjectMeta {
+                name: Some(DISRUPTION_BUDGET_NAME.to_string()),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            spec: Some(PodDisruptionBudgetSpec {
+                // Evictions are always refused, session pods are only deleted by the backend
+                max_unavailable: Some(IntOrString::Int(0)),
+                selector: Some(LabelSelector {
+                    match_labels: Some(BTreeMap::from([(
+                        COMPONENT_LABEL.to_string(),
+                        COMPONENT_VALUE.to_string(),
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        budget_api
+            .patch(
+                DISRUPTION_BUDGET_NAME,
+                &PatchParams::apply(APP_VALUE).force(),
+                &Patch::Apply(&budget),
+            )
             .await
             .map_err(|err| Error::Failure(err.into()))?;
+        Ok(())
+    }
 
-        let subdomain = subdomain(&self.env.host, id);
-        let ingress_api: Api<Ingress> = Api::namespaced(client, &self.env.namespace);
-        let mut ingress: Ingress = ingress_api
-            .get(INGRESS_NAME)
+    /// Returns hostnames of nodes marked unschedulable, e.g. while being drained. Nodes can't be read in restricted mode,
+    /// none is then reported.
+    pub async fn list_cordoned_nodes(&self) -> Result<BTreeSet<String>> {
+        if self.restricted {
+            return Ok(BTreeSet::new());
+        }
+        let client = new_client().await?;
+        let node_api: Api<Node> = Api::all(client);
+        Ok(node_api
+            .list(&ListParams::default())
             .await
             .map_err(|err| Error::Failure(err.into()))?
-            .clone();
-        let mut spec = ingress
-            .clone()
-            .spec
-            .ok_or(Error::MissingData("spec"))?
-            .clone();
-        let rules: Vec<IngressRule> = spec
-            .clone()
-            .rules
-            .unwrap()
+            .items
             .into_iter()
-            .filter(|rule| rule.clone().host.unwrap_or_else(|| "unknown".to_string()) != subdomain)
-            .collect();
-        spec.rules.replace(rules);
-        ingress.spec.replace(spec);
-
-        ingress_api
-            .replace(INGRESS_NAME, &PostParams::default(), &ingress)
-            .await
-            .map_err(|err| Error::Failure(err.into()))?;
-
-        Ok(())
+            .filter(|node| {
+                node.spec
+                    .as_ref()
+                    .and_then(|spec| spec.unschedulable)
+                    .unwrap_or(false)
+            })
+            .filter_map(|node| {
+                node.metadata
+                    .labels
+                    .and_then(|mut labels| labels.remove(HOSTNAME_LABEL))
+            })
+            .collect())
     }
 
     pub async fn get_pool(&self, id: &str) -> Result<Option<Pool>> {
+        if self.restricted {
+            return Ok(self
+                .static_pools
+                .get(id)
+                .map(|definition| self.static_pool(id, definition, None)));
+        }
         let client = new_client().await?;
         let node_api: Api<Node> = Api::all(client);
-        let nodes =
-            list_by_selector(&node_api, format!("{}={}", NODE_POOL_LABEL, id).to_string()).await?;
+        let selector = requirements_label_selector(&self.pool_requirements(id));
+        let nodes = list_by_selector(&node_api, selector).await?;
+        if let Some(definition) = self.static_pools.get(id) {
+            return Ok(Some(self.static_pool(id, definition, Some(&nodes))));
+        }
 
         match self.clone().nodes_to_pool(id.to_string(), nodes) {
             Ok(pool) => Ok(Some(pool)),
@@ -922,7 +6128,154 @@ impl Engine {
         }
     }
 
+    /// Pulls `templates` images on all nodes of `pool`, replacing previous pre-pulls of those templates
+    pub async fn prepull(&self, pool: &Pool, templates: &BTreeMap<String, Template>) -> Result<()> {
+        if templates.is_empty() {
+            return Ok(());
+        }
+        let client = new_client().await?;
+        let job_api: Api<Job> = Api::namespaced(client, &self.env.namespace);
+        let selector = format!(
+            "{}={},{}={},{} in ({})",
+            COMPONENT_LABEL,
+            PREPULL_COMPONENT_VALUE,
+            PREPULL_POOL_LABEL,
+            pool.name,
+            PREPULL_TEMPLATE_LABEL,
+            templates.keys().cloned().collect::<Vec<String>>().join(",")
+        );
+        job_api
+            .delete_collection(
+                &DeleteParams::background(),
+                &ListParams::default().labels(&selector),
+            )
+            .await
+            .map_err(|err| Error::Failure(err.into()))?;
+
+        for node in &pool.nodes {
+            for (template_id, template) in templates {
+                let job = create_prepull_job(&pool.name, &node.hostname, template_id, template);
+                job_api
+                    .create(&PostParams::default(), &job)
+                    .await
+                    .map_err(|err| Error::Failure(err.into()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns pre-pull status of `pool`, indexed by node hostname then template id. Pre-pulls finished more than
+    /// `PREPULL_JOB_TTL` ago are not reported.
+    pub async fn prepull_status(
+        &self,
+        pool_id: &str,
+    ) -> Result<BTreeMap<String, BTreeMap<String, PrepullStatus>>> {
+        let client = new_client().await?;
+        let job_api: Api<Job> = Api::namespaced(client, &self.env.namespace);
+        let jobs = list_by_selector(
+            &job_api,
+            format!(
+                "{}={},{}={}",
+                COMPONENT_LABEL, PREPULL_COMPONENT_VALUE, PREPULL_POOL_LABEL, pool_id
+            ),
+        )
+        .await?;
+        Ok(jobs.iter().fold(BTreeMap::new(), |mut acc, job| {
+            let labels = job.metadata.labels.clone().unwrap_or_default();
+            if let (Some(node), Some(template)) = (
+                labels.get(PREPULL_NODE_LABEL),
+                labels.get(PREPULL_TEMPLATE_LABEL),
+            ) {
+                let status = job.status.as_ref();
+                let status = if status.and_then(|status| status.succeeded).unwrap_or(0) > 0 {
+                    PrepullStatus::Pulled
+                } else if status.and_then(|status| status.conditions.as_ref()).map_or(
+                    false,
+                    |conditions| {
+                        conditions.iter().any(|condition| {
+                            condition.type_ == "Failed" && condition.status == "True"
+                        })
+                    },
+                ) {
+                    PrepullStatus::Failed
+                } else {
+                    PrepullStatus::Pending
+                };
+                acc.entry(node.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(template.clone(), status);
+            }
+            acc
+        }))
+    }
+
+    /// Returns pools `user` can create sessions on, with their availability
+    pub async fn list_usable_pools(
+        &self,
+        user: &LoggedUser,
+    ) -> Result<BTreeMap<String, UsablePool>> {
+        let defaults = self.configuration.session.for_role(user.role());
+        let default_pool = user
+            .pool_affinity
+            .clone()
+            .unwrap_or_else(|| defaults.pool_affinity.clone());
+        let sessions = self.list_sessions().await?;
+        let reservations = self.list_reservations().await?;
+        let templates = self.clone().list_templates().await?;
+        let mut usable_pools = BTreeMap::new();
+        for (id, pool) in self.list_pools().await? {
+            if !user.can_customize_pool_affinity() && id != default_pool {
+                continue;
+            }
+            let free_slots = free_slots(
+                &pool,
+                defaults.max_sessions_per_pod,
+                &reservations,
+                None,
+                &sessions,
+                self.clock.now(),
+            );
+            let statuses = self.prepull_status(&id).await?;
+            let prepulled_templates: Vec<String> = templates
+                .keys()
+                .filter(|template| {
+                    pool.nodes.iter().all(|node| {
+                        statuses
+                            .get(&node.hostname)
+                            .and_then(|statuses| statuses.get(*template))
+                            == Some(&PrepullStatus::Pulled)
+                    })
+                })
+                .cloned()
+                .collect();
+            let latency = if free_slots == 0 {
+                StartLatency::Unavailable
+            } else if prepulled_templates.len() == templates.len() {
+                StartLatency::Fast
+            } else {
+                StartLatency::Slow
+            };
+            usable_pools.insert(
+                id,
+                UsablePool {
+                    pool,
+                    free_slots,
+                    prepulled_templates,
+                    latency,
+                },
+            );
+        }
+        Ok(usable_pools)
+    }
+
     pub async fn list_pools(&self) -> Result<BTreeMap<String, Pool>> {
+        if self.restricted {
+            return Ok(self
+                .static_pools
+                .iter()
+                .map(|(id, definition)| (id.clone(), self.static_pool(id, definition, None)))
+                .collect());
+        }
         let client = new_client().await?;
         let node_api: Api<Node> = Api::all(client);
 
@@ -945,12 +6298,60 @@ impl Engine {
                 acc
             });
 
-        Ok(nodes_by_pool
+        let mut pools: BTreeMap<String, Pool> = nodes_by_pool
             .into_iter()
             .flat_map(|(s, v)| match self.clone().nodes_to_pool(s.clone(), v) {
                 Ok(pool) => Some((s, pool)),
                 Err(_) => None,
             })
-            .collect())
+            .collect();
+        // Static definitions take precedence, completed with live nodes
+        for (id, definition) in &self.static_pools {
+            pools.insert(id.clone(), self.static_pool(id, definition, Some(&nodes)));
+        }
+        Ok(pools)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_caches_only_after_a_full_list() {
+        let synced = AtomicBool::new(false);
+        track_sync::<Pod, ()>(&synced, &Ok(watcher::Event::Applied(Pod::default())));
+        assert!(!synced.load(Ordering::SeqCst));
+        track_sync::<Pod, ()>(&synced, &Ok(watcher::Event::Restarted(Vec::new())));
+        assert!(synced.load(Ordering::SeqCst));
+        track_sync::<Pod, ()>(&synced, &Err(()));
+        assert!(!synced.load(Ordering::SeqCst));
+        // Events following a failure might be incomplete
+        track_sync::<Pod, ()>(&synced, &Ok(watcher::Event::Applied(Pod::default())));
+        assert!(!synced.load(Ordering::SeqCst));
+        track_sync::<Pod, ()>(&synced, &Ok(watcher::Event::Restarted(Vec::new())));
+        assert!(synced.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reports_sessions_of_changed_pods() {
+        let pod = |owner: Option<&str>| {
+            let mut pod = Pod::default();
+            pod.metadata.labels =
+                owner.map(|owner| BTreeMap::from([(OWNER_LABEL.to_string(), owner.to_string())]));
+            pod
+        };
+        assert_eq!(
+            changed_sessions(&watcher::Event::Deleted(pod(Some("alice")))),
+            vec!["alice".to_string()]
+        );
+        assert_eq!(
+            changed_sessions(&watcher::Event::Restarted(vec![
+                pod(Some("alice")),
+                pod(None),
+                pod(Some("bob"))
+            ])),
+            vec!["alice".to_string(), "bob".to_string()]
+        );
     }
 }
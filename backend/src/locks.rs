@@ -0,0 +1,168 @@
+//! Per-resource locks
+//!
+//! Mutations of a same resource (e.g. a session deleted while still being created) are serialized so that they can't
+//! interleave. Locks are keyed by resource kind and id. `Locks` only covers this replica: callers then hold a `Lease`
+//! named after `lease_name` to cover other replicas, renewed while held and released along with the `ResourceLock`.
+use crate::error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+pub const SESSION: &str = "session";
+pub const USER: &str = "user";
+pub const ORG: &str = "org";
+/// Reservations are stored together and locked as a whole, using `ALL` as id
+pub const RESERVATIONS: &str = "reservations";
+pub const ALL: &str = "*";
+
+/// Maximum time spent waiting for a lock, so that a stuck mutation doesn't block others forever. Also the duration of
+/// leases, after which a lock held by a lost replica can be taken over.
+pub const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Time between two renewals of a held lease, well within `TIMEOUT`
+pub const RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// Time between two attempts to acquire a lease held by another replica
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type Key = (&'static str, String);
+
+/// Tracks locked resources
+#[derive(Clone, Debug, Default)]
+pub struct Locks {
+    state: Arc<(Mutex<HashSet<Key>>, Condvar)>,
+}
+
+/// Returns the name of the `Lease` locking resource `id` of `kind` across replicas. Ids are hashed as they might not
+/// be valid names.
+pub fn lease_name(kind: &str, id: &str) -> String {
+    let digest = Sha256::digest(id.as_bytes());
+    let hash: String = digest[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("playground-lock-{}-{}", kind, hash)
+}
+
+/// Keeps a resource locked until dropped
+pub struct ResourceLock {
+    locks: Locks,
+    key: Key,
+    release: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl ResourceLock {
+    /// Calls `release` once dropped, before unlocking the resource on this replica
+    pub fn with_release<F: FnOnce() + Send + 'static>(mut self, release: F) -> Self {
+        self.release = Some(Box::new(release));
+        self
+    }
+}
+
+impl Drop for ResourceLock {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+        let (lock, condvar) = &*self.locks.state;
+        if let Ok(mut locked) = lock.lock() {
+            locked.remove(&self.key);
+            condvar.notify_all();
+        }
+    }
+}
+
+impl Locks {
+    /// Waits until resource `id` of `kind` is unlocked then locks it. Returns the lock along with the time spent waiting.
+    pub fn acquire(&self, kind: &'static str, id: &str) -> Result<(ResourceLock, Duration)> {
+        let (lock, condvar) = &*self.state;
+        let start = Instant::now();
+        let key = (kind, id.to_string());
+        let mut locked = lock
+            .lock()
+            .map_err(|_| Error::Failure("Failed to acquire resource locks".into()))?;
+        while locked.contains(&key) {
+            let remaining = TIMEOUT.saturating_sub(start.elapsed());
+            if remaining == Duration::ZERO {
+                return Err(Error::Failure(
+                    format!("Timed out waiting for {} {}", kind, id).into(),
+                ));
+            }
+            locked = condvar
+                .wait_timeout(locked, remaining)
+                .map(|(locked, _)| locked)
+                .map_err(|_| Error::Failure("Failed to acquire resource locks".into()))?;
+        }
+        locked.insert(key.clone());
+        Ok((
+            ResourceLock {
+                locks: self.clone(),
+                key,
+                release: None,
+            },
+            start.elapsed(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    #[test]
+    fn serializes_a_same_resource() {
+        let locks = Locks::default();
+        let (lock, _) = locks.acquire(SESSION, "a").unwrap();
+        // Other resources and kinds are independent
+        assert!(locks.acquire(SESSION, "b").is_ok());
+        assert!(locks.acquire(USER, "a").is_ok());
+
+        let released = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let locks = locks.clone();
+            let released = released.clone();
+            thread::spawn(move || {
+                let _lock = locks.acquire(SESSION, "a").unwrap();
+                released.load(Ordering::SeqCst)
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn releases_once_dropped() {
+        let locks = Locks::default();
+        let released = Arc::new(AtomicBool::new(false));
+        let lock = {
+            let released = released.clone();
+            locks
+                .acquire(SESSION, "a")
+                .unwrap()
+                .0
+                .with_release(move || released.store(true, Ordering::SeqCst))
+        };
+        assert!(!released.load(Ordering::SeqCst));
+        drop(lock);
+        assert!(released.load(Ordering::SeqCst));
+        assert!(locks.acquire(SESSION, "a").is_ok());
+    }
+
+    #[test]
+    fn names_leases_validly() {
+        let name = lease_name(RESERVATIONS, ALL);
+        assert!(name.starts_with("playground-lock-reservations-"));
+        assert_ne!(lease_name(USER, "Alice"), lease_name(USER, "alice"));
+        assert_eq!(lease_name(USER, "Alice"), lease_name(USER, "Alice"));
+        assert!(name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+    }
+}
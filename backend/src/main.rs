@@ -1,36 +1,126 @@
-#![feature(async_closure, proc_macro_hygiene, decl_macro)]
+#![feature(async_closure, proc_macro_hygiene, decl_macro, once_cell)]
 
+mod alerts;
 mod api;
+mod assets;
+mod audit;
+mod auth;
+mod budget;
+mod clock;
+mod config;
+mod csrf;
+mod dns;
+mod entries;
 mod error;
+mod faucet;
+mod git;
 mod github;
+mod graphql;
+mod heartbeat;
+mod idempotency;
 mod kubernetes;
+mod locks;
 mod manager;
 mod metrics;
+mod oidc;
+mod plugins;
+mod policy;
+mod preview;
 mod prometheus;
+mod ratelimit;
+mod reaper;
+mod registry;
+mod scheduling;
+mod secrets;
+mod session_auth;
+mod shutdown;
+mod storage;
+mod telemetry;
 mod types;
+mod usage;
+mod versioning;
+mod webhooks;
+mod ws;
 
+use crate::assets::Assets;
+use crate::csrf::Origins;
+use crate::idempotency::Responses;
 use crate::manager::Manager;
+use crate::metrics::Metrics;
+use crate::oidc::OidcUser;
 use crate::prometheus::PrometheusMetrics;
+use crate::ratelimit::{Limits, RateLimiter};
 use ::prometheus::Registry;
 use github::GitHubUser;
 use rocket::fairing::AdHoc;
 use rocket::{catchers, config::Environment, http::Method, routes};
-use rocket_cors::{AllowedOrigins, CorsOptions};
+use rocket_cors::CorsOptions;
 use rocket_oauth2::{HyperSyncRustlsAdapter, OAuth2, OAuthConfig, StaticProvider};
 use std::{env, error::Error};
 
 pub struct Context {
     manager: Manager,
+    rate_limiter: RateLimiter,
+    origins: Origins,
+    idempotency: Responses,
+    /// Set if `GRAPHQL_ENABLED`
+    graphql: Option<graphql::Schema>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // Settings from the configuration file, if any, are exposed as env variables
+    let args: Vec<String> = env::args().collect();
+    let mut errors = config::path(&args)
+        .map(|path| config::load(&path))
+        .unwrap_or_default();
+    // Secrets fetched from a provider are exposed as env variables too
+    let secrets = match secrets::Loader::from_env() {
+        Ok(Some(loader)) => match loader.load().await {
+            Ok(()) => Some(loader),
+            Err(err) => {
+                errors.push(format!("SECRETS_PROVIDER: {}", err));
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(err) => {
+            errors.push(format!("SECRETS_PROVIDER: {}", err));
+            None
+        }
+    };
+    errors.extend(config::validate());
+    if args.iter().any(|arg| arg == config::CHECK_FLAG) {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        if !errors.is_empty() {
+            std::process::exit(1);
+        }
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
     // Initialize log configuration. Reads `RUST_LOG` if any, otherwise fallsback to `default`
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info");
     }
     env_logger::init();
 
+    if !errors.is_empty() {
+        for error in &errors {
+            log::error!("{}", error);
+        }
+        return Err(format!("Invalid configuration, {} error(s)", errors.len()).into());
+    }
+    if let Some(secrets) = secrets {
+        // Providers are not thread safe, refresh on a dedicated runtime
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        std::thread::spawn(move || runtime.block_on(secrets.refresh()));
+    }
+
     // Prints basic details
     log::info!("Running ROCKET in {:?} mode", Environment::active()?);
 
@@ -39,28 +129,168 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Err(_) => log::warn!("Unknown version"),
     }
 
+    if telemetry::init()? {
+        log::info!("Exporting traces via OTLP");
+    }
+
     let manager = Manager::new().await?;
     let engine = manager.clone().engine;
+    if !engine.plugins.names().is_empty() {
+        log::info!("Plugins: {}", engine.plugins.names().join(", "));
+    }
     manager.clone().spawn_background_thread();
+    if let Some(webhooks) = manager.audit.webhooks() {
+        webhooks.clone().spawn_delivery_thread(engine.clone());
+    }
+    tokio::spawn(shutdown::on_sigterm(manager.clone()));
+    tokio::spawn(ws::serve(manager.clone()));
 
-    // Configure CORS
-    let cors = CorsOptions {
-        allowed_origins: AllowedOrigins::all(),
-        allowed_methods: vec![Method::Get, Method::Post, Method::Put, Method::Delete]
-            .into_iter()
-            .map(From::from)
+    // Configure CORS. Defaults to the playground own origins.
+    let scheme = if engine.env.secured { "https" } else { "http" };
+    let origins = Origins::from_env(
+        engine
+            .configuration
+            .base_domains
+            .iter()
+            .map(|domain| format!("{}://{}", scheme, domain))
             .collect(),
+    );
+    let cors = CorsOptions {
+        allowed_origins: origins.to_cors(),
+        allowed_methods: vec![
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete,
+        ]
+        .into_iter()
+        .map(From::from)
+        .collect(),
         allow_credentials: true,
         ..Default::default()
     }
     .to_cors()?;
 
-    let registry = Registry::new_custom(Some("playground".to_string()), None)?;
+    let registry = Registry::new_custom(Some(Metrics::PREFIX.to_string()), None)?;
     manager.clone().metrics.register(registry.clone())?;
     let prometheus = PrometheusMetrics::with_registry(registry);
-    let error = rocket::ignite()
-        .register(catchers![api::bad_request_catcher])
+    let oidc = engine
+        .configuration
+        .oidc
+        .clone()
+        .zip(engine.secrets.oidc_client_secret.clone());
+    // Served under `/api/v1`, and under `/api` for older clients, see `versioning`
+    let api_routes = routes![
+        api::get,
+        api::get_unlogged,
+        // Templates
+        api::list_templates,
+        api::list_templates_strict,
+        api::validate_template,
+        api::set_template_canary,
+        api::promote_template_canary,
+        api::abort_template_canary,
+        // Users
+        api::get_user,
+        api::list_users,
+        api::create_user,
+        api::update_user,
+        api::delete_user,
+        api::restore_user,
+        api::export_user,
+        api::get_user_preferences,
+        api::update_user_preferences,
+        api::update_user_onboarding,
+        api::link_user_identity,
+        api::import_users,
+        api::import_users_csv,
+        api::export_users,
+        api::get_usage,
+        api::get_template_analytics,
+        api::get_storage_version,
+        api::backup_state,
+        api::restore_state,
+        api::handoff_sessions,
+        // Organizations
+        api::list_orgs,
+        api::get_org,
+        api::update_org,
+        api::delete_org,
+        // Current Session
+        api::get_current_session,
+        api::get_current_session_unlogged,
+        api::create_current_session,
+        api::create_current_session_unlogged,
+        api::update_current_session,
+        api::update_current_session_unlogged,
+        api::delete_current_session,
+        api::delete_current_session_unlogged,
+        // Sessions
+        api::get_session,
+        api::get_session_git_state,
+        api::list_session_events,
+        api::get_session_eviction,
+        api::restart_session,
+        api::publish_template,
+        api::session_heartbeat,
+        api::resync_session_heartbeat,
+        api::publish_session_viewer,
+        api::unpublish_session_viewer,
+        api::get_session_access_url,
+        api::authorize_session,
+        api::request_funds,
+        api::update_session_env,
+        api::add_session_port,
+        api::remove_session_port,
+        api::list_sessions,
+        api::create_session,
+        api::update_session,
+        api::delete_session,
+        api::restore_session,
+        api::migrate_session,
+        api::terminate_session,
+        api::run_session_batch,
+        api::resume_session,
+        api::list_audit_events,
+        api::list_webhook_deliveries,
+        api::graphql,
+        api::get_diagnostics,
+        api::github_webhook,
+        // Workshops
+        api::list_artifacts,
+        api::get_artifact,
+        api::publish_artifact,
+        // Pools
+        api::get_pool,
+        api::list_pools,
+        api::prepull_pool,
+        api::get_prepull_status,
+        api::list_reservations,
+        api::create_reservation,
+        api::delete_reservation,
+        // Login
+        api::github_login,
+        api::oidc_login,
+        api::oidc_callback,
+        api::post_install_callback,
+        api::login,
+        api::refresh,
+        api::create_ws_ticket,
+        api::revoke_sessions,
+        api::logout,
+    ];
+    let rocket = rocket::ignite()
+        .register(catchers![
+            api::bad_request_catcher,
+            api::too_many_requests_catcher
+        ])
         .attach(cors)
+        .attach(telemetry::Tracing)
+        .attach(shutdown::Requests {
+            operations: manager.operations.clone(),
+        })
+        .attach(versioning::Versioning::from_env())
         .attach(AdHoc::on_attach("github", |rocket| {
             let config = OAuthConfig::new(
                 StaticProvider {
@@ -76,46 +306,46 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 config,
             )))
         }))
-        .mount(
-            "/api",
-            routes![
-                api::get,
-                api::get_unlogged,
-                // Users
-                api::get_user,
-                api::list_users,
-                api::create_user,
-                api::update_user,
-                api::delete_user,
-                // Current Session
-                api::get_current_session,
-                api::get_current_session_unlogged,
-                api::create_current_session,
-                api::create_current_session_unlogged,
-                api::update_current_session,
-                api::update_current_session_unlogged,
-                api::delete_current_session,
-                api::delete_current_session_unlogged,
-                // Sessions
-                api::get_session,
-                api::list_sessions,
-                api::create_session,
-                api::update_session,
-                api::delete_session,
-                // Pools
-                api::get_pool,
-                api::list_pools,
-                // Login
-                api::github_login,
-                api::post_install_callback,
-                api::login,
-                api::logout,
-            ],
-        )
+        .attach(AdHoc::on_attach("oidc", |rocket| match oidc {
+            Some((oidc, client_secret)) => {
+                let config = OAuthConfig::new(
+                    StaticProvider {
+                        auth_uri: oidc.endpoints.authorization_endpoint.into(),
+                        token_uri: oidc.endpoints.token_endpoint.into(),
+                    },
+                    oidc.client_id,
+                    client_secret,
+                    None,
+                );
+                Ok(rocket.attach(OAuth2::<OidcUser>::custom(
+                    HyperSyncRustlsAdapter::default(),
+                    config,
+                )))
+            }
+            None => Ok(rocket),
+        }))
+        .mount(versioning::V1_PREFIX, api_routes.clone())
+        .mount(versioning::LEGACY_PREFIX, api_routes)
         .mount("/metrics", prometheus)
-        .manage(Context { manager })
-        .launch();
+        .manage(Context {
+            idempotency: Responses::new(manager.engine.clone()),
+            manager,
+            rate_limiter: RateLimiter::new(Limits::from_env()),
+            origins,
+            graphql: if env::var("GRAPHQL_ENABLED").as_deref() == Ok("true") {
+                Some(graphql::schema())
+            } else {
+                None
+            },
+        });
+    // Optionally serve the frontend, for deployments without a separate web server
+    let rocket = match Assets::from_env() {
+        Some(assets) => rocket.mount("/", assets),
+        None => rocket,
+    };
+    let error = rocket.launch();
 
     // Launch blocks unless an error is returned
+    telemetry::shutdown();
     Err(error.into())
 }
@@ -1,19 +1,53 @@
 use crate::{
+    alerts::{prometheus_rule_spec, AlertThresholds, PROMETHEUS_RULE_NAME},
+    audit::Audit,
+    auth::{random_token, AuthSessions},
+    budget::{Budget, BudgetState, Budgets, Consumer, Decision, Scope},
+    clock::{Clock, SystemClock},
     error::{Error, Result},
-    kubernetes::{Configuration, Engine, Environment},
+    faucet::RateLimitedFaucet,
+    github,
+    heartbeat::Heartbeats,
+    idempotency,
+    kubernetes::{
+        validate_workshop, Configuration, Drift, Engine, Environment,
+        TEMPLATE_ANALYTICS_RETENTION_DAYS, UNSCHEDULED_NODE,
+    },
+    locks::{self, Locks, ResourceLock},
     metrics::Metrics,
+    policy::{Analyzer, Policy, FLAG_SUSPENDED},
+    preview::{self, PendingPreview, PreviewRequest, Previews},
+    reaper, registry, secrets,
+    session_auth::{self, SessionTokens},
+    shutdown::Operations,
+    telemetry::{self, traced},
     types::{
-        LoggedUser, Phase, Pool, Session, SessionConfiguration, SessionUpdateConfiguration,
-        Template, User, UserConfiguration, UserUpdateConfiguration,
+        Artifact, AuditEvent, Canary, Check, Diagnostics, DisruptionPolicy, Entry, FaucetRequest,
+        GitState, HandoffReport, Heartbeat, Identity, InvalidEntry, LoggedUser, Notifications,
+        OnboardingState, Org, Page, Phase, Pool, Port, PrepullStatus, Reservation, RestoreReport,
+        Session, SessionBatch, SessionBatchAction, SessionBatchResult, SessionConfiguration,
+        SessionDuration, SessionEnvUpdate, SessionEvent, SessionEviction, SessionFailureReason,
+        SessionFilter, SessionHandoff, SessionPlan, SessionUpdateConfiguration, StateArchive,
+        StorageVersion, Template, TemplateAnalytics, TemplatePublication, TemplateQuery, Tombstone,
+        UsablePool, User, UserConfiguration, UserExport, UserImportReport, UserPreferencesUpdate,
+        UserUpdateConfiguration, UserUsage, WebhookDelivery, WorkspaceSnapshot, VIEWER_PORT,
     },
+    usage::Usage,
+    webhooks::Webhooks,
+    ws::{Notifier, Tickets},
 };
 use log::{error, info, warn};
 use serde::Serialize;
 use std::{
-    collections::{BTreeMap, HashSet},
-    sync::{Arc, Mutex},
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    env,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::runtime::Runtime;
 
@@ -28,7 +62,34 @@ fn running_sessions(sessions: Vec<&Session>) -> Vec<&Session> {
 pub struct Manager {
     pub engine: Engine,
     pub metrics: Metrics,
+    pub auth_sessions: AuthSessions,
+    pub audit: Audit,
+    pub ws_tickets: Tickets,
+    pub notifier: Notifier,
     sessions: Arc<Mutex<HashSet<String>>>,
+    usage: Usage,
+    tombstones: Arc<Mutex<BTreeMap<String, Tombstone>>>,
+    analyzer: Analyzer,
+    migrations: Arc<Mutex<HashSet<String>>>,
+    pub operations: Operations,
+    locks: Locks,
+    identity: String,
+    diagnostics: Arc<Mutex<Option<Diagnostics>>>,
+    /// Last seen image of each template, used to trigger pre-pulls
+    template_images: Arc<Mutex<Option<BTreeMap<String, String>>>>,
+    /// Time of the last workspace backup, per session
+    last_backups: Arc<Mutex<BTreeMap<String, Instant>>>,
+    faucet: Option<Arc<RateLimitedFaucet>>,
+    budgets: Budgets,
+    /// Drift found by the previous reconciliation
+    drift: Arc<Mutex<Drift>>,
+    /// Set if access to session hosts is restricted
+    session_tokens: Option<SessionTokens>,
+    heartbeats: Heartbeats,
+    /// If set, deleted sessions and users can be restored for this long
+    deletion_grace_period: Option<Duration>,
+    /// Set if pull requests can be previewed
+    previews: Option<Previews>,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -37,20 +98,62 @@ pub struct Playground {
     pub configuration: Configuration,
     pub templates: BTreeMap<String, Template>,
     pub user: Option<LoggedUser>,
+    /// Set if the user session was terminated by an admin
+    pub tombstone: Option<Tombstone>,
 }
 
 impl Manager {
     const SLEEP_TIME: Duration = Duration::from_secs(60);
+    /// Diagnostics older than this are run again when requested
+    const DIAGNOSTICS_TTL: Duration = Duration::from_secs(5 * 60);
+    const IMPORT_BATCH_SIZE: usize = 20;
+    /// Sessions updated concurrently by a `SessionBatch`
+    const SESSION_BATCH_CONCURRENCY: usize = 8;
+    const DEPLOYING_SESSIONS_STATE: &'static str = "deployingSessions";
+    /// Consumption of budgets, accounted by the leader
+    const BUDGETS_STATE: &'static str = "budgets";
+    /// Previews waiting for their session to run, indexed by session id
+    const PREVIEWS_STATE: &'static str = "previews";
+    /// Time of the last faucet request of each user, in seconds since epoch
+    const FAUCET_STATE: &'static str = "faucetRequests";
+    const LEASE_NAME: &'static str = "playground-backend";
+    /// Replaces identifiers of deleted users
+    const DELETED_USER: &'static str = "<deleted>";
+    /// Background loops move to another replica if the leader didn't renew its lease for this long
+    const LEASE_DURATION: Duration = Duration::from_secs(3 * 60);
 
     pub async fn new() -> Result<Self> {
+        Manager::with_clock(Arc::new(SystemClock)).await
+    }
+
+    /// Creates a `Manager` whose time dependent decisions read the current time from `clock`
+    pub async fn with_clock(clock: Arc<dyn Clock>) -> Result<Self> {
         let metrics = Metrics::new().map_err(|err| Error::Failure(err.into()))?;
-        let engine = Engine::new().await?;
-        // Go through all existing sessions and update the ingress
+        let engine = Engine::with_clock(clock).await?;
+        // Identifies this replica for leader election
+        let identity = env::var("POD_NAME")
+            .or_else(|_| env::var("HOSTNAME"))
+            .unwrap_or_else(|_| random_token(8));
+        // Go through all existing sessions and update the ingress. Only done by the leader to prevent concurrent patches.
+        let leader = engine
+            .acquire_lease(Manager::LEASE_NAME, &identity, Manager::LEASE_DURATION)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Failed to acquire lease: {}", err);
+                false
+            });
+        // Upgrade stored resources before they are parsed. Only done by the leader to prevent concurrent updates.
+        if leader {
+            if let Err(err) = engine.migrate_storage().await {
+                error!("Failed to migrate storage: {}", err);
+            }
+        }
         match engine.clone().list_sessions().await {
+            Ok(_) if !leader => info!("Not leader, sessions restoration skipped"),
             Ok(sessions) => {
                 let running = running_sessions(sessions.values().collect())
                     .iter()
-                    .map(|i| (i.user_id.clone(), &i.template))
+                    .map(|i| (i.user_id.clone(), (&i.template, i.url.as_str())))
                     .collect();
                 engine.clone().patch_ingress(&running).await?;
 
@@ -65,14 +168,175 @@ impl Manager {
                 err
             ),
         }
+        // Keep alerting rules in sync with exposed metrics
+        match AlertThresholds::from_env() {
+            Ok(thresholds) => {
+                if let Err(err) = engine
+                    .apply_prometheus_rule(PROMETHEUS_RULE_NAME, prometheus_rule_spec(&thresholds))
+                    .await
+                {
+                    warn!("Failed to apply alerting rules: {}", err);
+                }
+            }
+            Err(err) => error!("Invalid alerting thresholds: {}", err),
+        }
+        // Keep session pods protected from node drains as configured
+        if let Err(err) = engine.apply_disruption_budget().await {
+            warn!("Failed to apply disruption budget: {}", err);
+        }
+        // Sessions still being deployed when the previous backend shut down
+        let deploying_sessions = engine
+            .load_state(Manager::DEPLOYING_SESSIONS_STATE)
+            .await
+            .map_err(|err| warn!("Failed to load deploying sessions: {}", err))
+            .ok()
+            .flatten()
+            .and_then(|state| serde_json::from_str(&state).ok())
+            .unwrap_or_default();
+        let auth_sessions = AuthSessions::from_env(engine.clone());
+        let ws_tickets = Tickets::new(engine.clone());
         Ok(Manager {
             engine,
             metrics,
-            sessions: Arc::new(Mutex::new(HashSet::new())), // Temp map used to track session deployment time
+            auth_sessions,
+            audit: Audit::new(Webhooks::from_env()),
+            ws_tickets,
+            notifier: Notifier::default(),
+            sessions: Arc::new(Mutex::new(deploying_sessions)), // Temp map used to track session deployment time
+            usage: Usage::default(),
+            tombstones: Arc::new(Mutex::new(BTreeMap::new())),
+            analyzer: Analyzer::new(Policy::from_env()),
+            migrations: Arc::new(Mutex::new(HashSet::new())),
+            operations: Operations::default(),
+            locks: Locks::default(),
+            identity,
+            diagnostics: Arc::new(Mutex::new(None)),
+            template_images: Arc::new(Mutex::new(None)),
+            last_backups: Arc::new(Mutex::new(BTreeMap::new())),
+            faucet: RateLimitedFaucet::from_env().map(Arc::new),
+            budgets: Budgets::from_env(),
+            drift: Arc::new(Mutex::new(Drift::default())),
+            session_tokens: SessionTokens::from_env(),
+            heartbeats: Heartbeats::from_env(),
+            deletion_grace_period: env::var("DELETION_GRACE_PERIOD")
+                .ok()
+                .and_then(|minutes| minutes.parse::<u64>().ok())
+                .filter(|minutes| *minutes > 0)
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+            previews: Previews::from_env(),
+        })
+    }
+
+    /// Waits up to `timeout` for in-flight operations then persists state needed by the next backend.
+    /// Returns the number of operations still running.
+    pub fn shutdown(&self, timeout: Duration) -> usize {
+        let remaining = self.operations.drain(timeout);
+        let deploying_sessions = self
+            .sessions
+            .lock()
+            .map(|sessions| sessions.clone())
+            .unwrap_or_default();
+        let result = new_runtime().and_then(|runtime| {
+            runtime.block_on(async {
+                let sessions = self.engine.list_sessions().await?;
+                // Other replicas persist their own sessions, only those gone are dropped
+                self.engine
+                    .update_state(Manager::DEPLOYING_SESSIONS_STATE, |state| {
+                        let mut ids: HashSet<String> = state
+                            .and_then(|state| serde_json::from_str(state).ok())
+                            .unwrap_or_default();
+                        ids.extend(deploying_sessions.iter().cloned());
+                        ids.retain(|id| sessions.contains_key(id));
+                        serde_json::to_string(&ids).map_err(|err| Error::Failure(err.into()))
+                    })
+                    .await
+            })
+        });
+        if let Err(err) = result {
+            error!("Failed to persist state: {}", err);
+        }
+        remaining
+    }
+
+    /// Runs all self-diagnostics checks, and keeps the result as `Diagnostics`
+    fn diagnose(&self, runtime: &Runtime) -> Result<Diagnostics> {
+        let invalid_entries = runtime.block_on(self.engine.validate_config_maps())?;
+        for entry in &invalid_entries {
+            error!(
+                "Invalid entry {} in {}: {}",
+                entry.key, entry.config_map, entry.error
+            );
+        }
+        let mut checks = runtime.block_on(self.engine.diagnose())?;
+        checks.push(if invalid_entries.is_empty() {
+            Check::pass("config-maps")
+        } else {
+            Check::fail(
+                "config-maps",
+                format!("{} invalid entries", invalid_entries.len()),
+            )
+        });
+        checks.push(match runtime.block_on(github::ping()) {
+            Ok(()) => Check::pass("github"),
+            Err(err) => Check::fail("github", err.to_string()),
+        });
+        checks.push(self.check_template_images(runtime)?);
+        if let Some(app) = &self.engine.secrets.github_app {
+            checks.push(match runtime.block_on(app.installation_token()) {
+                Ok(_) => Check::pass("github-app"),
+                Err(err) => Check::fail("github-app", err.to_string()),
+            });
+        }
+        let diagnostics = Diagnostics {
+            checked_at: self.engine.clock.now(),
+            checks,
+            invalid_entries,
+        };
+        if let Ok(mut current) = self.diagnostics.lock() {
+            current.replace(diagnostics.clone());
+        }
+        Ok(diagnostics)
+    }
+
+    /// Verifies that all template images can be pulled
+    fn check_template_images(&self, runtime: &Runtime) -> Result<Check> {
+        let name = "template-images";
+        let templates = runtime.block_on(self.engine.clone().list_templates())?;
+        let mut missing = Vec::new();
+        let mut unknown = Vec::new();
+        for (id, template) in templates {
+            match runtime.block_on(registry::exists(&template.image)) {
+                Ok(true) => {}
+                Ok(false) => missing.push(id),
+                Err(err) => unknown.push(format!("{} ({})", id, err)),
+            }
+        }
+        Ok(if !missing.is_empty() {
+            Check::fail(name, format!("Missing images for {:?}", missing))
+        } else if !unknown.is_empty() {
+            Check::warn(name, format!("Failed to check images for {:?}", unknown))
+        } else {
+            Check::pass(name)
         })
     }
 
+    /// Returns true if this replica is in charge of background loops
+    fn is_leader(&self, runtime: &Runtime) -> bool {
+        runtime
+            .block_on(self.engine.acquire_lease(
+                Manager::LEASE_NAME,
+                &self.identity,
+                Manager::LEASE_DURATION,
+            ))
+            .unwrap_or_else(|err| {
+                warn!("Failed to acquire lease: {}", err);
+                false
+            })
+    }
+
     pub fn spawn_background_thread(self) -> JoinHandle<()> {
+        // Whether this replica led during the previous iteration
+        let mut leading = false;
         thread::spawn(move || loop {
             thread::sleep(Manager::SLEEP_TIME);
 
@@ -111,26 +375,95 @@ impl Manager {
                     error!("Failed to acquire sessions lock");
                 }
 
+                // Remaining loops must run on a single replica
+                if !self.is_leader(&runtime) {
+                    leading = false;
+                    continue;
+                }
+                // Stored resources might have been written by an older replica until now
+                if !leading {
+                    if let Err(err) = runtime.block_on(self.engine.migrate_storage()) {
+                        error!("Failed to migrate storage: {}", err);
+                    }
+                    leading = true;
+                }
+
+                // Detect resources broken by external edits, and other misconfigurations
+                if let Err(err) = self.diagnose(&runtime) {
+                    warn!("Failed to run diagnostics: {}", err);
+                }
+
+                self.check_budgets(&runtime);
+
+                self.start_previews(&runtime);
+
+                // Responses are kept once per key, by whichever replica served them
+                let before = self
+                    .engine
+                    .clock
+                    .now()
+                    .checked_sub(idempotency::ttl())
+                    .unwrap_or(UNIX_EPOCH);
+                if let Err(err) = runtime.block_on(self.engine.purge_idempotency_keys(before)) {
+                    warn!("Failed to purge idempotency keys: {}", err);
+                }
+                if let Err(err) = runtime.block_on(self.auth_sessions.purge()) {
+                    warn!("Failed to purge authenticated sessions: {}", err);
+                }
+                if let Err(err) = runtime.block_on(self.ws_tickets.purge()) {
+                    warn!("Failed to purge tickets: {}", err);
+                }
+
                 // Go through all Running pods and figure out if they have to be undeployed
                 match runtime.block_on(self.engine.list_sessions()) {
                     Ok(sessions) => {
-                        for session in sessions.values() {
-                            if let Some(duration) =
-                                &session.pod.start_time.and_then(|p| p.elapsed().ok())
-                            {
-                                if duration > &session.duration {
-                                    info!("Undeploying {}", session.user_id);
-
-                                    match runtime.block_on(
-                                        self.engine.delete_session(&session_id(&session.user_id)),
-                                    ) {
-                                        Ok(()) => (),
-                                        Err(err) => {
-                                            warn!(
-                                                "Error while undeploying {}: {}",
-                                                session.user_id, err
-                                            )
-                                        }
+                        // Sessions pending deletion are handled by `purge_deleted`
+                        for session in sessions
+                            .values()
+                            .filter(|session| session.deleted_at.is_none())
+                        {
+                            if reaper::should_undeploy(
+                                &*self.engine.clock,
+                                session.pod.start_time,
+                                session.last_activity,
+                                session.duration,
+                                self.heartbeats.idle_timeout.filter(|_| !session.unattended),
+                            ) {
+                                let _operation = match self.operations.begin() {
+                                    Ok(operation) => operation,
+                                    // Shutting down
+                                    Err(_) => break,
+                                };
+                                let id = session_id(&session.user_id);
+                                let _lock = match self.lock(locks::SESSION, &id) {
+                                    Ok(lock) => lock,
+                                    Err(err) => {
+                                        warn!("Failed to lock {}: {}", id, err);
+                                        continue;
+                                    }
+                                };
+                                // Might have been deleted or recreated in the meantime
+                                match runtime.block_on(self.engine.get_session(&id)) {
+                                    Ok(Some(current))
+                                        if current.deleted_at.is_none()
+                                            && current.pod.start_time == session.pod.start_time => {
+                                    }
+                                    _ => continue,
+                                }
+                                info!("Undeploying {}", session.user_id);
+
+                                // Expired sessions are deleted regardless
+                                if let Err(err) = self.engine.plugins.pre_delete(&id, Some(session))
+                                {
+                                    warn!("Deleting {} anyway: {}", session.user_id, err);
+                                }
+                                match runtime.block_on(self.engine.delete_session(&id)) {
+                                    Ok(()) => self.record_session_end(&id),
+                                    Err(err) => {
+                                        warn!(
+                                            "Error while undeploying {}: {}",
+                                            session.user_id, err
+                                        )
                                     }
                                 }
                             }
@@ -138,9 +471,627 @@ impl Manager {
                     }
                     Err(err) => error!("Failed to call list_all: {}", err),
                 }
+
+                self.record_evictions(&runtime);
+
+                self.release_lost_pods(&runtime);
+
+                self.retry_failed_sessions(&runtime);
+
+                self.migrate_drained_sessions(&runtime);
+
+                self.purge_deleted(&runtime);
+
+                self.reconcile_ingress(&runtime);
+
+                self.analyze_sessions(&runtime);
+
+                self.prepull_updated_templates(&runtime);
+
+                self.backup_sessions(&runtime);
+            }
+        })
+    }
+
+    /// Repairs drift between the ingress, session services and live sessions.
+    /// Only drift seen twice in a row is repaired, so that sessions being created or deleted are left alone.
+    fn reconcile_ingress(&self, runtime: &Runtime) {
+        let drift = match runtime.block_on(self.engine.detect_drift()) {
+            Ok(drift) => drift,
+            Err(err) => {
+                warn!("Failed to detect ingress drift: {}", err);
+                return;
+            }
+        };
+        self.metrics
+            .set_ingress_drift("missing_rule", drift.missing_rules.len());
+        self.metrics
+            .set_ingress_drift("stale_rule", drift.stale_rules.len());
+        self.metrics
+            .set_ingress_drift("orphaned_service", drift.orphaned_services.len());
+
+        let previous = match self.drift.lock() {
+            Ok(mut previous) => std::mem::replace(&mut *previous, drift.clone()),
+            Err(_) => {
+                error!("Failed to acquire drift lock");
+                return;
+            }
+        };
+        let persistent = drift.intersection(&previous);
+        if persistent.is_empty() {
+            return;
+        }
+        let _operation = match self.operations.begin() {
+            Ok(operation) => operation,
+            // Shutting down
+            Err(_) => return,
+        };
+        info!("Repairing ingress drift {:?}", persistent);
+        if let Err(err) = runtime.block_on(self.engine.repair_drift(&persistent)) {
+            warn!("Failed to repair ingress drift: {}", err);
+        }
+    }
+
+    /// Lets sessions running on lost nodes be recreated elsewhere
+    fn release_lost_pods(&self, runtime: &Runtime) {
+        match runtime.block_on(self.engine.release_lost_pods()) {
+            Ok(ids) => {
+                for id in ids {
+                    warn!("Released pod of session {} from a lost node", id);
+                    self.audit
+                        .record(&self.identity, "release_lost_pod", &id, None);
+                }
+            }
+            Err(err) => warn!("Failed to release lost pods: {}", err),
+        }
+    }
+
+    /// Recreates sessions failing to start for transient reasons, as configured by `RetryPolicy`
+    fn retry_failed_sessions(&self, runtime: &Runtime) {
+        let policy = match &self.engine.configuration.retry_policy {
+            Some(policy) => policy,
+            None => return,
+        };
+        let sessions = match runtime.block_on(self.engine.list_sessions()) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                warn!("Failed to list sessions: {}", err);
+                return;
+            }
+        };
+        let now = self.engine.clock.now();
+        for (id, session) in sessions {
+            let failure = match &session.pod.failure {
+                Some(failure) if failure.reason.is_transient() => failure,
+                _ => continue,
+            };
+            if session.retries >= policy.max_attempts {
+                continue;
+            }
+            // Give some time to recover, e.g. while the cluster scales up
+            let created_at = session.pod.steps.first().and_then(|step| step.completed_at);
+            if created_at
+                .and_then(|time| now.duration_since(time).ok())
+                .map_or(true, |elapsed| elapsed < policy.delay)
+            {
+                continue;
+            }
+
+            let _operation = match self.operations.begin() {
+                Ok(operation) => operation,
+                // Shutting down
+                Err(_) => return,
+            };
+            let pool = match failure.reason {
+                SessionFailureReason::InsufficientResources => policy
+                    .fallback_pool
+                    .as_deref()
+                    .filter(|pool| session.pool.as_deref() != Some(*pool)),
+                _ => None,
+            };
+            // Never scheduled, there is nowhere else to place it
+            if session.node == UNSCHEDULED_NODE && pool.is_none() {
+                continue;
+            }
+            let _lock = match self.lock(locks::SESSION, &session_id(&id)) {
+                Ok(lock) => lock,
+                Err(err) => {
+                    warn!("Failed to lock {}: {}", id, err);
+                    continue;
+                }
+            };
+            // Might have been deleted or retried in the meantime
+            match runtime.block_on(self.engine.get_session(&session_id(&id))) {
+                Ok(Some(current))
+                    if current.deleted_at.is_none() && current.retries == session.retries => {}
+                _ => continue,
+            }
+            match runtime.block_on(self.engine.retry_session(&id, pool)) {
+                Ok(retries) => {
+                    info!(
+                        "Retried session {} ({:?}), attempt {}",
+                        id, failure.reason, retries
+                    );
+                    self.audit.record(
+                        &self.identity,
+                        "retry_session",
+                        &id,
+                        Some(format!("{:?}", failure.reason)),
+                    );
+                }
+                Err(err) => warn!("Failed to retry session {}: {}", id, err),
+            }
+        }
+    }
+
+    /// Moves sessions off nodes being drained, when protected by `DisruptionPolicy::Migrate`
+    fn migrate_drained_sessions(&self, runtime: &Runtime) {
+        if self.engine.configuration.disruption_policy != DisruptionPolicy::Migrate {
+            return;
+        }
+        let nodes = match runtime.block_on(self.engine.list_cordoned_nodes()) {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                warn!("Failed to list cordoned nodes: {}", err);
+                return;
+            }
+        };
+        if nodes.is_empty() {
+            return;
+        }
+        let (sessions, pools) = match (
+            runtime.block_on(self.engine.list_sessions()),
+            runtime.block_on(self.engine.list_pools()),
+        ) {
+            (Ok(sessions), Ok(pools)) => (sessions, pools),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Failed to list sessions and pools: {}", err);
+                return;
+            }
+        };
+        for (id, session) in sessions {
+            let id = session_id(&id);
+            if session.pod.phase != Phase::Running || !nodes.contains(&session.node) {
+                continue;
+            }
+            if self
+                .migrations
+                .lock()
+                .map_or(true, |migrations| migrations.contains(&id))
+            {
+                continue;
+            }
+            // Stay in the same pool, the scheduler skips cordoned nodes
+            let pool = match pools
+                .values()
+                .find(|pool| pool.nodes.iter().any(|node| node.hostname == session.node))
+            {
+                Some(pool) => pool.name.clone(),
+                None => {
+                    warn!("No pool found for node {} of {}", session.node, id);
+                    continue;
+                }
+            };
+            match self.spawn_migration(id.clone(), pool) {
+                Ok(()) => {
+                    info!("Migrating session {} off drained node {}", id, session.node);
+                    self.audit.record(
+                        &self.identity,
+                        "migrate_session",
+                        &id,
+                        Some(format!("node {} drained", session.node)),
+                    );
+                }
+                Err(err) => warn!("Failed to migrate session {}: {}", id, err),
+            }
+        }
+    }
+
+    /// Deletes sessions and users whose deletion grace period is over
+    fn purge_deleted(&self, runtime: &Runtime) {
+        let grace_period = match self.deletion_grace_period {
+            Some(grace_period) => grace_period,
+            None => return,
+        };
+        let now = self
+            .engine
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let users = runtime
+            .block_on(self.engine.list_users())
+            .map_err(|err| warn!("Failed to list users: {}", err))
+            .unwrap_or_default();
+        for (id, user) in users {
+            if !user.deleted_at.map_or(false, |deleted_at| {
+                now >= deleted_at + grace_period.as_secs()
+            }) {
+                continue;
+            }
+            let result = self.lock(locks::USER, &id).and_then(|_lock| {
+                // Might have been restored in the meantime
+                match runtime.block_on(self.engine.get_user(&id))? {
+                    Some(user) if user.deleted_at.is_some() => self.purge_user(&self.identity, &id),
+                    _ => Ok(()),
+                }
+            });
+            if let Err(err) = result {
+                warn!("Failed to purge user {}: {}", id, err);
+            }
+        }
+
+        let sessions = runtime
+            .block_on(self.engine.list_sessions())
+            .map_err(|err| warn!("Failed to list sessions: {}", err))
+            .unwrap_or_default();
+        for id in sessions
+            .values()
+            .filter(|session| session.deleted_at.is_some())
+            .map(|session| &session.user_id)
+        {
+            let result = self.lock(locks::SESSION, id).and_then(|_lock| {
+                match runtime.block_on(self.engine.get_session(id))? {
+                    Some(session)
+                        if reaper::is_purgeable(
+                            &*self.engine.clock,
+                            session.deleted_at,
+                            grace_period,
+                        ) =>
+                    {
+                        self.undeploy_locked_session(id)
+                    }
+                    _ => Ok(()),
+                }
+            });
+            if let Err(err) = result {
+                warn!("Failed to purge session {}: {}", id, err);
+            }
+        }
+    }
+
+    /// Keeps track of session pods evicted or preempted, so that users can be told why their session vanished. Their
+    /// control channel is notified, and admins via the audit trail.
+    fn record_evictions(&self, runtime: &Runtime) {
+        let evictions = match runtime.block_on(self.engine.record_evictions()) {
+            Ok(evictions) => evictions,
+            Err(err) => {
+                warn!("Failed to record evictions: {}", err);
+                return;
+            }
+        };
+        for (id, eviction) in evictions {
+            warn!("Session {} {}", id, eviction.reason.to_lowercase());
+            self.notifier.notify(&id);
+            self.audit.record(
+                &self.identity,
+                "session_evicted",
+                &id,
+                Some(format!(
+                    "{}: {}",
+                    eviction.reason,
+                    eviction.message.unwrap_or_default()
+                )),
+            );
+        }
+    }
+
+    /// Accounts consumption of running sessions against budgets, and notifies admins via the audit trail of
+    /// budgets crossing a threshold
+    fn check_budgets(&self, runtime: &Runtime) {
+        let sessions = match runtime.block_on(self.engine.list_sessions()) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                warn!("Failed to list sessions: {}", err);
+                return;
+            }
+        };
+        let orgs = runtime
+            .block_on(self.engine.list_orgs())
+            .map_err(|err| warn!("Failed to list organizations: {}", err))
+            .unwrap_or_default();
+        let consumers: Vec<Consumer> = sessions
+            .values()
+            .filter(|session| matches!(session.pod.phase, Phase::Running | Phase::Pending))
+            .filter_map(|session| {
+                Some(Consumer {
+                    role: session.role.as_deref()?,
+                    organizations: &session.organizations,
+                    started_at: session.pod.start_time?,
+                })
+            })
+            .collect();
+        let extra = org_budgets(&orgs);
+        // Only the last attempt of a conflicting update counts
+        let crossed = RefCell::new(Vec::new());
+        let result = runtime.block_on(self.engine.update_state(Manager::BUDGETS_STATE, |state| {
+            let mut state: BudgetState = state
+                .and_then(|state| serde_json::from_str(state).ok())
+                .unwrap_or_default();
+            state.account(
+                &self.budgets.all(&extra),
+                &consumers,
+                self.engine.clock.now(),
+            );
+            crossed.replace(self.budgets.crossed_thresholds(&mut state, &extra));
+            serde_json::to_string(&state).map_err(|err| Error::Failure(err.into()))
+        }));
+        if let Err(err) = result {
+            warn!("Failed to account budgets: {}", err);
+            return;
+        }
+        for (budget, threshold) in crossed.into_inner() {
+            warn!(
+                "Budget of {} reached {}% of {} minutes",
+                budget.scope, threshold, budget.minutes
+            );
+            self.audit.record(
+                &self.identity,
+                "budget_threshold",
+                &budget.scope.to_string(),
+                Some(format!("{}% of {} minutes", threshold, budget.minutes)),
+            );
+        }
+    }
+
+    /// Pushes workspaces of sessions configured for backup, once their interval has elapsed
+    fn backup_sessions(&self, runtime: &Runtime) {
+        let sessions = match runtime.block_on(self.engine.list_sessions()) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                warn!("Failed to list sessions: {}", err);
+                return;
+            }
+        };
+        let now = Instant::now();
+        for (id, session) in &sessions {
+            let backup = match &session.backup {
+                Some(backup) if session.pod.phase == Phase::Running => backup,
+                _ => continue,
+            };
+            let due = match self.last_backups.lock() {
+                // Newly seen sessions are backed up after a full interval
+                Ok(mut last_backups) => {
+                    let last_backup = last_backups.entry(id.clone()).or_insert(now);
+                    // Intervals too large to be represented are never due
+                    backup.interval.checked_mul(60).map_or(false, |interval| {
+                        now.duration_since(*last_backup) >= Duration::from_secs(interval)
+                    })
+                }
+                Err(_) => {
+                    error!("Failed to acquire backups lock");
+                    return;
+                }
+            };
+            if !due {
+                continue;
+            }
+            if let Err(err) = runtime.block_on(self.engine.backup_session(id, backup)) {
+                warn!("Failed to backup session {}: {}", id, err);
+            }
+            if let Ok(mut last_backups) = self.last_backups.lock() {
+                last_backups.insert(id.clone(), now);
+            }
+        }
+        if let Ok(mut last_backups) = self.last_backups.lock() {
+            last_backups.retain(|id, _| sessions.contains_key(id));
+        }
+    }
+
+    /// Pre-pulls on all pools images of templates that changed since last call
+    fn prepull_updated_templates(&self, runtime: &Runtime) {
+        let templates = match runtime.block_on(self.engine.clone().list_templates()) {
+            Ok(templates) => templates,
+            Err(err) => {
+                warn!("Failed to list templates: {}", err);
+                return;
+            }
+        };
+        let images: BTreeMap<String, String> = templates
+            .iter()
+            .map(|(id, template)| (id.clone(), template.image.clone()))
+            .collect();
+        let previous_images = match self.template_images.lock() {
+            Ok(mut template_images) => template_images.replace(images.clone()),
+            Err(_) => {
+                error!("Failed to acquire template images lock");
+                return;
+            }
+        };
+        // First run only records current images
+        let previous_images = match previous_images {
+            Some(previous_images) => previous_images,
+            None => return,
+        };
+        let updated: BTreeMap<String, Template> = templates
+            .into_iter()
+            .filter(|(id, _)| previous_images.get(id) != images.get(id))
+            .collect();
+        if updated.is_empty() {
+            return;
+        }
+
+        info!("Pre-pulling updated templates {:?}", updated.keys());
+        match runtime.block_on(self.engine.list_pools()) {
+            Ok(pools) => {
+                for pool in pools.values() {
+                    if let Err(err) = runtime.block_on(self.engine.prepull(pool, &updated)) {
+                        warn!("Failed to pre-pull on pool {}: {}", pool.name, err);
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to list pools: {}", err),
+        }
+    }
+
+    /// Checks running sessions against `Policy`, flagging and possibly suspending suspicious ones
+    fn analyze_sessions(&self, runtime: &Runtime) {
+        let sessions = match runtime.block_on(self.engine.list_sessions()) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                error!("Failed to list sessions: {}", err);
+                return;
+            }
+        };
+        let cpu_usage = runtime
+            .block_on(self.engine.sessions_cpu_usage())
+            .unwrap_or_else(|err| {
+                warn!("Failed to retrieve CPU usage: {}", err);
+                BTreeMap::new()
+            });
+        self.analyzer
+            .retain(&sessions.keys().cloned().collect::<Vec<_>>());
+
+        for session in sessions.values() {
+            if session.pod.phase != Phase::Running
+                || session.flags.contains(&FLAG_SUSPENDED.to_string())
+            {
+                continue;
+            }
+            let id = &session.user_id;
+            let mut flags =
+                match runtime.block_on(self.engine.exec_session(id, vec!["ps", "-eo", "comm"])) {
+                    Ok(processes) => self.analyzer.check_processes(&processes),
+                    Err(err) => {
+                        warn!("Failed to list processes of {}: {}", id, err);
+                        Vec::new()
+                    }
+                };
+            if let Some(flag) = cpu_usage
+                .get(id)
+                .and_then(|millicores| self.analyzer.check_cpu(id, *millicores))
+            {
+                flags.push(flag);
+            }
+            if flags.iter().all(|flag| session.flags.contains(flag)) {
+                continue;
+            }
+
+            flags.extend(session.flags.iter().cloned());
+            flags.sort();
+            flags.dedup();
+            warn!("Flagging session {}: {:?}", id, flags);
+            if self.analyzer.policy.auto_suspend {
+                match runtime.block_on(self.engine.set_session_suspended(id, true)) {
+                    Ok(_) => flags.push(FLAG_SUSPENDED.to_string()),
+                    Err(err) => error!("Failed to suspend {}: {}", id, err),
+                }
+            }
+            self.audit
+                .record("policy", "flag_session", id, Some(flags.join(",")));
+            if let Err(err) = runtime.block_on(self.engine.update_session_flags(id, &flags)) {
+                error!("Failed to flag {}: {}", id, err);
             }
+        }
+    }
+}
+
+/// Parses users from CSV lines formatted as `id[,admin[,pool_affinity]]`.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn users_from_csv(data: &str) -> Result<Vec<Entry<UserConfiguration>>> {
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+            let admin = match columns.get(1) {
+                None | Some(&"") => false,
+                Some(value) => value.parse().map_err(|_| {
+                    Error::InvalidParameter(format!("'{}' is not a valid value for admin", value))
+                })?,
+            };
+            Ok(Entry {
+                id: columns[0].to_string(),
+                value: UserConfiguration {
+                    admin,
+                    can_customize_duration: false,
+                    can_customize_pool_affinity: false,
+                    pool_affinity: columns
+                        .get(2)
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.to_string()),
+                    preferences: BTreeMap::new(),
+                    onboarding: OnboardingState::default(),
+                    accepted_terms_version: None,
+                    identities: Vec::new(),
+                    deleted_at: None,
+                    implicit: false,
+                },
+            })
         })
+        .collect()
+}
+
+fn is_valid_user_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Fails with details about all `invalid_entries`, if any
+fn ensure_no_invalid_entries(invalid_entries: &[InvalidEntry]) -> Result<()> {
+    if invalid_entries.is_empty() {
+        return Ok(());
     }
+    Err(Error::Failure(
+        invalid_entries
+            .iter()
+            .map(|entry| match (entry.line, entry.column) {
+                (Some(line), Some(column)) => format!("{} ({}:{})", entry, line, column),
+                (Some(line), None) => format!("{} (line {})", entry, line),
+                _ => entry.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+            .into(),
+    ))
+}
+
+/// Budgets defined by organizations
+fn org_budgets(orgs: &BTreeMap<String, Org>) -> Vec<Budget> {
+    orgs.iter()
+        .filter_map(|(id, org)| {
+            org.budget.map(|minutes| Budget {
+                scope: Scope::Organization(id.clone()),
+                minutes,
+            })
+        })
+        .collect()
+}
+
+/// Preference keys are dot separated namespaces, e.g. `theia.theme`
+fn is_valid_preference_key(key: &str) -> bool {
+    key.split('.').count() > 1
+        && key.split('.').all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Artifact names are used as ConfigMap keys, e.g. `chain-spec.json`
+fn is_valid_artifact_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Env variable names must be usable by shells, e.g. `RPC_ENDPOINT`
+fn is_valid_env_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn to_system_time(secs: Option<u64>, default: SystemTime) -> SystemTime {
+    secs.map_or(default, |secs| UNIX_EPOCH + Duration::from_secs(secs))
 }
 
 fn new_runtime() -> Result<Runtime> {
@@ -152,11 +1103,78 @@ fn session_id(id: &str) -> String {
     id.to_string().to_lowercase()
 }
 
+/// Returns the owner of session `id` among `users`, i.e. the user it is named after. Its rights are those of its entry:
+/// rights granted by organizations or OIDC roles are only known once logged in.
+fn session_owner(users: &BTreeMap<String, User>, id: &str) -> LoggedUser {
+    let (owner, user) = users
+        .iter()
+        .find(|(owner, _)| session_id(owner) == id)
+        .map(|(owner, user)| (owner.clone(), user.clone()))
+        .unwrap_or_else(|| (id.to_string(), User::implicit()));
+    LoggedUser {
+        id: owner,
+        admin: user.admin,
+        organizations: Vec::new(),
+        pool_affinity: user.pool_affinity,
+        can_customize_duration: user.can_customize_duration,
+        can_customize_pool_affinity: user.can_customize_pool_affinity,
+        onboarding: user.onboarding,
+        accepted_terms_version: user.accepted_terms_version,
+        org_role: None,
+    }
+}
+
+/// Returns true if `session` matches all criteria of `filter`. `pools` is used to find the pool of a session node.
+fn matches_filter(
+    session: &Session,
+    filter: &SessionFilter,
+    pools: &BTreeMap<String, Pool>,
+    now: SystemTime,
+) -> bool {
+    if let Some(template) = &filter.template {
+        if &session.template.name != template {
+            return false;
+        }
+    }
+    if let Some(pool) = &filter.pool {
+        let in_pool = pools.get(pool).map_or(false, |pool| {
+            pool.nodes.iter().any(|node| node.hostname == session.node)
+        });
+        if !in_pool {
+            return false;
+        }
+    }
+    if let Some(minutes) = filter.older_than {
+        let age = session
+            .pod
+            .start_time
+            .and_then(|start| now.duration_since(start).ok());
+        if age.map_or(true, |age| age < Duration::from_secs(minutes * 60)) {
+            return false;
+        }
+    }
+    if let Some(users) = &filter.users {
+        if !users
+            .iter()
+            .any(|user| user.eq_ignore_ascii_case(&session.user_id))
+        {
+            return false;
+        }
+    }
+    true
+}
+
 impl Manager {
     pub fn get(self, user: LoggedUser) -> Result<Playground> {
         let templates = new_runtime()?.block_on(self.clone().engine.list_templates())?;
+        let tombstone = self
+            .tombstones
+            .lock()
+            .ok()
+            .and_then(|tombstones| tombstones.get(&session_id(&user.id)).cloned());
         Ok(Playground {
             templates,
+            tombstone,
             user: Some(user),
             env: self.engine.env,
             configuration: self.engine.configuration,
@@ -167,163 +1185,1640 @@ impl Manager {
         let templates = new_runtime()?.block_on(self.clone().engine.list_templates())?;
         Ok(Playground {
             templates,
+            tombstone: None,
             user: None,
             env: self.clone().engine.env,
             configuration: self.clone().engine.configuration,
         })
     }
 
-    // Users
+    // Templates
 
-    pub fn get_user(&self, user: &LoggedUser, id: &str) -> Result<Option<User>> {
-        if user.id != id && !user.has_admin_read_rights() {
-            return Err(Error::Unauthorized());
-        }
+    /// Lists templates matching `query`. Invalid templates are ignored, unless in `strict` mode where they make the call fail.
+    /// Strict mode is restricted to admins.
+    pub fn list_templates(
+        &self,
+        user: Option<&LoggedUser>,
+        query: TemplateQuery,
+        strict: bool,
+    ) -> Result<Page<Entry<Template>>> {
+        let templates = if strict {
+            if !user.map_or(false, LoggedUser::has_admin_read_rights) {
+                return Err(Error::Unauthorized());
+            }
+            let (templates, invalid_entries) =
+                new_runtime()?.block_on(self.engine.list_templates_with_invalid_entries(true))?;
+            ensure_no_invalid_entries(&invalid_entries)?;
+            templates
+        } else {
+            new_runtime()?.block_on(self.clone().engine.list_templates())?
+        };
+        let entries = templates
+            .into_iter()
+            .filter(|(id, template)| query.matches(id, template))
+            .map(|(id, value)| Entry { id, value })
+            .collect();
+        query.paginate(entries).map_err(Error::InvalidParameter)
+    }
 
-        new_runtime()?.block_on(self.engine.get_user(id))
+    /// Returns why `value`, a YAML template definition, would be rejected. Empty if valid.
+    /// Images are only checked for logged users, as it involves contacting their registry. Unless `user` is an admin,
+    /// only images of allowed registries are checked.
+    pub fn validate_template(
+        &self,
+        user: Option<&LoggedUser>,
+        id: &str,
+        value: &str,
+    ) -> Result<Vec<InvalidEntry>> {
+        let _span = telemetry::enter("manager.validate_template");
+        Ok(new_runtime()?.block_on(self.engine.validate_template(id, value, user)))
     }
 
-    pub fn list_users(&self, user: &LoggedUser) -> Result<BTreeMap<String, User>> {
-        if !user.has_admin_read_rights() {
+    /// Applies `update` to template `id` and stores it
+    fn update_template<F>(&self, id: &str, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut Template) -> Result<()>,
+    {
+        let runtime = new_runtime()?;
+        let mut template = runtime
+            .block_on(self.engine.clone().list_templates())?
+            .remove(id)
+            .ok_or_else(|| Error::InvalidParameter(format!("Unknown template {}", id)))?;
+        update(&mut template)?;
+        runtime.block_on(self.engine.store_template(id, &template))
+    }
+
+    /// Starts rolling out a new image for template `id`, or changes the share of sessions using it
+    pub fn set_template_canary(&self, user: &LoggedUser, id: &str, canary: Canary) -> Result<()> {
+        if !user.has_admin_edit_rights() {
             return Err(Error::Unauthorized());
         }
+        if canary.percentage > 100 {
+            return Err(Error::InvalidParameter(format!(
+                "percentage {}",
+                canary.percentage
+            )));
+        }
 
-        new_runtime()?.block_on(self.engine.list_users())
+        let details = format!("{} {}%", canary.image, canary.percentage);
+        self.update_template(id, |template| {
+            template.canary = Some(canary);
+            Ok(())
+        })?;
+        self.audit
+            .record(&user.id, "set_template_canary", id, Some(details));
+        Ok(())
     }
 
-    pub fn create_user(self, user: &LoggedUser, id: String, conf: UserConfiguration) -> Result<()> {
+    /// Makes the canary image of template `id` the one used by all new sessions
+    pub fn promote_template_canary(&self, user: &LoggedUser, id: &str) -> Result<()> {
         if !user.has_admin_edit_rights() {
             return Err(Error::Unauthorized());
         }
 
-        new_runtime()?.block_on(self.engine.create_user(id, conf))
+        let mut image = None;
+        self.update_template(id, |template| {
+            let canary = template
+                .canary
+                .take()
+                .ok_or_else(|| Error::InvalidParameter(format!("No canary for {}", id)))?;
+            template.image = canary.image.clone();
+            image = Some(canary.image);
+            Ok(())
+        })?;
+        self.audit
+            .record(&user.id, "promote_template_canary", id, image);
+        Ok(())
     }
 
-    pub fn update_user(
-        self,
-        user: LoggedUser,
-        id: String,
-        conf: UserUpdateConfiguration,
+    /// Registers a new template from the live session `id`: its workspace is pushed to `TEMPLATE_SNAPSHOT_REMOTE`, and
+    /// restored in sessions based on the new template, that reuse the session image and runtime configuration.
+    pub fn publish_template(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        publication: TemplatePublication,
     ) -> Result<()> {
-        if user.id != id && !user.has_admin_edit_rights() {
+        let _span = telemetry::enter("manager.publish_template");
+        if !user.has_admin_edit_rights() {
             return Err(Error::Unauthorized());
         }
+        let remote = self
+            .engine
+            .configuration
+            .template_snapshot_remote
+            .as_ref()
+            .ok_or(Error::MissingData("TEMPLATE_SNAPSHOT_REMOTE"))?;
+        // Template ids are also used as branch names
+        if publication.id.is_empty()
+            || !publication
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(Error::InvalidParameter(format!(
+                "template id {}",
+                publication.id
+            )));
+        }
 
-        new_runtime()?.block_on(self.engine.update_user(id, conf))
+        let _operation = self.operations.begin()?;
+        let runtime = new_runtime()?;
+        if runtime
+            .block_on(self.engine.clone().list_templates())?
+            .contains_key(&publication.id)
+        {
+            return Err(Error::InvalidParameter(format!(
+                "template {} already exists",
+                publication.id
+            )));
+        }
+        let session = runtime
+            .block_on(self.engine.get_session(&session_id(id)))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        if session.pod.phase != Phase::Running {
+            return Err(Error::InvalidParameter(format!(
+                "session {} is not running",
+                id
+            )));
+        }
+        let commit = runtime.block_on(self.engine.snapshot_session(
+            &session.user_id,
+            remote,
+            self.engine.secrets.template_snapshot_token.as_deref(),
+            &format!("templates/{}", publication.id),
+        ))?;
+        let mut template = session.template;
+        template.canary = None;
+        template.snapshot = Some(WorkspaceSnapshot {
+            remote: remote.clone(),
+            commit: commit.clone(),
+        });
+        if let Some(name) = publication.name {
+            template.name = name;
+        }
+        if let Some(description) = publication.description {
+            template.description = description;
+        }
+        runtime.block_on(self.engine.store_template(&publication.id, &template))?;
+        self.audit.record(
+            &user.id,
+            "publish_template",
+            &publication.id,
+            Some(format!("{}@{}", id, commit)),
+        );
+        Ok(())
     }
 
-    pub fn delete_user(self, user: &LoggedUser, id: String) -> Result<()> {
-        if user.id != id && !user.has_admin_edit_rights() {
+    /// Stops rolling out the canary image of template `id`. Running sessions are left untouched.
+    pub fn abort_template_canary(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        if !user.has_admin_edit_rights() {
             return Err(Error::Unauthorized());
         }
 
-        new_runtime()?.block_on(self.engine.delete_user(id))
+        self.update_template(id, |template| {
+            template
+                .canary
+                .take()
+                .map(|_| ())
+                .ok_or_else(|| Error::InvalidParameter(format!("No canary for {}", id)))
+        })?;
+        self.audit
+            .record(&user.id, "abort_template_canary", id, None);
+        Ok(())
     }
 
-    // Sessions
+    // Users
 
-    pub fn get_session(&self, user: &LoggedUser, id: &str) -> Result<Option<Session>> {
-        if session_id(&user.id) != id && !user.has_admin_read_rights() {
+    pub fn get_user(&self, user: &LoggedUser, id: &str) -> Result<Option<User>> {
+        if user.id != id && !user.has_admin_read_rights() {
             return Err(Error::Unauthorized());
         }
 
-        new_runtime()?.block_on(self.engine.get_session(id))
+        new_runtime()?.block_on(self.engine.get_user(id))
     }
 
-    pub fn list_sessions(&self, user: &LoggedUser) -> Result<BTreeMap<String, Session>> {
+    /// Lists all users. In `strict` mode, unknown fields make the call fail too.
+    pub fn list_users(&self, user: &LoggedUser, strict: bool) -> Result<BTreeMap<String, User>> {
         if !user.has_admin_read_rights() {
             return Err(Error::Unauthorized());
         }
 
-        new_runtime()?.block_on(self.engine.list_sessions())
+        if strict {
+            let (users, invalid_entries) =
+                new_runtime()?.block_on(self.engine.list_users_with_invalid_entries(true))?;
+            ensure_no_invalid_entries(&invalid_entries)?;
+            return Ok(users);
+        }
+        new_runtime()?.block_on(self.engine.list_users())
     }
 
-    pub fn create_session(
-        &self,
-        user: &LoggedUser,
-        id: &str,
-        conf: SessionConfiguration,
-    ) -> Result<()> {
-        // Ids can only customized by users with proper rights
-        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+    /// Checks if `user` can create user `id`
+    fn check_user_creation(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        if !user.has_admin_edit_rights() {
             return Err(Error::Unauthorized());
         }
-
-        if conf.duration.is_some() {
-            // Duration can only customized by users with proper rights
-            if !user.can_customize_duration() {
-                return Err(Error::Unauthorized());
-            }
-        }
-        if conf.pool_affinity.is_some() {
-            // Duration can only customized by users with proper rights
-            if !user.can_customize_pool_affinity() {
-                return Err(Error::Unauthorized());
-            }
+        if !is_valid_user_id(id) {
+            return Err(Error::InvalidParameter(format!("user id {}", id)));
         }
-
-        let session_id = session_id(id);
-        // Ensure a workspace with the same id is not alread running
-        if new_runtime()?
-            .block_on(self.engine.get_session(&session_id))?
-            .is_some()
-        {
-            return Err(Error::Unauthorized());
+        if new_runtime()?.block_on(self.engine.get_user(id))?.is_some() {
+            return Err(Error::Forbidden(format!("user {} already exists", id)));
         }
+        Ok(())
+    }
+
+    /// Validates the creation of user `id` and returns what would be stored, without touching the cluster
+    pub fn plan_user(
+        &self,
+        user: &LoggedUser,
+        id: String,
+        conf: UserConfiguration,
+    ) -> Result<Entry<UserConfiguration>> {
+        self.check_user_creation(user, &id)?;
+        Ok(Entry { id, value: conf })
+    }
+
+    pub fn create_user(self, user: &LoggedUser, id: String, conf: UserConfiguration) -> Result<()> {
+        // Unauthorized callers must not hold locks
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::USER, &id)?;
+        // Existence is only reliable once locked
+        self.check_user_creation(user, &id)?;
+
+        new_runtime()?.block_on(self.engine.create_user(id, conf))
+    }
+
+    /// Creates all `users`, by batches. Returns which users could be imported.
+    /// Existing users are left untouched, as importing them would reset them, e.g. demoting admins.
+    /// Imports listing a same user more than once are rejected as a whole.
+    pub fn import_users(
+        &self,
+        user: &LoggedUser,
+        users: Vec<Entry<UserConfiguration>>,
+    ) -> Result<UserImportReport> {
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let mut ids = BTreeSet::new();
+        let duplicates: BTreeSet<&str> = users
+            .iter()
+            .filter(|entry| !ids.insert(entry.id.as_str()))
+            .map(|entry| entry.id.as_str())
+            .collect();
+        if !duplicates.is_empty() {
+            return Err(Error::InvalidParameter(format!(
+                "users listed more than once: {}",
+                duplicates.into_iter().collect::<Vec<_>>().join(", ")
+            )));
+        }
+
+        let mut report = UserImportReport::default();
+        let (valid, invalid): (Vec<_>, Vec<_>) = users
+            .into_iter()
+            .partition(|entry| is_valid_user_id(&entry.id));
+        for entry in invalid {
+            report
+                .failed
+                .insert(entry.id, "Invalid user id".to_string());
+        }
+
+        let runtime = new_runtime()?;
+        let existing = runtime.block_on(self.engine.list_users())?;
+        let (valid, existing): (Vec<_>, Vec<_>) = valid
+            .into_iter()
+            .partition(|entry| !existing.contains_key(&entry.id));
+        for entry in existing {
+            report
+                .failed
+                .insert(entry.id, "User already exists".to_string());
+        }
+
+        let batches = valid.chunks(Self::IMPORT_BATCH_SIZE);
+        let count = batches.len();
+        for (i, batch) in batches.enumerate() {
+            match runtime.block_on(self.engine.create_users(batch)) {
+                Ok(()) => report
+                    .imported
+                    .extend(batch.iter().map(|entry| entry.id.clone())),
+                Err(err) => {
+                    for entry in batch {
+                        report.failed.insert(entry.id.clone(), err.to_string());
+                    }
+                }
+            }
+            info!("Imported users batch {}/{}", i + 1, count);
+        }
+        Ok(report)
+    }
+
+    pub fn export_users(&self, user: &LoggedUser) -> Result<Vec<Entry<User>>> {
+        Ok(self
+            .list_users(user, false)?
+            .into_iter()
+            .map(|(id, value)| Entry { id, value })
+            .collect())
+    }
+
+    pub fn update_user(
+        self,
+        user: LoggedUser,
+        id: String,
+        conf: UserUpdateConfiguration,
+    ) -> Result<()> {
+        if user.id != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::USER, &id)?;
+        let runtime = new_runtime()?;
+        let downgraded = runtime
+            .block_on(self.engine.get_user(&id))?
+            .map_or(false, |existing| existing.admin && !conf.admin);
+        runtime.block_on(self.engine.update_user(id.clone(), conf))?;
+        if downgraded {
+            // Make sure previous rights can't be used anymore
+            self.auth_sessions.revoke_user(&id);
+        }
+        Ok(())
+    }
+
+    /// Lets user `id` also log in with `identity`, so that sessions and permissions follow the person across providers
+    pub fn link_user_identity(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        identity: Identity,
+    ) -> Result<()> {
+        let _span = telemetry::enter("manager.link_user_identity");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::USER, id)?;
+        let runtime = new_runtime()?;
+        let users = runtime.block_on(self.engine.clone().list_users())?;
+        if !users.contains_key(id) {
+            return Err(Error::MissingData("no matching user"));
+        }
+        // Identities can't be shared, nor shadow an existing user
+        if let Some((other, _)) = users
+            .iter()
+            .find(|(other, user)| *other != id && user.identities.contains(&identity))
+        {
+            return Err(Error::InvalidParameter(format!(
+                "identity already linked to {}",
+                other
+            )));
+        }
+        if identity.subject != id && users.contains_key(&identity.subject) {
+            return Err(Error::InvalidParameter(format!(
+                "{} is an existing user",
+                identity.subject
+            )));
+        }
+
+        let _operation = self.operations.begin()?;
+        let subject = identity.subject.clone();
+        runtime.block_on(self.engine.link_user_identity(id, identity))?;
+        // Sessions opened with this identity must resolve to `id` from now on
+        self.auth_sessions.revoke_user(&subject);
+        self.audit
+            .record(&user.id, "link_user_identity", id, Some(subject));
+        Ok(())
+    }
+
+    pub fn get_user_preferences(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        Ok(self
+            .get_user(user, id)?
+            .ok_or(Error::MissingData("no matching user"))?
+            .preferences)
+    }
+
+    pub fn update_user_preferences(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        update: UserPreferencesUpdate,
+    ) -> Result<BTreeMap<String, String>> {
+        if user.id != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        if let Some(key) = update.keys().find(|key| !is_valid_preference_key(key)) {
+            return Err(Error::InvalidParameter(format!(
+                "'{}' is not a valid preference key",
+                key
+            )));
+        }
+
+        let _lock = self.lock(locks::USER, id)?;
+        new_runtime()?.block_on(self.engine.update_user_preferences(id, update))
+    }
+
+    pub fn update_user_onboarding(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        state: OnboardingState,
+    ) -> Result<()> {
+        if user.id != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::USER, id)?;
+        let runtime = new_runtime()?;
+        // Users allowed via an organization or a role have no entry until they go through onboarding
+        let existing = runtime
+            .block_on(self.engine.get_user(id))?
+            .unwrap_or_else(User::implicit);
+        let (state, accepted_terms_version) = existing
+            .onboarding_update(
+                state,
+                self.engine.configuration.legal.terms_version.as_deref(),
+            )
+            .map_err(Error::InvalidParameter)?;
+
+        runtime.block_on(
+            self.engine
+                .update_user_onboarding(id, state, accepted_terms_version),
+        )
+    }
+
+    /// Ensures `user` went through the onboarding steps required by this deployment
+    fn ensure_terms_accepted(&self, user: &LoggedUser) -> Result<()> {
+        let configuration = &self.engine.configuration;
+        if configuration.onboarding_required && user.onboarding == OnboardingState::Pending {
+            return Err(Error::Forbidden(
+                "Terms of service must be accepted first".to_string(),
+            ));
+        }
+        if let Some(version) = &configuration.legal.terms_version {
+            if user.accepted_terms_version.as_ref() != Some(version) {
+                return Err(Error::Forbidden(format!(
+                    "Terms of service version {} must be accepted first",
+                    version
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes user `id` and all associated data: its session is terminated and identifiers are scrubbed from
+    /// audit events and usage records
+    pub fn delete_user(self, user: &LoggedUser, id: String) -> Result<()> {
+        if user.id != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::USER, &id)?;
+        if self.deletion_grace_period.is_some() {
+            let runtime = new_runtime()?;
+            let now = self
+                .engine
+                .clock
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            runtime.block_on(self.engine.update_user_deletion(&id, Some(now)))?;
+            // Restored alongside the user
+            let session_id = session_id(&id);
+            if runtime
+                .block_on(self.engine.get_session(&session_id))?
+                .is_some()
+            {
+                self.soft_delete_session(&user.id, &session_id)?;
+            }
+            self.auth_sessions.revoke_user(&id);
+            self.audit.record(&user.id, "soft_delete_user", &id, None);
+            return Ok(());
+        }
+
+        self.purge_user(&user.id, &id)
+    }
+
+    /// Deletes user `id` for good, on behalf of `actor`. The lock of `id` must be held.
+    /// Its session is terminated in the background, then its identifiers are scrubbed.
+    fn purge_user(&self, actor: &str, id: &str) -> Result<()> {
+        let session_id = session_id(id);
+        // Held until the session is gone, so that it can't be re-created in the meantime
+        let lock = self.lock(locks::SESSION, &session_id)?;
+        let runtime = new_runtime()?;
+        runtime.block_on(self.engine.delete_user(id.to_string()))?;
+        self.auth_sessions.revoke_user(id);
+        self.audit
+            .record(actor, "delete_user", Manager::DELETED_USER, None);
+        let manager = self.clone();
+        let id = id.to_string();
+        thread::spawn(move || {
+            let result = new_runtime().and_then(|runtime| {
+                if runtime
+                    .block_on(manager.engine.get_session(&session_id))?
+                    .is_some()
+                {
+                    manager.undeploy_locked_session(&session_id)?;
+                }
+                runtime.block_on(manager.engine.forget_last_node(&session_id))
+            });
+            drop(lock);
+            if let Err(err) = result {
+                error!("Failed to terminate the session of a deleted user: {}", err);
+            }
+            if let Ok(mut tombstones) = manager.tombstones.lock() {
+                tombstones.remove(&session_id);
+            }
+            // Once the session ended, so that its usage record is closed
+            manager.usage.scrub(&id, Manager::DELETED_USER);
+            manager
+                .audit
+                .scrub(&[id, session_id], Manager::DELETED_USER);
+        });
+        Ok(())
+    }
+
+    /// Restores user `id`, deleted less than the deletion grace period ago, alongside its session
+    pub fn restore_user(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.restore_user");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::USER, id)?;
+        let runtime = new_runtime()?;
+        let existing = runtime
+            .block_on(self.engine.get_user(id))?
+            .ok_or(Error::MissingData("no matching user"))?;
+        if existing.deleted_at.is_none() {
+            return Err(Error::InvalidParameter(format!(
+                "user {} is not deleted",
+                id
+            )));
+        }
+        runtime.block_on(self.engine.update_user_deletion(id, None))?;
+        let session_id = session_id(id);
+        if runtime
+            .block_on(self.engine.get_session(&session_id))?
+            .map_or(false, |session| session.deleted_at.is_some())
+        {
+            self.restore_deleted_session(&user.id, &session_id)?;
+        }
+        self.audit.record(&user.id, "restore_user", id, None);
+        Ok(())
+    }
+
+    /// Returns all data held about user `id`
+    pub fn export_user(&self, user: &LoggedUser, id: &str) -> Result<UserExport> {
+        let _span = telemetry::enter("manager.export_user");
+        if user.id != id && !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let session_id = session_id(id);
+        let runtime = new_runtime()?;
+        Ok(UserExport {
+            id: id.to_string(),
+            user: runtime.block_on(self.engine.get_user(id))?,
+            session: runtime.block_on(self.engine.get_session(&session_id))?,
+            sessions_history: self.usage.history(id),
+            audit_events: self
+                .audit
+                .list()
+                .into_iter()
+                .filter(|event| {
+                    event.actor == id || event.target == id || event.target == session_id
+                })
+                .collect(),
+        })
+    }
+
+    // Sessions
+
+    pub fn get_session(&self, user: &LoggedUser, id: &str) -> Result<Option<Session>> {
+        let _span = telemetry::enter("manager.get_session");
+        if session_id(&user.id) != id && !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let runtime = new_runtime()?;
+        let mut session = runtime.block_on(self.engine.get_session(id))?;
+        if let Some(session) = session.as_mut() {
+            session.dns = runtime.block_on(self.engine.dns_status(session));
+        }
+        if !user.has_admin_read_rights() {
+            // Raw failure details can leak cluster internals
+            if let Some(failure) = session
+                .as_mut()
+                .and_then(|session| session.pod.failure.as_mut())
+            {
+                failure.detail = None;
+            }
+        }
+        Ok(session)
+    }
+
+    /// Records activity of session `id`. Returns the nonce expected by the next heartbeat.
+    pub fn session_heartbeat(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        nonce: Option<&str>,
+    ) -> Result<Heartbeat> {
+        // Only owners keep their session alive
+        if session_id(&user.id) != id {
+            return Err(Error::Unauthorized());
+        }
+
+        let session_id = session_id(id);
+        // Nonces must not be accepted twice by concurrent heartbeats
+        let _lock = self.lock(locks::SESSION, &session_id)?;
+        let runtime = new_runtime()?;
+        let session = runtime
+            .block_on(self.engine.get_session(&session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default()
+        };
+        let now = seconds(self.engine.clock.now());
+        self.heartbeats
+            .check(&session_id, session.last_activity.map(seconds), nonce, now)
+            .map_err(Error::Forbidden)?;
+        runtime.block_on(self.engine.update_session_activity(&session_id, now))?;
+        Ok(Heartbeat {
+            nonce: self.heartbeats.nonce(&session_id, now),
+            interval: self.heartbeats.interval.as_secs(),
+        })
+    }
+
+    /// Returns the nonce expected by the next heartbeat of session `id`, for frontends that lost theirs.
+    /// No activity is recorded.
+    pub fn resync_session_heartbeat(&self, user: &LoggedUser, id: &str) -> Result<Heartbeat> {
+        let _span = telemetry::enter("manager.resync_session_heartbeat");
+        // Only owners keep their session alive
+        if session_id(&user.id) != id {
+            return Err(Error::Unauthorized());
+        }
+
+        let session_id = session_id(id);
+        let session = new_runtime()?
+            .block_on(self.engine.get_session(&session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        // The first heartbeat doesn't need a nonce
+        let nonce = session
+            .last_activity
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| self.heartbeats.nonce(&session_id, duration.as_secs()))
+            .unwrap_or_default();
+        Ok(Heartbeat {
+            nonce,
+            interval: self.heartbeats.interval.as_secs(),
+        })
+    }
+
+    /// Returns uncommitted changes of session `id` workspace, so that users can be warned before losing them
+    pub fn get_session_git_state(&self, user: &LoggedUser, id: &str) -> Result<Option<GitState>> {
+        let _span = telemetry::enter("manager.get_session_git_state");
+        if session_id(&user.id) != id && !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.session_git_state(id))
+    }
+
+    /// Lists kubernetes events about session `id`, e.g. to understand why it fails to start
+    pub fn list_session_events(&self, user: &LoggedUser, id: &str) -> Result<Vec<SessionEvent>> {
+        let _span = telemetry::enter("manager.list_session_events");
+        if session_id(&user.id) != id && !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let mut events =
+            new_runtime()?.block_on(self.engine.list_session_events(&session_id(id)))?;
+        if !user.has_admin_read_rights() {
+            // Raw messages can leak cluster internals
+            for event in events.iter_mut() {
+                event.message = None;
+            }
+        }
+        Ok(events)
+    }
+
+    /// Returns why the pod of session `id` was evicted, unless the session was created again since then
+    pub fn get_session_eviction(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+    ) -> Result<Option<SessionEviction>> {
+        let _span = telemetry::enter("manager.get_session_eviction");
+        if session_id(&user.id) != id && !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let runtime = new_runtime()?;
+        let id = session_id(id);
+        let mut eviction = match runtime.block_on(self.engine.get_eviction(&id))? {
+            Some(eviction) => eviction,
+            None => return Ok(None),
+        };
+        let created_at = runtime
+            .block_on(self.engine.get_session(&id))?
+            .and_then(|session| session.pod.steps.first().and_then(|step| step.completed_at))
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        if created_at.map_or(false, |created_at| created_at.as_secs() > eviction.time) {
+            return Ok(None);
+        }
+        if !user.has_admin_read_rights() {
+            // Raw messages can leak cluster internals
+            eviction.message = None;
+        }
+        Ok(Some(eviction))
+    }
+
+    /// Returns notable events affecting the current session of `user`
+    pub fn get_notifications(&self, user: &LoggedUser) -> Result<Notifications> {
+        let id = session_id(&user.id);
+        let tombstone = self
+            .tombstones
+            .lock()
+            .ok()
+            .and_then(|tombstones| tombstones.get(&id).cloned());
+        Ok(Notifications {
+            eviction: self.get_session_eviction(user, &id)?,
+            tombstone,
+        })
+    }
+
+    /// Lists all sessions. Sessions that can't be read are reported as warnings rather than failing the whole call.
+    pub fn list_sessions(
+        &self,
+        user: &LoggedUser,
+    ) -> Result<(BTreeMap<String, Session>, Vec<String>)> {
+        let _span = telemetry::enter("manager.list_sessions");
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.list_sessions_with_warnings())
+    }
+
+    /// Checks if `user` can create session `id`. Returns the normalized session id.
+    fn check_session_creation(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        conf: &SessionConfiguration,
+    ) -> Result<String> {
+        // Ids can only customized by users with proper rights
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        if new_runtime()?.block_on(self.engine.handed_off()) {
+            return Err(Error::Forbidden(
+                "sessions moved to another cluster".to_string(),
+            ));
+        }
+
+        self.ensure_terms_accepted(user)?;
+
+        if !user.has_admin_edit_rights() {
+            // Members of organizations restricting templates can only use those templates
+            let orgs = new_runtime()?.block_on(self.engine.list_orgs())?;
+            let member_orgs: Vec<&Org> = user
+                .organizations
+                .iter()
+                .filter_map(|org| orgs.get(org))
+                .collect();
+            if !member_orgs.is_empty()
+                && member_orgs.iter().all(|org| {
+                    org.allowed_templates
+                        .as_ref()
+                        .map_or(false, |templates| !templates.contains(&conf.template))
+                })
+            {
+                return Err(Error::Forbidden(format!(
+                    "template {} is not allowed",
+                    conf.template
+                )));
+            }
+        }
+
+        // Handed off sessions keep the duration they had left
+        if conf.duration.is_some() && conf.handoff.is_none() {
+            // Duration can only customized by users with proper rights
+            if !user.can_customize_duration() {
+                return Err(Error::Unauthorized());
+            }
+        }
+        if conf.pool_affinity.is_some() {
+            // Duration can only customized by users with proper rights
+            if !user.can_customize_pool_affinity() {
+                return Err(Error::Unauthorized());
+            }
+        }
+
+        let session_id = session_id(id);
+        // Ensure a workspace with the same id is not alread running. Sessions pending deletion are replaced.
+        if new_runtime()?
+            .block_on(self.engine.get_session(&session_id))?
+            .map_or(false, |session| session.deleted_at.is_none())
+        {
+            return Err(Error::Unauthorized());
+        }
+        Ok(session_id)
+    }
+
+    /// Validates the creation of session `id` and returns what would be created, without touching the cluster
+    pub fn plan_session(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        conf: SessionConfiguration,
+    ) -> Result<SessionPlan> {
+        let _span = telemetry::enter("manager.plan_session");
+        let session_id = self.check_session_creation(user, id, &conf)?;
+        new_runtime()?.block_on(self.engine.plan_session(user, &session_id, &conf))
+    }
+
+    pub fn create_session(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        conf: SessionConfiguration,
+    ) -> Result<()> {
+        let _span = telemetry::enter("manager.create_session");
+        // Held from the existence check so that concurrent creations or deletions can't interleave
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        let session_id = self.check_session_creation(user, id, &conf)?;
+        let mut conf = conf;
+        let runtime = new_runtime()?;
+        let orgs = runtime.block_on(self.engine.list_orgs())?;
+        let budgets: BudgetState = runtime
+            .block_on(self.engine.load_state(Manager::BUDGETS_STATE))
+            .map(|state| {
+                state
+                    .and_then(|state| serde_json::from_str(&state).ok())
+                    .unwrap_or_default()
+            })
+            .unwrap_or_else(|err| {
+                warn!("Failed to load budgets, not enforcing them: {}", err);
+                BudgetState::default()
+            });
+        match self.budgets.check(&budgets, user, &org_budgets(&orgs)) {
+            Decision::Allow => {}
+            Decision::Downgrade(pool) => {
+                info!("Budget exhausted, scheduling {} on {}", session_id, pool);
+                conf.pool_affinity = Some(pool);
+            }
+            Decision::Deny(reason) => return Err(Error::Forbidden(reason)),
+        }
+        self.engine
+            .plugins
+            .pre_create(user, &session_id, &mut conf)?;
+        // Only sessions pending deletion can still be around
+        if new_runtime()?
+            .block_on(self.engine.get_session(&session_id))?
+            .is_some()
+        {
+            self.undeploy_locked_session(&session_id)?;
+        }
+        let _operation = self.operations.begin()?;
 
         let template = conf.clone().template;
-        let result = new_runtime()?.block_on(self.engine.create_session(user, &session_id, conf));
+        let created = conf.clone();
+        let result = new_runtime()?.block_on(traced(
+            "kubernetes.create_session",
+            self.engine.create_session(user, &session_id, conf),
+        ));
+
+        info!("Created session {} with template {}", session_id, template);
+
+        match &result {
+            Ok(_session) => {
+                self.engine.plugins.post_create(user, &session_id, &created);
+                if let Ok(mut tombstones) = self.tombstones.lock() {
+                    tombstones.remove(&session_id);
+                }
+                self.usage
+                    .record_start(&session_id, &user.id, user.role(), &user.organizations);
+                self.metrics
+                    .inc_user_sessions_counter(&user.id, user.role());
+                if let Ok(mut sessions) = self.sessions.lock() {
+                    sessions.insert(session_id);
+                } else {
+                    error!("Failed to acquire sessions lock");
+                }
+                self.metrics.inc_deploy_counter(&template);
+            }
+            Err(e) => {
+                self.metrics.inc_deploy_failures_counter(&template);
+                error!("Error during deployment {}", e);
+            }
+        }
+        if let Err(err) = new_runtime()?.block_on(
+            self.engine
+                .record_template_usage(&template, result.is_err()),
+        ) {
+            warn!("Failed to record usage of template {}: {}", template, err);
+        }
+        result
+    }
+
+    pub fn update_session(
+        &self,
+        id: &str,
+        user: &LoggedUser,
+        conf: SessionUpdateConfiguration,
+    ) -> Result<()> {
+        let _span = telemetry::enter("manager.update_session");
+        if conf.duration.is_some() {
+            // Duration can only customized by users with proper rights
+            if session_id(&user.id) != id && !user.can_customize_duration() {
+                return Err(Error::Unauthorized());
+            }
+        }
+
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        new_runtime()?.block_on(self.engine.update_session(user, &session_id(id), conf))
+    }
+
+    /// Returns true if `user` has a session that joined `workshop`
+    fn is_workshop_member(&self, user: &LoggedUser, workshop: &str) -> Result<bool> {
+        let session = new_runtime()?.block_on(self.engine.get_session(&session_id(&user.id)))?;
+        Ok(session.and_then(|session| session.workshop).as_deref() == Some(workshop))
+    }
+
+    pub fn list_artifacts(&self, user: &LoggedUser, workshop: &str) -> Result<Vec<Artifact>> {
+        let _span = telemetry::enter("manager.list_artifacts");
+        if !user.has_admin_read_rights() && !self.is_workshop_member(user, workshop)? {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.list_artifacts(workshop))
+    }
+
+    pub fn get_artifact(
+        &self,
+        user: &LoggedUser,
+        workshop: &str,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let _span = telemetry::enter("manager.get_artifact");
+        if !user.has_admin_read_rights() && !self.is_workshop_member(user, workshop)? {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.get_artifact(workshop, name))
+    }
+
+    /// Shares `content` with other members of `workshop`
+    pub fn publish_artifact(
+        &self,
+        user: &LoggedUser,
+        workshop: &str,
+        name: &str,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        let _span = telemetry::enter("manager.publish_artifact");
+        if !user.has_admin_edit_rights() && !self.is_workshop_member(user, workshop)? {
+            return Err(Error::Unauthorized());
+        }
+        if !is_valid_artifact_name(name) {
+            return Err(Error::InvalidParameter(format!(
+                "'{}' is not a valid artifact name",
+                name
+            )));
+        }
+
+        new_runtime()?.block_on(self.engine.publish_artifact(workshop, name, content))
+    }
+
+    /// Requests testnet funds on behalf of session `id`, at most once per faucet interval
+    pub fn request_funds(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        request: FaucetRequest,
+    ) -> Result<serde_json::Value> {
+        let _span = telemetry::enter("manager.request_funds");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        let faucet = self
+            .faucet
+            .as_ref()
+            .ok_or_else(|| Error::Forbidden("no faucet configured".to_string()))?;
+        let runtime = new_runtime()?;
+        runtime
+            .block_on(self.engine.get_session(&session_id(id)))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let now = self
+            .engine
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        // Only the last attempt of a conflicting update counts
+        let wait = RefCell::new(None);
+        runtime.block_on(self.engine.update_state(Manager::FAUCET_STATE, |state| {
+            let mut last_requests: BTreeMap<String, u64> = state
+                .and_then(|state| serde_json::from_str(state).ok())
+                .unwrap_or_default();
+            wait.replace(faucet.check(&mut last_requests, &user.id, now).err());
+            serde_json::to_string(&last_requests).map_err(|err| Error::Failure(err.into()))
+        }))?;
+        if let Some(wait) = wait.into_inner() {
+            return Err(Error::Forbidden(format!(
+                "funds already requested, retry in {}s",
+                wait.as_secs()
+            )));
+        }
+
+        let response = runtime.block_on(faucet.fund(&request)).map_err(|err| {
+            // Lets the user try again right away
+            let result =
+                runtime.block_on(self.engine.update_state(Manager::FAUCET_STATE, |state| {
+                    let mut last_requests: BTreeMap<String, u64> = state
+                        .and_then(|state| serde_json::from_str(state).ok())
+                        .unwrap_or_default();
+                    last_requests.remove(&user.id);
+                    serde_json::to_string(&last_requests).map_err(|err| Error::Failure(err.into()))
+                }));
+            if let Err(err) = result {
+                warn!("Failed to forget faucet request of {}: {}", user.id, err);
+            }
+            Error::Failure(err)
+        })?;
+        self.audit
+            .record(&user.id, "request_funds", &request.address, request.chain);
+        Ok(response)
+    }
+
+    /// Exposes the read-only view of session `id` under a tokenized url on a dedicated host, returned.
+    /// Requires `SESSION_AUTH_SECRET`, otherwise the IDE itself is open.
+    pub fn publish_session_viewer(&self, user: &LoggedUser, id: &str) -> Result<String> {
+        let _span = telemetry::enter("manager.publish_session_viewer");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        let tokens = self
+            .session_tokens
+            .as_ref()
+            .ok_or_else(|| Error::Forbidden("viewers require SESSION_AUTH_SECRET".to_string()))?;
+
+        let _operation = self.operations.begin()?;
+        let runtime = new_runtime()?;
+        let session_id = session_id(id);
+        let _lock = self.lock(locks::SESSION, &session_id)?;
+        let session = runtime
+            .block_on(self.engine.get_session(&session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let viewer = session
+            .template
+            .viewer
+            .ok_or_else(|| Error::Forbidden("template has no read-only viewer".to_string()))?;
+        let (label, domain) = session
+            .url
+            .split_once('.')
+            .ok_or(Error::MissingData("session#url"))?;
+        let viewer_label = format!("{}{}", session_auth::VIEWER_HOST_PREFIX, label);
+        if viewer_label.len() > 63 {
+            return Err(Error::Forbidden(
+                "session subdomain is too long to publish a viewer".to_string(),
+            ));
+        }
+        let host = format!("{}.{}", viewer_label, domain);
+        let path = tokens.viewer_path(label);
+        runtime.block_on(self.engine.add_session_port(
+            &session_id,
+            &Port {
+                name: VIEWER_PORT.to_string(),
+                protocol: Some("TCP".to_string()),
+                path: path.clone(),
+                port: viewer.port,
+                target: None,
+            },
+            Some(&host),
+        ))?;
+        self.audit
+            .record(&user.id, "publish_session_viewer", &session_id, None);
+        Ok(format!("{}://{}{}", self.scheme(), host, path))
+    }
+
+    fn scheme(&self) -> &'static str {
+        if self.engine.env.secured {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Returns the url `user` can open session `id` with, embedding a short-lived token if access is restricted
+    pub fn get_session_access_url(&self, user: &LoggedUser, id: &str) -> Result<String> {
+        let _span = telemetry::enter("manager.get_session_access_url");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let session_id = session_id(id);
+        let session = new_runtime()?
+            .block_on(self.engine.get_session(&session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        let url = format!("{}://{}/", self.scheme(), session.url);
+        // Tokens are bound to the subdomain, that is all `authorize_session_access` knows of
+        let subdomain = self
+            .session_subdomain(&session.url)
+            .unwrap_or(session_id.as_str());
+        Ok(match &self.session_tokens {
+            Some(tokens) => format!(
+                "{}?{}={}",
+                url,
+                session_auth::TOKEN_PARAMETER,
+                tokens.handoff(subdomain, &user.id, self.engine.clock.now())
+            ),
+            None => url,
+        })
+    }
+
+    /// Returns the DNS label of session `host`, or `None` if `host` isn't one
+    fn session_subdomain<'a>(&self, host: &'a str) -> Option<&'a str> {
+        match self
+            .engine
+            .configuration
+            .base_domains
+            .iter()
+            .find_map(|domain| host.strip_suffix(domain.as_str())?.strip_suffix('.'))
+        {
+            Some(subdomain) if !subdomain.contains('.') => Some(subdomain),
+            _ => None,
+        }
+    }
+
+    /// Checks a request to `host` originally targeting `url`, authenticated either by an `access` token or a handoff
+    /// token part of `url`. Returns a new access token when authenticated by the latter.
+    /// Hosts other than session ones are left open. Viewer hosts only serve published viewers.
+    pub fn authorize_session_access(
+        &self,
+        host: &str,
+        url: Option<&str>,
+        access: Option<&str>,
+    ) -> Result<Option<String>> {
+        let tokens = match &self.session_tokens {
+            Some(tokens) => tokens,
+            None => return Ok(None),
+        };
+        let subdomain = match self.session_subdomain(host) {
+            Some(subdomain) => subdomain,
+            None => return Ok(None),
+        };
+        if let Some(label) = subdomain.strip_prefix(session_auth::VIEWER_HOST_PREFIX) {
+            let path = url
+                .map(|url| url.split_once("://").map_or(url, |(_, url)| url))
+                .and_then(|url| url.find('/').map(|index| &url[index..]));
+            return if path.map_or(false, |path| tokens.verify_viewer(label, path)) {
+                Ok(None)
+            } else {
+                Err(Error::Unauthorized())
+            };
+        }
+        let now = self.engine.clock.now();
+        if access.map_or(false, |access| {
+            tokens.verify(subdomain, access, now).is_some()
+        }) {
+            return Ok(None);
+        }
+        match url
+            .and_then(session_auth::token_from_url)
+            .and_then(|token| tokens.verify(subdomain, token, now))
+        {
+            Some(user_id) => Ok(Some(tokens.access(subdomain, &user_id, now))),
+            None => Err(Error::Unauthorized()),
+        }
+    }
+
+    /// Revokes the url returned by `publish_session_viewer`
+    pub fn unpublish_session_viewer(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.unpublish_session_viewer");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        new_runtime()?.block_on(
+            self.engine
+                .remove_session_port(&session_id(id), VIEWER_PORT),
+        )
+    }
+
+    /// Recreates the pod of session `id`, keeping its url but not its workspace, see `Engine::restart_session`
+    pub fn restart_session(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.restart_session");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        new_runtime()?.block_on(self.engine.restart_session(&session_id(id)))
+    }
+
+    /// Sets or removes env variables of running session `id`, without recreating it
+    pub fn update_session_env(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        update: SessionEnvUpdate,
+    ) -> Result<BTreeMap<String, String>> {
+        let _span = telemetry::enter("manager.update_session_env");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        if let Some(name) = update.keys().find(|name| !is_valid_env_name(name)) {
+            return Err(Error::InvalidParameter(format!(
+                "'{}' is not a valid env variable name",
+                name
+            )));
+        }
+
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        new_runtime()?.block_on(self.engine.update_session_env(&session_id(id), update))
+    }
+
+    /// Exposes `port` on running session `id`
+    pub fn add_session_port(&self, user: &LoggedUser, id: &str, port: Port) -> Result<()> {
+        let _span = telemetry::enter("manager.add_session_port");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        if port.name == VIEWER_PORT {
+            return Err(Error::InvalidParameter(format!(
+                "Port name {} is reserved for the read-only viewer",
+                VIEWER_PORT
+            )));
+        }
+
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        new_runtime()?.block_on(self.engine.add_session_port(&session_id(id), &port, None))
+    }
+
+    /// Stops exposing port `name` of running session `id`
+    pub fn remove_session_port(&self, user: &LoggedUser, id: &str, name: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.remove_session_port");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, &session_id(id))?;
+        new_runtime()?.block_on(self.engine.remove_session_port(&session_id(id), name))
+    }
+
+    /// Moves session `id` to `pool`. The migration happens in the background and is reported via `Session::migration`.
+    pub fn migrate_session(&self, user: &LoggedUser, id: &str, pool: String) -> Result<()> {
+        let _span = telemetry::enter("manager.migrate_session");
+        if !user.has_admin_edit_rights()
+            && !(session_id(&user.id) == id && user.can_customize_pool_affinity())
+        {
+            return Err(Error::Unauthorized());
+        }
+
+        self.spawn_migration(session_id(id), pool)
+    }
+
+    /// Migrates session `session_id` to `pool` in the background
+    fn spawn_migration(&self, session_id: String, pool: String) -> Result<()> {
+        if let Ok(mut migrations) = self.migrations.lock() {
+            if !migrations.insert(session_id.clone()) {
+                return Err(Error::Forbidden(
+                    "a migration is already in progress".to_string(),
+                ));
+            }
+        }
+        let operation = self.operations.begin()?;
+        let lock = self.lock(locks::SESSION, &session_id)?;
+        let manager = self.clone();
+        thread::spawn(move || {
+            let _operation = operation;
+            let _lock = lock;
+            let result = new_runtime().and_then(|runtime| {
+                runtime.block_on(manager.engine.migrate_session(&session_id, &pool))
+            });
+            match result {
+                Ok(()) => info!("Migrated session {} to {}", session_id, pool),
+                Err(err) => error!("Failed to migrate session {}: {}", session_id, err),
+            }
+            if let Ok(mut migrations) = manager.migrations.lock() {
+                migrations.remove(&session_id);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn delete_session(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.delete_session");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let session_id = session_id(id);
+        if self.deletion_grace_period.is_some() {
+            return self.soft_delete_session(&user.id, &session_id);
+        }
+        self.undeploy_session(&session_id)
+    }
+
+    /// Stops routing traffic to session `session_id` and suspends it, on behalf of `actor`.
+    /// The session can be restored until the deletion grace period is over.
+    fn soft_delete_session(&self, actor: &str, session_id: &str) -> Result<()> {
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, session_id)?;
+        let runtime = new_runtime()?;
+        let session = runtime
+            .block_on(self.engine.get_session(session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        if session.deleted_at.is_some() {
+            return Ok(());
+        }
+        let now = self
+            .engine
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        runtime.block_on(self.engine.soft_delete_session(session_id, now))?;
+        if session.pod.phase != Phase::Suspended {
+            if let Err(err) = runtime.block_on(self.engine.set_session_suspended(session_id, true))
+            {
+                warn!("Failed to suspend session {}: {}", session_id, err);
+            }
+        }
+        self.audit
+            .record(actor, "soft_delete_session", session_id, None);
+        Ok(())
+    }
+
+    /// Restores session `id`, deleted less than the deletion grace period ago
+    pub fn restore_session(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.restore_session");
+        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        self.restore_deleted_session(&user.id, &session_id(id))
+    }
+
+    fn restore_deleted_session(&self, actor: &str, session_id: &str) -> Result<()> {
+        let _operation = self.operations.begin()?;
+        let _lock = self.lock(locks::SESSION, session_id)?;
+        let runtime = new_runtime()?;
+        let session = runtime
+            .block_on(self.engine.get_session(session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        if session.deleted_at.is_none() {
+            return Err(Error::InvalidParameter(format!(
+                "session {} is not deleted",
+                session_id
+            )));
+        }
+        runtime.block_on(self.engine.restore_session(session_id))?;
+        // Sessions suspended by policies stay suspended
+        if !session.flags.contains(&FLAG_SUSPENDED.to_string()) {
+            runtime.block_on(self.engine.set_session_suspended(session_id, false))?;
+        }
+        self.audit
+            .record(actor, "restore_session", session_id, None);
+        Ok(())
+    }
+
+    /// Terminates session `id` regardless of its owner. If `tombstone` is true, `reason` will be displayed to its owner.
+    pub fn terminate_session(
+        &self,
+        user: &LoggedUser,
+        id: &str,
+        reason: String,
+        tombstone: bool,
+    ) -> Result<()> {
+        let _span = telemetry::enter("manager.terminate_session");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let session_id = session_id(id);
+        self.undeploy_session(&session_id)?;
+        self.audit.record(
+            &user.id,
+            "terminate_session",
+            &session_id,
+            Some(reason.clone()),
+        );
+        if tombstone {
+            if let Ok(mut tombstones) = self.tombstones.lock() {
+                tombstones.insert(
+                    session_id.clone(),
+                    Tombstone {
+                        reason,
+                        terminated_at: self.engine.clock.now(),
+                    },
+                );
+            }
+            self.notifier.notify(&session_id);
+        }
+        Ok(())
+    }
 
-        info!("Created session {} with template {}", session_id, template);
+    /// Applies `batch` to all sessions matching its filter, a few at a time. Returns the outcome for each session.
+    pub fn run_session_batch(
+        &self,
+        user: &LoggedUser,
+        batch: SessionBatch,
+    ) -> Result<Vec<SessionBatchResult>> {
+        let _span = telemetry::enter("manager.run_session_batch");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        // Prevents acting on all sessions by mistake
+        if batch.filter.is_empty() {
+            return Err(Error::InvalidParameter(
+                "filter must set at least one criteria".to_string(),
+            ));
+        }
 
-        match &result {
-            Ok(_session) => {
-                if let Ok(mut sessions) = self.sessions.lock() {
-                    sessions.insert(session_id);
-                } else {
-                    error!("Failed to acquire sessions lock");
-                }
-                self.metrics.inc_deploy_counter(&template);
-            }
-            Err(e) => {
-                self.metrics.inc_deploy_failures_counter(&template);
-                error!("Error during deployment {}", e);
+        let runtime = new_runtime()?;
+        let sessions = runtime.block_on(self.engine.list_sessions())?;
+        let pools = match batch.filter.pool {
+            Some(_) => runtime.block_on(self.engine.list_pools())?,
+            None => BTreeMap::new(),
+        };
+        let now = self.engine.clock.now();
+        let matching: Vec<(String, Duration)> = sessions
+            .into_iter()
+            .filter(|(_, session)| matches_filter(session, &batch.filter, &pools, now))
+            .map(|(id, session)| (id, session.duration))
+            .collect();
+        self.audit.record(
+            &user.id,
+            "run_session_batch",
+            &format!("{} sessions", matching.len()),
+            serde_json::to_string(&batch).ok(),
+        );
+
+        let mut results = Vec::with_capacity(matching.len());
+        for chunk in matching.chunks(Self::SESSION_BATCH_CONCURRENCY) {
+            let handles: Vec<(String, JoinHandle<Result<()>>)> = chunk
+                .iter()
+                .map(|(id, duration)| {
+                    let manager = self.clone();
+                    let user = user.clone();
+                    let action = batch.action.clone();
+                    let (id, duration) = (id.clone(), *duration);
+                    let handle = thread::spawn(move || {
+                        manager.apply_session_batch_action(&user, &id, duration, action)
+                    });
+                    (id, handle)
+                })
+                .collect();
+            for (session_id, handle) in handles {
+                let error = match handle.join() {
+                    Ok(Ok(())) => None,
+                    Ok(Err(err)) => Some(err.to_string()),
+                    Err(_) => Some("Unexpected failure".to_string()),
+                };
+                results.push(SessionBatchResult { session_id, error });
             }
         }
-        result
+        Ok(results)
     }
 
-    pub fn update_session(
+    fn apply_session_batch_action(
         &self,
-        id: &str,
         user: &LoggedUser,
-        conf: SessionUpdateConfiguration,
+        id: &str,
+        duration: Duration,
+        action: SessionBatchAction,
     ) -> Result<()> {
-        if conf.duration.is_some() {
-            // Duration can only customized by users with proper rights
-            if session_id(&user.id) != id && !user.can_customize_duration() {
-                return Err(Error::Unauthorized());
+        match action {
+            SessionBatchAction::Delete => self.delete_session(user, id),
+            SessionBatchAction::Extend { minutes } => {
+                let duration = reaper::extended_duration(duration, minutes)
+                    .map_err(Error::InvalidParameter)?;
+                self.update_session(
+                    id,
+                    user,
+                    SessionUpdateConfiguration {
+                        duration: Some(duration),
+                    },
+                )
             }
+            SessionBatchAction::Migrate { pool } => self.migrate_session(user, id, pool),
         }
-
-        new_runtime()?.block_on(self.engine.update_session(&session_id(id), conf))
     }
 
-    pub fn delete_session(&self, user: &LoggedUser, id: &str) -> Result<()> {
-        if session_id(&user.id) != id && !user.has_admin_edit_rights() {
-            return Err(Error::Unauthorized());
+    /// Serializes mutations of resource `id` of `kind` across replicas, see `locks`. The resource is first locked on
+    /// this replica, then via a `Lease`.
+    fn lock(&self, kind: &'static str, id: &str) -> Result<ResourceLock> {
+        let start = Instant::now();
+        let (lock, _) = self.locks.acquire(kind, id)?;
+        let name = locks::lease_name(kind, id);
+        // Unique per holder, so that a lease is only released by whoever acquired it
+        let holder = format!("{}-{}", self.identity, random_token(8));
+        let runtime = new_runtime()?;
+        while !runtime.block_on(self.engine.acquire_lease(&name, &holder, locks::TIMEOUT))? {
+            if start.elapsed() >= locks::TIMEOUT {
+                return Err(Error::Failure(
+                    format!("Timed out waiting for {} {}", kind, id).into(),
+                ));
+            }
+            thread::sleep(locks::POLL_INTERVAL);
         }
+        self.metrics
+            .observe_lock_wait_duration(kind, start.elapsed().as_secs_f64());
+        // Renews the lease while held, as some holders (e.g. migrations) outlive `locks::TIMEOUT`
+        let (stop, stopped) = mpsc::channel::<()>();
+        let renewal = {
+            let engine = self.engine.clone();
+            let (name, holder) = (name.clone(), holder.clone());
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(locks::RENEW_INTERVAL)
+                {
+                    let result = new_runtime().and_then(|runtime| {
+                        runtime.block_on(engine.acquire_lease(&name, &holder, locks::TIMEOUT))
+                    });
+                    match result {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            warn!("Lost lock {} to another replica", name);
+                            break;
+                        }
+                        Err(err) => warn!("Failed to renew lock {}: {}", name, err),
+                    }
+                }
+            })
+        };
+        let engine = self.engine.clone();
+        Ok(lock.with_release(move || {
+            // Renewals must stop first, so that the released lease isn't recreated
+            drop(stop);
+            let _ = renewal.join();
+            let result = new_runtime()
+                .and_then(|runtime| runtime.block_on(engine.release_lease(&name, &holder)));
+            if let Err(err) = result {
+                warn!("Failed to release lock {}: {}", name, err);
+            }
+        }))
+    }
 
-        let session_id = session_id(id);
-        let result = new_runtime()?.block_on(self.engine.delete_session(&session_id));
+    fn undeploy_session(&self, session_id: &str) -> Result<()> {
+        let _lock = self.lock(locks::SESSION, session_id)?;
+        self.undeploy_locked_session(session_id)
+    }
+
+    /// Like `undeploy_session`, for callers already holding the lock of `session_id`
+    fn undeploy_locked_session(&self, session_id: &str) -> Result<()> {
+        let _operation = self.operations.begin()?;
+        let runtime = new_runtime()?;
+        let session = runtime
+            .block_on(self.engine.get_session(session_id))
+            .ok()
+            .flatten();
+        self.engine
+            .plugins
+            .pre_delete(session_id, session.as_ref())?;
+        // Last chance to save the workspace
+        if let Some(session) = session {
+            if let (Some(backup), Phase::Running) = (&session.backup, &session.pod.phase) {
+                if let Err(err) = runtime.block_on(self.engine.backup_session(session_id, backup)) {
+                    warn!("Failed final backup of session {}: {}", session_id, err);
+                }
+            }
+        }
+        let result = runtime.block_on(traced(
+            "kubernetes.delete_session",
+            self.engine.delete_session(session_id),
+        ));
 
         info!("Deleted session {}", session_id);
 
         match &result {
             Ok(_) => {
+                self.record_session_end(session_id);
                 self.metrics.inc_undeploy_counter();
                 if let Ok(mut sessions) = self.sessions.lock() {
-                    sessions.remove(session_id.as_str());
+                    sessions.remove(session_id);
                 } else {
                     error!("Failed to acquire sessions lock");
                 }
@@ -336,6 +2831,711 @@ impl Manager {
         result
     }
 
+    /// Clears policy flags of session `id`, resuming it if it was suspended
+    pub fn resume_session(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _operation = self.operations.begin()?;
+        let session_id = session_id(id);
+        let _lock = self.lock(locks::SESSION, &session_id)?;
+        let runtime = new_runtime()?;
+        let session = runtime
+            .block_on(self.engine.get_session(&session_id))?
+            .ok_or(Error::MissingData("no matching session"))?;
+        // Soft deleted sessions stay suspended until restored
+        if session.flags.contains(&FLAG_SUSPENDED.to_string()) && session.deleted_at.is_none() {
+            runtime.block_on(self.engine.set_session_suspended(&session_id, false))?;
+        }
+        runtime.block_on(self.engine.update_session_flags(&session_id, &[]))?;
+        self.audit.record(
+            &user.id,
+            "resume_session",
+            &session_id,
+            Some(session.flags.join(",")),
+        );
+        Ok(())
+    }
+
+    /// Handles a GitHub webhook delivery, after verifying its signature
+    pub fn handle_github_event(
+        &self,
+        event: &str,
+        signature: Option<&str>,
+        payload: &[u8],
+    ) -> Result<()> {
+        // Read when used, so that rotated secrets are picked up
+        let secret = secrets::get("GITHUB_WEBHOOK_SECRET")
+            .ok_or(Error::MissingData("GITHUB_WEBHOOK_SECRET"))?;
+        if !signature.map_or(false, |signature| {
+            github::verify_signature(&secret, payload, signature)
+        }) {
+            return Err(Error::Unauthorized());
+        }
+
+        let payload: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|err| Error::Failure(err.into()))?;
+        let target = payload["repository"]["full_name"]
+            .as_str()
+            .or_else(|| payload["installation"]["account"]["login"].as_str())
+            .unwrap_or_default();
+        self.audit.record(
+            "github",
+            &format!("github.{}", event),
+            target,
+            payload["action"].as_str().map(str::to_string),
+        );
+        match self
+            .previews
+            .as_ref()
+            .and_then(|previews| previews.request(event, &payload))
+        {
+            Some(request) => self.enqueue_preview(request),
+            None => Ok(()),
+        }
+    }
+
+    /// Queues a preview of a pull request, created by the leader, see `start_previews`
+    fn enqueue_preview(&self, request: PreviewRequest) -> Result<()> {
+        let session_id = request.session_id();
+        self.audit.record(
+            preview::ACTOR,
+            "create_preview",
+            &session_id,
+            Some(format!(
+                "{}#{} requested by {}",
+                request.repository, request.number, request.requester
+            )),
+        );
+        new_runtime()?.block_on(self.update_previews(|previews| {
+            // Previews already created are updated with the new head
+            previews.insert(
+                session_id.clone(),
+                PendingPreview {
+                    request: request.clone(),
+                    created: false,
+                },
+            );
+        }))
+    }
+
+    /// Applies `update` to queued previews
+    async fn update_previews<F: Fn(&mut BTreeMap<String, PendingPreview>)>(
+        &self,
+        update: F,
+    ) -> Result<()> {
+        self.engine
+            .update_state(Manager::PREVIEWS_STATE, |state| {
+                let mut previews = state
+                    .and_then(|state| serde_json::from_str(state).ok())
+                    .unwrap_or_default();
+                update(&mut previews);
+                serde_json::to_string(&previews).map_err(|err| Error::Failure(err.into()))
+            })
+            .await
+    }
+
+    /// Creates the session previewing a pull request, unless it already exists. Its head is checked out once the
+    /// session runs.
+    fn create_preview(&self, runtime: &Runtime, request: &PreviewRequest) -> Result<()> {
+        let _span = telemetry::enter("manager.create_preview");
+        let session_id = request.session_id();
+        if runtime
+            .block_on(self.engine.get_session(&session_id))?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let configuration = &self.engine.configuration;
+        // Previews are created on behalf of the playground, not of the requester
+        let user = LoggedUser {
+            id: preview::ACTOR.to_string(),
+            admin: true,
+            organizations: Vec::new(),
+            pool_affinity: None,
+            can_customize_duration: true,
+            can_customize_pool_affinity: true,
+            onboarding: OnboardingState::Completed,
+            accepted_terms_version: configuration.legal.terms_version.clone(),
+            org_role: None,
+        };
+        let conf = SessionConfiguration {
+            template: request.template.clone(),
+            duration: self
+                .previews
+                .as_ref()
+                .and_then(|previews| previews.duration),
+            pool_affinity: None,
+            domain: None,
+            backup: None,
+            workshop: None,
+            handoff: None,
+            // No one sends heartbeats for previews
+            unattended: true,
+        };
+        self.create_session(&user, &session_id, conf)
+    }
+
+    /// Creates sessions of queued previews, then checks out pull requests in those now running and posts their url.
+    /// Failures are reported on the pull request.
+    fn start_previews(&self, runtime: &Runtime) {
+        let previews: BTreeMap<String, PendingPreview> =
+            match runtime.block_on(self.engine.load_state(Manager::PREVIEWS_STATE)) {
+                Ok(state) => state
+                    .and_then(|state| serde_json::from_str(&state).ok())
+                    .unwrap_or_default(),
+                Err(err) => {
+                    warn!("Failed to load previews: {}", err);
+                    return;
+                }
+            };
+        let build_command = self
+            .previews
+            .as_ref()
+            .and_then(|previews| previews.build_command.as_deref());
+        for (id, preview) in previews {
+            let request = &preview.request;
+            let body = if request.private {
+                "Previews of private repositories aren't supported".to_string()
+            } else if !preview.created {
+                match self.create_preview(runtime, request) {
+                    Ok(()) => {
+                        self.mark_preview_created(runtime, &id, &preview);
+                        continue;
+                    }
+                    Err(err) => format!("Failed to create preview: {}", err),
+                }
+            } else {
+                let session = match runtime.block_on(self.engine.get_session(&id)) {
+                    Ok(Some(session)) => session,
+                    // Deleted before it started
+                    Ok(None) => {
+                        self.forget_preview(runtime, &id, &preview);
+                        continue;
+                    }
+                    Err(err) => {
+                        warn!("Failed to get preview session {}: {}", id, err);
+                        continue;
+                    }
+                };
+                match session.pod.phase {
+                    Phase::Running => match runtime.block_on(self.engine.checkout_session(
+                        &id,
+                        &request.remote(),
+                        &request.head_ref(),
+                        build_command,
+                    )) {
+                        Ok(commit) => format!(
+                            "Preview of {} is available at {}://{}/ for {} minutes",
+                            commit,
+                            self.scheme(),
+                            session.url,
+                            session.duration.as_secs() / 60
+                        ),
+                        Err(err) => format!("Failed to check out this pull request: {}", err),
+                    },
+                    Phase::Failed | Phase::Succeeded | Phase::Suspended => {
+                        format!("Preview session {} stopped before it could start", id)
+                    }
+                    Phase::Pending | Phase::Unknown => continue,
+                }
+            };
+            self.comment_preview(runtime, request, &body);
+            self.forget_preview(runtime, &id, &preview);
+        }
+    }
+
+    fn mark_preview_created(&self, runtime: &Runtime, id: &str, preview: &PendingPreview) {
+        let result = runtime.block_on(self.update_previews(|previews| {
+            // Unless requested again meanwhile
+            if let Some(current) = previews.get_mut(id).filter(|current| **current == *preview) {
+                current.created = true;
+            }
+        }));
+        if let Err(err) = result {
+            warn!("Failed to update preview {}: {}", id, err);
+        }
+    }
+
+    fn forget_preview(&self, runtime: &Runtime, id: &str, preview: &PendingPreview) {
+        let result = runtime.block_on(self.update_previews(|previews| {
+            // Unless requested again meanwhile
+            if previews.get(id) == Some(preview) {
+                previews.remove(id);
+            }
+        }));
+        if let Err(err) = result {
+            warn!("Failed to forget preview {}: {}", id, err);
+        }
+    }
+
+    /// Posts `body` on the pull request `request` was made from, on a best effort basis
+    fn comment_preview(&self, runtime: &Runtime, request: &PreviewRequest, body: &str) {
+        let app = match &self.engine.secrets.github_app {
+            Some(app) => app,
+            None => {
+                warn!(
+                    "No GitHub App configured, can't comment on {}#{}",
+                    request.repository, request.number
+                );
+                return;
+            }
+        };
+        let result = runtime.block_on(async {
+            let token = app.installation_token().await?;
+            github::create_issue_comment(&token.token, &request.repository, request.number, body)
+                .await
+        });
+        if let Err(err) = result {
+            warn!(
+                "Failed to comment on {}#{}: {}",
+                request.repository, request.number, err
+            );
+        }
+    }
+
+    pub fn list_reservations(&self, user: &LoggedUser) -> Result<BTreeMap<String, Reservation>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.list_reservations())
+    }
+
+    /// Sets aside capacity for a workshop. Returns the reservation id.
+    pub fn create_reservation(
+        &self,
+        user: &LoggedUser,
+        reservation: Reservation,
+    ) -> Result<String> {
+        let _span = telemetry::enter("manager.create_reservation");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        if reservation.slots == 0 || reservation.start >= reservation.end {
+            return Err(Error::InvalidParameter("reservation".to_string()));
+        }
+        validate_workshop(&reservation.workshop)?;
+
+        let _lock = self.lock(locks::RESERVATIONS, locks::ALL)?;
+        let runtime = new_runtime()?;
+        runtime
+            .block_on(self.engine.get_pool(&reservation.pool))?
+            .ok_or(Error::MissingData("no matching pool"))?;
+        let now = self
+            .engine
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let id = random_token(8).to_lowercase();
+        let details = format!(
+            "{} slots of {} for {}",
+            reservation.slots, reservation.pool, reservation.workshop
+        );
+        runtime.block_on(self.engine.update_reservations(|reservations| {
+            // Forget about past reservations
+            reservations.retain(|_, reservation| reservation.end > now);
+            reservations.insert(id.clone(), reservation.clone());
+            Ok(())
+        }))?;
+        self.audit
+            .record(&user.id, "create_reservation", &id, Some(details));
+        Ok(id)
+    }
+
+    pub fn delete_reservation(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.delete_reservation");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::RESERVATIONS, locks::ALL)?;
+        let runtime = new_runtime()?;
+        runtime.block_on(self.engine.update_reservations(|reservations| {
+            reservations
+                .remove(id)
+                .map(|_| ())
+                .ok_or(Error::MissingData("no matching reservation"))
+        }))?;
+        self.audit.record(&user.id, "delete_reservation", id, None);
+        Ok(())
+    }
+
+    /// Returns organizations `user` can manage
+    pub fn list_orgs(&self, user: &LoggedUser) -> Result<BTreeMap<String, Org>> {
+        let orgs = new_runtime()?.block_on(self.engine.list_orgs())?;
+        if user.has_admin_read_rights() {
+            return Ok(orgs);
+        }
+
+        Ok(orgs
+            .into_iter()
+            .filter(|(_, org)| org.admins.contains(&user.id))
+            .collect())
+    }
+
+    pub fn get_org(&self, user: &LoggedUser, id: &str) -> Result<Option<Org>> {
+        let org = new_runtime()?.block_on(self.engine.list_orgs())?.remove(id);
+        let is_org_admin = org
+            .as_ref()
+            .map_or(false, |org| org.admins.contains(&user.id));
+        if !is_org_admin && !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        Ok(org)
+    }
+
+    /// Creates or replaces settings of organization `id`.
+    /// Organization admins can update allowed templates. Only admins can create organizations or change other settings.
+    pub fn update_org(&self, user: &LoggedUser, id: &str, org: Org) -> Result<()> {
+        let _span = telemetry::enter("manager.update_org");
+        if !is_valid_user_id(id) {
+            return Err(Error::InvalidParameter(format!("org id {}", id)));
+        }
+
+        let _lock = self.lock(locks::ORG, id)?;
+        let runtime = new_runtime()?;
+        if !user.has_admin_edit_rights() {
+            // Settings granting privileges or resources are left to admins
+            match runtime.block_on(self.engine.list_orgs())?.get(id) {
+                Some(existing)
+                    if existing.admins.contains(&user.id)
+                        && existing.admins == org.admins
+                        && existing.can_customize_duration == org.can_customize_duration
+                        && existing.can_customize_pool_affinity
+                            == org.can_customize_pool_affinity
+                        && existing.pool_affinity == org.pool_affinity
+                        && existing.budget == org.budget
+                        && existing.role == org.role => {}
+                _ => return Err(Error::Unauthorized()),
+            }
+        }
+        if let Some(role) = &org.role {
+            // Built-in roles are granted by other means
+            if ["admin", "paritytech"].contains(&role.as_str())
+                || !self.engine.configuration.session.roles.contains_key(role)
+            {
+                return Err(Error::InvalidParameter(format!("role {}", role)));
+            }
+        }
+        if let Some(pool_affinity) = &org.pool_affinity {
+            runtime
+                .block_on(self.engine.get_pool(pool_affinity))?
+                .ok_or(Error::MissingData("no matching pool"))?;
+        }
+
+        runtime.block_on(self.engine.store_org(id, &org))?;
+        self.audit.record(&user.id, "update_org", id, None);
+        Ok(())
+    }
+
+    pub fn delete_org(&self, user: &LoggedUser, id: &str) -> Result<()> {
+        let _span = telemetry::enter("manager.delete_org");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let _lock = self.lock(locks::ORG, id)?;
+        new_runtime()?.block_on(self.engine.delete_org(id))?;
+        self.audit.record(&user.id, "delete_org", id, None);
+        Ok(())
+    }
+
+    /// Returns the last diagnostics, running them now if none are recent enough
+    pub fn get_diagnostics(&self, user: &LoggedUser) -> Result<Diagnostics> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let current = self
+            .diagnostics
+            .lock()
+            .map_err(|_| Error::Failure("Failed to acquire diagnostics lock".into()))?
+            .clone();
+        // Diagnostics are refreshed by the leader only
+        match current {
+            Some(diagnostics)
+                if diagnostics
+                    .checked_at
+                    .elapsed()
+                    .map_or(false, |elapsed| elapsed < Manager::DIAGNOSTICS_TTL) =>
+            {
+                Ok(diagnostics)
+            }
+            _ => self.diagnose(&new_runtime()?),
+        }
+    }
+
+    pub fn list_audit_events(&self, user: &LoggedUser) -> Result<Vec<AuditEvent>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        Ok(self.audit.list())
+    }
+
+    /// Returns recent webhook deliveries, including dead letters. Empty if webhooks are not configured.
+    pub fn list_webhook_deliveries(&self, user: &LoggedUser) -> Result<Vec<WebhookDelivery>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        match self.audit.webhooks() {
+            Some(webhooks) => Ok(new_runtime()?.block_on(webhooks.list(&self.engine))),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn record_session_end(&self, session_id: &str) {
+        if let Some(record) = self.usage.record_end(session_id) {
+            let minutes = record
+                .ended_at
+                .and_then(|end| end.duration_since(record.started_at).ok())
+                .unwrap_or_default()
+                .as_secs()
+                / 60;
+            self.metrics
+                .inc_user_session_minutes_counter(&record.user_id, &record.role, minutes);
+        }
+    }
+
+    // Usage
+
+    /// Returns per user sessions usage between `from` and `to`, expressed in seconds since epoch
+    pub fn get_usage(
+        &self,
+        user: &LoggedUser,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Result<BTreeMap<String, UserUsage>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let from = to_system_time(from, UNIX_EPOCH);
+        let to = to_system_time(to, self.engine.clock.now());
+        if from > to {
+            return Err(Error::InvalidParameter(
+                "from must be before to".to_string(),
+            ));
+        }
+        Ok(self.usage.report(from, to))
+    }
+
+    pub fn get_storage_version(
+        &self,
+        user: &LoggedUser,
+    ) -> Result<BTreeMap<String, StorageVersion>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.storage_versions())
+    }
+
+    /// Returns a copy of the state managed by the playground, see `Engine::export_state`
+    pub fn backup_state(&self, user: &LoggedUser) -> Result<StateArchive> {
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let archive = new_runtime()?.block_on(self.engine.export_state())?;
+        self.audit
+            .record(&user.id, "backup_state", &self.engine.env.namespace, None);
+        Ok(archive)
+    }
+
+    /// Replaces the state managed by the playground with `archive`, e.g. when moving to a new cluster. Sessions handed
+    /// off by the previous cluster are then re-created on behalf of their owner, as known from restored users. Their
+    /// workspace is restored once running.
+    pub fn restore_state(
+        &self,
+        user: &LoggedUser,
+        mut archive: StateArchive,
+    ) -> Result<RestoreReport> {
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let handoffs = std::mem::take(&mut archive.sessions);
+        let details = format!(
+            "archive of {} with {} sessions",
+            archive.created_at,
+            handoffs.len()
+        );
+        {
+            let _lock = self.lock(locks::RESERVATIONS, locks::ALL)?;
+            new_runtime()?.block_on(self.engine.import_state(archive))?;
+        }
+        self.audit.record(
+            &user.id,
+            "restore_state",
+            &self.engine.env.namespace,
+            Some(details),
+        );
+
+        let mut report = RestoreReport::default();
+        if handoffs.is_empty() {
+            return Ok(report);
+        }
+        let users = new_runtime()?.block_on(self.engine.list_users())?;
+        for handoff in handoffs {
+            let id = session_id(&handoff.id);
+            let owner = session_owner(&users, &id);
+            let result = SessionDuration::from_minutes(handoff.remaining.max(1))
+                .map_err(Error::InvalidParameter)
+                .and_then(|duration| {
+                    let conf = SessionConfiguration {
+                        template: handoff.template.name.clone(),
+                        duration: Some(duration),
+                        pool_affinity: None,
+                        // Base domains might differ on this cluster
+                        domain: Some(handoff.domain).filter(|domain| {
+                            self.engine.configuration.base_domains.contains(domain)
+                        }),
+                        backup: None,
+                        workshop: handoff.workshop,
+                        handoff: Some(handoff.template),
+                        unattended: false,
+                    };
+                    self.create_session(&owner, &id, conf)
+                });
+            match result {
+                Ok(()) => report.sessions.push(id),
+                Err(err) => {
+                    warn!("Failed to re-create handed off session {}: {}", id, err);
+                    report.failed.insert(id, err.to_string());
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Moves running sessions to another cluster. Their workspace is pushed to `TEMPLATE_SNAPSHOT_REMOTE`, then they are
+    /// stopped and returned along with the state to restore on the new cluster. Sessions that can't be moved are
+    /// reported. From then on, this playground refuses new sessions and leaves DNS records to the new cluster.
+    pub fn handoff_sessions(&self, user: &LoggedUser) -> Result<HandoffReport> {
+        let _span = telemetry::enter("manager.handoff_sessions");
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+        let remote = self
+            .engine
+            .configuration
+            .template_snapshot_remote
+            .as_ref()
+            .ok_or(Error::MissingData("TEMPLATE_SNAPSHOT_REMOTE"))?;
+
+        let _operation = self.operations.begin()?;
+        let runtime = new_runtime()?;
+        runtime.block_on(self.engine.start_handoff())?;
+        let mut archive = runtime.block_on(self.engine.export_state())?;
+        let mut skipped = BTreeMap::new();
+        let sessions = runtime.block_on(self.engine.list_sessions())?;
+        for (id, session) in sessions {
+            if session.deleted_at.is_some() {
+                skipped.insert(id, "pending deletion".to_string());
+                continue;
+            }
+            if session.pod.phase != Phase::Running {
+                skipped.insert(id, format!("not running ({:?})", session.pod.phase));
+                continue;
+            }
+            let result = self.lock(locks::SESSION, &id).and_then(|_lock| {
+                runtime.block_on(self.engine.handoff_session(
+                    &id,
+                    remote,
+                    self.engine.secrets.template_snapshot_token.as_deref(),
+                    &format!("handoffs/{}", id),
+                ))
+            });
+            let commit = match result {
+                Ok(commit) => commit,
+                Err(err) => {
+                    warn!("Failed to hand off session {}: {}", id, err);
+                    skipped.insert(id, err.to_string());
+                    continue;
+                }
+            };
+            let remaining = reaper::remaining(
+                &*self.engine.clock,
+                session.pod.start_time,
+                session.duration,
+            );
+            let mut template = session.template;
+            template.snapshot = Some(WorkspaceSnapshot {
+                remote: remote.clone(),
+                commit,
+            });
+            archive.sessions.push(SessionHandoff {
+                id,
+                template,
+                remaining: remaining.as_secs() / 60,
+                domain: session.domain,
+                workshop: session.workshop,
+            });
+        }
+        self.audit.record(
+            &user.id,
+            "handoff_sessions",
+            &self.engine.env.namespace,
+            Some(format!(
+                "{} sessions, {} skipped",
+                archive.sessions.len(),
+                skipped.len()
+            )),
+        );
+        Ok(HandoffReport { archive, skipped })
+    }
+
+    /// Returns sessions created per template over the last `days` (30 by default)
+    pub fn get_template_analytics(
+        &self,
+        user: &LoggedUser,
+        days: Option<u64>,
+    ) -> Result<BTreeMap<String, TemplateAnalytics>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+        let days = days.unwrap_or(30);
+        if days == 0 || days > TEMPLATE_ANALYTICS_RETENTION_DAYS {
+            return Err(Error::InvalidParameter(format!(
+                "days must be between 1 and {}",
+                TEMPLATE_ANALYTICS_RETENTION_DAYS
+            )));
+        }
+
+        let today = self
+            .engine
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() / 86400)
+            .unwrap_or_default();
+        let mut analytics: BTreeMap<String, TemplateAnalytics> = BTreeMap::new();
+        for (_, templates) in new_runtime()?
+            .block_on(self.engine.template_analytics())?
+            .into_iter()
+            .filter(|(day, _)| day + days > today)
+        {
+            for (template, stats) in templates {
+                let entry = analytics.entry(template).or_default();
+                entry.sessions += stats.created;
+                entry.failures += stats.failed;
+            }
+        }
+        for entry in analytics.values_mut() {
+            if entry.sessions > 0 {
+                entry.failure_rate = entry.failures as f64 / entry.sessions as f64;
+            }
+        }
+        Ok(analytics)
+    }
+
     // Pools
 
     pub fn get_pool(&self, user: &LoggedUser, pool_id: &str) -> Result<Option<Pool>> {
@@ -353,4 +3553,60 @@ impl Manager {
 
         new_runtime()?.block_on(self.clone().engine.list_pools())
     }
+
+    /// Lists pools `usable_by` can create sessions on. Only available for the caller itself.
+    pub fn list_usable_pools(
+        &self,
+        user: &LoggedUser,
+        usable_by: &str,
+    ) -> Result<BTreeMap<String, UsablePool>> {
+        let _span = telemetry::enter("manager.list_usable_pools");
+        if user.id != usable_by {
+            return Err(Error::Forbidden(
+                "usable pools can only be listed for the caller".to_string(),
+            ));
+        }
+
+        new_runtime()?.block_on(self.engine.list_usable_pools(user))
+    }
+
+    /// Pulls images of all templates, or only `template_id`, on every node of `pool_id`
+    pub fn prepull_pool(
+        &self,
+        user: &LoggedUser,
+        pool_id: &str,
+        template_id: Option<String>,
+    ) -> Result<()> {
+        if !user.has_admin_edit_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        let runtime = new_runtime()?;
+        let pool = runtime
+            .block_on(self.engine.get_pool(pool_id))?
+            .ok_or_else(|| Error::InvalidParameter(format!("Unknown pool {}", pool_id)))?;
+        let mut templates = runtime.block_on(self.engine.clone().list_templates())?;
+        if let Some(template_id) = &template_id {
+            let template = templates.remove(template_id).ok_or_else(|| {
+                Error::InvalidParameter(format!("Unknown template {}", template_id))
+            })?;
+            templates = BTreeMap::from([(template_id.clone(), template)]);
+        }
+        runtime.block_on(self.engine.prepull(&pool, &templates))?;
+        self.audit
+            .record(&user.id, "prepull_pool", pool_id, template_id);
+        Ok(())
+    }
+
+    pub fn get_prepull_status(
+        &self,
+        user: &LoggedUser,
+        pool_id: &str,
+    ) -> Result<BTreeMap<String, BTreeMap<String, PrepullStatus>>> {
+        if !user.has_admin_read_rights() {
+            return Err(Error::Unauthorized());
+        }
+
+        new_runtime()?.block_on(self.engine.prepull_status(pool_id))
+    }
 }
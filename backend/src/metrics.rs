@@ -1,5 +1,10 @@
 use prometheus::{
-    exponential_buckets, histogram_opts, opts, Error, HistogramVec, IntCounterVec, Registry,
+    exponential_buckets, histogram_opts, opts, Error, HistogramVec, IntCounterVec, IntGaugeVec,
+    Registry,
+};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
 };
 
 #[derive(Debug, Clone)]
@@ -9,38 +14,89 @@ pub struct Metrics {
     undeploy_counter: IntCounterVec,
     undeploy_failures_counter: IntCounterVec,
     deploy_duration: HistogramVec,
+    user_sessions_counter: IntCounterVec,
+    user_session_minutes_counter: IntCounterVec,
+    ingress_drift: IntGaugeVec,
+    lock_wait_duration: HistogramVec,
+    user_labels: Arc<Mutex<HashSet<String>>>,
 }
 
 impl Metrics {
+    /// Prefix added to all metric names by the `Registry`
+    pub const PREFIX: &'static str = "playground";
+    pub const DEPLOY_COUNTER: &'static str = "deploy_counter";
+    pub const DEPLOY_FAILURES_COUNTER: &'static str = "deploy_failures_counter";
+    pub const UNDEPLOY_COUNTER: &'static str = "undeploy_counter";
+    pub const UNDEPLOY_FAILURES_COUNTER: &'static str = "undeploy_failures_counter";
+    pub const DEPLOY_DURATION: &'static str = "deploy_duration";
+    pub const INGRESS_DRIFT: &'static str = "ingress_drift";
+    pub const LOCK_WAIT_DURATION: &'static str = "lock_wait_duration";
     const TEMPLATE_LABEL: &'static str = "template";
+    const USER_LABEL: &'static str = "user";
+    const ROLE_LABEL: &'static str = "role";
+    const KIND_LABEL: &'static str = "kind";
+    /// Maximum number of distinct users tracked. Others are aggregated under `OTHER_USER`.
+    const MAX_USER_LABELS: usize = 500;
+    const OTHER_USER: &'static str = "other";
 
     pub fn new() -> Result<Self, Error> {
         let opts = histogram_opts!(
-            "deploy_duration",
+            Self::DEPLOY_DURATION,
             "Deployment duration in seconds",
             exponential_buckets(1.0, 2.0, 8).unwrap()
         );
+        let lock_wait_opts = histogram_opts!(
+            Self::LOCK_WAIT_DURATION,
+            "Time spent waiting for resource locks in seconds",
+            exponential_buckets(0.01, 4.0, 8).unwrap()
+        );
         Ok(Metrics {
             deploy_counter: IntCounterVec::new(
-                opts!("deploy_counter", "Count of deployments"),
+                opts!(Self::DEPLOY_COUNTER, "Count of deployments"),
                 &[Self::TEMPLATE_LABEL],
             )?,
             deploy_failures_counter: IntCounterVec::new(
-                opts!("deploy_failures_counter", "Count of deployment failures"),
+                opts!(
+                    Self::DEPLOY_FAILURES_COUNTER,
+                    "Count of deployment failures"
+                ),
                 &[Self::TEMPLATE_LABEL],
             )?,
             undeploy_counter: IntCounterVec::new(
-                opts!("undeploy_counter", "Count of undeployment"),
+                opts!(Self::UNDEPLOY_COUNTER, "Count of undeployment"),
                 &[],
             )?,
             undeploy_failures_counter: IntCounterVec::new(
                 opts!(
-                    "undeploy_failures_counter",
+                    Self::UNDEPLOY_FAILURES_COUNTER,
                     "Count of undeployment failures"
                 ),
                 &[],
             )?,
             deploy_duration: HistogramVec::new(opts, &[])?,
+            user_sessions_counter: IntCounterVec::new(
+                opts!(
+                    "user_sessions_counter",
+                    "Count of sessions created per user"
+                ),
+                &[Self::USER_LABEL, Self::ROLE_LABEL],
+            )?,
+            user_session_minutes_counter: IntCounterVec::new(
+                opts!(
+                    "user_session_minutes_counter",
+                    "Total minutes of sessions per user"
+                ),
+                &[Self::USER_LABEL, Self::ROLE_LABEL],
+            )?,
+            ingress_drift: IntGaugeVec::new(
+                opts!(
+                    Self::INGRESS_DRIFT,
+                    "Count of ingress rules and services out of sync with sessions"
+                ),
+                &[Self::KIND_LABEL],
+            )?,
+            lock_wait_duration: HistogramVec::new(lock_wait_opts, &[Self::KIND_LABEL])?,
+            user_labels: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
@@ -51,12 +107,62 @@ impl Metrics {
         registry.register(Box::new(self.undeploy_counter))?;
         registry.register(Box::new(self.undeploy_failures_counter))?;
         registry.register(Box::new(self.deploy_duration))?;
+        registry.register(Box::new(self.user_sessions_counter))?;
+        registry.register(Box::new(self.user_session_minutes_counter))?;
+        registry.register(Box::new(self.ingress_drift))?;
+        registry.register(Box::new(self.lock_wait_duration))?;
         Ok(())
     }
 }
 
 // Helper functions
 impl Metrics {
+    /// Returns the fully qualified name of metric `name`, as exposed to Prometheus
+    pub fn qualified_name(name: &str) -> String {
+        format!("{}_{}", Self::PREFIX, name)
+    }
+
+    /// Returns the label used for `user`, making sure the number of distinct labels stays bounded
+    fn user_label(&self, user: &str) -> String {
+        match self.user_labels.lock() {
+            Ok(mut labels) => {
+                if labels.contains(user) || labels.len() < Self::MAX_USER_LABELS {
+                    labels.insert(user.to_string());
+                    user.to_string()
+                } else {
+                    Self::OTHER_USER.to_string()
+                }
+            }
+            Err(_) => Self::OTHER_USER.to_string(),
+        }
+    }
+
+    pub fn inc_user_sessions_counter(&self, user: &str, role: &str) {
+        self.user_sessions_counter
+            .with_label_values(&[&self.user_label(user), role])
+            .inc();
+    }
+
+    pub fn inc_user_session_minutes_counter(&self, user: &str, role: &str, minutes: u64) {
+        self.user_session_minutes_counter
+            .with_label_values(&[&self.user_label(user), role])
+            .inc_by(minutes);
+    }
+
+    /// Records the drift found by the last reconciliation, per `kind`
+    pub fn set_ingress_drift(&self, kind: &str, count: usize) {
+        self.ingress_drift
+            .with_label_values(&[kind])
+            .set(count as i64);
+    }
+
+    /// Records the time spent waiting for a lock on a resource of `kind`
+    pub fn observe_lock_wait_duration(&self, kind: &str, duration: f64) {
+        self.lock_wait_duration
+            .with_label_values(&[kind])
+            .observe(duration);
+    }
+
     pub fn inc_deploy_counter(&self, template: &str) {
         self.deploy_counter.with_label_values(&[template]).inc();
     }
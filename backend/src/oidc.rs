@@ -0,0 +1,188 @@
+//! OpenID Connect single sign-on, alongside GitHub
+//!
+//! Enabled when `OIDC_ISSUER_URL` is set, e.g. to a Keycloak realm or an Azure AD tenant. Endpoints are discovered from
+//! the issuer at startup. Users are identified by their `sub` claim, as `oidc--<sub>` (see `user_id`), so that they
+//! can't be mistaken for GitHub users. Groups listed in the `OIDC_GROUPS_CLAIM` claim (defaults to `groups`) are
+//! mapped to playground roles via `OIDC_ROLES`, e.g. `playground-admins=admin,students=user`. They are exposed as
+//! `oidc:<group>` organizations, never matching GitHub organizations settings.
+use hyper::{
+    body::{self, Buf},
+    client::HttpConnector,
+    header::{AUTHORIZATION, USER_AGENT},
+    Body, Client, Request,
+};
+use hyper_tls::HttpsConnector;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, env, error::Error as StdError, str::FromStr};
+
+/// Prefix of ids of OIDC users. GitHub logins can't contain consecutive hyphens.
+const USER_PREFIX: &str = "oidc--";
+/// Prefix of OIDC groups, as exposed in `LoggedUser::organizations`
+const GROUP_PREFIX: &str = "oidc:";
+
+/// Marker type of the OIDC `rocket_oauth2` provider
+pub struct OidcUser;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can access the playground, like whitelisted users
+    User,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {}", s)),
+        }
+    }
+}
+
+/// Endpoints advertised by the issuer, see https://openid.net/specs/openid-connect-discovery-1_0.html
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Endpoints {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcConfiguration {
+    pub issuer_url: String,
+    pub client_id: String,
+    #[serde(skip)]
+    pub scopes: Vec<String>,
+    #[serde(skip)]
+    pub groups_claim: String,
+    #[serde(skip)]
+    pub roles: BTreeMap<String, Role>,
+    #[serde(skip)]
+    pub endpoints: Endpoints,
+}
+
+/// A user as described by the issuer
+#[derive(Clone, Debug)]
+pub struct OidcIdentity {
+    /// The `sub` claim, unique for this issuer
+    pub subject: String,
+    pub groups: Vec<String>,
+}
+
+/// Returns the id of the user identified by `subject`. Ids are also used in DNS labels and resource names: other
+/// subjects are hashed.
+pub fn user_id(subject: &str) -> String {
+    let valid = !subject.is_empty()
+        && subject.len() <= 48
+        && subject
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if valid {
+        format!("{}{}", USER_PREFIX, subject)
+    } else {
+        let digest: String = Sha256::digest(subject.as_bytes())
+            .iter()
+            .take(16)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}{}", USER_PREFIX, digest)
+    }
+}
+
+/// Returns `groups` as organizations of a `LoggedUser`
+pub fn organizations(groups: &[String]) -> Vec<String> {
+    groups
+        .iter()
+        .map(|group| format!("{}{}", GROUP_PREFIX, group))
+        .collect()
+}
+
+async fn get<T: DeserializeOwned>(uri: &str, token: Option<&str>) -> Result<T, Box<dyn StdError>> {
+    let mut builder = Request::builder()
+        .uri(uri)
+        .header(USER_AGENT, "Substrate Playground");
+    if let Some(token) = token {
+        builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+    let response = client.request(builder.body(Body::empty())?).await?;
+    let status = response.status();
+    let whole_body = body::aggregate(response).await?;
+    if !status.is_success() {
+        return Err(format!("{} returned {}", uri, status).into());
+    }
+    Ok(serde_json::from_reader(whole_body.reader())?)
+}
+
+impl OidcConfiguration {
+    /// Reads the configuration from env variables and discovers the issuer endpoints. Returns `None` if not configured.
+    pub async fn from_env() -> Result<Option<Self>, Box<dyn StdError>> {
+        let issuer_url = match env::var("OIDC_ISSUER_URL") {
+            Ok(issuer_url) => issuer_url.trim_end_matches('/').to_string(),
+            Err(_) => return Ok(None),
+        };
+        let client_id = env::var("OIDC_CLIENT_ID").map_err(|_| "missing OIDC_CLIENT_ID")?;
+        let roles = env::var("OIDC_ROLES")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|mapping| !mapping.trim().is_empty())
+            .map(|mapping| {
+                let (group, role) = mapping
+                    .trim()
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid OIDC_ROLES mapping {}", mapping))?;
+                Ok((group.to_string(), role.parse()?))
+            })
+            .collect::<Result<BTreeMap<String, Role>, String>>()?;
+        let endpoints = get(
+            &format!("{}/.well-known/openid-configuration", issuer_url),
+            None,
+        )
+        .await?;
+        Ok(Some(OidcConfiguration {
+            issuer_url,
+            client_id,
+            scopes: env::var("OIDC_SCOPES")
+                .unwrap_or_else(|_| "openid profile".to_string())
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            groups_claim: env::var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| "groups".to_string()),
+            roles,
+            endpoints,
+        }))
+    }
+
+    /// Returns the identity associated to the access `token`
+    pub async fn user_info(&self, token: &str) -> Result<OidcIdentity, Box<dyn StdError>> {
+        let claims: serde_json::Value = get(&self.endpoints.userinfo_endpoint, Some(token)).await?;
+        let subject = claims
+            .get("sub")
+            .and_then(|subject| subject.as_str())
+            .ok_or("missing claim sub")?
+            .to_string();
+        let groups = match claims.get(&self.groups_claim) {
+            Some(serde_json::Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_string))
+                .collect(),
+            Some(serde_json::Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        Ok(OidcIdentity { subject, groups })
+    }
+
+    /// Returns the highest role granted to `groups`
+    pub fn role(&self, groups: &[String]) -> Option<Role> {
+        groups
+            .iter()
+            .filter_map(|group| self.roles.get(group).copied())
+            .max()
+    }
+}
@@ -0,0 +1,213 @@
+//! Session lifecycle hooks
+//!
+//! Deployments with specific policies (naming conventions, extra sidecars, billing, ...) implement `Plugin` and add it
+//! to `registered`, rather than patching `manager.rs`. Plugins are compiled in. Hooks run in registration order, and
+//! those returning a `Result` can reject the operation.
+use crate::{
+    error::{Error, Result},
+    types::{LoggedUser, Session, SessionConfiguration},
+};
+use k8s_openapi::api::core::v1::Pod;
+use log::warn;
+use std::sync::Arc;
+
+pub trait Plugin: Send + Sync {
+    /// Identifies the plugin in logs and errors
+    fn name(&self) -> &str;
+
+    /// Called before session `session_id` is created on behalf of `user`. `conf` can be amended.
+    fn pre_create(
+        &self,
+        _user: &LoggedUser,
+        _session_id: &str,
+        _conf: &mut SessionConfiguration,
+    ) -> std::result::Result<(), String> {
+        Ok(())
+    }
+
+    /// Called on the pod of session `session_id` before it is deployed, e.g. to add sidecars
+    fn customize_pod(&self, _session_id: &str, _pod: &mut Pod) {}
+
+    /// Called once session `session_id` is created. Failures are only logged.
+    fn post_create(
+        &self,
+        _user: &LoggedUser,
+        _session_id: &str,
+        _conf: &SessionConfiguration,
+    ) -> std::result::Result<(), String> {
+        Ok(())
+    }
+
+    /// Called before session `session_id` is deleted. `session` is unset if it can't be accessed anymore. Expired
+    /// sessions are deleted even if rejected.
+    fn pre_delete(
+        &self,
+        _session_id: &str,
+        _session: Option<&Session>,
+    ) -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Plugins of this build. Deployment-specific ones are added here.
+pub fn registered() -> Plugins {
+    Plugins::default()
+}
+
+/// Registered plugins, in order
+#[derive(Clone, Default)]
+pub struct Plugins(Vec<Arc<dyn Plugin>>);
+
+impl Plugins {
+    // Only called from deployment-specific builds, see `registered`
+    #[allow(dead_code)]
+    pub fn register(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.0.push(Arc::new(plugin));
+        self
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|plugin| plugin.name()).collect()
+    }
+
+    fn rejected(plugin: &Arc<dyn Plugin>, reason: String) -> Error {
+        Error::Forbidden(format!("rejected by {}: {}", plugin.name(), reason))
+    }
+
+    pub fn pre_create(
+        &self,
+        user: &LoggedUser,
+        session_id: &str,
+        conf: &mut SessionConfiguration,
+    ) -> Result<()> {
+        for plugin in &self.0 {
+            plugin
+                .pre_create(user, session_id, conf)
+                .map_err(|reason| Self::rejected(plugin, reason))?;
+        }
+        Ok(())
+    }
+
+    pub fn customize_pod(&self, session_id: &str, pod: &mut Pod) {
+        for plugin in &self.0 {
+            plugin.customize_pod(session_id, pod);
+        }
+    }
+
+    pub fn post_create(&self, user: &LoggedUser, session_id: &str, conf: &SessionConfiguration) {
+        for plugin in &self.0 {
+            if let Err(err) = plugin.post_create(user, session_id, conf) {
+                warn!(
+                    "Plugin {} failed after creation of {}: {}",
+                    plugin.name(),
+                    session_id,
+                    err
+                );
+            }
+        }
+    }
+
+    pub fn pre_delete(&self, session_id: &str, session: Option<&Session>) -> Result<()> {
+        for plugin in &self.0 {
+            plugin
+                .pre_delete(session_id, session)
+                .map_err(|reason| Self::rejected(plugin, reason))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sets the workshop of sessions that have none
+    struct DefaultWorkshop;
+
+    impl Plugin for DefaultWorkshop {
+        fn name(&self) -> &str {
+            "default-workshop"
+        }
+
+        fn pre_create(
+            &self,
+            _user: &LoggedUser,
+            _session_id: &str,
+            conf: &mut SessionConfiguration,
+        ) -> std::result::Result<(), String> {
+            conf.workshop.get_or_insert_with(|| "default".to_string());
+            Ok(())
+        }
+    }
+
+    /// Rejects sessions of a workshop
+    struct ClosedWorkshop(&'static str);
+
+    impl Plugin for ClosedWorkshop {
+        fn name(&self) -> &str {
+            "closed-workshop"
+        }
+
+        fn pre_create(
+            &self,
+            _user: &LoggedUser,
+            _session_id: &str,
+            conf: &mut SessionConfiguration,
+        ) -> std::result::Result<(), String> {
+            if conf.workshop.as_deref() == Some(self.0) {
+                return Err(format!("workshop {} is closed", self.0));
+            }
+            Ok(())
+        }
+    }
+
+    fn user() -> LoggedUser {
+        LoggedUser {
+            id: "jdoe".to_string(),
+            admin: false,
+            organizations: Vec::new(),
+            pool_affinity: None,
+            can_customize_duration: false,
+            can_customize_pool_affinity: false,
+            onboarding: Default::default(),
+            accepted_terms_version: None,
+            org_role: None,
+        }
+    }
+
+    fn conf() -> SessionConfiguration {
+        SessionConfiguration {
+            template: "node".to_string(),
+            duration: None,
+            pool_affinity: None,
+            domain: None,
+            backup: None,
+            workshop: None,
+            handoff: None,
+            unattended: false,
+        }
+    }
+
+    #[test]
+    fn runs_pre_create_hooks_in_order() {
+        let plugins = Plugins::default()
+            .register(DefaultWorkshop)
+            .register(ClosedWorkshop("other"));
+        assert_eq!(plugins.names(), vec!["default-workshop", "closed-workshop"]);
+        let mut conf = conf();
+        assert!(plugins.pre_create(&user(), "jdoe", &mut conf).is_ok());
+        assert_eq!(conf.workshop.as_deref(), Some("default"));
+
+        // Later plugins see what earlier ones amended
+        let plugins = Plugins::default()
+            .register(DefaultWorkshop)
+            .register(ClosedWorkshop("default"));
+        match plugins.pre_create(&user(), "jdoe", &mut conf()) {
+            Err(Error::Forbidden(reason)) => assert_eq!(
+                reason,
+                "rejected by closed-workshop: workshop default is closed"
+            ),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Heuristics flagging sessions abusing resources, typically cryptominers
+//!
+//! Sessions are periodically sampled: running processes are matched against a denylist and sustained CPU usage is tracked.
+use std::{
+    collections::BTreeMap,
+    env,
+    sync::{Arc, Mutex},
+};
+
+/// Flag set when a denylisted process is found running. Suffixed with the process name.
+pub const FLAG_DENYLISTED_PROCESS: &str = "denylisted-process";
+/// Flag set when CPU usage stayed over the threshold for too many consecutive samples
+pub const FLAG_SUSTAINED_CPU: &str = "sustained-cpu";
+/// Flag set when all session processes have been stopped pending admin review
+pub const FLAG_SUSPENDED: &str = "suspended";
+
+const DEFAULT_PROCESS_DENYLIST: &[&str] = &[
+    "xmrig",
+    "xmr-stak",
+    "minerd",
+    "cpuminer",
+    "cgminer",
+    "bfgminer",
+    "ethminer",
+    "nbminer",
+    "t-rex",
+    "lolminer",
+    "phoenixminer",
+    "nanominer",
+];
+
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// Process names (or fragments) considered suspicious
+    pub process_denylist: Vec<String>,
+    /// CPU usage, in millicores, above which a sample is considered high
+    pub cpu_threshold: u64,
+    /// Number of consecutive high samples before flagging a session
+    pub cpu_samples: u32,
+    /// If true, flagged sessions are suspended until an admin resumes them
+    pub auto_suspend: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            process_denylist: DEFAULT_PROCESS_DENYLIST
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            cpu_threshold: 1800,
+            cpu_samples: 10,
+            auto_suspend: false,
+        }
+    }
+}
+
+impl Policy {
+    /// Reads the policy from `POLICY_*` env variables, falling back to defaults
+    pub fn from_env() -> Self {
+        let defaults = Policy::default();
+        Policy {
+            process_denylist: env::var("POLICY_PROCESS_DENYLIST")
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|name| name.trim().to_lowercase())
+                        .filter(|name| !name.is_empty())
+                        .collect()
+                })
+                .unwrap_or(defaults.process_denylist),
+            cpu_threshold: env::var("POLICY_CPU_THRESHOLD")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(defaults.cpu_threshold),
+            cpu_samples: env::var("POLICY_CPU_SAMPLES")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(defaults.cpu_samples),
+            auto_suspend: env::var("POLICY_AUTO_SUSPEND").map_or(false, |value| value == "true"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Analyzer {
+    pub policy: Policy,
+    /// Number of consecutive high CPU samples, per session
+    high_cpu_samples: Arc<Mutex<BTreeMap<String, u32>>>,
+}
+
+impl Analyzer {
+    pub fn new(policy: Policy) -> Self {
+        Analyzer {
+            policy,
+            high_cpu_samples: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Matches the output of `ps -eo comm` against the denylist
+    pub fn check_processes(&self, processes: &str) -> Vec<String> {
+        let mut flags: Vec<String> = processes
+            .lines()
+            .skip(1) // Header
+            .map(|process| process.trim().to_lowercase())
+            .filter_map(|process| {
+                self.policy
+                    .process_denylist
+                    .iter()
+                    .find(|name| process.contains(name.as_str()))
+                    .map(|name| format!("{}:{}", FLAG_DENYLISTED_PROCESS, name))
+            })
+            .collect();
+        flags.sort();
+        flags.dedup();
+        flags
+    }
+
+    /// Records a CPU sample for `session_id`. Returns a flag once usage has been high for long enough.
+    pub fn check_cpu(&self, session_id: &str, millicores: u64) -> Option<String> {
+        let mut samples = self.high_cpu_samples.lock().ok()?;
+        if millicores < self.policy.cpu_threshold {
+            samples.remove(session_id);
+            return None;
+        }
+        let count = samples.entry(session_id.to_string()).or_insert(0);
+        *count += 1;
+        if *count >= self.policy.cpu_samples {
+            Some(FLAG_SUSTAINED_CPU.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Forgets samples of sessions not in `session_ids`
+    pub fn retain(&self, session_ids: &[String]) {
+        if let Ok(mut samples) = self.high_cpu_samples.lock() {
+            samples.retain(|id, _| session_ids.contains(id));
+        }
+    }
+}
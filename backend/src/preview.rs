@@ -0,0 +1,181 @@
+//! Ephemeral preview sessions of pull requests
+//!
+//! Commenting `/playground preview` on a pull request of a repository listed in `PREVIEW_TEMPLATES` (e.g.
+//! `paritytech/substrate-node-template=node-template`) creates a session based on the matching template. Once running,
+//! the pull request head is checked out in the session workspace, `PREVIEW_BUILD_COMMAND` is started in the background
+//! and the session url is posted back to the pull request. Previews last `PREVIEW_DURATION` minutes if set, or the
+//! default session duration. Comments are posted via the GitHub App, that must be allowed to write issues. Only owners,
+//! members and collaborators of a repository can request previews.
+//!
+//! Requests are queued in the backend state, so that webhooks are answered right away, and handled by the leader.
+//! Pull requests are checked out anonymously: previews of private repositories are refused.
+use crate::types::SessionDuration;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, env};
+
+pub const COMMAND: &str = "/playground preview";
+/// Actor of preview sessions, as seen in audit events
+pub const ACTOR: &str = "github";
+/// Author associations allowed to request previews
+const TRUSTED_ASSOCIATIONS: [&str; 3] = ["OWNER", "MEMBER", "COLLABORATOR"];
+/// Keeps session ids, and thus hosts, short enough to be DNS labels
+const MAX_NAME_LENGTH: usize = 40;
+
+#[derive(Clone, Debug)]
+pub struct Previews {
+    /// Template used for each repository, indexed by full name
+    templates: BTreeMap<String, String>,
+    pub duration: Option<SessionDuration>,
+    /// Started in the workspace once the pull request is checked out
+    pub build_command: Option<String>,
+}
+
+/// A preview requested on a pull request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewRequest {
+    /// Full name of the repository, e.g. `paritytech/substrate-node-template`
+    pub repository: String,
+    pub number: u64,
+    pub template: String,
+    /// GitHub login of the commenter
+    pub requester: String,
+    /// Private repositories can't be checked out
+    pub private: bool,
+}
+
+/// A queued preview, until its session runs
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingPreview {
+    pub request: PreviewRequest,
+    /// Set once its session is created
+    pub created: bool,
+}
+
+impl PreviewRequest {
+    /// Id of the preview session, stable for a given pull request
+    pub fn session_id(&self) -> String {
+        let name: String = self
+            .repository
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .take(MAX_NAME_LENGTH)
+            .collect();
+        format!("pr-{}-{}", name.trim_matches('-'), self.number)
+    }
+
+    pub fn remote(&self) -> String {
+        format!("https://github.com/{}.git", self.repository)
+    }
+
+    /// Ref of the pull request head, also available for pull requests opened from forks
+    pub fn head_ref(&self) -> String {
+        format!("refs/pull/{}/head", self.number)
+    }
+}
+
+impl Previews {
+    /// Returns `None` if `PREVIEW_TEMPLATES` is unset or empty
+    pub fn from_env() -> Option<Self> {
+        let templates: BTreeMap<String, String> = env::var("PREVIEW_TEMPLATES")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|mapping| {
+                let (repository, template) = mapping.trim().split_once('=')?;
+                Some((repository.to_string(), template.to_string()))
+            })
+            .collect();
+        if templates.is_empty() {
+            return None;
+        }
+        Some(Previews {
+            templates,
+            duration: env::var("PREVIEW_DURATION")
+                .ok()
+                .and_then(|duration| duration.parse().ok()),
+            build_command: env::var("PREVIEW_BUILD_COMMAND")
+                .ok()
+                .filter(|command| !command.trim().is_empty()),
+        })
+    }
+
+    /// Returns the preview requested by a GitHub `event`, if any. Only new `issue_comment` on pull requests are
+    /// considered.
+    pub fn request(&self, event: &str, payload: &Value) -> Option<PreviewRequest> {
+        if event != "issue_comment" || payload["action"] != "created" {
+            return None;
+        }
+        let issue = &payload["issue"];
+        // Issues and pull requests share comments, only the latter have a `pull_request` field
+        if issue["pull_request"].is_null() {
+            return None;
+        }
+        let comment = &payload["comment"];
+        if !comment["body"]
+            .as_str()?
+            .lines()
+            .any(|line| line.trim() == COMMAND)
+        {
+            return None;
+        }
+        if !TRUSTED_ASSOCIATIONS.contains(&comment["author_association"].as_str()?) {
+            return None;
+        }
+        let repository = payload["repository"]["full_name"].as_str()?;
+        Some(PreviewRequest {
+            repository: repository.to_string(),
+            number: issue["number"].as_u64()?,
+            template: self.templates.get(repository)?.clone(),
+            requester: comment["user"]["login"].as_str()?.to_string(),
+            private: payload["repository"]["private"]
+                .as_bool()
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_requests() {
+        let previews = Previews {
+            templates: vec![("a/b".to_string(), "node-template".to_string())]
+                .into_iter()
+                .collect(),
+            duration: None,
+            build_command: None,
+        };
+        let payload = |association: &str, private: bool| {
+            json!({
+                "action": "created",
+                "issue": { "number": 1, "pull_request": {} },
+                "comment": { "body": COMMAND, "author_association": association, "user": { "login": "u" } },
+                "repository": { "full_name": "a/b", "private": private },
+            })
+        };
+        assert_eq!(
+            previews.request("issue_comment", &payload("MEMBER", true)),
+            Some(PreviewRequest {
+                repository: "a/b".to_string(),
+                number: 1,
+                template: "node-template".to_string(),
+                requester: "u".to_string(),
+                private: true,
+            })
+        );
+        assert_eq!(
+            previews.request("issue_comment", &payload("NONE", false)),
+            None
+        );
+        assert_eq!(previews.request("issues", &payload("MEMBER", false)), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! Token bucket based rate limiting of API calls
+//!
+//! Limits are expressed in requests per minute and applied per caller (user id or IP) and `EndpointClass`.
+use crate::{versioning, Context};
+use rocket::{
+    http::{Method, Status},
+    request::{self, FromRequest, Request},
+    Outcome, State,
+};
+use std::{
+    collections::HashMap,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EndpointClass {
+    Read,
+    Mutation,
+    SessionCreation,
+}
+
+impl EndpointClass {
+    pub fn of(request: &Request) -> Self {
+        let path = versioning::unversioned(request.uri().path());
+        match request.method() {
+            Method::Get | Method::Head | Method::Options => EndpointClass::Read,
+            Method::Put if path == "/api/session" || path.starts_with("/api/sessions/") => {
+                EndpointClass::SessionCreation
+            }
+            _ => EndpointClass::Mutation,
+        }
+    }
+}
+
+/// Maximum number of requests per minute, per `EndpointClass`
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub reads: u32,
+    pub mutations: u32,
+    pub session_creations: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            reads: 120,
+            mutations: 30,
+            session_creations: 5,
+        }
+    }
+}
+
+fn env_or(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+impl Limits {
+    /// Reads limits from `RATE_LIMIT_*` env variables, falling back to defaults
+    pub fn from_env() -> Self {
+        let defaults = Limits::default();
+        Limits {
+            reads: env_or("RATE_LIMIT_READS", defaults.reads),
+            mutations: env_or("RATE_LIMIT_MUTATIONS", defaults.mutations),
+            session_creations: env_or("RATE_LIMIT_SESSION_CREATIONS", defaults.session_creations),
+        }
+    }
+
+    fn per_minute(&self, class: EndpointClass) -> u32 {
+        match class {
+            EndpointClass::Read => self.reads,
+            EndpointClass::Mutation => self.mutations,
+            EndpointClass::SessionCreation => self.session_creations,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    /// Returns the number of tokens available at `now`
+    fn tokens_at(&self, now: Instant, refill_per_sec: f64, capacity: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        (self.tokens + elapsed * refill_per_sec).min(capacity)
+    }
+}
+
+pub struct RateLimiter {
+    limits: Limits,
+    buckets: Mutex<HashMap<(String, EndpointClass), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Buckets are pruned once this number of callers is reached
+    const MAX_BUCKETS: usize = 10_000;
+    /// Share of `MAX_BUCKETS` kept when pruning isn't enough, so that eviction doesn't run on each call
+    const EVICTION_TARGET: usize = Self::MAX_BUCKETS * 9 / 10;
+
+    pub fn new(limits: Limits) -> Self {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the capacity of buckets of `class` and their refill rate, in tokens per second
+    fn rates(&self, class: EndpointClass) -> (f64, f64) {
+        let capacity = f64::from(self.limits.per_minute(class));
+        (capacity, capacity / 60.0)
+    }
+
+    /// Consumes a token for `key`. Returns how long to wait before retrying if none is left.
+    pub fn check(&self, key: &str, class: EndpointClass) -> Result<(), Duration> {
+        let (capacity, refill_per_sec) = self.rates(class);
+        let now = Instant::now();
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            // Do not block calls if the limiter is broken
+            Err(_) => return Ok(()),
+        };
+        if buckets.len() >= Self::MAX_BUCKETS {
+            // Full buckets behave exactly as missing ones
+            buckets.retain(|(_, class), bucket| {
+                let (capacity, refill_per_sec) = self.rates(*class);
+                bucket.tokens_at(now, refill_per_sec, capacity) < capacity
+            });
+        }
+        if buckets.len() >= Self::MAX_BUCKETS {
+            // Too many active callers, forget the least recently seen ones
+            let mut updates: Vec<Instant> =
+                buckets.values().map(|bucket| bucket.updated_at).collect();
+            updates.sort_unstable();
+            let cutoff = updates[buckets.len() - Self::EVICTION_TARGET];
+            buckets.retain(|_, bucket| bucket.updated_at >= cutoff);
+        }
+        let bucket = buckets.entry((key.to_string(), class)).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        bucket.tokens = bucket.tokens_at(now, refill_per_sec, capacity);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if refill_per_sec > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / refill_per_sec,
+            ))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+}
+
+/// Number of seconds to wait before retrying, cached on rate limited requests
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryAfter(pub u64);
+
+/// Consumes a token for `key` on behalf of `request`, failing with `429` if none is left
+pub fn limit(request: &Request, key: &str) -> request::Outcome<(), String> {
+    let state = request
+        .guard::<State<Context>>()
+        .map_failure(|_f| (Status::BadRequest, "Can't access state".to_string()))?;
+    match state.rate_limiter.check(key, EndpointClass::of(request)) {
+        Ok(()) => Outcome::Success(()),
+        Err(retry_after) => {
+            let retry_after = RetryAfter(retry_after.as_secs().max(1));
+            request.local_cache(|| retry_after);
+            Outcome::Failure((Status::TooManyRequests, "Too many requests".to_string()))
+        }
+    }
+}
+
+/// Returns the key anonymous calls of `request` are limited by, i.e. its client IP
+pub fn client_key(request: &Request) -> String {
+    request
+        .client_ip()
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+}
+
+/// A request guard rate limiting anonymous calls, keyed by client IP
+pub struct RateLimit;
+
+impl<'a, 'r> FromRequest<'a, 'r> for RateLimit {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RateLimit, String> {
+        limit(request, &client_key(request)).map(|_| RateLimit)
+    }
+}
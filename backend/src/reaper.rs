@@ -0,0 +1,191 @@
+//! Decisions of the reaper, that undeploys sessions once expired or idle and purges those pending deletion
+//!
+//! Times are read from a `Clock`, see `Manager::spawn_background_thread` for how they are applied.
+use crate::{clock::Clock, types::SessionDuration};
+use std::time::{Duration, SystemTime};
+
+/// Returns true if a session started at `start_time` outlived its `duration`
+pub fn is_expired(clock: &dyn Clock, start_time: SystemTime, duration: Duration) -> bool {
+    clock
+        .elapsed(start_time)
+        .map_or(false, |elapsed| elapsed > duration)
+}
+
+/// Returns true if a session had no activity since `last_activity` for longer than `idle_timeout`, if any
+pub fn is_idle(
+    clock: &dyn Clock,
+    last_activity: SystemTime,
+    idle_timeout: Option<Duration>,
+) -> bool {
+    idle_timeout.map_or(false, |idle_timeout| {
+        clock
+            .elapsed(last_activity)
+            .map_or(false, |idle| idle > idle_timeout)
+    })
+}
+
+/// Returns true if a running session has to be undeployed. Sessions without heartbeat are considered active when they
+/// started, those not started yet are left alone.
+pub fn should_undeploy(
+    clock: &dyn Clock,
+    start_time: Option<SystemTime>,
+    last_activity: Option<SystemTime>,
+    duration: Duration,
+    idle_timeout: Option<Duration>,
+) -> bool {
+    match start_time {
+        Some(start_time) => {
+            is_expired(clock, start_time, duration)
+                || is_idle(clock, last_activity.unwrap_or(start_time), idle_timeout)
+        }
+        None => false,
+    }
+}
+
+/// Returns true once a session deleted at `deleted_at` can't be restored anymore
+pub fn is_purgeable(
+    clock: &dyn Clock,
+    deleted_at: Option<SystemTime>,
+    grace_period: Duration,
+) -> bool {
+    deleted_at
+        .and_then(|deleted_at| clock.elapsed(deleted_at))
+        .map_or(false, |elapsed| elapsed >= grace_period)
+}
+
+/// Returns what is left of `duration` for a session started at `start_time`
+pub fn remaining(
+    clock: &dyn Clock,
+    start_time: Option<SystemTime>,
+    duration: Duration,
+) -> Duration {
+    let elapsed = start_time
+        .and_then(|start_time| clock.elapsed(start_time))
+        .unwrap_or_default();
+    duration.checked_sub(elapsed).unwrap_or_default()
+}
+
+/// Returns `duration` extended by `minutes`, within `SessionDuration` bounds
+pub fn extended_duration(duration: Duration, minutes: u64) -> Result<SessionDuration, String> {
+    SessionDuration::from_minutes(duration.as_secs() / 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+    use std::time::UNIX_EPOCH;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn clock() -> (FakeClock, SystemTime) {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        (FakeClock::new(start), start)
+    }
+
+    #[test]
+    fn expires_once_duration_is_exceeded() {
+        let (clock, start) = clock();
+        let duration = 60 * MINUTE;
+        assert!(!is_expired(&clock, start, duration));
+        clock.advance(duration);
+        assert!(!is_expired(&clock, start, duration));
+        clock.advance(Duration::from_secs(1));
+        assert!(is_expired(&clock, start, duration));
+    }
+
+    #[test]
+    fn start_in_the_future_is_not_expired() {
+        let (clock, start) = clock();
+        assert!(!is_expired(&clock, start + MINUTE, Duration::default()));
+    }
+
+    #[test]
+    fn idles_from_last_activity() {
+        let (clock, start) = clock();
+        let timeout = Some(10 * MINUTE);
+        clock.advance(8 * MINUTE);
+        let activity = clock.now();
+        clock.advance(8 * MINUTE);
+        assert!(!should_undeploy(
+            &clock,
+            Some(start),
+            Some(activity),
+            60 * MINUTE,
+            timeout
+        ));
+        assert!(should_undeploy(
+            &clock,
+            Some(start),
+            None,
+            60 * MINUTE,
+            timeout
+        ));
+        clock.advance(3 * MINUTE);
+        assert!(should_undeploy(
+            &clock,
+            Some(start),
+            Some(activity),
+            60 * MINUTE,
+            timeout
+        ));
+    }
+
+    #[test]
+    fn never_idles_without_timeout() {
+        let (clock, start) = clock();
+        clock.advance(59 * MINUTE);
+        assert!(!should_undeploy(
+            &clock,
+            Some(start),
+            None,
+            60 * MINUTE,
+            None
+        ));
+    }
+
+    #[test]
+    fn pending_sessions_are_not_undeployed() {
+        let (clock, _) = clock();
+        clock.advance(24 * 60 * MINUTE);
+        assert!(!should_undeploy(&clock, None, None, MINUTE, Some(MINUTE)));
+    }
+
+    #[test]
+    fn extension_postpones_expiry() {
+        let (clock, start) = clock();
+        let duration = 60 * MINUTE;
+        clock.advance(59 * MINUTE);
+        let extended = extended_duration(duration, 30).unwrap().as_duration();
+        clock.advance(2 * MINUTE);
+        assert!(is_expired(&clock, start, duration));
+        assert!(!is_expired(&clock, start, extended));
+        assert_eq!(remaining(&clock, Some(start), extended), 29 * MINUTE);
+        clock.advance(30 * MINUTE);
+        assert!(is_expired(&clock, start, extended));
+        assert_eq!(
+            remaining(&clock, Some(start), extended),
+            Duration::default()
+        );
+    }
+
+    #[test]
+    fn extension_is_bounded() {
+        let almost_max = Duration::from_secs((SessionDuration::MAX_MINUTES - 1) * 60);
+        assert!(extended_duration(almost_max, 1).is_ok());
+        assert!(extended_duration(almost_max, 2).is_err());
+        // Leftover seconds are not rounded up
+        assert!(extended_duration(almost_max + Duration::from_secs(59), 1).is_ok());
+    }
+
+    #[test]
+    fn purges_after_grace_period() {
+        let (clock, start) = clock();
+        let grace_period = 24 * 60 * MINUTE;
+        assert!(!is_purgeable(&clock, None, Duration::default()));
+        clock.advance(grace_period - Duration::from_secs(1));
+        assert!(!is_purgeable(&clock, Some(start), grace_period));
+        clock.advance(Duration::from_secs(1));
+        assert!(is_purgeable(&clock, Some(start), grace_period));
+    }
+}
@@ -0,0 +1,225 @@
+//! Container registry utilities
+//!
+//! Implements the subset of the Docker Registry HTTP API v2 needed to check that images exist and to resolve tags to digests.
+//! Only public images are supported, using anonymous tokens when the registry requires them.
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use std::{collections::BTreeMap, error::Error as StdError};
+
+const DOCKER_HUB: &str = "registry-1.docker.io";
+/// Well known public registries, that images can be checked against on behalf of any user
+pub const PUBLIC_REGISTRIES: &[&str] = &[DOCKER_HUB, "ghcr.io", "gcr.io", "quay.io"];
+const MANIFEST_TYPES: &str = "application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.docker.distribution.manifest.v2+json, application/vnd.oci.image.index.v1+json, application/vnd.oci.image.manifest.v1+json";
+const DIGEST_HEADER: &str = "Docker-Content-Digest";
+
+/// A parsed image name, e.g. `paritytech/substrate-playground-template-base:latest`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageReference {
+    /// Image name without tag nor digest, as provided
+    pub name: String,
+    pub registry: String,
+    pub repository: String,
+    /// A tag or a digest
+    pub reference: String,
+}
+
+impl ImageReference {
+    pub fn parse(image: &str) -> Option<Self> {
+        let (image, digest) = match image.split_once('@') {
+            Some((image, digest)) => (image, Some(digest)),
+            None => (image, None),
+        };
+        // A colon after the last slash separates the tag, other ones are registry ports
+        let (name, tag) = match image.rfind(':') {
+            Some(i) if !image[i..].contains('/') => (&image[..i], &image[i + 1..]),
+            _ => (image, "latest"),
+        };
+        // Digests win over tags, e.g. in `name:tag@sha256:...`
+        let reference = digest.unwrap_or(tag);
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), path.to_string())
+            }
+            Some(_) => (DOCKER_HUB.to_string(), name.to_string()),
+            None => (DOCKER_HUB.to_string(), format!("library/{}", name)),
+        };
+        if repository.is_empty() || reference.is_empty() {
+            return None;
+        }
+        Some(ImageReference {
+            name: name.to_string(),
+            registry,
+            repository,
+            reference: reference.to_string(),
+        })
+    }
+
+    pub fn is_digest(&self) -> bool {
+        self.reference.starts_with("sha256:")
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+fn create_client() -> Client<HttpsConnector<HttpConnector>> {
+    Client::builder().build(HttpsConnector::new())
+}
+
+fn manifest_request(uri: &str, token: Option<&str>) -> Result<Request<Body>, hyper::http::Error> {
+    let mut builder = Request::builder()
+        .method(Method::HEAD)
+        .uri(uri)
+        .header(ACCEPT, MANIFEST_TYPES);
+    if let Some(token) = token {
+        builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    builder.body(Body::empty())
+}
+
+/// Parses a `Bearer realm="...",service="...",scope="..."` challenge
+fn parse_challenge(challenge: &str) -> Option<BTreeMap<String, String>> {
+    let params = challenge.strip_prefix("Bearer ")?;
+    Some(
+        params
+            .split(',')
+            .filter_map(|param| {
+                let (key, value) = param.trim().split_once('=')?;
+                Some((key.to_string(), value.trim_matches('"').to_string()))
+            })
+            .collect(),
+    )
+}
+
+async fn anonymous_token(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    challenge: &str,
+) -> Result<String, Box<dyn StdError>> {
+    let params = parse_challenge(challenge).ok_or("Unsupported authentication challenge")?;
+    let realm = params.get("realm").ok_or("Missing realm")?;
+    let query: Vec<String> = ["service", "scope"]
+        .iter()
+        .filter_map(|key| params.get(*key).map(|value| format!("{}={}", key, value)))
+        .collect();
+    let response = client
+        .get(format!("{}?{}", realm, query.join("&")).parse()?)
+        .await?;
+    let bytes = body::to_bytes(response.into_body()).await?;
+    let response: TokenResponse = serde_json::from_slice(&bytes)?;
+    response
+        .token
+        .or(response.access_token)
+        .ok_or_else(|| "Missing token".into())
+}
+
+async fn head_manifest(image: &ImageReference) -> Result<Response<Body>, Box<dyn StdError>> {
+    let uri = format!(
+        "https://{}/v2/{}/manifests/{}",
+        image.registry, image.repository, image.reference
+    );
+    let client = create_client();
+    let response = client.request(manifest_request(&uri, None)?).await?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    let challenge = response
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or("Missing authentication challenge")?;
+    let token = anonymous_token(&client, challenge).await?;
+    Ok(client
+        .request(manifest_request(&uri, Some(&token))?)
+        .await?)
+}
+
+///
+/// Returns true if `image` can be found in its registry.
+///
+pub async fn exists(image: &str) -> Result<bool, Box<dyn StdError>> {
+    let reference = ImageReference::parse(image).ok_or("Invalid image")?;
+    match head_manifest(&reference).await?.status() {
+        status if status.is_success() => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        status => Err(format!("Unexpected status {}", status).into()),
+    }
+}
+
+///
+/// Resolves the tag of `image` to a digest, so that the returned image never changes.
+///
+pub async fn pin(image: &str) -> Result<String, Box<dyn StdError>> {
+    let reference = ImageReference::parse(image).ok_or("Invalid image")?;
+    if reference.is_digest() {
+        return Ok(image.to_string());
+    }
+    let response = head_manifest(&reference).await?;
+    if !response.status().is_success() {
+        return Err(format!("Unexpected status {}", response.status()).into());
+    }
+    let digest = response
+        .headers()
+        .get(DIGEST_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or("Missing digest")?;
+    Ok(format!("{}@{}", reference.name, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(image: &str) -> Option<(String, String, String, String)> {
+        ImageReference::parse(image).map(|reference| {
+            (
+                reference.name,
+                reference.registry,
+                reference.repository,
+                reference.reference,
+            )
+        })
+    }
+
+    #[test]
+    fn parses_references() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let hub = |name: &str, repository: &str, reference: &str| {
+            Some((
+                name.to_string(),
+                DOCKER_HUB.to_string(),
+                repository.to_string(),
+                reference.to_string(),
+            ))
+        };
+        assert_eq!(parse("ubuntu"), hub("ubuntu", "library/ubuntu", "latest"));
+        assert_eq!(parse("a/b:1.0"), hub("a/b", "a/b", "1.0"));
+        assert_eq!(
+            parse(&format!("a/b@{}", digest)),
+            hub("a/b", "a/b", &digest)
+        );
+        assert_eq!(
+            parse(&format!("a/b:1.0@{}", digest)),
+            hub("a/b", "a/b", &digest)
+        );
+        assert_eq!(
+            parse(&format!("localhost:5000/a:1.0@{}", digest)),
+            Some((
+                "localhost:5000/a".to_string(),
+                "localhost:5000".to_string(),
+                "a".to_string(),
+                digest
+            ))
+        );
+        assert_eq!(parse("a:"), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Session placement strategies
+//!
+//! Session pods are placed by the Kubernetes scheduler on the nodes of their pool. A strategy steers it by preferring
+//! some of those nodes, based on the number of sessions each of them currently runs. Strategies are selected per pool
+//! via `SCHEDULING_STRATEGIES`, a YAML mapping of pool names to one of `bin-packing`, `spread` or `least-loaded`.
+//! Pools without a strategy are left to the Kubernetes scheduler.
+use crate::types::Pool;
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, sync::Arc};
+
+/// Maximum weight of a preferred scheduling term
+const MAX_WEIGHT: usize = 100;
+
+/// Current load of a node
+#[derive(Clone, Debug)]
+pub struct NodeLoad {
+    pub hostname: String,
+    /// Sessions running or pending on this node
+    pub sessions: usize,
+    /// Maximum number of sessions this node can run
+    pub capacity: usize,
+}
+
+impl NodeLoad {
+    fn free(&self) -> usize {
+        self.capacity.saturating_sub(self.sessions)
+    }
+}
+
+/// Returns the load of each node of `pool`, given the nodes sessions run on. Nodes can run `max_sessions_per_node`
+/// sessions, unless `pool` declares its capacity, in which case it is evenly split among its nodes.
+pub fn node_loads(
+    pool: &Pool,
+    session_nodes: &[&str],
+    max_sessions_per_node: usize,
+) -> Vec<NodeLoad> {
+    let capacity = match pool.capacity {
+        Some(capacity) if !pool.nodes.is_empty() => {
+            (capacity + pool.nodes.len() - 1) / pool.nodes.len()
+        }
+        _ => max_sessions_per_node,
+    };
+    pool.nodes
+        .iter()
+        .map(|node| NodeLoad {
+            hostname: node.hostname.clone(),
+            // Node names usually match their hostname
+            sessions: session_nodes
+                .iter()
+                .filter(|hostname| **hostname == node.hostname)
+                .count(),
+            capacity,
+        })
+        .collect()
+}
+
+pub trait SchedulingStrategy: Send + Sync {
+    /// Returns the weight (between 1 and 100) given to each preferred node, by hostname. Other nodes are not preferred.
+    fn weights(&self, nodes: &[NodeLoad]) -> BTreeMap<String, i32>;
+}
+
+/// Fills busy nodes first, so that idle ones can be scaled down. Optimizes for cost.
+pub struct BinPacking;
+
+impl SchedulingStrategy for BinPacking {
+    fn weights(&self, nodes: &[NodeLoad]) -> BTreeMap<String, i32> {
+        nodes
+            .iter()
+            .filter(|node| node.free() > 0 && node.sessions > 0)
+            .map(|node| {
+                (
+                    node.hostname.clone(),
+                    (node.sessions * MAX_WEIGHT / node.capacity).max(1) as i32,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Only prefers nodes running the fewest sessions, so that sessions are evenly spread
+pub struct Spread;
+
+impl SchedulingStrategy for Spread {
+    fn weights(&self, nodes: &[NodeLoad]) -> BTreeMap<String, i32> {
+        let available = nodes.iter().filter(|node| node.free() > 0);
+        let min = available.clone().map(|node| node.sessions).min();
+        available
+            .filter(|node| Some(node.sessions) == min)
+            .map(|node| (node.hostname.clone(), MAX_WEIGHT as i32))
+            .collect()
+    }
+}
+
+/// Prefers nodes in proportion of their free room, limiting contention. Optimizes for latency.
+pub struct LeastLoaded;
+
+impl SchedulingStrategy for LeastLoaded {
+    fn weights(&self, nodes: &[NodeLoad]) -> BTreeMap<String, i32> {
+        nodes
+            .iter()
+            .filter(|node| node.free() > 0)
+            .map(|node| {
+                (
+                    node.hostname.clone(),
+                    (node.free() * MAX_WEIGHT / node.capacity).max(1) as i32,
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Strategy {
+    BinPacking,
+    Spread,
+    LeastLoaded,
+}
+
+/// Scheduling strategies, by pool
+#[derive(Clone, Default)]
+pub struct Scheduling {
+    strategies: BTreeMap<String, Arc<dyn SchedulingStrategy>>,
+}
+
+impl Scheduling {
+    pub fn from_env() -> Result<Self, String> {
+        let strategies: BTreeMap<String, Strategy> = match env::var("SCHEDULING_STRATEGIES") {
+            Ok(value) => serde_yaml::from_str(&value)
+                .map_err(|err| format!("SCHEDULING_STRATEGIES: {}", err))?,
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(Scheduling {
+            strategies: strategies
+                .into_iter()
+                .map(|(pool, strategy)| {
+                    let strategy: Arc<dyn SchedulingStrategy> = match strategy {
+                        Strategy::BinPacking => Arc::new(BinPacking),
+                        Strategy::Spread => Arc::new(Spread),
+                        Strategy::LeastLoaded => Arc::new(LeastLoaded),
+                    };
+                    (pool, strategy)
+                })
+                .collect(),
+        })
+    }
+
+    /// Returns the strategy of `pool`, if any
+    pub fn strategy(&self, pool: &str) -> Option<&dyn SchedulingStrategy> {
+        self.strategies.get(pool).map(|strategy| strategy.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Node;
+
+    fn pool(capacity: Option<usize>) -> Pool {
+        Pool {
+            name: "pool".to_string(),
+            instance_type: None,
+            nodes: vec![
+                Node {
+                    hostname: "a".to_string(),
+                },
+                Node {
+                    hostname: "b".to_string(),
+                },
+            ],
+            capacity,
+        }
+    }
+
+    fn loads(nodes: &[NodeLoad]) -> Vec<(&str, usize, usize)> {
+        nodes
+            .iter()
+            .map(|node| (node.hostname.as_str(), node.sessions, node.capacity))
+            .collect()
+    }
+
+    #[test]
+    fn computes_node_loads() {
+        let sessions = ["a", "a", "c"];
+        assert_eq!(
+            loads(&node_loads(&pool(None), &sessions, 4)),
+            vec![("a", 2, 4), ("b", 0, 4)]
+        );
+        // Declared capacities take precedence
+        assert_eq!(
+            loads(&node_loads(&pool(Some(5)), &sessions, 4)),
+            vec![("a", 2, 3), ("b", 0, 3)]
+        );
+    }
+
+    #[test]
+    fn weighs_nodes() {
+        let nodes = node_loads(&pool(None), &["a", "a", "a"], 4);
+        assert_eq!(
+            BinPacking.weights(&nodes),
+            vec![("a".to_string(), 75)].into_iter().collect()
+        );
+        assert_eq!(
+            Spread.weights(&nodes),
+            vec![("b".to_string(), 100)].into_iter().collect()
+        );
+        assert_eq!(
+            LeastLoaded.weights(&nodes),
+            vec![("a".to_string(), 25), ("b".to_string(), 100)]
+                .into_iter()
+                .collect()
+        );
+        // Full nodes are never preferred
+        let nodes = node_loads(&pool(Some(2)), &["a"], 4);
+        assert_eq!(
+            LeastLoaded.weights(&nodes),
+            vec![("b".to_string(), 100)].into_iter().collect()
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Secrets providers
+//!
+//! Secrets (GitHub credentials, webhook and token signing keys, ...) are read from env variables. With `SECRETS_PROVIDER`
+//! they are instead fetched from:
+//! * `kubernetes`: the Secret `SECRETS_KUBERNETES_NAME` (defaults to `playground-secrets`) of the backend namespace
+//! * `vault`: the HashiCorp Vault KV v2 secret `VAULT_SECRET_PATH` (e.g. `secret/data/playground`) at `VAULT_ADDR`.
+//!   Authenticates with `VAULT_TOKEN`, or with the pod service account via the Kubernetes auth method and `VAULT_ROLE`.
+//!   The Vault token is renewed before it expires.
+//!
+//! Keys are the names of the env variables they stand for, e.g. `GITHUB_CLIENT_SECRET`. Fetched secrets are exposed as env
+//! variables, unless set explicitly, before the configuration is read. They are then refreshed every
+//! `SECRETS_REFRESH_INTERVAL` minutes (defaults to 10) into an in-memory store; the environment is never modified once
+//! the backend runs. Webhook and token signing keys are read via `get` when used, so that rotated values are picked up:
+//! for those the fetched value always wins over the env variable, e.g. one injected from a Kubernetes Secret. The value
+//! preceding a rotation stays available via `versions` so that signatures issued before can still be verified. Other
+//! secrets require a restart.
+use futures::future::{FutureExt, LocalBoxFuture};
+use hyper::{
+    body::{self, Bytes},
+    client::HttpConnector,
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use k8s_openapi::api::core::v1::Secret;
+use kube::Api;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    error::Error as StdError,
+    fs,
+    lazy::SyncLazy,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+const DEFAULT_KUBERNETES_SECRET: &str = "playground-secrets";
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+/// Renewals are attempted when that much of the token lease is left
+const RENEWAL_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Secrets fetched from the provider
+#[derive(Default)]
+struct Store {
+    current: BTreeMap<String, String>,
+    /// Values replaced by the last rotation of each secret
+    previous: BTreeMap<String, String>,
+}
+
+impl Store {
+    fn update(&mut self, secrets: BTreeMap<String, String>) {
+        for (name, value) in &secrets {
+            if let Some(old) = self.current.get(name).filter(|old| *old != value) {
+                self.previous.insert(name.clone(), old.clone());
+            }
+        }
+        self.current = secrets;
+    }
+}
+
+static STORE: SyncLazy<RwLock<Store>> = SyncLazy::new(Default::default);
+
+/// Returns the current value of secret `name`, if set and not empty
+pub fn get(name: &str) -> Option<String> {
+    STORE
+        .read()
+        .ok()
+        .and_then(|store| store.current.get(name).cloned())
+        .or_else(|| env::var(name).ok())
+        .filter(|value| !value.is_empty())
+}
+
+/// Returns the current value of secret `name` followed by the one it replaced, if any
+pub fn versions(name: &str) -> Vec<String> {
+    let previous = STORE
+        .read()
+        .ok()
+        .and_then(|store| store.previous.get(name).cloned())
+        .filter(|value| !value.is_empty());
+    get(name).into_iter().chain(previous).collect()
+}
+
+pub trait SecretsProvider: Send + Sync {
+    /// Returns all secrets, keyed by env variable name
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<BTreeMap<String, String>, Box<dyn StdError>>>;
+
+    /// Keeps credentials to the provider valid. Returns the delay before the next renewal, if any is needed.
+    fn renew(&self) -> LocalBoxFuture<'_, Result<Option<Duration>, Box<dyn StdError>>> {
+        async { Ok(None) }.boxed_local()
+    }
+}
+
+async fn send(request: Request<Body>) -> Result<(StatusCode, Bytes), Box<dyn StdError>> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+    let response = client.request(request).await?;
+    let status = response.status();
+    Ok((status, body::to_bytes(response.into_body()).await?))
+}
+
+struct KubernetesSecret {
+    name: String,
+}
+
+impl SecretsProvider for KubernetesSecret {
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<BTreeMap<String, String>, Box<dyn StdError>>> {
+        async move {
+            let client = kube::Client::try_default().await?;
+            let secret_api: Api<Secret> = Api::default_namespaced(client);
+            let secret = secret_api.get(&self.name).await?;
+            Ok(secret
+                .data
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, String::from_utf8(value.0).ok()?)))
+                .collect())
+        }
+        .boxed_local()
+    }
+}
+
+/// See https://www.vaultproject.io/api-docs/secret/kv/kv-v2
+struct Vault {
+    address: String,
+    path: String,
+    /// Used to log in via the Kubernetes auth method, if no static token is provided
+    role: Option<String>,
+    token: Arc<Mutex<Option<String>>>,
+}
+
+#[derive(Deserialize)]
+struct VaultAuth {
+    client_token: String,
+    lease_duration: u64,
+    renewable: bool,
+}
+
+#[derive(Deserialize)]
+struct VaultAuthResponse {
+    auth: VaultAuth,
+}
+
+#[derive(Deserialize)]
+struct VaultData {
+    data: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct VaultSecretResponse {
+    data: VaultData,
+}
+
+impl Vault {
+    fn token(&self) -> Option<String> {
+        self.token.lock().ok().and_then(|token| token.clone())
+    }
+
+    fn set_token(&self, value: String) {
+        if let Ok(mut token) = self.token.lock() {
+            token.replace(value);
+        }
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Bytes, Box<dyn StdError>> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(format!("{}/v1/{}", self.address, path));
+        if let Some(token) = self.token() {
+            builder = builder.header("X-Vault-Token", token);
+        }
+        let body = match body {
+            Some(body) => Body::from(body.to_string()),
+            None => Body::empty(),
+        };
+        let (status, bytes) = send(builder.body(body)?).await?;
+        if !status.is_success() {
+            return Err(format!("Vault returned {} for {}", status, path).into());
+        }
+        Ok(bytes)
+    }
+
+    /// Logs in with the pod service account
+    async fn login(&self, role: &str) -> Result<VaultAuth, Box<dyn StdError>> {
+        let jwt = fs::read_to_string(SERVICE_ACCOUNT_TOKEN)?;
+        let bytes = self
+            .request(
+                Method::POST,
+                "auth/kubernetes/login",
+                Some(json!({ "role": role, "jwt": jwt.trim() })),
+            )
+            .await?;
+        let response: VaultAuthResponse = serde_json::from_slice(&bytes)?;
+        self.set_token(response.auth.client_token.clone());
+        Ok(response.auth)
+    }
+}
+
+/// Returns the delay before renewing a lease of `auth`
+fn renewal_delay(auth: &VaultAuth) -> Option<Duration> {
+    if !auth.renewable || auth.lease_duration == 0 {
+        return None;
+    }
+    let lease = Duration::from_secs(auth.lease_duration);
+    Some(lease.checked_sub(RENEWAL_MARGIN).unwrap_or(lease / 2))
+}
+
+impl SecretsProvider for Vault {
+    fn fetch(&self) -> LocalBoxFuture<'_, Result<BTreeMap<String, String>, Box<dyn StdError>>> {
+        async move {
+            if self.token().is_none() {
+                if let Some(role) = &self.role {
+                    self.login(role).await?;
+                }
+            }
+            let bytes = self.request(Method::GET, &self.path, None).await?;
+            let response: VaultSecretResponse = serde_json::from_slice(&bytes)?;
+            Ok(response.data.data)
+        }
+        .boxed_local()
+    }
+
+    fn renew(&self) -> LocalBoxFuture<'_, Result<Option<Duration>, Box<dyn StdError>>> {
+        async move {
+            let renewed = self
+                .request(Method::POST, "auth/token/renew-self", Some(json!({})))
+                .await
+                .and_then(|bytes| Ok(serde_json::from_slice::<VaultAuthResponse>(&bytes)?));
+            match (renewed, &self.role) {
+                (Ok(response), _) => Ok(renewal_delay(&response.auth)),
+                // Tokens eventually reach their max TTL, log in again
+                (Err(err), Some(role)) => {
+                    warn!("Failed to renew Vault token, logging in again: {}", err);
+                    Ok(renewal_delay(&self.login(role).await?))
+                }
+                (Err(err), None) => Err(err),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[derive(Clone)]
+pub struct Loader {
+    provider: Arc<dyn SecretsProvider>,
+    refresh_interval: Duration,
+    /// Secrets set explicitly via env variables, that are never overridden
+    explicit: Arc<BTreeSet<String>>,
+}
+
+impl Loader {
+    /// Returns `None` if `SECRETS_PROVIDER` is unset
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let provider: Arc<dyn SecretsProvider> = match env::var("SECRETS_PROVIDER").ok().as_deref()
+        {
+            None => return Ok(None),
+            Some("kubernetes") => Arc::new(KubernetesSecret {
+                name: env::var("SECRETS_KUBERNETES_NAME")
+                    .unwrap_or_else(|_| DEFAULT_KUBERNETES_SECRET.to_string()),
+            }),
+            Some("vault") => {
+                let token = env::var("VAULT_TOKEN").ok();
+                let role = env::var("VAULT_ROLE").ok();
+                if token.is_none() && role.is_none() {
+                    return Err("missing VAULT_TOKEN or VAULT_ROLE".to_string());
+                }
+                Arc::new(Vault {
+                    address: env::var("VAULT_ADDR")
+                        .map_err(|_| "missing VAULT_ADDR".to_string())?
+                        .trim_end_matches('/')
+                        .to_string(),
+                    path: env::var("VAULT_SECRET_PATH")
+                        .map_err(|_| "missing VAULT_SECRET_PATH".to_string())?,
+                    role,
+                    token: Arc::new(Mutex::new(token)),
+                })
+            }
+            Some(provider) => return Err(format!("unknown SECRETS_PROVIDER {}", provider)),
+        };
+        Ok(Some(Loader {
+            provider,
+            refresh_interval: Duration::from_secs(
+                env::var("SECRETS_REFRESH_INTERVAL")
+                    .ok()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(10)
+                    * 60,
+            ),
+            explicit: Arc::new(env::vars().map(|(name, _)| name).collect()),
+        }))
+    }
+
+    /// Fetches secrets and exposes them as env variables. Must be called before other threads are started.
+    pub async fn load(&self) -> Result<(), Box<dyn StdError>> {
+        let secrets = self.provider.fetch().await?;
+        for (name, value) in &secrets {
+            if !self.explicit.contains(name) {
+                env::set_var(name, value);
+            }
+        }
+        if let Ok(mut store) = STORE.write() {
+            store.update(secrets);
+        }
+        Ok(())
+    }
+
+    /// Fetches secrets into the store
+    async fn reload(&self) -> Result<(), Box<dyn StdError>> {
+        let secrets = self.provider.fetch().await?;
+        STORE
+            .write()
+            .map_err(|_| "poisoned secrets store")?
+            .update(secrets);
+        Ok(())
+    }
+
+    /// Renews provider credentials before they expire, forever
+    async fn renew(&self) {
+        loop {
+            let delay = match self.provider.renew().await {
+                Ok(Some(delay)) => delay,
+                // Nothing to renew
+                Ok(None) => return,
+                Err(err) => {
+                    warn!("Failed to renew secrets provider credentials: {}", err);
+                    Duration::from_secs(60)
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Refreshes secrets and renews provider credentials, forever
+    pub async fn refresh(self) {
+        let refresh = async {
+            loop {
+                tokio::time::sleep(self.refresh_interval).await;
+                match self.reload().await {
+                    Ok(()) => info!("Refreshed secrets"),
+                    Err(err) => warn!("Failed to refresh secrets: {}", err),
+                }
+            }
+        };
+        futures::join!(self.renew(), refresh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_rotated_secrets() {
+        let secrets = |value: &str| {
+            vec![("SECRET".to_string(), value.to_string())]
+                .into_iter()
+                .collect()
+        };
+        let mut store = Store::default();
+        store.update(secrets("a"));
+        assert_eq!(store.previous.get("SECRET"), None);
+        store.update(secrets("a"));
+        assert_eq!(store.previous.get("SECRET"), None);
+        store.update(secrets("b"));
+        assert_eq!(store.current.get("SECRET"), Some(&"b".to_string()));
+        assert_eq!(store.previous.get("SECRET"), Some(&"a".to_string()));
+    }
+}
@@ -0,0 +1,233 @@
+//! Access control of session hosts
+//!
+//! Session hosts are exposed via the shared ingress, that delegates authentication of each request to
+//! `/api/v1/sessions/<host>/authorize` (see the nginx `auth-url` annotation). Users open their session via a url embedding a
+//! short-lived handoff token. Once validated, it is exchanged for a longer-lived access token stored in a cookie scoped to
+//! the session host. Tokens are signed with `SESSION_AUTH_SECRET`; if unset, sessions are left open.
+//!
+//! Published read-only viewers are served on a host of their own, `viewer--<session label>`, so that they don't share
+//! an origin (and cookies) with the IDE. Only `/view/<nonce>.<signature>` is served there, valid until unpublished.
+use crate::{auth::random_token, github::decode_hex, secrets};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Query parameter holding the handoff token
+pub const TOKEN_PARAMETER: &str = "playground-token";
+/// Cookie holding the access token
+pub const COOKIE_ACCESS: &str = "playground-access";
+/// Prefix of paths of published viewers
+pub const VIEWER_PATH: &str = "/view/";
+/// Prefix of the DNS label of viewer hosts. Neither GitHub logins nor other session labels contain `--` at this position.
+pub const VIEWER_HOST_PREFIX: &str = "viewer--";
+
+#[derive(Clone)]
+pub struct SessionTokens {
+    secret: String,
+    /// Validity of tokens embedded in urls
+    handoff_ttl: Duration,
+    /// Validity of tokens stored in cookies
+    access_ttl: Duration,
+}
+
+impl SessionTokens {
+    /// Reads the secret from `SESSION_AUTH_SECRET` and TTLs, in seconds, from `SESSION_AUTH_HANDOFF_TTL` and
+    /// `SESSION_AUTH_ACCESS_TTL`
+    pub fn from_env() -> Option<Self> {
+        let secret = env::var("SESSION_AUTH_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())?;
+        let ttl = |name: &str, default: u64| {
+            Duration::from_secs(
+                env::var(name)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default),
+            )
+        };
+        Some(SessionTokens {
+            secret,
+            handoff_ttl: ttl("SESSION_AUTH_HANDOFF_TTL", 60),
+            access_ttl: ttl("SESSION_AUTH_ACCESS_TTL", 12 * 60 * 60),
+        })
+    }
+
+    /// Returns the secrets tokens are verified with, the one they are signed with first
+    fn secrets(&self) -> Vec<String> {
+        // Picks up rotated secrets
+        let secrets = secrets::versions("SESSION_AUTH_SECRET");
+        if secrets.is_empty() {
+            vec![self.secret.clone()]
+        } else {
+            secrets
+        }
+    }
+
+    fn sign(&self, message: &str) -> String {
+        self.secrets()
+            .first()
+            .and_then(|secret| Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok())
+            .map(|mut mac| {
+                mac.update(message.as_bytes());
+                encode_hex(&mac.finalize().into_bytes())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns true if `signature` signs `message` with the current or previous secret. Comparisons are constant time.
+    fn verify_signature(&self, message: &str, signature: &[u8]) -> bool {
+        self.secrets().iter().any(|secret| {
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .map(|mut mac| {
+                    mac.update(message.as_bytes());
+                    mac.verify(signature).is_ok()
+                })
+                .unwrap_or(false)
+        })
+    }
+
+    fn access_message(session_id: &str, user_id: &str, expires_at: u64) -> String {
+        format!("{}:{}:{}", session_id, user_id, expires_at)
+    }
+
+    fn viewer_message(session_id: &str, nonce: &str) -> String {
+        format!("viewer:{}:{}", session_id, nonce)
+    }
+
+    /// Returns a fresh path a viewer of `session_id` can be published under
+    pub fn viewer_path(&self, session_id: &str) -> String {
+        let nonce = random_token(16);
+        let signature = self.sign(&Self::viewer_message(session_id, &nonce));
+        format!("{}{}.{}", VIEWER_PATH, nonce, signature)
+    }
+
+    /// Returns true if `path` targets a viewer published by `viewer_path` for `session_id`
+    pub fn verify_viewer(&self, session_id: &str, path: &str) -> bool {
+        let verify = || {
+            let (nonce, signature) = viewer_token(path)?.split_once('.')?;
+            Some(self.verify_signature(
+                &Self::viewer_message(session_id, nonce),
+                &decode_hex(signature)?,
+            ))
+        };
+        verify().unwrap_or(false)
+    }
+
+    /// Returns a token granting `user_id` access to `session_id` for `ttl` from `now`, as
+    /// `<user_id>.<expires_at>.<signature>`
+    fn mint(&self, session_id: &str, user_id: &str, ttl: Duration, now: SystemTime) -> String {
+        let expires_at = (now + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let signature = self.sign(&Self::access_message(session_id, user_id, expires_at));
+        format!("{}.{}.{}", user_id, expires_at, signature)
+    }
+
+    pub fn handoff(&self, session_id: &str, user_id: &str, now: SystemTime) -> String {
+        self.mint(session_id, user_id, self.handoff_ttl, now)
+    }
+
+    pub fn access(&self, session_id: &str, user_id: &str, now: SystemTime) -> String {
+        self.mint(session_id, user_id, self.access_ttl, now)
+    }
+
+    /// Returns the user `token` was minted for, if not expired at `now` and valid for `session_id`
+    pub fn verify(&self, session_id: &str, token: &str, now: SystemTime) -> Option<String> {
+        let mut parts = token.rsplitn(3, '.');
+        let signature = decode_hex(parts.next()?)?;
+        let expires_at: u64 = parts.next()?.parse().ok()?;
+        let user_id = parts.next()?;
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if expires_at < now {
+            return None;
+        }
+        if !self.verify_signature(
+            &Self::access_message(session_id, user_id, expires_at),
+            &signature,
+        ) {
+            return None;
+        }
+        Some(user_id.to_string())
+    }
+}
+
+/// Extracts the handoff token from the query of `url`
+pub fn token_from_url(url: &str) -> Option<&str> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(TOKEN_PARAMETER)?.strip_prefix('='))
+}
+
+/// Extracts the token of a viewer `path`. Paths that could resolve elsewhere once decoded or normalized are rejected.
+fn viewer_token(path: &str) -> Option<&str> {
+    let path = path.split(|c| c == '?' || c == '#').next()?;
+    if path.contains(|c| c == '%' || c == '\\') {
+        return None;
+    }
+    let mut segments = path.strip_prefix(VIEWER_PATH)?.split('/');
+    let token = segments.next().filter(|token| !token.is_empty())?;
+    if segments.any(|segment| segment == "." || segment == "..") {
+        return None;
+    }
+    Some(token)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> SessionTokens {
+        SessionTokens {
+            secret: "secret".to_string(),
+            handoff_ttl: Duration::from_secs(60),
+            access_ttl: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn viewer_paths_are_bound_to_their_session() {
+        let tokens = tokens();
+        let path = tokens.viewer_path("alice");
+        assert!(tokens.verify_viewer("alice", &path));
+        assert!(tokens.verify_viewer("alice", &format!("{}/static/app.js?v=1", path)));
+        assert!(!tokens.verify_viewer("bob", &path));
+    }
+
+    #[test]
+    fn viewer_paths_reject_traversals() {
+        let tokens = tokens();
+        let path = tokens.viewer_path("alice");
+        assert!(!tokens.verify_viewer("alice", &format!("{}/../", path)));
+        assert!(!tokens.verify_viewer("alice", &format!("{}/%2e%2e/", path)));
+        assert!(!tokens.verify_viewer("alice", &format!("{}/..\\", path)));
+        assert!(!tokens.verify_viewer("alice", "/view/../"));
+        assert!(!tokens.verify_viewer("alice", "/view/"));
+        assert!(!tokens.verify_viewer("alice", "/"));
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let tokens = tokens();
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let token = tokens.handoff("alice", "bob", now);
+        assert_eq!(tokens.verify("alice", &token, now), Some("bob".to_string()));
+        assert_eq!(
+            tokens.verify("alice", &token, now + Duration::from_secs(60)),
+            Some("bob".to_string())
+        );
+        assert_eq!(
+            tokens.verify("alice", &token, now + Duration::from_secs(61)),
+            None
+        );
+        assert_eq!(tokens.verify("carol", &token, now), None);
+    }
+}
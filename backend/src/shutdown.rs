@@ -0,0 +1,171 @@
+//! Graceful shutdown
+//!
+//! On `SIGTERM` new operations are refused while in-flight ones are given some time to complete, so that
+//! rolling upgrades do not leave half-created sessions or a partially replaced ingress behind. HTTP requests being
+//! handled are waited for too: Rocket can't be stopped, so the process only exits once none is left.
+use crate::{
+    error::{Error, Result},
+    manager::Manager,
+    telemetry,
+};
+use log::{error, info, warn};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Data, Request, Response,
+};
+use std::{
+    env, process,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+#[derive(Debug, Default)]
+struct State {
+    draining: bool,
+    in_flight: usize,
+    /// HTTP requests being handled
+    requests: usize,
+}
+
+/// Tracks operations mutating kubernetes resources
+#[derive(Clone, Debug, Default)]
+pub struct Operations {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+/// Marks an operation as in-flight until dropped
+pub struct Operation {
+    operations: Operations,
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.operations.state;
+        if let Ok(mut state) = lock.lock() {
+            state.in_flight -= 1;
+            condvar.notify_all();
+        }
+    }
+}
+
+impl Operations {
+    /// Starts a new operation. Fails once shutdown has started.
+    pub fn begin(&self) -> Result<Operation> {
+        let (lock, _) = &*self.state;
+        let mut state = lock
+            .lock()
+            .map_err(|_| Error::Failure("Failed to acquire operations lock".into()))?;
+        if state.draining {
+            return Err(Error::ShuttingDown());
+        }
+        state.in_flight += 1;
+        Ok(Operation {
+            operations: self.clone(),
+        })
+    }
+
+    /// Refuses new operations and waits up to `timeout` for in-flight ones and HTTP requests. Returns the number of
+    /// operations and requests still running.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let (lock, condvar) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut state = match lock.lock() {
+            Ok(state) => state,
+            Err(_) => return 0,
+        };
+        state.draining = true;
+        while state.in_flight + state.requests > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                break;
+            }
+            state = match condvar.wait_timeout(state, remaining) {
+                Ok((state, _)) => state,
+                Err(_) => return 0,
+            };
+        }
+        state.in_flight + state.requests
+    }
+
+    fn start_request(&self) {
+        let (lock, _) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.requests += 1;
+        }
+    }
+
+    /// Returns true if shutdown has started
+    fn end_request(&self) -> bool {
+        let (lock, condvar) = &*self.state;
+        match lock.lock() {
+            Ok(mut state) => {
+                state.requests = state.requests.saturating_sub(1);
+                condvar.notify_all();
+                state.draining
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// A `Fairing` tracking HTTP requests being handled, so that shutdown waits for them. Connections are closed once
+/// shutdown has started, so that clients reconnect to another replica.
+pub struct Requests {
+    pub operations: Operations,
+}
+
+impl Fairing for Requests {
+    fn info(&self) -> Info {
+        Info {
+            name: "Graceful shutdown",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, _: &mut Request, _: &Data) {
+        self.operations.start_request();
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        if self.operations.end_request() {
+            response.set_header(Header::new("Connection", "close"));
+        }
+    }
+}
+
+/// Maximum time given to in-flight operations, read from `SHUTDOWN_TIMEOUT` (in seconds)
+fn timeout() -> Duration {
+    Duration::from_secs(
+        env::var("SHUTDOWN_TIMEOUT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(25),
+    )
+}
+
+/// Waits for `SIGTERM` then drains `manager` and exits the process once in-flight operations and HTTP requests are
+/// done, or after `timeout`
+pub async fn on_sigterm(manager: Manager) {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(err) => {
+            error!("Failed to listen to SIGTERM: {}", err);
+            return;
+        }
+    };
+    sigterm.recv().await;
+
+    info!("Received SIGTERM, draining in-flight operations");
+    // `Manager` relies on its own runtimes, it can't be called from within this one
+    thread::spawn(move || {
+        let remaining = manager.shutdown(timeout());
+        if remaining > 0 {
+            warn!("Exiting with {} operations still in-flight", remaining);
+        }
+        telemetry::shutdown();
+        process::exit(0);
+    });
+}
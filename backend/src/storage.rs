@@ -0,0 +1,76 @@
+//! Versioned storage of ConfigMap backed resources
+//!
+//! ConfigMaps holding serialized resources (users, templates, organizations) are annotated with the version of their format.
+//! At startup pending `Migration`s are applied in place to all entries, so that changes to `types` don't break parsing of
+//! existing entries. A ConfigMap without annotation is at version 0.
+use serde_yaml::{Mapping, Value};
+
+pub const VERSION_ANNOTATION: &str = "playground.substrate.io/storage-version";
+
+pub struct Migration {
+    /// Version of the format once applied
+    pub version: u32,
+    pub description: &'static str,
+    /// Upgrades a single entry
+    pub apply: fn(&mut Mapping),
+}
+
+pub const USER_MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "rename snake_case keys to camelCase",
+    apply: camel_case_user_keys,
+}];
+
+pub const TEMPLATE_MIGRATIONS: &[Migration] = &[];
+
+pub const ORG_MIGRATIONS: &[Migration] = &[];
+
+/// Returns the version reached once all `migrations` are applied
+pub fn latest_version(migrations: &[Migration]) -> u32 {
+    migrations
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Applies `migrations` newer than `version` to the YAML `entry`. Returns `None` if the entry is left unchanged.
+pub fn migrate_entry(
+    migrations: &[Migration],
+    version: u32,
+    entry: &str,
+) -> Result<Option<String>, serde_yaml::Error> {
+    let mut mapping = match serde_yaml::from_str(entry)? {
+        Value::Mapping(mapping) => mapping,
+        // Not a resource, let parsing report it
+        _ => return Ok(None),
+    };
+    let original = mapping.clone();
+    for migration in migrations
+        .iter()
+        .filter(|migration| migration.version > version)
+    {
+        (migration.apply)(&mut mapping);
+    }
+    if mapping == original {
+        return Ok(None);
+    }
+    serde_yaml::to_string(&mapping).map(Some)
+}
+
+/// Users were initially written by hand, with keys silently ignored when not in camelCase
+fn camel_case_user_keys(entry: &mut Mapping) {
+    for (from, to) in [
+        ("can_customize_duration", "canCustomizeDuration"),
+        ("can_customize_pool_affinity", "canCustomizePoolAffinity"),
+        ("pool_affinity", "poolAffinity"),
+        ("accepted_terms_version", "acceptedTermsVersion"),
+    ] {
+        if let Some(value) = entry.remove(&Value::from(from)) {
+            let to = Value::from(to);
+            if !entry.contains_key(&to) {
+                entry.insert(to, value);
+            }
+        }
+    }
+}
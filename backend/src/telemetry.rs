@@ -0,0 +1,111 @@
+//! OpenTelemetry tracing of HTTP requests, `Manager` operations and k8s calls
+//!
+//! Spans are exported via OTLP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Otherwise a no-op tracer is used.
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Span, TraceContextExt, Tracer},
+    Context, ContextGuard, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::{cell::RefCell, env, error::Error, future::Future};
+
+const TRACER_NAME: &str = "playground";
+
+thread_local! {
+    // Rocket handles a request on a single thread, from `on_request` to `on_response`
+    static REQUEST_CONTEXT: RefCell<Option<ContextGuard>> = RefCell::new(None);
+}
+
+/// Installs the OTLP exporter if configured. Returns true if spans will be exported.
+pub fn init() -> Result<bool, Box<dyn Error>> {
+    match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .install_batch(opentelemetry::runtime::Tokio)?;
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+/// Flushes pending spans
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Ends the associated span when dropped
+pub struct SpanGuard {
+    _guard: ContextGuard,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        Context::current().span().end();
+    }
+}
+
+/// Starts a new span named `name`, child of the current span, that lasts as long as the returned guard
+pub fn enter(name: &'static str) -> SpanGuard {
+    let span = global::tracer(TRACER_NAME).start(name);
+    SpanGuard {
+        _guard: Context::current_with_span(span).attach(),
+    }
+}
+
+/// Awaits `future` as part of a new span named `name`, child of the current span
+pub async fn traced<F: Future>(name: &'static str, future: F) -> F::Output {
+    let span = global::tracer(TRACER_NAME).start(name);
+    let cx = Context::current_with_span(span);
+    let output = future.with_context(cx.clone()).await;
+    cx.span().end();
+    output
+}
+
+/// A `Fairing` wrapping each HTTP request in a span
+pub struct Tracing;
+
+impl Fairing for Tracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenTelemetry tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let mut span = global::tracer(TRACER_NAME).start(format!(
+            "{} {}",
+            request.method(),
+            request.uri().path()
+        ));
+        span.set_attribute(KeyValue::new("http.method", request.method().as_str()));
+        span.set_attribute(KeyValue::new("http.target", request.uri().to_string()));
+        let guard = Context::current_with_span(span).attach();
+        REQUEST_CONTEXT.with(|cx| cx.replace(Some(guard)));
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        REQUEST_CONTEXT.with(|cx| {
+            if let Some(guard) = cx.borrow_mut().take() {
+                let cx = Context::current();
+                let span = cx.span();
+                span.set_attribute(KeyValue::new(
+                    "http.status_code",
+                    i64::from(response.status().code),
+                ));
+                span.end();
+                drop(guard);
+            }
+        });
+    }
+}
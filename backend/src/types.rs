@@ -1,8 +1,12 @@
+use crate::auth::Provider;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    ops::RangeInclusive,
     str::FromStr,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Serialize, Clone, Debug)]
@@ -14,6 +18,51 @@ pub struct Session {
     #[serde(with = "duration")]
     pub duration: Duration,
     pub node: String,
+    /// One of `Configuration::base_domains`
+    pub domain: String,
+    /// Set by policies when suspicious activity is detected
+    pub flags: Vec<String>,
+    /// Progress of an ongoing migration to another pool
+    pub migration: Option<String>,
+    pub backup: Option<SessionBackup>,
+    pub workshop: Option<String>,
+    /// Pool requested at creation, unknown for sessions created by earlier versions
+    #[serde(skip)]
+    pub pool: Option<String>,
+    /// Role of the owner at creation, unknown for sessions created by earlier versions
+    #[serde(skip)]
+    pub role: Option<String>,
+    /// Organizations of the owner at creation
+    #[serde(skip)]
+    pub organizations: Vec<String>,
+    /// Number of times the session pod was recreated after a transient failure
+    pub retries: u32,
+    /// Time of the last heartbeat, if any
+    #[serde(with = "optional_timestamp")]
+    pub last_activity: Option<SystemTime>,
+    /// Not subject to the idle timeout, see `SessionConfiguration::unattended`
+    pub unattended: bool,
+    /// Set while the session is pending deletion, and can still be restored
+    #[serde(with = "optional_timestamp")]
+    pub deleted_at: Option<SystemTime>,
+    /// Propagation of the session DNS record, only set for single sessions when records are managed by the playground
+    pub dns: Option<DnsStatus>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum DnsStatus {
+    /// The record is not resolvable yet
+    Pending,
+    Propagated,
+}
+
+/// Returned by an accepted heartbeat
+#[derive(Serialize, Clone, Debug)]
+pub struct Heartbeat {
+    /// Must be sent with the next heartbeat
+    pub nonce: String,
+    /// Seconds to wait before the next heartbeat
+    pub interval: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -23,6 +72,8 @@ pub enum Phase {
     Succeeded,
     Failed,
     Unknown,
+    /// Scaled to zero, see `Engine::set_session_suspended`
+    Suspended,
 }
 
 impl FromStr for Phase {
@@ -34,6 +85,7 @@ impl FromStr for Phase {
             "Succeeded" => Ok(Phase::Succeeded),
             "Failed" => Ok(Phase::Failed),
             "Unknown" => Ok(Phase::Unknown),
+            "Suspended" => Ok(Phase::Suspended),
             _ => Err(format!("'{}' is not a valid value for Phase", s)),
         }
     }
@@ -48,6 +100,121 @@ pub struct Pod {
     #[serde(with = "system_time")]
     pub start_time: Option<SystemTime>,
     pub container: Option<ContainerStatus>,
+    /// Deployment progress, in order
+    pub steps: Vec<DeploymentStep>,
+    /// Set when the session can't start
+    pub failure: Option<SessionFailure>,
+}
+
+/// Common causes of sessions failing to start
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub enum SessionFailureReason {
+    ImagePull,
+    InsufficientResources,
+    VolumePending,
+    QuotaExceeded,
+    Crash,
+    /// Preempted by higher priority sessions, or evicted by its node
+    Evicted,
+    Unknown,
+}
+
+impl SessionFailureReason {
+    /// Returns true if recreating the session elsewhere is likely to fix this failure
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            SessionFailureReason::ImagePull | SessionFailureReason::InsufficientResources
+        )
+    }
+
+    /// What users can do about this failure
+    pub fn message(&self) -> &'static str {
+        match self {
+            SessionFailureReason::ImagePull => {
+                "The template image can't be pulled. Try again later or pick another template."
+            }
+            SessionFailureReason::InsufficientResources => {
+                "Not enough capacity is available. Try again in a few minutes or pick another pool."
+            }
+            SessionFailureReason::VolumePending => {
+                "Storage for this session isn't available yet. Try again in a few minutes."
+            }
+            SessionFailureReason::QuotaExceeded => {
+                "The playground reached its resource quota. Try again once other sessions have ended."
+            }
+            SessionFailureReason::Crash => {
+                "The session crashed while starting. Contact an admin if this keeps happening."
+            }
+            SessionFailureReason::Evicted => {
+                "The session was stopped to free up resources, e.g. for higher priority sessions. Create a new session to continue."
+            }
+            SessionFailureReason::Unknown => {
+                "The session failed to start. Contact an admin if this keeps happening."
+            }
+        }
+    }
+}
+
+/// A kubernetes event about one of the resources of a session, e.g. a pod failing to be scheduled
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionEvent {
+    /// `Normal` or `Warning`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// e.g. `FailedScheduling`
+    pub reason: String,
+    /// Only exposed to admins
+    pub message: Option<String>,
+    /// Kind and name of the resource, e.g. `Pod/session-jdoe`
+    pub object: String,
+    /// Number of occurrences
+    pub count: u32,
+    #[serde(with = "optional_timestamp")]
+    pub first_seen: Option<SystemTime>,
+    #[serde(with = "optional_timestamp")]
+    pub last_seen: Option<SystemTime>,
+}
+
+/// Why the pod of a session was evicted, kept after the pod is gone
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionEviction {
+    /// `Preempted` or `Evicted`
+    pub reason: String,
+    /// Only exposed to admins
+    pub message: Option<String>,
+    /// Seconds since epoch
+    pub time: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SessionFailure {
+    pub reason: SessionFailureReason,
+    pub message: String,
+    /// Raw kubernetes reason and message, only exposed to admins
+    pub detail: Option<String>,
+}
+
+impl SessionFailure {
+    pub fn new(reason: SessionFailureReason, detail: String) -> Self {
+        SessionFailure {
+            message: reason.message().to_string(),
+            reason,
+            detail: Some(detail),
+        }
+    }
+}
+
+/// A milestone of a session deployment
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentStep {
+    pub name: String,
+    pub completed: bool,
+    /// Not always known, even for completed steps
+    #[serde(with = "optional_timestamp")]
+    pub completed_at: Option<SystemTime>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -71,6 +238,55 @@ pub struct Pool {
     pub name: String,
     pub instance_type: Option<String>,
     pub nodes: Vec<Node>,
+    /// Maximum number of concurrent sessions, if declared in `STATIC_POOLS`
+    pub capacity: Option<usize>,
+}
+
+/// A pool declared in configuration, for clusters whose nodes can't be labelled or listed
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StaticPool {
+    /// Labels of the nodes part of this pool. Defaults to the hostnames of `nodes` if set, else to the node pool label set
+    /// to the pool name.
+    pub selector: Option<BTreeMap<String, String>>,
+    /// Maximum number of concurrent sessions. Defaults to the number of nodes times the maximum sessions per node.
+    pub capacity: Option<usize>,
+    /// Hostnames of the nodes, used when nodes can't be listed
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    pub instance_type: Option<String>,
+}
+
+/// A pool a user can create sessions on, with its current availability
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UsablePool {
+    #[serde(flatten)]
+    pub pool: Pool,
+    /// Sessions that can still be created, once reservations are accounted for
+    pub free_slots: usize,
+    /// Templates whose image is pulled on every node
+    pub prepulled_templates: Vec<String>,
+    pub latency: StartLatency,
+}
+
+/// How long a new session is expected to take to start
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum StartLatency {
+    /// Room is available and all template images are pre-pulled
+    Fast,
+    /// Room is available but some images will have to be pulled
+    Slow,
+    /// No room left, sessions will be rejected
+    Unavailable,
+}
+
+/// State of an image pre-pull on a node
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub enum PrepullStatus {
+    Pending,
+    Pulled,
+    Failed,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -79,21 +295,164 @@ pub struct Node {
     pub hostname: String,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct SessionConfiguration {
     pub template: String,
     #[serde(default)]
-    #[serde(with = "option_duration")]
-    pub duration: Option<Duration>,
+    pub duration: Option<SessionDuration>,
     pub pool_affinity: Option<String>,
+    /// One of `Configuration::base_domains`, defaults to the first one
+    pub domain: Option<String>,
+    /// If set, the workspace is periodically pushed to a git remote
+    pub backup: Option<SessionBackup>,
+    /// Sessions joining the same workshop can reach each other
+    pub workshop: Option<String>,
+    /// Template of a session handed off by another cluster, used instead of `template`
+    #[serde(skip)]
+    pub handoff: Option<Template>,
+    /// Set for sessions nobody interacts with, e.g. previews, that never time out for lack of activity
+    #[serde(skip)]
+    pub unattended: bool,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+/// Periodic push of a session workspace to a git remote
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionBackup {
+    /// HTTPS url of the git remote
+    pub remote: String,
+    pub branch: String,
+    /// Minutes between two backups
+    #[serde(default = "default_backup_interval")]
+    pub interval: u64,
+    /// Used to authenticate against `remote`. Stored as a k8s Secret and never returned.
+    #[serde(skip_serializing)]
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SessionUpdateConfiguration {
     #[serde(default)]
-    #[serde(with = "option_duration")]
-    pub duration: Option<Duration>,
+    pub duration: Option<SessionDuration>,
+}
+
+/// Selects sessions matching all set criteria
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SessionFilter {
+    pub template: Option<String>,
+    pub pool: Option<String>,
+    /// Minimum age, in minutes
+    pub older_than: Option<u64>,
+    /// Ids of session owners
+    pub users: Option<Vec<String>>,
+}
+
+impl SessionFilter {
+    pub fn is_empty(&self) -> bool {
+        self.template.is_none()
+            && self.pool.is_none()
+            && self.older_than.is_none()
+            && self.users.is_none()
+    }
+}
+
+/// An operation applied to many sessions at once, e.g. `{type: extend, minutes: 30}`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum SessionBatchAction {
+    Delete,
+    /// Adds `minutes` to the duration of sessions
+    Extend {
+        minutes: u64,
+    },
+    Migrate {
+        pool: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SessionBatch {
+    pub action: SessionBatchAction,
+    pub filter: SessionFilter,
+}
+
+/// Outcome of a `SessionBatchAction` for one session
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionBatchResult {
+    pub session_id: String,
+    /// Set if the action failed for this session
+    pub error: Option<String>,
+}
+
+/// Duration of a session, expressed in minutes on the wire
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct SessionDuration(Duration);
+
+impl SessionDuration {
+    /// Upper bound of any session duration, whatever the configuration
+    pub const MAX_MINUTES: u64 = 7 * 24 * 60;
+
+    pub fn from_minutes(minutes: u64) -> Result<Self, String> {
+        if minutes == 0 || minutes > Self::MAX_MINUTES {
+            return Err(format!(
+                "duration must be between 1 and {} minutes, got {}",
+                Self::MAX_MINUTES,
+                minutes
+            ));
+        }
+        Ok(SessionDuration(Duration::from_secs(minutes * 60)))
+    }
+
+    pub fn minutes(&self) -> u64 {
+        self.0.as_secs() / 60
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+
+    /// Checks this duration doesn't exceed `max`
+    pub fn check_max(&self, max: Duration) -> Result<(), String> {
+        if self.0 > max {
+            return Err(format!(
+                "duration must be at most {} minutes, got {}",
+                max.as_secs() / 60,
+                self.minutes()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<u64> for SessionDuration {
+    type Error = String;
+
+    fn try_from(minutes: u64) -> Result<Self, Self::Error> {
+        SessionDuration::from_minutes(minutes)
+    }
+}
+
+impl From<SessionDuration> for u64 {
+    fn from(duration: SessionDuration) -> Self {
+        duration.minutes()
+    }
+}
+
+impl FromStr for SessionDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SessionDuration::from_minutes(
+            s.trim()
+                .parse()
+                .map_err(|err| format!("invalid duration '{}': {}", s, err))?,
+        )
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -104,7 +463,143 @@ pub struct SessionDefaults {
     #[serde(with = "duration")]
     pub max_duration: Duration,
     pub pool_affinity: String,
+    /// Number of sessions, whatever their role, a node can run. A session is only created if its pool has room left,
+    /// i.e. this many sessions per node or the capacity the pool declares, and placed on nodes that do.
     pub max_sessions_per_pod: usize,
+    /// Resources requested by session pods, if not the built-in ones
+    pub resource_profile: Option<ResourceProfile>,
+    /// PriorityClass of session pods, so that sessions of some roles preempt others under pressure
+    pub priority_class: Option<String>,
+    /// Overrides per role, see `LoggedUser::role`
+    pub roles: BTreeMap<String, RoleDefaults>,
+}
+
+impl SessionDefaults {
+    /// Returns defaults applying to users with `role`, falling back to global ones
+    pub fn for_role(&self, role: &str) -> SessionDefaults {
+        match self.roles.get(role) {
+            Some(defaults) => SessionDefaults {
+                duration: defaults
+                    .duration
+                    .map_or(self.duration, |duration| duration.as_duration()),
+                max_duration: self.max_duration,
+                pool_affinity: defaults
+                    .pool_affinity
+                    .clone()
+                    .unwrap_or_else(|| self.pool_affinity.clone()),
+                max_sessions_per_pod: defaults
+                    .max_sessions_per_pod
+                    .unwrap_or(self.max_sessions_per_pod),
+                resource_profile: defaults
+                    .resource_profile
+                    .clone()
+                    .or_else(|| self.resource_profile.clone()),
+                priority_class: defaults
+                    .priority_class
+                    .clone()
+                    .or_else(|| self.priority_class.clone()),
+                roles: BTreeMap::new(),
+            },
+            None => self.clone(),
+        }
+    }
+}
+
+/// Session defaults of a role, used when a `SessionConfiguration` omits values
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RoleDefaults {
+    pub duration: Option<SessionDuration>,
+    pub pool_affinity: Option<String>,
+    pub resource_profile: Option<ResourceProfile>,
+    /// Overrides `SessionDefaults::max_sessions_per_pod` for sessions of this role
+    #[serde(alias = "maxSessions")]
+    pub max_sessions_per_pod: Option<usize>,
+    /// e.g. `playground-high`
+    pub priority_class: Option<String>,
+}
+
+/// Resources requested by a session pod, as Kubernetes quantities
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceProfile {
+    /// e.g. `4Gi`
+    pub memory: Option<String>,
+    /// e.g. `500m`
+    pub cpu: Option<String>,
+}
+
+/// How sessions failing to start are retried
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Time given to a session to recover on its own before being retried
+    #[serde(skip)]
+    pub delay: Duration,
+    /// Pool sessions lacking resources are moved to, if any
+    pub fallback_pool: Option<String>,
+}
+
+/// How running sessions are protected from voluntary disruptions, e.g. node drains
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisruptionPolicy {
+    /// Sessions are evicted along with their node
+    Evict,
+    /// Drains wait for sessions to end
+    Block,
+    /// Drains wait for sessions to be migrated to other nodes of their pool
+    Migrate,
+}
+
+impl FromStr for DisruptionPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "evict" => Ok(DisruptionPolicy::Evict),
+            "block" => Ok(DisruptionPolicy::Block),
+            "migrate" => Ok(DisruptionPolicy::Migrate),
+            _ => Err(format!("'{}' is not a valid value for DisruptionPolicy", s)),
+        }
+    }
+}
+
+/// How the DNS label sessions are served under is derived from their id
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubdomainStrategy {
+    /// The session id itself. Exposes user names, and breaks for ids containing dots.
+    Id,
+    /// A digest of the session id, stable across recreations
+    Hashed,
+    /// A random slug, chosen when the session is created
+    Random,
+}
+
+impl FromStr for SubdomainStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(SubdomainStrategy::Id),
+            "hashed" => Ok(SubdomainStrategy::Hashed),
+            "random" => Ok(SubdomainStrategy::Random),
+            _ => Err(format!(
+                "'{}' is not a valid value for SubdomainStrategy",
+                s
+            )),
+        }
+    }
+}
+
+/// Legal details displayed to users
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Legal {
+    pub terms_url: Option<String>,
+    /// When set, users must have accepted this version of the terms to create sessions
+    pub terms_version: Option<String>,
+    pub banner: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -116,6 +611,76 @@ pub struct User {
     #[serde(default = "default_as_false")]
     pub can_customize_pool_affinity: bool,
     pub pool_affinity: Option<String>,
+    #[serde(default)]
+    pub preferences: BTreeMap<String, String>,
+    #[serde(default)]
+    pub onboarding: OnboardingState,
+    pub accepted_terms_version: Option<String>,
+    /// Other accounts this user can log in with
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+    /// Seconds since epoch of the deletion of this user, set while it can still be restored
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+    /// Set for entries created to record the state of users allowed via an organization or an OIDC role, e.g. their
+    /// onboarding. Such entries don't allow users on their own.
+    #[serde(default)]
+    pub implicit: bool,
+}
+
+impl User {
+    /// Returns an implicit entry, with default settings
+    pub fn implicit() -> Self {
+        User {
+            admin: false,
+            can_customize_duration: false,
+            can_customize_pool_affinity: false,
+            pool_affinity: None,
+            preferences: BTreeMap::new(),
+            onboarding: OnboardingState::default(),
+            accepted_terms_version: None,
+            identities: Vec::new(),
+            deleted_at: None,
+            implicit: true,
+        }
+    }
+
+    /// Returns the onboarding state and newly accepted terms version resulting from moving to `state`. Accepting terms
+    /// is recorded against the current `terms_version`. Users that already went through onboarding can accept a newer
+    /// version of the terms.
+    pub fn onboarding_update(
+        &self,
+        state: OnboardingState,
+        terms_version: Option<&str>,
+    ) -> Result<(OnboardingState, Option<String>), String> {
+        let current = self.onboarding;
+        let accepted_terms_version = match state {
+            OnboardingState::AcceptedTerms => terms_version.map(str::to_string),
+            _ => None,
+        };
+        if current != OnboardingState::Pending
+            && state == OnboardingState::AcceptedTerms
+            && terms_version.is_some()
+            && self.accepted_terms_version.as_deref() != terms_version
+        {
+            return Ok((current, accepted_terms_version));
+        }
+        if !current.can_transition_to(&state) {
+            return Err(format!(
+                "Can't transition onboarding from {:?} to {:?}",
+                current, state
+            ));
+        }
+        Ok((state, accepted_terms_version))
+    }
+}
+
+/// An account at an identity provider, e.g. `{provider: oidc, subject: jdoe}`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub provider: Provider,
+    /// GitHub login or OIDC `sub` claim
+    pub subject: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -127,6 +692,17 @@ pub struct UserConfiguration {
     #[serde(default = "default_as_false")]
     pub can_customize_pool_affinity: bool,
     pub pool_affinity: Option<String>,
+    #[serde(default)]
+    pub preferences: BTreeMap<String, String>,
+    #[serde(default)]
+    pub onboarding: OnboardingState,
+    pub accepted_terms_version: Option<String>,
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+    #[serde(default)]
+    pub implicit: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -139,6 +715,102 @@ pub struct UserUpdateConfiguration {
     pub can_customize_pool_affinity: bool,
     pub pool_affinity: Option<String>,
 }
+
+impl From<User> for UserConfiguration {
+    fn from(user: User) -> Self {
+        UserConfiguration {
+            admin: user.admin,
+            can_customize_duration: user.can_customize_duration,
+            can_customize_pool_affinity: user.can_customize_pool_affinity,
+            pool_affinity: user.pool_affinity,
+            preferences: user.preferences,
+            onboarding: user.onboarding,
+            accepted_terms_version: user.accepted_terms_version,
+            identities: user.identities,
+            deleted_at: user.deleted_at,
+            implicit: user.implicit,
+        }
+    }
+}
+
+impl From<UserConfiguration> for User {
+    fn from(conf: UserConfiguration) -> Self {
+        User {
+            admin: conf.admin,
+            can_customize_duration: conf.can_customize_duration,
+            can_customize_pool_affinity: conf.can_customize_pool_affinity,
+            pool_affinity: conf.pool_affinity,
+            preferences: conf.preferences,
+            onboarding: conf.onboarding,
+            accepted_terms_version: conf.accepted_terms_version,
+            identities: conf.identities,
+            deleted_at: conf.deleted_at,
+            implicit: conf.implicit,
+        }
+    }
+}
+
+/// Onboarding steps a user goes through on first login
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OnboardingState {
+    Pending,
+    AcceptedTerms,
+    Completed,
+}
+
+impl Default for OnboardingState {
+    fn default() -> Self {
+        OnboardingState::Pending
+    }
+}
+
+impl OnboardingState {
+    /// Onboarding only moves forward, one step at a time
+    pub fn can_transition_to(&self, state: &OnboardingState) -> bool {
+        matches!(
+            (self, state),
+            (OnboardingState::Pending, OnboardingState::AcceptedTerms)
+                | (OnboardingState::AcceptedTerms, OnboardingState::Completed)
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OnboardingTransition {
+    pub state: OnboardingState,
+}
+
+/// A partial update of user preferences. `None` values remove the associated key.
+pub type UserPreferencesUpdate = BTreeMap<String, Option<String>>;
+
+/// Testnet funds requested by a session
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FaucetRequest {
+    /// Account to fund, e.g. an SS58 address
+    pub address: String,
+    /// Identifies the chain when the faucet serves several
+    pub chain: Option<String>,
+}
+
+/// A file shared between sessions of a workshop
+#[derive(Serialize, Clone, Debug)]
+pub struct Artifact {
+    pub name: String,
+    /// In bytes
+    pub size: usize,
+}
+
+/// A partial update of session env variables. `None` values remove the associated variable.
+pub type SessionEnvUpdate = BTreeMap<String, Option<String>>;
+
+/// Outcome of a bulk user import
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct UserImportReport {
+    pub imported: Vec<String>,
+    pub failed: BTreeMap<String, String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LoggedUser {
     pub id: String,
@@ -147,6 +819,12 @@ pub struct LoggedUser {
     pub pool_affinity: Option<String>,
     pub can_customize_duration: bool,
     pub can_customize_pool_affinity: bool,
+    #[serde(default)]
+    pub onboarding: OnboardingState,
+    pub accepted_terms_version: Option<String>,
+    /// Set by the first organization with a `role`, see `Org::role`
+    #[serde(default)]
+    pub org_role: Option<String>,
 }
 
 impl LoggedUser {
@@ -168,74 +846,754 @@ impl LoggedUser {
     pub fn has_admin_edit_rights(&self) -> bool {
         self.admin
     }
+
+    /// A coarse classification of this user, used to label metrics and pick session defaults
+    pub fn role(&self) -> &str {
+        if self.admin {
+            "admin"
+        } else if self.is_paritytech_member() {
+            "paritytech"
+        } else {
+            self.org_role.as_deref().unwrap_or("user")
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Template {
-    pub name: String,
-    pub image: String,
-    pub description: String,
-    pub tags: Option<BTreeMap<String, String>>,
-    pub runtime: Option<RuntimeConfiguration>,
+/// What a session creation would result in, returned by dry runs
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionPlan {
+    pub id: String,
+    pub template: Template,
+    pub pool: String,
+    pub domain: String,
+    /// DNS label of `url` under `domain`, see `SubdomainStrategy`
+    pub subdomain: String,
+    pub url: String,
+    pub pod_name: String,
+    #[serde(with = "duration")]
+    pub duration: Duration,
+    pub resource_profile: Option<ResourceProfile>,
+    pub priority_class: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct RuntimeConfiguration {
-    pub env: Option<Vec<NameValuePair>>,
-    pub ports: Option<Vec<Port>>,
+/// An entry of a ConfigMap that can't be parsed
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InvalidEntry {
+    pub config_map: String,
+    pub key: String,
+    pub error: String,
+    /// Path of the offending field, e.g. `runtime.ports[0].port`
+    pub field: Option<String>,
+    /// Position of the error in the entry, as reported by the YAML parser
+    pub line: Option<usize>,
+    pub column: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct NameValuePair {
-    pub name: String,
-    pub value: String,
+impl fmt::Display for InvalidEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}: {}", self.config_map, self.key, self.error)
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Port {
-    pub name: String,
-    pub protocol: Option<String>,
-    pub path: String,
-    pub port: i32,
-    pub target: Option<i32>,
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct Command {
+/// Outcome of a single self-diagnostics check
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
     pub name: String,
-    pub run: String,
-    pub working_directory: String,
+    pub status: CheckStatus,
+    pub message: Option<String>,
 }
 
-/// Utils
-
-mod system_time {
-    use serde::{self, Serializer};
-    use std::time::SystemTime;
-
-    pub fn serialize<S>(date: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        match date.and_then(|v| v.elapsed().ok()) {
-            Some(value) => serializer.serialize_some(&value.as_secs()),
-            None => serializer.serialize_none(),
+impl Check {
+    pub fn pass(name: &str) -> Self {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Pass,
+            message: None,
         }
     }
-}
 
-mod option_duration {
-    use serde::{self, Deserialize, Deserializer};
-    use std::time::Duration;
+    pub fn warn(name: &str, message: String) -> Self {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Warn,
+            message: Some(message),
+        }
+    }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        Ok(Some(Duration::from_secs(
-            u64::deserialize(deserializer)? * 60,
-        )))
+    pub fn fail(name: &str, message: String) -> Self {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Fail,
+            message: Some(message),
+        }
+    }
+}
+
+/// Result of the last self-diagnostics run
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostics {
+    #[serde(with = "timestamp")]
+    pub checked_at: SystemTime,
+    pub checks: Vec<Check>,
+    pub invalid_entries: Vec<InvalidEntry>,
+}
+
+/// Settings applied to members of a GitHub organization
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Org {
+    /// GitHub logins allowed to manage this organization settings, on top of admins
+    #[serde(default)]
+    pub admins: Vec<String>,
+    #[serde(default = "default_as_false")]
+    pub can_customize_duration: bool,
+    #[serde(default = "default_as_false")]
+    pub can_customize_pool_affinity: bool,
+    pub pool_affinity: Option<String>,
+    /// If set, members can only create sessions from those templates
+    pub allowed_templates: Option<Vec<String>>,
+    /// Session minutes available to all members, per month
+    pub budget: Option<u64>,
+    /// Role members get, one of `SessionDefaults::roles`, unless they are admins or Parity members
+    pub role: Option<String>,
+}
+
+/// All data held about a user
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserExport {
+    pub id: String,
+    pub user: Option<User>,
+    pub session: Option<Session>,
+    pub sessions_history: Vec<SessionHistoryEntry>,
+    pub audit_events: Vec<AuditEvent>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionHistoryEntry {
+    pub session_id: String,
+    #[serde(with = "timestamp")]
+    pub started_at: SystemTime,
+    #[serde(with = "optional_timestamp")]
+    pub ended_at: Option<SystemTime>,
+}
+
+/// Pool capacity set aside for a workshop during a time window
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Reservation {
+    pub pool: String,
+    /// Only sessions joining this workshop can use the reserved slots
+    pub workshop: String,
+    pub slots: usize,
+    /// Seconds since epoch
+    pub start: u64,
+    /// Seconds since epoch
+    pub end: u64,
+}
+
+impl Reservation {
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        self.start <= now && now < self.end
+    }
+}
+
+/// A sensitive operation performed by `actor` on `target`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEvent {
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub details: Option<String>,
+    #[serde(with = "timestamp")]
+    pub time: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    /// All attempts failed, the delivery won't be retried
+    DeadLetter,
+}
+
+/// Delivery of an `AuditEvent` to the configured webhook
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDelivery {
+    /// Sent as `X-Playground-Delivery`, identical across retries
+    pub id: String,
+    pub event: AuditEvent,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(with = "optional_timestamp")]
+    pub last_attempt: Option<SystemTime>,
+    #[serde(with = "optional_timestamp")]
+    pub next_attempt: Option<SystemTime>,
+}
+
+/// State of the git repository of a session workspace
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GitState {
+    /// None when HEAD is detached
+    pub branch: Option<String>,
+    /// Number of local commits not pushed upstream
+    pub ahead: u32,
+    /// Uncommitted changes, including untracked files
+    pub changes: Vec<GitChange>,
+    pub files_changed: u32,
+    pub insertions: u32,
+    pub deletions: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GitChange {
+    /// Two letters status, as reported by `git status --porcelain`
+    pub status: String,
+    pub path: String,
+}
+
+/// Notable events affecting the current session of a user
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Notifications {
+    pub eviction: Option<SessionEviction>,
+    pub tombstone: Option<Tombstone>,
+}
+
+/// Left behind when a session is terminated by an admin
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    pub reason: String,
+    #[serde(with = "timestamp")]
+    pub terminated_at: SystemTime,
+}
+
+/// Sessions usage of a single user over a period of time
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct UserUsage {
+    pub role: String,
+    pub sessions: usize,
+    pub minutes: u64,
+}
+
+/// Format version of a ConfigMap holding resources
+#[derive(Serialize, Clone, Debug)]
+pub struct StorageVersion {
+    pub version: u32,
+    /// Version supported by this backend
+    pub latest: u32,
+}
+
+/// Portable copy of the state managed by the playground, used to move it to another cluster
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StateArchive {
+    /// Format version of the archive
+    pub version: u32,
+    /// Seconds since epoch
+    pub created_at: u64,
+    /// Archived ConfigMaps, by name
+    pub config_maps: BTreeMap<String, ArchivedConfigMap>,
+    /// Running sessions to re-create, set when handed off by another cluster
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sessions: Vec<SessionHandoff>,
+}
+
+/// A session moved from another cluster
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionHandoff {
+    pub id: String,
+    /// Template of the original session, whose `snapshot` holds its workspace
+    pub template: Template,
+    /// Minutes left before the session expires
+    pub remaining: u64,
+    pub domain: String,
+    pub workshop: Option<String>,
+}
+
+/// Outcome of a restore
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RestoreReport {
+    /// Handed off sessions re-created
+    pub sessions: Vec<String>,
+    pub failed: BTreeMap<String, String>,
+}
+
+/// Outcome of a handoff
+#[derive(Serialize, Clone, Debug)]
+pub struct HandoffReport {
+    /// State to restore on the new cluster, holding handed off sessions
+    pub archive: StateArchive,
+    /// Sessions left on this cluster, with the reason
+    pub skipped: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedConfigMap {
+    /// Format version of stored resources, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_version: Option<u32>,
+    pub data: BTreeMap<String, String>,
+}
+
+/// Sessions created from a template during a day
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TemplateStats {
+    pub created: u64,
+    pub failed: u64,
+}
+
+/// Popularity of a template over a period of time
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateAnalytics {
+    /// Creation attempts, including failed ones
+    pub sessions: u64,
+    pub failures: u64,
+    /// Ratio of failed attempts, between 0 and 1
+    pub failure_rate: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Template {
+    pub name: String,
+    pub image: String,
+    pub description: String,
+    pub tags: Option<BTreeMap<String, String>>,
+    pub runtime: Option<RuntimeConfiguration>,
+    /// If set, sessions based on this template can only be scheduled on those pools
+    pub allowed_pools: Option<Vec<String>>,
+    /// Set while a new image is being rolled out
+    pub canary: Option<Canary>,
+    pub theia: Option<TheiaConfiguration>,
+    /// Web IDE served by `image`, defaults to Theia
+    pub ide: Option<Ide>,
+    pub telemetry: Option<TelemetryConfiguration>,
+    pub viewer: Option<ViewerConfiguration>,
+    /// Workspace content checked out before sessions start, set when published from a session
+    pub snapshot: Option<WorkspaceSnapshot>,
+}
+
+/// A commit holding the content of a session workspace
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorkspaceSnapshot {
+    /// HTTPS url of a git remote sessions can fetch from
+    pub remote: String,
+    pub commit: String,
+}
+
+/// Identifies a template published from a live session. `name` and `description` default to the session ones.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TemplatePublication {
+    pub id: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Template {
+    pub fn ide(&self) -> Ide {
+        self.ide.clone().unwrap_or_default()
+    }
+
+    /// Checks env variables and ports provided via `runtime` can't interfere with the ones set by the playground, and
+    /// that `snapshot` can't be used to inject arguments in git commands
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        if let Some(snapshot) = &self.snapshot {
+            let valid_remote = snapshot
+                .remote
+                .strip_prefix("https://")
+                .map_or(false, |remote| {
+                    !remote.contains('@') && !remote.chars().any(char::is_whitespace)
+                });
+            let valid_commit = snapshot.commit.len() == 40
+                && snapshot.commit.chars().all(|c| c.is_ascii_hexdigit());
+            if !valid_remote || !valid_commit {
+                return Err(TemplateError::InvalidSnapshot(format!(
+                    "{}@{}",
+                    snapshot.remote, snapshot.commit
+                )));
+            }
+        }
+        let runtime = match &self.runtime {
+            Some(runtime) => runtime,
+            None => return Ok(()),
+        };
+        if let Some(env) = runtime
+            .env
+            .iter()
+            .flatten()
+            .find(|env| env.name.starts_with(RESERVED_ENV_PREFIX))
+        {
+            return Err(TemplateError::ReservedEnv(env.name.clone()));
+        }
+        let ide_port = self.ide().port();
+        for port in runtime.ports.iter().flatten() {
+            if port.name == VIEWER_PORT {
+                return Err(TemplateError::ReservedPortName(port.name.clone()));
+            }
+            for number in std::iter::once(port.port).chain(port.target) {
+                if !TEMPLATE_PORTS.contains(&number) {
+                    return Err(TemplateError::PortOutOfRange {
+                        name: port.name.clone(),
+                        port: number,
+                    });
+                }
+                if number == ide_port {
+                    return Err(TemplateError::ReservedPort {
+                        name: port.name.clone(),
+                        port: number,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prefix of env variables set by the playground, that templates can't override
+pub const RESERVED_ENV_PREFIX: &str = "SUBSTRATE_PLAYGROUND";
+
+/// Name of the session port serving the read-only viewer, that templates and users can't use
+pub const VIEWER_PORT: &str = "viewer";
+
+/// Ports templates can expose
+pub const TEMPLATE_PORTS: RangeInclusive<i32> = 1024..=65535;
+
+/// Reasons a `Template` is rejected
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateError {
+    /// Env variable prefixed by `RESERVED_ENV_PREFIX`
+    ReservedEnv(String),
+    /// Port outside of `TEMPLATE_PORTS`
+    PortOutOfRange { name: String, port: i32 },
+    /// Port already used by the IDE
+    ReservedPort { name: String, port: i32 },
+    /// Port named `VIEWER_PORT`
+    ReservedPortName(String),
+    /// Snapshot remote not using https, or commit not a full hash
+    InvalidSnapshot(String),
+}
+
+impl TemplateError {
+    /// Path of the offending field
+    pub fn field(&self) -> &'static str {
+        match self {
+            TemplateError::ReservedEnv(_) => "runtime.env",
+            TemplateError::PortOutOfRange { .. }
+            | TemplateError::ReservedPort { .. }
+            | TemplateError::ReservedPortName(_) => "runtime.ports",
+            TemplateError::InvalidSnapshot(_) => "snapshot",
+        }
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::ReservedEnv(name) => write!(
+                f,
+                "env variable {} can't override {}* variables",
+                name, RESERVED_ENV_PREFIX
+            ),
+            TemplateError::PortOutOfRange { name, port } => write!(
+                f,
+                "port {} ({}) must be between {} and {}",
+                name,
+                port,
+                TEMPLATE_PORTS.start(),
+                TEMPLATE_PORTS.end()
+            ),
+            TemplateError::ReservedPort { name, port } => {
+                write!(f, "port {} ({}) is reserved for the IDE", name, port)
+            }
+            TemplateError::ReservedPortName(name) => {
+                write!(f, "port name {} is reserved for the read-only viewer", name)
+            }
+            TemplateError::InvalidSnapshot(snapshot) => write!(f, "invalid snapshot {}", snapshot),
+        }
+    }
+}
+
+/// A web IDE, e.g. `{type: code-server}` or `{type: custom, port: 8000, path: /ide}`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Ide {
+    Theia,
+    CodeServer,
+    Jupyter,
+    #[serde(rename_all = "camelCase")]
+    Custom {
+        port: i32,
+        /// Path the IDE is served under, defaults to `/`
+        path: Option<String>,
+        /// Path answering `200` once the IDE is ready
+        health_check: Option<String>,
+    },
+}
+
+impl Default for Ide {
+    fn default() -> Self {
+        Ide::Theia
+    }
+}
+
+impl Ide {
+    pub fn port(&self) -> i32 {
+        match self {
+            Ide::Theia => 3000,
+            Ide::CodeServer => 8080,
+            Ide::Jupyter => 8888,
+            Ide::Custom { port, .. } => *port,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            Ide::Custom {
+                path: Some(path), ..
+            } => path,
+            _ => "/",
+        }
+    }
+
+    pub fn health_check(&self) -> Option<&str> {
+        match self {
+            Ide::Theia => Some("/"),
+            Ide::CodeServer => Some("/healthz"),
+            Ide::Jupyter => Some("/api"),
+            Ide::Custom { health_check, .. } => health_check.as_deref(),
+        }
+    }
+}
+
+/// Theia customizations applied when a session starts
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TheiaConfiguration {
+    /// Plugins installed on startup, e.g. `vscode:extension/matklad.rust-analyzer`
+    #[serde(default)]
+    pub plugins: Vec<String>,
+    /// Initial user settings. Left untouched if the user already has some.
+    pub settings: Option<serde_json::Value>,
+}
+
+/// Telemetry of the in-session substrate node, exposed as `wss://<session>.<host>/telemetry`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TelemetryConfiguration {
+    /// Port serving the node telemetry feed, e.g. a local `substrate-telemetry` the node submits to
+    pub port: i32,
+}
+
+/// A read-only view of a session, e.g. a preview, that owners can publish under a tokenized url
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ViewerConfiguration {
+    /// Port serving the view, received requests are prefixed with `/view/<token>`.
+    /// Whatever is served there must not give exec nor write access to the workspace.
+    pub port: i32,
+}
+
+/// A new template image, used by a share of new sessions until promoted or aborted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Canary {
+    pub image: String,
+    /// Between 0 and 100
+    pub percentage: u8,
+}
+
+/// Filtering, sorting and pagination parameters used when listing templates
+#[derive(Clone, Debug, Default)]
+pub struct TemplateQuery {
+    /// Case insensitive text matched against id, name and description
+    pub q: Option<String>,
+    /// Either a tag key or a `key=value` pair
+    pub tag: Option<String>,
+    /// One of `id` (default) or `name`
+    pub sort: Option<String>,
+    /// 1-based page index
+    pub page: Option<usize>,
+    /// Clamped between 1 and `MAX_PER_PAGE`
+    pub per_page: Option<usize>,
+}
+
+impl TemplateQuery {
+    pub const DEFAULT_PER_PAGE: usize = 20;
+    /// Larger pages are reduced to this size
+    pub const MAX_PER_PAGE: usize = 100;
+
+    pub fn matches(&self, id: &str, template: &Template) -> bool {
+        let text_matches = self.q.as_ref().map_or(true, |q| {
+            let q = q.to_lowercase();
+            id.to_lowercase().contains(&q)
+                || template.name.to_lowercase().contains(&q)
+                || template.description.to_lowercase().contains(&q)
+        });
+        let tag_matches = self.tag.as_ref().map_or(true, |tag| {
+            let tags = template.tags.clone().unwrap_or_default();
+            match tag.split_once('=') {
+                Some((key, value)) => tags.get(key).map_or(false, |v| v == value),
+                None => tags.contains_key(tag),
+            }
+        });
+        text_matches && tag_matches
+    }
+
+    /// Sorts and paginates already filtered `entries`
+    pub fn paginate(
+        &self,
+        mut entries: Vec<Entry<Template>>,
+    ) -> Result<Page<Entry<Template>>, String> {
+        match self.sort.as_deref() {
+            None | Some("id") => entries.sort_by(|a, b| a.id.cmp(&b.id)),
+            Some("name") => entries.sort_by(|a, b| a.value.name.cmp(&b.value.name)),
+            Some(sort) => return Err(format!("'{}' is not a valid value for sort", sort)),
+        }
+        let page = self.page.unwrap_or(1).max(1);
+        let per_page = self
+            .per_page
+            .unwrap_or(Self::DEFAULT_PER_PAGE)
+            .clamp(1, Self::MAX_PER_PAGE);
+        let skipped = page
+            .saturating_sub(1)
+            .checked_mul(per_page)
+            .ok_or_else(|| format!("page {} is out of range", page))?;
+        let total = entries.len();
+        Ok(Page {
+            items: entries.into_iter().skip(skipped).take(per_page).collect(),
+            total,
+            page,
+            per_page,
+        })
+    }
+}
+
+/// A resource alongside its unique id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry<T> {
+    pub id: String,
+    #[serde(flatten)]
+    pub value: T,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuntimeConfiguration {
+    pub env: Option<Vec<NameValuePair>>,
+    pub ports: Option<Vec<Port>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NameValuePair {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Port {
+    pub name: String,
+    pub protocol: Option<String>,
+    pub path: String,
+    pub port: i32,
+    pub target: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Command {
+    pub name: String,
+    pub run: String,
+    pub working_directory: String,
+}
+
+/// Utils
+
+mod system_time {
+    use serde::{self, Serializer};
+    use std::time::SystemTime;
+
+    pub fn serialize<S>(date: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date.and_then(|v| v.elapsed().ok()) {
+            Some(value) => serializer.serialize_some(&value.as_secs()),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+/// Serializes an optional `SystemTime` as seconds since epoch
+mod optional_timestamp {
+    use serde::{self, Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+            Some(duration) => serializer.serialize_some(&duration.as_secs()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<u64>::deserialize(deserializer)?
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}
+
+/// Serializes a `SystemTime` as seconds since epoch
+mod timestamp {
+    use serde::{self, Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u64(
+            time.duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(UNIX_EPOCH + Duration::from_secs(u64::deserialize(deserializer)?))
     }
 }
 
@@ -254,3 +1612,864 @@ mod duration {
 fn default_as_false() -> bool {
     false
 }
+
+fn default_backup_interval() -> u64 {
+    30
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::btree_map, collection::vec, option, prelude::*};
+    use serde::de::DeserializeOwned;
+
+    #[test]
+    fn rejects_out_of_range_pages() {
+        let query = |page, per_page| TemplateQuery {
+            page: Some(page),
+            per_page: Some(per_page),
+            ..Default::default()
+        };
+        assert!(query(usize::MAX, 100).paginate(Vec::new()).is_err());
+        let page = query(2, usize::MAX).paginate(Vec::new()).unwrap();
+        assert_eq!(page.per_page, TemplateQuery::MAX_PER_PAGE);
+        assert!(page.items.is_empty());
+    }
+
+    /// Checks `value` is left unchanged once serialized then parsed back, both as JSON and as YAML
+    fn check_round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Result<(), TestCaseError> {
+        let expected = serde_json::to_value(value).unwrap();
+        let json: T = serde_json::from_str(&serde_json::to_string(value).unwrap())
+            .map_err(|err| TestCaseError::fail(format!("JSON: {}", err)))?;
+        prop_assert_eq!(&serde_json::to_value(&json).unwrap(), &expected);
+        let yaml: T = serde_yaml::from_str(&serde_yaml::to_string(value).unwrap())
+            .map_err(|err| TestCaseError::fail(format!("YAML: {}", err)))?;
+        prop_assert_eq!(&serde_json::to_value(&yaml).unwrap(), &expected);
+        Ok(())
+    }
+
+    fn string() -> impl Strategy<Value = String> {
+        any::<String>()
+    }
+
+    fn strings() -> impl Strategy<Value = Vec<String>> {
+        vec(string(), 0..4)
+    }
+
+    fn string_map() -> impl Strategy<Value = BTreeMap<String, String>> {
+        btree_map(string(), string(), 0..4)
+    }
+
+    fn session_duration() -> impl Strategy<Value = SessionDuration> {
+        (1..=SessionDuration::MAX_MINUTES)
+            .prop_map(|minutes| SessionDuration::from_minutes(minutes).unwrap())
+    }
+
+    fn phase() -> impl Strategy<Value = Phase> {
+        prop_oneof![
+            Just(Phase::Pending),
+            Just(Phase::Running),
+            Just(Phase::Succeeded),
+            Just(Phase::Failed),
+            Just(Phase::Unknown),
+            Just(Phase::Suspended),
+        ]
+    }
+
+    fn container_phase() -> impl Strategy<Value = ContainerPhase> {
+        prop_oneof![
+            Just(ContainerPhase::Running),
+            Just(ContainerPhase::Terminated),
+            Just(ContainerPhase::Waiting),
+            Just(ContainerPhase::Unknown),
+        ]
+    }
+
+    fn session_eviction() -> impl Strategy<Value = SessionEviction> {
+        (string(), option::of(string()), any::<u64>()).prop_map(|(reason, message, time)| {
+            SessionEviction {
+                reason,
+                message,
+                time,
+            }
+        })
+    }
+
+    fn session_backup() -> impl Strategy<Value = SessionBackup> {
+        (string(), string(), any::<u64>(), option::of(string())).prop_map(
+            |(remote, branch, interval, token)| SessionBackup {
+                remote,
+                branch,
+                interval,
+                token,
+            },
+        )
+    }
+
+    fn session_configuration() -> impl Strategy<Value = SessionConfiguration> {
+        (
+            string(),
+            option::of(session_duration()),
+            option::of(string()),
+            option::of(string()),
+            option::of(session_backup()),
+            option::of(string()),
+        )
+            .prop_map(
+                |(template, duration, pool_affinity, domain, backup, workshop)| {
+                    SessionConfiguration {
+                        template,
+                        duration,
+                        pool_affinity,
+                        domain,
+                        backup,
+                        workshop,
+                        handoff: None,
+                        unattended: false,
+                    }
+                },
+            )
+    }
+
+    fn session_filter() -> impl Strategy<Value = SessionFilter> {
+        (
+            option::of(string()),
+            option::of(string()),
+            option::of(any::<u64>()),
+            option::of(strings()),
+        )
+            .prop_map(|(template, pool, older_than, users)| SessionFilter {
+                template,
+                pool,
+                older_than,
+                users,
+            })
+    }
+
+    fn session_batch_action() -> impl Strategy<Value = SessionBatchAction> {
+        prop_oneof![
+            Just(SessionBatchAction::Delete),
+            any::<u64>().prop_map(|minutes| SessionBatchAction::Extend { minutes }),
+            string().prop_map(|pool| SessionBatchAction::Migrate { pool }),
+        ]
+    }
+
+    fn resource_profile() -> impl Strategy<Value = ResourceProfile> {
+        (option::of(string()), option::of(string()))
+            .prop_map(|(memory, cpu)| ResourceProfile { memory, cpu })
+    }
+
+    fn role_defaults() -> impl Strategy<Value = RoleDefaults> {
+        (
+            option::of(session_duration()),
+            option::of(string()),
+            option::of(resource_profile()),
+            option::of(any::<usize>()),
+            option::of(string()),
+        )
+            .prop_map(
+                |(
+                    duration,
+                    pool_affinity,
+                    resource_profile,
+                    max_sessions_per_pod,
+                    priority_class,
+                )| {
+                    RoleDefaults {
+                        duration,
+                        pool_affinity,
+                        resource_profile,
+                        max_sessions_per_pod,
+                        priority_class,
+                    }
+                },
+            )
+    }
+
+    fn onboarding_state() -> impl Strategy<Value = OnboardingState> {
+        prop_oneof![
+            Just(OnboardingState::Pending),
+            Just(OnboardingState::AcceptedTerms),
+            Just(OnboardingState::Completed),
+        ]
+    }
+
+    fn identity() -> impl Strategy<Value = Identity> {
+        (
+            prop_oneof![Just(Provider::GitHub), Just(Provider::Oidc)],
+            string(),
+        )
+            .prop_map(|(provider, subject)| Identity { provider, subject })
+    }
+
+    fn user_configuration() -> impl Strategy<Value = UserConfiguration> {
+        (
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+            option::of(string()),
+            string_map(),
+            onboarding_state(),
+            option::of(string()),
+            vec(identity(), 0..3),
+            option::of(any::<u64>()),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(
+                    admin,
+                    can_customize_duration,
+                    can_customize_pool_affinity,
+                    pool_affinity,
+                    preferences,
+                    onboarding,
+                    accepted_terms_version,
+                    identities,
+                    deleted_at,
+                    implicit,
+                )| UserConfiguration {
+                    admin,
+                    can_customize_duration,
+                    can_customize_pool_affinity,
+                    pool_affinity,
+                    preferences,
+                    onboarding,
+                    accepted_terms_version,
+                    identities,
+                    deleted_at,
+                    implicit,
+                },
+            )
+    }
+
+    fn user() -> impl Strategy<Value = User> {
+        user_configuration().prop_map(User::from)
+    }
+
+    fn user_update_configuration() -> impl Strategy<Value = UserUpdateConfiguration> {
+        (
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+            option::of(string()),
+        )
+            .prop_map(
+                |(admin, can_customize_duration, can_customize_pool_affinity, pool_affinity)| {
+                    UserUpdateConfiguration {
+                        admin,
+                        can_customize_duration,
+                        can_customize_pool_affinity,
+                        pool_affinity,
+                    }
+                },
+            )
+    }
+
+    fn logged_user() -> impl Strategy<Value = LoggedUser> {
+        (
+            string(),
+            any::<bool>(),
+            strings(),
+            option::of(string()),
+            any::<bool>(),
+            any::<bool>(),
+            onboarding_state(),
+            option::of(string()),
+            option::of(string()),
+        )
+            .prop_map(
+                |(
+                    id,
+                    admin,
+                    organizations,
+                    pool_affinity,
+                    can_customize_duration,
+                    can_customize_pool_affinity,
+                    onboarding,
+                    accepted_terms_version,
+                    org_role,
+                )| LoggedUser {
+                    id,
+                    admin,
+                    organizations,
+                    pool_affinity,
+                    can_customize_duration,
+                    can_customize_pool_affinity,
+                    onboarding,
+                    accepted_terms_version,
+                    org_role,
+                },
+            )
+    }
+
+    fn org() -> impl Strategy<Value = Org> {
+        (
+            strings(),
+            any::<bool>(),
+            any::<bool>(),
+            option::of(string()),
+            option::of(strings()),
+            option::of(any::<u64>()),
+            option::of(string()),
+        )
+            .prop_map(
+                |(
+                    admins,
+                    can_customize_duration,
+                    can_customize_pool_affinity,
+                    pool_affinity,
+                    allowed_templates,
+                    budget,
+                    role,
+                )| Org {
+                    admins,
+                    can_customize_duration,
+                    can_customize_pool_affinity,
+                    pool_affinity,
+                    allowed_templates,
+                    budget,
+                    role,
+                },
+            )
+    }
+
+    fn reservation() -> impl Strategy<Value = Reservation> {
+        (
+            string(),
+            string(),
+            any::<usize>(),
+            any::<u64>(),
+            any::<u64>(),
+        )
+            .prop_map(|(pool, workshop, slots, start, end)| Reservation {
+                pool,
+                workshop,
+                slots,
+                start,
+                end,
+            })
+    }
+
+    fn ide() -> impl Strategy<Value = Ide> {
+        prop_oneof![
+            Just(Ide::Theia),
+            Just(Ide::CodeServer),
+            Just(Ide::Jupyter),
+            (any::<i32>(), option::of(string()), option::of(string())).prop_map(
+                |(port, path, health_check)| Ide::Custom {
+                    port,
+                    path,
+                    health_check
+                }
+            ),
+        ]
+    }
+
+    fn json_value() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            string().prop_map(serde_json::Value::from),
+        ];
+        leaf.prop_recursive(3, 16, 4, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..4).prop_map(serde_json::Value::from),
+                btree_map(string(), inner, 0..4)
+                    .prop_map(|map| serde_json::Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    fn theia_configuration() -> impl Strategy<Value = TheiaConfiguration> {
+        (strings(), option::of(json_value()))
+            .prop_map(|(plugins, settings)| TheiaConfiguration { plugins, settings })
+    }
+
+    fn port() -> impl Strategy<Value = Port> {
+        (
+            string(),
+            option::of(string()),
+            string(),
+            any::<i32>(),
+            option::of(any::<i32>()),
+        )
+            .prop_map(|(name, protocol, path, port, target)| Port {
+                name,
+                protocol,
+                path,
+                port,
+                target,
+            })
+    }
+
+    fn runtime_configuration() -> impl Strategy<Value = RuntimeConfiguration> {
+        (
+            option::of(vec(
+                (string(), string()).prop_map(|(name, value)| NameValuePair { name, value }),
+                0..3,
+            )),
+            option::of(vec(port(), 0..3)),
+        )
+            .prop_map(|(env, ports)| RuntimeConfiguration { env, ports })
+    }
+
+    fn workspace_snapshot() -> impl Strategy<Value = WorkspaceSnapshot> {
+        (string(), string()).prop_map(|(remote, commit)| WorkspaceSnapshot { remote, commit })
+    }
+
+    fn template() -> impl Strategy<Value = Template> {
+        (
+            (string(), string(), string(), option::of(string_map())),
+            option::of(runtime_configuration()),
+            option::of(strings()),
+            option::of(
+                (string(), any::<u8>())
+                    .prop_map(|(image, percentage)| Canary { image, percentage }),
+            ),
+            option::of(theia_configuration()),
+            option::of(ide()),
+            option::of(any::<i32>().prop_map(|port| TelemetryConfiguration { port })),
+            option::of(any::<i32>().prop_map(|port| ViewerConfiguration { port })),
+            option::of(workspace_snapshot()),
+        )
+            .prop_map(
+                |(
+                    (name, image, description, tags),
+                    runtime,
+                    allowed_pools,
+                    canary,
+                    theia,
+                    ide,
+                    telemetry,
+                    viewer,
+                    snapshot,
+                )| Template {
+                    name,
+                    image,
+                    description,
+                    tags,
+                    runtime,
+                    allowed_pools,
+                    canary,
+                    theia,
+                    ide,
+                    telemetry,
+                    viewer,
+                    snapshot,
+                },
+            )
+    }
+
+    fn entry<T: fmt::Debug>(value: impl Strategy<Value = T>) -> impl Strategy<Value = Entry<T>> {
+        (string(), value).prop_map(|(id, value)| Entry { id, value })
+    }
+
+    fn state_archive() -> impl Strategy<Value = StateArchive> {
+        let config_map =
+            (option::of(any::<u32>()), string_map()).prop_map(|(storage_version, data)| {
+                ArchivedConfigMap {
+                    storage_version,
+                    data,
+                }
+            });
+        let handoff = (
+            string(),
+            template(),
+            any::<u64>(),
+            string(),
+            option::of(string()),
+        )
+            .prop_map(
+                |(id, template, remaining, domain, workshop)| SessionHandoff {
+                    id,
+                    template,
+                    remaining,
+                    domain,
+                    workshop,
+                },
+            );
+        (
+            any::<u32>(),
+            any::<u64>(),
+            btree_map(string(), config_map, 0..3),
+            vec(handoff, 0..2),
+        )
+            .prop_map(
+                |(version, created_at, config_maps, sessions)| StateArchive {
+                    version,
+                    created_at,
+                    config_maps,
+                    sessions,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn session_types_round_trip(
+            phase in phase(),
+            container_phase in container_phase(),
+            eviction in session_eviction(),
+            conf in session_configuration(),
+            duration in option::of(session_duration()),
+            batch in (session_batch_action(), session_filter()),
+        ) {
+            check_round_trip(&phase)?;
+            check_round_trip(&container_phase)?;
+            check_round_trip(&eviction)?;
+            check_round_trip(&conf)?;
+            check_round_trip(&SessionUpdateConfiguration { duration })?;
+            let (action, filter) = batch;
+            check_round_trip(&SessionBatch { action, filter })?;
+        }
+
+        #[test]
+        fn session_durations_are_minutes(duration in session_duration()) {
+            let minutes = serde_json::to_value(duration).unwrap();
+            prop_assert_eq!(minutes, serde_json::json!(duration.minutes()));
+            check_round_trip(&duration)?;
+        }
+
+        /// `Session::duration` is read back as a `SessionDuration`, e.g. by clients re-creating a session
+        #[test]
+        fn serialized_durations_parse_as_session_durations(duration in session_duration()) {
+            #[derive(Serialize)]
+            struct Serialized(#[serde(with = "super::duration")] Duration);
+
+            let value = serde_json::to_string(&Serialized(duration.as_duration())).unwrap();
+            let parsed: SessionDuration = serde_json::from_str(&value).unwrap();
+            prop_assert_eq!(parsed.as_duration(), duration.as_duration());
+        }
+
+        #[test]
+        fn durations_are_range_checked(minutes in 0..SessionDuration::MAX_MINUTES * 2) {
+            let valid = (1..=SessionDuration::MAX_MINUTES).contains(&minutes);
+            prop_assert_eq!(SessionDuration::from_minutes(minutes).is_ok(), valid);
+            prop_assert_eq!(SessionDuration::from_str(&minutes.to_string()).is_ok(), valid);
+            prop_assert_eq!(
+                serde_json::from_str::<SessionDuration>(&minutes.to_string()).is_ok(),
+                valid
+            );
+        }
+
+        /// The maximum itself is a valid duration
+        #[test]
+        fn durations_are_checked_against_max(duration in session_duration(), max in session_duration()) {
+            prop_assert_eq!(
+                duration.check_max(max.as_duration()).is_ok(),
+                duration.minutes() <= max.minutes()
+            );
+            prop_assert!(max.check_max(max.as_duration()).is_ok());
+        }
+
+        #[test]
+        fn user_types_round_trip(
+            user in entry(user()),
+            conf in user_configuration(),
+            update in user_update_configuration(),
+            logged_user in logged_user(),
+            role_defaults in role_defaults(),
+            org in entry(org()),
+        ) {
+            check_round_trip(&user)?;
+            check_round_trip(&conf)?;
+            check_round_trip(&update)?;
+            check_round_trip(&logged_user)?;
+            check_round_trip(&role_defaults)?;
+            check_round_trip(&org)?;
+        }
+
+        #[test]
+        fn template_round_trips(template in entry(template())) {
+            check_round_trip(&template)?;
+        }
+
+        #[test]
+        fn state_round_trips(
+            archive in state_archive(),
+            reservations in btree_map(string(), reservation(), 0..3),
+            stats in (any::<u64>(), any::<u64>()),
+            faucet in (string(), option::of(string())),
+        ) {
+            check_round_trip(&archive)?;
+            check_round_trip(&reservations)?;
+            let (created, failed) = stats;
+            check_round_trip(&TemplateStats { created, failed })?;
+            let (address, chain) = faucet;
+            check_round_trip(&FaucetRequest { address, chain })?;
+        }
+    }
+
+    #[test]
+    fn out_of_range_durations_are_rejected() {
+        assert!(serde_json::from_str::<SessionDuration>("0").is_err());
+        assert!(serde_json::from_str::<SessionDuration>(
+            &(SessionDuration::MAX_MINUTES + 1).to_string()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn onboarding_records_accepted_terms() {
+        let user = User::implicit();
+        assert_eq!(
+            user.onboarding_update(OnboardingState::AcceptedTerms, Some("2")),
+            Ok((OnboardingState::AcceptedTerms, Some("2".to_string())))
+        );
+        assert!(user
+            .onboarding_update(OnboardingState::Completed, Some("2"))
+            .is_err());
+
+        // Newer terms can be accepted once onboarded, without going back
+        let user = User {
+            onboarding: OnboardingState::Completed,
+            accepted_terms_version: Some("1".to_string()),
+            ..User::implicit()
+        };
+        assert_eq!(
+            user.onboarding_update(OnboardingState::AcceptedTerms, Some("2")),
+            Ok((OnboardingState::Completed, Some("2".to_string())))
+        );
+        assert!(user
+            .onboarding_update(OnboardingState::AcceptedTerms, Some("1"))
+            .is_err());
+    }
+
+    // The v1 wire format, as read by clients. Changing these snapshots breaks them.
+
+    fn v1_template() -> Template {
+        Template {
+            name: "Node".to_string(),
+            image: "paritytech/node:latest".to_string(),
+            description: "A node".to_string(),
+            tags: None,
+            runtime: Some(RuntimeConfiguration {
+                env: None,
+                ports: Some(vec![Port {
+                    name: "web".to_string(),
+                    protocol: None,
+                    path: "/".to_string(),
+                    port: 80,
+                    target: None,
+                }]),
+            }),
+            allowed_pools: None,
+            canary: None,
+            theia: None,
+            ide: None,
+            telemetry: None,
+            viewer: None,
+            snapshot: None,
+        }
+    }
+
+    fn v1_template_json() -> serde_json::Value {
+        serde_json::json!({
+            "name": "Node",
+            "image": "paritytech/node:latest",
+            "description": "A node",
+            "tags": null,
+            "runtime": {
+                "env": null,
+                "ports": [{"name": "web", "protocol": null, "path": "/", "port": 80, "target": null}]
+            },
+            "allowed_pools": null,
+            "canary": null,
+            "theia": null,
+            "ide": null,
+            "telemetry": null,
+            "viewer": null,
+            "snapshot": null
+        })
+    }
+
+    #[test]
+    fn v1_session_format() {
+        let session = Session {
+            user_id: "jdoe".to_string(),
+            template: v1_template(),
+            url: "jdoe.playground.substrate.dev".to_string(),
+            pod: Pod {
+                phase: Phase::Running,
+                reason: String::new(),
+                message: String::new(),
+                start_time: None,
+                container: None,
+                steps: vec![DeploymentStep {
+                    name: "scheduled".to_string(),
+                    completed: true,
+                    completed_at: Some(UNIX_EPOCH + Duration::from_secs(60)),
+                }],
+                failure: None,
+            },
+            duration: Duration::from_secs(3600),
+            node: "node-1".to_string(),
+            domain: "playground.substrate.dev".to_string(),
+            flags: Vec::new(),
+            migration: None,
+            backup: None,
+            workshop: None,
+            pool: None,
+            role: None,
+            organizations: Vec::new(),
+            retries: 1,
+            last_activity: Some(UNIX_EPOCH + Duration::from_secs(120)),
+            unattended: false,
+            deleted_at: None,
+            dns: Some(DnsStatus::Propagated),
+        };
+        assert_eq!(
+            serde_json::to_value(&session).unwrap(),
+            serde_json::json!({
+                "user_id": "jdoe",
+                "template": v1_template_json(),
+                "url": "jdoe.playground.substrate.dev",
+                "pod": {
+                    "phase": "Running",
+                    "reason": "",
+                    "message": "",
+                    "startTime": null,
+                    "container": null,
+                    "steps": [{"name": "scheduled", "completed": true, "completedAt": 60}],
+                    "failure": null
+                },
+                "duration": 60,
+                "node": "node-1",
+                "domain": "playground.substrate.dev",
+                "flags": [],
+                "migration": null,
+                "backup": null,
+                "workshop": null,
+                "retries": 1,
+                "last_activity": 120,
+                "unattended": false,
+                "deleted_at": null,
+                "dns": "Propagated"
+            })
+        );
+    }
+
+    #[test]
+    fn v1_user_format() {
+        let user = User {
+            onboarding: OnboardingState::Completed,
+            accepted_terms_version: Some("1".to_string()),
+            identities: vec![Identity {
+                provider: Provider::GitHub,
+                subject: "jdoe".to_string(),
+            }],
+            implicit: false,
+            ..User::implicit()
+        };
+        assert_eq!(
+            serde_json::to_value(&user).unwrap(),
+            serde_json::json!({
+                "admin": false,
+                "canCustomizeDuration": false,
+                "canCustomizePoolAffinity": false,
+                "poolAffinity": null,
+                "preferences": {},
+                "onboarding": "Completed",
+                "acceptedTermsVersion": "1",
+                "identities": [{"provider": "github", "subject": "jdoe"}],
+                "deletedAt": null,
+                "implicit": false
+            })
+        );
+    }
+
+    #[test]
+    fn v1_logged_user_format() {
+        let user = LoggedUser {
+            id: "jdoe".to_string(),
+            admin: false,
+            organizations: vec!["paritytech".to_string()],
+            pool_affinity: None,
+            can_customize_duration: true,
+            can_customize_pool_affinity: false,
+            onboarding: OnboardingState::Pending,
+            accepted_terms_version: None,
+            org_role: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&user).unwrap(),
+            serde_json::json!({
+                "id": "jdoe",
+                "admin": false,
+                "organizations": ["paritytech"],
+                "pool_affinity": null,
+                "can_customize_duration": true,
+                "can_customize_pool_affinity": false,
+                "onboarding": "Pending",
+                "accepted_terms_version": null,
+                "org_role": null
+            })
+        );
+    }
+
+    #[test]
+    fn v1_template_format() {
+        assert_eq!(
+            serde_json::to_value(&v1_template()).unwrap(),
+            v1_template_json()
+        );
+        // As sent by clients publishing templates
+        let template: Template = serde_json::from_value(v1_template_json()).unwrap();
+        assert_eq!(
+            template
+                .runtime
+                .and_then(|runtime| runtime.ports)
+                .map(|ports| ports[0].port),
+            Some(80)
+        );
+    }
+
+    #[test]
+    fn v1_session_configuration_format() {
+        let conf: SessionConfiguration = serde_json::from_value(serde_json::json!({
+            "template": "node",
+            "duration": 60,
+            "poolAffinity": "default",
+            "workshop": "w"
+        }))
+        .unwrap();
+        assert_eq!(conf.template, "node");
+        assert_eq!(
+            conf.duration.map(|duration| duration.as_duration()),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(conf.pool_affinity.as_deref(), Some("default"));
+        assert_eq!(conf.domain, None);
+        assert_eq!(conf.workshop.as_deref(), Some("w"));
+    }
+
+    #[test]
+    fn v1_pool_format() {
+        let pool = UsablePool {
+            pool: Pool {
+                name: "default".to_string(),
+                instance_type: Some("n2-standard-8".to_string()),
+                nodes: vec![Node {
+                    hostname: "node-1".to_string(),
+                }],
+                capacity: None,
+            },
+            free_slots: 2,
+            prepulled_templates: vec!["node".to_string()],
+            latency: StartLatency::Fast,
+        };
+        assert_eq!(
+            serde_json::to_value(&pool).unwrap(),
+            serde_json::json!({
+                "name": "default",
+                "instanceType": "n2-standard-8",
+                "nodes": [{"hostname": "node-1"}],
+                "capacity": null,
+                "freeSlots": 2,
+                "prepulledTemplates": ["node"],
+                "latency": "Fast"
+            })
+        );
+    }
+}
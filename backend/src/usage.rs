@@ -0,0 +1,143 @@
+//! In-memory tracking of sessions usage, used for capacity planning reports
+//!
+//! Records are lost when the backend restarts.
+use crate::types::{SessionHistoryEntry, UserUsage};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Clone, Debug)]
+pub struct UsageRecord {
+    pub session_id: String,
+    pub user_id: String,
+    pub role: String,
+    pub organizations: Vec<String>,
+    pub started_at: SystemTime,
+    pub ended_at: Option<SystemTime>,
+}
+
+impl UsageRecord {
+    /// Returns how long this session overlapped with [`from`, `to`]
+    fn overlap(&self, from: SystemTime, to: SystemTime) -> Duration {
+        let start = self.started_at.max(from);
+        let end = self.ended_at.unwrap_or_else(SystemTime::now).min(to);
+        end.duration_since(start).unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    records: Arc<Mutex<VecDeque<UsageRecord>>>,
+}
+
+impl Usage {
+    /// Maximum number of records kept. Oldest records are dropped first.
+    const MAX_RECORDS: usize = 10_000;
+
+    pub fn record_start(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        role: &str,
+        organizations: &[String],
+    ) {
+        if let Ok(mut records) = self.records.lock() {
+            if records.len() >= Self::MAX_RECORDS {
+                records.pop_front();
+            }
+            records.push_back(UsageRecord {
+                session_id: session_id.to_string(),
+                user_id: user_id.to_string(),
+                role: role.to_string(),
+                organizations: organizations.to_vec(),
+                started_at: SystemTime::now(),
+                ended_at: None,
+            });
+        }
+    }
+
+    /// Marks the latest record of `session_id` as ended. Returns the updated record, if any.
+    pub fn record_end(&self, session_id: &str) -> Option<UsageRecord> {
+        let mut records = self.records.lock().ok()?;
+        let record = records
+            .iter_mut()
+            .rev()
+            .find(|record| record.session_id == session_id && record.ended_at.is_none())?;
+        record.ended_at = Some(SystemTime::now());
+        Some(record.clone())
+    }
+
+    /// Sessions started by `user_id`, oldest first
+    pub fn history(&self, user_id: &str) -> Vec<SessionHistoryEntry> {
+        self.records
+            .lock()
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|record| record.user_id == user_id)
+                    .map(|record| SessionHistoryEntry {
+                        session_id: record.session_id.clone(),
+                        started_at: record.started_at,
+                        ended_at: record.ended_at,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Anonymizes records of `user_id`, keeping them for aggregated reports
+    pub fn scrub(&self, user_id: &str, replacement: &str) {
+        if let Ok(mut records) = self.records.lock() {
+            for record in records
+                .iter_mut()
+                .filter(|record| record.user_id == user_id)
+            {
+                record.user_id = replacement.to_string();
+                record.session_id = replacement.to_string();
+            }
+        }
+    }
+
+    /// Total minutes of sessions matching `filter` overlapping [`from`, `to`]
+    pub fn minutes<F>(&self, from: SystemTime, to: SystemTime, filter: F) -> u64
+    where
+        F: Fn(&UsageRecord) -> bool,
+    {
+        self.records
+            .lock()
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|record| filter(record))
+                    .map(|record| record.overlap(from, to).as_secs() / 60)
+                    .sum()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Aggregates per user usage of sessions overlapping [`from`, `to`]
+    pub fn report(&self, from: SystemTime, to: SystemTime) -> BTreeMap<String, UserUsage> {
+        let records = match self.records.lock() {
+            Ok(records) => records.clone(),
+            Err(_) => return BTreeMap::new(),
+        };
+        records
+            .iter()
+            .filter(|record| {
+                record.started_at <= to && record.ended_at.map_or(true, |end| end >= from)
+            })
+            .fold(BTreeMap::new(), |mut acc, record| {
+                let usage = acc
+                    .entry(record.user_id.clone())
+                    .or_insert_with(|| UserUsage {
+                        role: record.role.clone(),
+                        ..Default::default()
+                    });
+                usage.sessions += 1;
+                usage.minutes += record.overlap(from, to).as_secs() / 60;
+                acc
+            })
+    }
+}
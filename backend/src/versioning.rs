@@ -0,0 +1,122 @@
+//! API versioning
+//!
+//! The API is served under `/api/v1`. Changes to its wire format must be backward compatible; breaking ones go to a new
+//! version. Clients can also pin a version via the `Api-Version` request header: unsupported versions are rejected with
+//! `406`. The version serving a request is returned as `Api-Version`.
+//!
+//! Unversioned `/api` routes are kept for older clients. They are deprecated: responses carry `Deprecation` (see
+//! https://datatracker.ietf.org/doc/draft-ietf-httpapi-deprecation-header/), a `Link` to the `successor-version` and, if
+//! `API_LEGACY_SUNSET` is set, a `Sunset` header (RFC 8594) holding the HTTP date after which they will be removed.
+//! Callbacks registered with third parties (OAuth providers, GitHub webhooks) are not deprecated.
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, Header, Status},
+    Data, Request, Response,
+};
+use std::{env, io::Cursor};
+
+pub const V1_PREFIX: &str = "/api/v1";
+pub const LEGACY_PREFIX: &str = "/api";
+const VERSION_HEADER: &str = "Api-Version";
+const CURRENT_VERSION: &str = "1";
+const SUPPORTED_VERSIONS: &[&str] = &[CURRENT_VERSION];
+/// Matched by no route, so that requests for unsupported versions are not handled
+const UNSUPPORTED_PATH: &str = "/api/v1/unsupported-version";
+
+/// Prefixes of legacy routes registered with third parties, that can't easily move
+const EXTERNAL_PREFIXES: &[&str] = &["/api/auth/", "/api/github/"];
+
+/// Version requested via `Api-Version`, if not supported
+struct UnsupportedVersion(Option<String>);
+
+/// A `Fairing` negotiating the API version and flagging deprecated routes
+pub struct Versioning {
+    sunset: Option<String>,
+}
+
+impl Versioning {
+    pub fn from_env() -> Self {
+        Versioning {
+            sunset: env::var("API_LEGACY_SUNSET").ok(),
+        }
+    }
+}
+
+/// Returns true if `path` is part of the API
+fn is_api(path: &str) -> bool {
+    path == LEGACY_PREFIX || path.starts_with("/api/")
+}
+
+fn is_legacy(path: &str) -> bool {
+    is_api(path) && path != V1_PREFIX && !path.starts_with("/api/v1/")
+}
+
+/// Returns `path` as served under the legacy prefix, e.g. `/api/session` for `/api/v1/session`
+pub fn unversioned(path: &str) -> String {
+    match path.strip_prefix(V1_PREFIX) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", LEGACY_PREFIX, rest)
+        }
+        _ => path.to_string(),
+    }
+}
+
+impl Fairing for Versioning {
+    fn info(&self) -> Info {
+        Info {
+            name: "API versioning",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        if !is_api(request.uri().path()) {
+            return;
+        }
+        let version = request
+            .headers()
+            .get_one(VERSION_HEADER)
+            .filter(|version| !SUPPORTED_VERSIONS.contains(version))
+            .map(str::to_string);
+        if version.is_some() {
+            request.local_cache(|| UnsupportedVersion(version));
+            request.set_uri(Origin::parse(UNSUPPORTED_PATH).expect("valid path"));
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let path = request.uri().path();
+        if !is_api(path) {
+            return;
+        }
+        if let UnsupportedVersion(Some(version)) = request.local_cache(|| UnsupportedVersion(None))
+        {
+            let message = format!(
+                "Unsupported API version {}, supported: {}",
+                version,
+                SUPPORTED_VERSIONS.join(", ")
+            );
+            response.set_status(Status::NotAcceptable);
+            response.set_sized_body(Cursor::new(message));
+        }
+        response.set_header(Header::new(VERSION_HEADER, CURRENT_VERSION));
+        if is_legacy(path)
+            && !EXTERNAL_PREFIXES
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+        {
+            response.set_header(Header::new("Deprecation", "true"));
+            response.set_header(Header::new(
+                "Link",
+                format!(
+                    "<{}{}>; rel=\"successor-version\"",
+                    V1_PREFIX,
+                    &path[LEGACY_PREFIX.len()..]
+                ),
+            ));
+            if let Some(sunset) = &self.sunset {
+                response.set_header(Header::new("Sunset", sunset.clone()));
+            }
+        }
+    }
+}
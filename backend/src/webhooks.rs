@@ -0,0 +1,352 @@
+//! Outbound webhooks
+//!
+//! Audit events are delivered to `WEBHOOK_URL` as JSON `POST`s. `WEBHOOK_EVENTS` (comma separated actions, e.g.
+//! `budget_threshold,session_evicted`) restricts which ones. Payloads are signed with `WEBHOOK_SECRET` the same way
+//! GitHub does: `X-Playground-Signature` holds `sha256=` followed by the hex encoded HMAC of the body.
+//! `X-Playground-Delivery` identifies a delivery across retries.
+//! Failed deliveries are retried with an exponential backoff, up to `WEBHOOK_MAX_ATTEMPTS` attempts (defaults to 8), then
+//! kept as dead letters. Deliveries are attempted concurrently by the replica that recorded their event, and tracked in
+//! its memory. Dead letters, including deliveries dropped when too many are pending, are persisted in the backend
+//! state, shared by all replicas.
+use crate::{
+    auth::random_token,
+    error::Error,
+    kubernetes::Engine,
+    secrets,
+    types::{AuditEvent, WebhookDelivery, WebhookDeliveryStatus},
+};
+use futures::stream::{self, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{body, client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use log::warn;
+use sha2::Sha256;
+use std::{
+    collections::{HashSet, VecDeque},
+    env,
+    error::Error as StdError,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+const SIGNATURE_HEADER: &str = "X-Playground-Signature";
+const DELIVERY_HEADER: &str = "X-Playground-Delivery";
+const EVENT_HEADER: &str = "X-Playground-Event";
+/// Delay before the first retry, doubled after each failed attempt
+const BASE_DELAY: Duration = Duration::from_secs(30);
+const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries attempted at once, so that a slow endpoint doesn't delay others
+const CONCURRENCY: usize = 8;
+/// Dead letters shared by all replicas, most recent last
+const DEAD_LETTERS_STATE: &str = "webhookDeadLetters";
+/// Maximum number of dead letters kept. Oldest ones are dropped first.
+const MAX_DEAD_LETTERS: usize = 100;
+
+#[derive(Clone, Debug)]
+pub struct Webhooks {
+    url: String,
+    /// Actions delivered, all if unset
+    events: Option<HashSet<String>>,
+    max_attempts: u32,
+    deliveries: Arc<Mutex<VecDeque<WebhookDelivery>>>,
+    /// Dead letters not persisted yet
+    dead_letters: Arc<Mutex<Vec<WebhookDelivery>>>,
+}
+
+fn sign(secret: &str, payload: &[u8]) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(payload);
+    Some(format!(
+        "sha256={}",
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    ))
+}
+
+/// Returns the delay before the attempt following `attempts` failed ones
+fn backoff(attempts: u32) -> Duration {
+    BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+}
+
+async fn post(url: &str, delivery: &WebhookDelivery) -> Result<(), Box<dyn StdError>> {
+    let payload = serde_json::to_vec(&delivery.event)?;
+    let mut builder = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(EVENT_HEADER, &delivery.event.action);
+    // Read when used, so that rotated secrets are picked up
+    if let Some(signature) =
+        secrets::get("WEBHOOK_SECRET").and_then(|secret| sign(&secret, &payload))
+    {
+        builder = builder.header(SIGNATURE_HEADER, signature);
+    }
+    let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+    let response =
+        tokio::time::timeout(TIMEOUT, client.request(builder.body(Body::from(payload))?))
+            .await
+            .map_err(|_| "timed out")??;
+    let status = response.status();
+    if !status.is_success() {
+        let bytes = body::to_bytes(response.into_body()).await?;
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&bytes)).into());
+    }
+    Ok(())
+}
+
+impl Webhooks {
+    /// Maximum number of deliveries kept in memory. Oldest delivered ones are dropped first, then the oldest pending
+    /// ones become dead letters.
+    const MAX_DELIVERIES: usize = 1_000;
+    /// Delay between two checks for due deliveries
+    const SLEEP_TIME: Duration = Duration::from_secs(5);
+
+    /// Returns `None` if `WEBHOOK_URL` is unset
+    pub fn from_env() -> Option<Self> {
+        let url = env::var("WEBHOOK_URL").ok()?;
+        if secrets::get("WEBHOOK_SECRET").is_none() {
+            warn!("WEBHOOK_SECRET is unset, webhook payloads won't be signed");
+        }
+        Some(Webhooks {
+            url,
+            events: env::var("WEBHOOK_EVENTS").ok().map(|value| {
+                value
+                    .split(',')
+                    .map(|event| event.trim().to_string())
+                    .filter(|event| !event.is_empty())
+                    .collect()
+            }),
+            max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(8),
+            deliveries: Arc::new(Mutex::new(VecDeque::new())),
+            dead_letters: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Schedules the delivery of `event`, unless filtered out
+    pub fn enqueue(&self, event: &AuditEvent) {
+        if let Some(events) = &self.events {
+            if !events.contains(&event.action) {
+                return;
+            }
+        }
+        if let Ok(mut deliveries) = self.deliveries.lock() {
+            if deliveries.len() >= Self::MAX_DELIVERIES {
+                match deliveries
+                    .iter()
+                    .position(|delivery| delivery.status == WebhookDeliveryStatus::Delivered)
+                {
+                    Some(index) => {
+                        deliveries.remove(index);
+                    }
+                    None => {
+                        if let Some(mut delivery) = deliveries.pop_front() {
+                            warn!(
+                                "Too many pending webhook deliveries, giving up on {}",
+                                delivery.id
+                            );
+                            delivery.status = WebhookDeliveryStatus::DeadLetter;
+                            delivery.last_error = Some(format!(
+                                "More than {} deliveries pending",
+                                Self::MAX_DELIVERIES
+                            ));
+                            delivery.next_attempt = None;
+                            self.add_dead_letter(delivery);
+                        }
+                    }
+                }
+            }
+            deliveries.push_back(WebhookDelivery {
+                id: random_token(16).to_lowercase(),
+                event: event.clone(),
+                status: WebhookDeliveryStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                last_attempt: None,
+                next_attempt: Some(SystemTime::now()),
+            });
+        }
+    }
+
+    fn add_dead_letter(&self, delivery: WebhookDelivery) {
+        if let Ok(mut dead_letters) = self.dead_letters.lock() {
+            dead_letters.push(delivery);
+        }
+    }
+
+    /// Returns deliveries tracked by this replica and dead letters of all replicas, most recent first
+    pub async fn list(&self, engine: &Engine) -> Vec<WebhookDelivery> {
+        let mut deliveries: Vec<WebhookDelivery> = engine
+            .load_state(DEAD_LETTERS_STATE)
+            .await
+            .map_err(|err| warn!("Failed to load webhook dead letters: {}", err))
+            .ok()
+            .flatten()
+            .and_then(|state| serde_json::from_str(&state).ok())
+            .unwrap_or_default();
+        if let Ok(dead_letters) = self.dead_letters.lock() {
+            deliveries.extend(dead_letters.iter().cloned());
+        }
+        if let Ok(tracked) = self.deliveries.lock() {
+            deliveries.extend(tracked.iter().cloned());
+        }
+        deliveries.sort_by(|a, b| b.event.time.cmp(&a.event.time));
+        deliveries
+    }
+
+    /// Appends dead letters of this replica to those persisted
+    async fn persist_dead_letters(&self, engine: &Engine) {
+        let dead_letters = match self.dead_letters.lock() {
+            Ok(dead_letters) if !dead_letters.is_empty() => dead_letters.clone(),
+            _ => return,
+        };
+        let result = engine
+            .update_state(DEAD_LETTERS_STATE, |state| {
+                let mut persisted: Vec<WebhookDelivery> = state
+                    .and_then(|state| serde_json::from_str(state).ok())
+                    .unwrap_or_default();
+                persisted.extend(dead_letters.iter().cloned());
+                let excess = persisted.len().saturating_sub(MAX_DEAD_LETTERS);
+                persisted.drain(..excess);
+                serde_json::to_string(&persisted).map_err(|err| Error::Failure(err.into()))
+            })
+            .await;
+        match result {
+            Ok(()) => {
+                if let Ok(mut pending) = self.dead_letters.lock() {
+                    pending.retain(|delivery| {
+                        !dead_letters
+                            .iter()
+                            .any(|persisted| persisted.id == delivery.id)
+                    });
+                }
+            }
+            Err(err) => warn!("Failed to persist webhook dead letters: {}", err),
+        }
+    }
+
+    /// Attempts deliveries that are due
+    async fn deliver(&self) {
+        let now = SystemTime::now();
+        let due: Vec<WebhookDelivery> = match self.deliveries.lock() {
+            Ok(deliveries) => deliveries
+                .iter()
+                .filter(|delivery| {
+                    delivery.status == WebhookDeliveryStatus::Pending
+                        && delivery.next_attempt.map_or(false, |time| time <= now)
+                })
+                .cloned()
+                .collect(),
+            Err(_) => return,
+        };
+        let results: Vec<(String, Result<(), String>)> = stream::iter(due)
+            .map(|delivery| async move {
+                let result = post(&self.url, &delivery)
+                    .await
+                    .map_err(|err| err.to_string());
+                (delivery.id, result)
+            })
+            .buffer_unordered(CONCURRENCY)
+            .collect()
+            .await;
+        for (id, result) in results {
+            if let Ok(mut deliveries) = self.deliveries.lock() {
+                let index = match deliveries.iter().position(|d| d.id == id) {
+                    Some(index) => index,
+                    // Dropped in the meantime
+                    None => continue,
+                };
+                let delivery = &mut deliveries[index];
+                delivery.attempts += 1;
+                delivery.last_attempt = Some(SystemTime::now());
+                match result {
+                    Ok(()) => {
+                        delivery.status = WebhookDeliveryStatus::Delivered;
+                        delivery.last_error = None;
+                        delivery.next_attempt = None;
+                    }
+                    Err(err) => {
+                        warn!("Failed to deliver webhook {}: {}", delivery.id, err);
+                        delivery.last_error = Some(err);
+                        if delivery.attempts >= self.max_attempts {
+                            delivery.status = WebhookDeliveryStatus::DeadLetter;
+                            delivery.next_attempt = None;
+                            if let Some(delivery) = deliveries.remove(index) {
+                                self.add_dead_letter(delivery);
+                            }
+                        } else {
+                            delivery.next_attempt =
+                                Some(SystemTime::now() + backoff(delivery.attempts));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn spawn_delivery_thread(self, engine: Engine) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    warn!("Failed to start webhook deliveries: {}", err);
+                    return;
+                }
+            };
+            loop {
+                thread::sleep(Self::SLEEP_TIME);
+                runtime.block_on(async {
+                    self.deliver().await;
+                    self.persist_dead_letters(&engine).await;
+                });
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_dropped_deliveries_as_dead_letters() {
+        let webhooks = Webhooks {
+            url: "https://example.com".to_string(),
+            events: None,
+            max_attempts: 1,
+            deliveries: Arc::default(),
+            dead_letters: Arc::default(),
+        };
+        let event = AuditEvent {
+            actor: "a".to_string(),
+            action: "b".to_string(),
+            target: "c".to_string(),
+            details: None,
+            time: SystemTime::now(),
+        };
+        for _ in 0..=Webhooks::MAX_DELIVERIES {
+            webhooks.enqueue(&event);
+        }
+        let dead_letters = webhooks.dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].status, WebhookDeliveryStatus::DeadLetter);
+        assert_eq!(
+            webhooks.deliveries.lock().unwrap().len(),
+            Webhooks::MAX_DELIVERIES
+        );
+    }
+}
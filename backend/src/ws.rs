@@ -0,0 +1,401 @@
+//! Control channel
+//!
+//! The frontend keeps a single websocket open at `/api/v1/ws` rather than polling several endpoints. Rocket doesn't support
+//! websockets, so it is served by a dedicated server on `WS_PORT` (defaults to 8001) that the ingress routes to.
+//! Connections are authenticated by a short-lived, single-use ticket obtained via `POST /api/v1/ws/ticket` and passed as
+//! `?ticket=`. Tickets are kept in a `Secret` each, so that any replica can redeem them, and deleted when redeemed. Connections
+//! are closed once the authenticated session they were opened from is revoked or expires.
+//!
+//! Messages are JSON objects. Clients send:
+//! * `{"type": "subscribe", "topics": [...]}` and `{"type": "unsubscribe", "topics": [...]}`
+//! * `{"type": "heartbeat", "nonce": ...}`, replied to with `{"topic": "heartbeat", "data": ...}`
+//!
+//! The server sends `{"topic": ..., "data": ...}` each time the state of a subscribed topic changes:
+//! * `session`: the current session of the user, including its deployment progress. `null` if there is none.
+//! * `notifications`: eviction or termination by an admin of the current session.
+//!
+//! Channels don't poll: the replica pod watch and `Notifier` fan changes out to the channels of the sessions concerned,
+//! that only then read their state. Changes made by other replicas and not reflected on pods are picked up by a full
+//! check every `RESYNC_INTERVAL`.
+//!
+//! Failures are sent as `{"topic": ..., "error": ...}`.
+use crate::{
+    auth::{hash_key, random_token},
+    error::Error,
+    kubernetes::Engine,
+    manager::Manager,
+    types::LoggedUser,
+};
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    service::{make_service_fn, service_fn},
+    upgrade::Upgraded,
+    Body, Request, Response, Server, StatusCode,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    env,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    runtime::Runtime,
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+/// Also served under the legacy prefix, see `versioning`
+const PATHS: &[&str] = &["/api/v1/ws", "/api/ws"];
+const DEFAULT_PORT: u16 = 8001;
+/// Delay between two full checks of the state of subscribed topics, for changes no one notified about
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// Delay between two checks that the authenticated session of a channel is still valid
+const AUTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const TICKET_KIND: &str = "ws-ticket";
+/// Notifications not yet received by all channels, before the slowest ones lag
+const NOTIFIER_CAPACITY: usize = 64;
+
+/// Wakes up control channels of sessions whose notifications changed
+#[derive(Clone)]
+pub struct Notifier {
+    /// Ids of sessions
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(NOTIFIER_CAPACITY);
+        Notifier { sender }
+    }
+}
+
+impl Notifier {
+    pub fn notify(&self, id: &str) {
+        // Fails if no channel is open
+        let _ = self.sender.send(id.to_string());
+    }
+}
+
+/// What is kept for a ticket
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ticket {
+    user: LoggedUser,
+    /// Key of the authenticated session the ticket was issued from
+    auth_key: String,
+    issued_at: SystemTime,
+}
+
+impl Ticket {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        now.duration_since(self.issued_at)
+            .map_or(false, |elapsed| elapsed >= Tickets::TTL)
+    }
+}
+
+/// Tickets authenticating the next connection of a user
+#[derive(Clone)]
+pub struct Tickets {
+    engine: Engine,
+}
+
+impl Tickets {
+    const TTL: Duration = Duration::from_secs(30);
+
+    pub fn new(engine: Engine) -> Self {
+        Tickets { engine }
+    }
+
+    /// Returns a ticket for `user`, authenticated via the session `auth_key`
+    pub fn issue(&self, user: &LoggedUser, auth_key: &str) -> crate::error::Result<String> {
+        let ticket = random_token(32);
+        let value = serde_json::to_string(&Ticket {
+            user: user.clone(),
+            auth_key: auth_key.to_string(),
+            issued_at: SystemTime::now(),
+        })
+        .map_err(|err| Error::Failure(err.into()))?;
+        Runtime::new()
+            .map_err(|err| Error::Failure(err.into()))?
+            .block_on(
+                self.engine
+                    .save_record(TICKET_KIND, &hash_key(&ticket), &value),
+            )?;
+        Ok(ticket)
+    }
+
+    /// Consumes `ticket`. Returns the user it was issued for and its authenticated session, unless expired.
+    async fn redeem(&self, ticket: &str) -> Option<(LoggedUser, String)> {
+        let value = match self
+            .engine
+            .take_record(TICKET_KIND, &hash_key(ticket))
+            .await
+        {
+            Ok(value) => value?,
+            Err(err) => {
+                warn!("Failed to redeem ticket: {}", err);
+                return None;
+            }
+        };
+        let ticket: Ticket = serde_json::from_str(&value).ok()?;
+        if ticket.is_expired(SystemTime::now()) {
+            return None;
+        }
+        Some((ticket.user, ticket.auth_key))
+    }
+
+    /// Deletes expired tickets, that were never redeemed
+    pub async fn purge(&self) -> crate::error::Result<()> {
+        let now = SystemTime::now();
+        for (id, value) in self.engine.list_records(TICKET_KIND).await? {
+            let ticket: Option<Ticket> = serde_json::from_str(&value).ok();
+            if ticket.map_or(true, |ticket| ticket.is_expired(now)) {
+                self.engine.delete_record(TICKET_KIND, &id).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+enum Topic {
+    Session,
+    Notifications,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum ClientMessage {
+    Subscribe { topics: Vec<Topic> },
+    Unsubscribe { topics: Vec<Topic> },
+    Heartbeat { nonce: Option<String> },
+}
+
+fn to_message(topic: &str, result: Result<Value, String>) -> String {
+    match result {
+        Ok(data) => json!({ "topic": topic, "data": data }),
+        Err(err) => json!({ "topic": topic, "error": err }),
+    }
+    .to_string()
+}
+
+/// Calls the manager, that blocks, outside of the async runtime
+async fn blocking<T: Serialize + 'static>(
+    f: impl FnOnce() -> crate::error::Result<T> + Send + 'static,
+) -> Result<Value, String> {
+    tokio::task::spawn_blocking(move || {
+        f().map_err(|err| err.to_string())
+            .and_then(|data| serde_json::to_value(data).map_err(|err| err.to_string()))
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+async fn topic_state(manager: &Manager, user: &LoggedUser, topic: Topic) -> Result<Value, String> {
+    let (manager, user) = (manager.clone(), user.clone());
+    match topic {
+        Topic::Session => {
+            blocking(move || manager.get_session(&user, &user.id.to_lowercase())).await
+        }
+        Topic::Notifications => blocking(move || manager.get_notifications(&user)).await,
+    }
+}
+
+/// Returns messages for subscribed topics whose state changed since last sent
+async fn poll(
+    manager: &Manager,
+    user: &LoggedUser,
+    subscriptions: &mut BTreeMap<Topic, Option<String>>,
+) -> Vec<String> {
+    let mut messages = Vec::new();
+    for (topic, last) in subscriptions.iter_mut() {
+        let name = match topic {
+            Topic::Session => "session",
+            Topic::Notifications => "notifications",
+        };
+        let message = to_message(name, topic_state(manager, user, *topic).await);
+        if last.as_ref() != Some(&message) {
+            last.replace(message.clone());
+            messages.push(message);
+        }
+    }
+    messages
+}
+
+/// Returns replies to a message sent by the client
+async fn on_message(
+    manager: &Manager,
+    user: &LoggedUser,
+    subscriptions: &mut BTreeMap<Topic, Option<String>>,
+    text: &str,
+) -> Vec<String> {
+    match serde_json::from_str(text) {
+        Ok(ClientMessage::Subscribe { topics }) => {
+            for topic in topics {
+                subscriptions.entry(topic).or_insert(None);
+            }
+            // Newly subscribed topics get their current state
+            poll(manager, user, subscriptions).await
+        }
+        Ok(ClientMessage::Unsubscribe { topics }) => {
+            for topic in topics {
+                subscriptions.remove(&topic);
+            }
+            Vec::new()
+        }
+        Ok(ClientMessage::Heartbeat { nonce }) => {
+            let (manager, user) = (manager.clone(), user.clone());
+            let result = blocking(move || {
+                manager.session_heartbeat(&user, &user.id.to_lowercase(), nonce.as_deref())
+            })
+            .await;
+            vec![to_message("heartbeat", result)]
+        }
+        Err(err) => vec![json!({ "error": format!("Invalid message: {}", err) }).to_string()],
+    }
+}
+
+async fn channel(
+    manager: Manager,
+    user: LoggedUser,
+    auth_key: String,
+    socket: WebSocketStream<Upgraded>,
+) {
+    let (mut sink, mut stream) = socket.split();
+    // Last message sent, by subscribed topic
+    let mut subscriptions = BTreeMap::new();
+    let mut resync = tokio::time::interval(RESYNC_INTERVAL);
+    let mut auth_check = tokio::time::interval(AUTH_CHECK_INTERVAL);
+    let mut notified = manager.notifier.sender.subscribe();
+    let mut changed = manager.engine.subscribe_session_changes();
+    let id = user.id.to_lowercase();
+    loop {
+        let messages = tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    on_message(&manager, &user, &mut subscriptions, &text).await
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                // Pings are answered by tungstenite
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => {
+                    warn!("Control channel of {} failed: {}", user.id, err);
+                    break;
+                }
+            },
+            notification = notified.recv() => match notification {
+                Ok(notified_id) if notified_id != id => continue,
+                // Lagging channels might have missed their notification
+                Ok(_) | Err(RecvError::Lagged(_)) => poll(&manager, &user, &mut subscriptions).await,
+                Err(RecvError::Closed) => break,
+            },
+            change = changed.recv() => match change {
+                Ok(changed_id) if changed_id != id => continue,
+                Ok(_) | Err(RecvError::Lagged(_)) => poll(&manager, &user, &mut subscriptions).await,
+                Err(RecvError::Closed) => break,
+            },
+            _ = resync.tick() => poll(&manager, &user, &mut subscriptions).await,
+            _ = auth_check.tick() => {
+                if manager.auth_sessions.fetch(&auth_key).await.is_none() {
+                    break;
+                }
+                continue;
+            }
+        };
+        for message in messages {
+            if sink.send(Message::Text(message)).await.is_err() {
+                return;
+            }
+        }
+    }
+    let _ = sink.close().await;
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+}
+
+/// Completes the websocket handshake, then serves the channel once the connection is upgraded
+async fn upgrade(manager: Manager, mut request: Request<Body>) -> Response<Body> {
+    if !PATHS.contains(&request.uri().path()) {
+        return error_response(StatusCode::NOT_FOUND, "Not found");
+    }
+    // Tickets are single-use: they must not be consumed by requests that can't be upgraded
+    let websocket = request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .map_or(false, |upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let accept = match request.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) if websocket => derive_accept_key(key.as_bytes()),
+        _ => return error_response(StatusCode::BAD_REQUEST, "Not a websocket request"),
+    };
+    let ticket = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("ticket="))
+    });
+    let redeemed = match ticket {
+        Some(ticket) => manager.ws_tickets.redeem(ticket).await,
+        None => None,
+    };
+    let (user, auth_key) = match redeemed {
+        Some(redeemed) => redeemed,
+        None => return error_response(StatusCode::UNAUTHORIZED, "Invalid ticket"),
+    };
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                channel(manager, user, auth_key, socket).await;
+            }
+            Err(err) => warn!("Failed to upgrade control channel: {}", err),
+        }
+    });
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    if let Ok(accept) = HeaderValue::from_str(&accept) {
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+    }
+    response
+}
+
+/// Serves control channels until the process exits
+pub async fn serve(manager: Manager) {
+    let port = env::var("WS_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+    let service = make_service_fn(move |_| {
+        let manager = manager.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let manager = manager.clone();
+                async move { Ok::<_, Infallible>(upgrade(manager, request).await) }
+            }))
+        }
+    });
+    info!("Serving control channels on port {}", port);
+    if let Err(err) = Server::bind(&SocketAddr::from(([0, 0, 0, 0], port)))
+        .serve(service)
+        .await
+    {
+        warn!("Control channels server failed: {}", err);
+    }
+}
@@ -0,0 +1,94 @@
+import { Heartbeat, Notifications, Session } from './types';
+
+export type Topic = 'session' | 'notifications';
+
+export interface TopicData {
+    session: Session | null,
+    notifications: Notifications,
+}
+
+type Listener<T> = (data: T | undefined, error?: string) => void;
+
+// A single websocket multiplexing updates of subscribed topics, see `Client.openControlChannel`
+export class ControlChannel {
+
+    private readonly socket: WebSocket;
+    private readonly listeners: Map<string, Set<Listener<any>>> = new Map();
+    private readonly pending: string[] = [];
+    // Last state received, by topic
+    private readonly states: Map<string, {data: any, error?: string}> = new Map();
+    // Replies are received in order
+    private readonly heartbeats: {resolve: (heartbeat: Heartbeat) => void, reject: (error: string) => void}[] = [];
+
+    constructor(url: string) {
+        this.socket = new WebSocket(url);
+        this.socket.addEventListener('open', () => {
+            this.pending.splice(0).forEach(message => this.socket.send(message));
+        });
+        this.socket.addEventListener('message', event => {
+            const { topic, data, error } = JSON.parse(event.data);
+            if (topic == 'heartbeat') {
+                const heartbeat = this.heartbeats.shift();
+                if (error) {
+                    heartbeat?.reject(error);
+                } else {
+                    heartbeat?.resolve(data);
+                }
+            } else {
+                this.states.set(topic, {data, error});
+                this.listeners.get(topic)?.forEach(listener => listener(data, error));
+            }
+        });
+        this.socket.addEventListener('close', () => {
+            this.heartbeats.splice(0).forEach(heartbeat => heartbeat.reject('Channel closed'));
+        });
+    }
+
+    private send(message: object): void {
+        const data = JSON.stringify(message);
+        if (this.socket.readyState == WebSocket.OPEN) {
+            this.socket.send(data);
+        } else {
+            this.pending.push(data);
+        }
+    }
+
+    /* Calls `listener` with the current state of `topic`, then each time it changes. Returns a function unsubscribing. */
+    subscribe<T extends Topic>(topic: T, listener: Listener<TopicData[T]>): () => void {
+        const listeners = this.listeners.get(topic) || new Set();
+        this.listeners.set(topic, listeners);
+        if (listeners.size == 0) {
+            this.send({type: 'subscribe', topics: [topic]});
+        } else {
+            const state = this.states.get(topic);
+            if (state) {
+                listener(state.data, state.error);
+            }
+        }
+        listeners.add(listener);
+        return () => {
+            listeners.delete(listener);
+            if (listeners.size == 0) {
+                this.states.delete(topic);
+                this.send({type: 'unsubscribe', topics: [topic]});
+            }
+        };
+    }
+
+    /* Keeps the current session from being considered idle, see `Client.sessionHeartbeat` */
+    heartbeat(nonce?: string): Promise<Heartbeat> {
+        return new Promise((resolve, reject) => {
+            this.heartbeats.push({resolve, reject});
+            this.send({type: 'heartbeat', nonce});
+        });
+    }
+
+    onClose(listener: () => void): void {
+        this.socket.addEventListener('close', listener);
+    }
+
+    close(): void {
+        this.socket.close();
+    }
+
+}
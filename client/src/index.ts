@@ -1,13 +1,17 @@
+import { ControlChannel } from './channel';
 import { fetchWithTimeout, rpc } from './rpc';
-import { Playground, Pool, Session, SessionConfiguration, SessionUpdateConfiguration, User, UserConfiguration, UserUpdateConfiguration, } from './types';
+import { Artifact, AuditEvent, Canary, Diagnostics, Entry, FaucetRequest, GitState, HandoffReport, Heartbeat, Identity, InvalidEntry, OnboardingState, Org, Page, Playground, Pool, Port, PrepullStatus, Reservation, RestoreReport, Session, SessionConfiguration, SessionBatch, SessionBatchResult, SessionEnvUpdate, SessionEvent, SessionEviction, SessionPlan, SessionUpdateConfiguration, StateArchive, StorageVersion, Template, TemplateAnalytics, TemplatePublication, TemplateQuery, UsablePool, User, UserConfiguration, UserExport, UserImportReport, UserPreferencesUpdate, UserUpdateConfiguration, UserUsage, WebhookDelivery, } from './types';
 
 export class Client {
 
+    static templatesResource = 'templates';
     static userResource = 'user';
     static usersResource = 'users';
     static sessionResource = 'session';
     static sessionsResource = 'sessions';
     static poolsResource = 'pools';
+    static workshopsResource = 'workshops';
+    static orgsResource = 'orgs';
 
     private readonly base: string;
     private readonly timeout: number;
@@ -23,14 +27,57 @@ export class Client {
         return [this.base, ...resources].join("/");
     }
 
-    loginPath(queryParams: string = window.location.search): string {
-        return this.path(`login/github${queryParams}`);
+    loginPath(queryParams: string = window.location.search, provider: 'github' | 'oidc' = 'github'): string {
+        return this.path(`login/${provider}${queryParams}`);
     }
 
     async get(init: RequestInit = this.defaultInit): Promise<Playground> {
         return rpc(this.path(""), init, this.timeout);
     }
 
+    // Templates
+
+    async listTemplates(query: TemplateQuery = {}, init: RequestInit = this.defaultInit): Promise<Page<Entry<Template>>> {
+        const { perPage, ...rest } = query;
+        const params = Object.entries({ ...rest, per_page: perPage })
+            .filter(([, value]) => value !== undefined)
+            .map(([key, value]) => `${key}=${encodeURIComponent(String(value))}`);
+        const search = params.length > 0 ? `?${params.join('&')}` : '';
+        return rpc(`${this.path(Client.templatesResource)}${search}`, init, this.timeout);
+    }
+
+    /* Returns why the YAML or JSON `definition` of template `id` would be rejected. Empty if valid. */
+    async validateTemplate(definition: string, id?: string, init: RequestInit = this.defaultInit): Promise<InvalidEntry[]> {
+        const search = id ? `?id=${encodeURIComponent(id)}` : '';
+        return rpc(`${this.path('validate', 'template')}${search}`, {
+            method: 'POST',
+            body: definition,
+            ...init
+        }, this.timeout);
+    }
+
+    async setTemplateCanary(id: string, canary: Canary, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path('admin', Client.templatesResource, id, 'canary'), {
+            method: 'PUT',
+            body: JSON.stringify(canary),
+            ...init
+        }, this.timeout);
+    }
+
+    async promoteTemplateCanary(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path('admin', Client.templatesResource, id, 'canary', 'promote'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    async abortTemplateCanary(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path('admin', Client.templatesResource, id, 'canary'), {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
     // Current User
 
     async getCurrentUser(init: RequestInit = this.defaultInit): Promise<User> {
@@ -45,8 +92,10 @@ export class Client {
         return rpc(this.path(Client.usersResource, id), init, this.timeout);
     }
 
-    async listUsers(init: RequestInit = this.defaultInit): Promise<Record<string, User>> {
-        return rpc(this.path(Client.usersResource), init, this.timeout);
+    /* With `strict`, fails if some users are invalid, including unknown fields */
+    async listUsers(strict?: boolean, init: RequestInit = this.defaultInit): Promise<Record<string, User>> {
+        const search = strict ? '?strict=true' : '';
+        return rpc(`${this.path(Client.usersResource)}${search}`, init, this.timeout);
     }
 
     async createUser(id: string, conf: UserConfiguration, init: RequestInit = this.defaultInit): Promise<void> {
@@ -57,6 +106,15 @@ export class Client {
         }, this.timeout);
     }
 
+    /* Validates a user creation without performing it */
+    async planUser(id: string, conf: UserConfiguration, init: RequestInit = this.defaultInit): Promise<Entry<UserConfiguration>> {
+        return rpc(`${this.path(Client.usersResource, id)}?dry_run=true`, {
+            method: 'PUT',
+            body: JSON.stringify(conf),
+            ...init
+        }, this.timeout);
+    }
+
     async updateUser(id: string, conf: UserUpdateConfiguration, init: RequestInit = this.defaultInit): Promise<void> {
         return rpc(this.path(Client.usersResource, id), {
             method: 'PATCH',
@@ -72,6 +130,161 @@ export class Client {
         }, this.timeout);
     }
 
+    /* Restores a deleted user, during the deletion grace period */
+    async restoreUser(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.usersResource, id, 'restore'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Returns all data held about a user */
+    async exportUser(id: string, init: RequestInit = this.defaultInit): Promise<UserExport> {
+        return rpc(this.path(Client.usersResource, id, 'export'), init, this.timeout);
+    }
+
+    async getUserPreferences(id: string, init: RequestInit = this.defaultInit): Promise<Record<string, string>> {
+        return rpc(this.path(Client.usersResource, id, 'preferences'), init, this.timeout);
+    }
+
+    async updateUserPreferences(id: string, update: UserPreferencesUpdate, init: RequestInit = this.defaultInit): Promise<Record<string, string>> {
+        return rpc(this.path(Client.usersResource, id, 'preferences'), {
+            method: 'PATCH',
+            body: JSON.stringify(update),
+            ...init
+        }, this.timeout);
+    }
+
+    /* Lets user `id` also log in with `identity` */
+    async linkUserIdentity(id: string, identity: Identity, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.usersResource, id, 'identities'), {
+            method: 'POST',
+            body: JSON.stringify(identity),
+            ...init
+        }, this.timeout);
+    }
+
+    async updateUserOnboarding(id: string, state: OnboardingState, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.usersResource, id, 'onboarding'), {
+            method: 'POST',
+            body: JSON.stringify({state: state}),
+            ...init
+        }, this.timeout);
+    }
+
+    async importUsers(users: Entry<UserConfiguration>[], init: RequestInit = this.defaultInit): Promise<UserImportReport> {
+        return rpc(this.path('admin', `${Client.usersResource}:import`), {
+            method: 'POST',
+            body: JSON.stringify(users),
+            ...init
+        }, this.timeout);
+    }
+
+    async exportUsers(init: RequestInit = this.defaultInit): Promise<Entry<User>[]> {
+        return rpc(this.path('admin', `${Client.usersResource}:export`), init, this.timeout);
+    }
+
+    /* `from` and `to` are expressed in seconds since epoch */
+    async getUsage(from?: number, to?: number, init: RequestInit = this.defaultInit): Promise<Record<string, UserUsage>> {
+        const params = Object.entries({from: from, to: to})
+            .filter(([, value]) => value !== undefined)
+            .map(([key, value]) => `${key}=${value}`);
+        const search = params.length > 0 ? `?${params.join('&')}` : '';
+        return rpc(`${this.path('admin', 'usage')}${search}`, init, this.timeout);
+    }
+
+    /* Format version of stored resources, keyed by ConfigMap */
+    async getStorageVersion(init: RequestInit = this.defaultInit): Promise<Record<string, StorageVersion>> {
+        return rpc(this.path('admin', 'storage', 'version'), init, this.timeout);
+    }
+
+    async backupState(init: RequestInit = this.defaultInit): Promise<StateArchive> {
+        return rpc(this.path('admin', 'backup'), init, this.timeout);
+    }
+
+    async restoreState(archive: StateArchive, init: RequestInit = this.defaultInit): Promise<RestoreReport> {
+        return rpc(this.path('admin', 'restore'), {
+            method: 'POST',
+            body: JSON.stringify(archive),
+            ...init
+        }, this.timeout);
+    }
+
+    /* Moves running sessions to another cluster. The returned archive is to be restored there. */
+    async handoffSessions(init: RequestInit = this.defaultInit): Promise<HandoffReport> {
+        return rpc(this.path('admin', 'handoff'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Sessions created per template over the last `days` */
+    async getTemplateAnalytics(days?: number, init: RequestInit = this.defaultInit): Promise<Record<string, TemplateAnalytics>> {
+        const search = days !== undefined ? `?days=${days}` : '';
+        return rpc(`${this.path('admin', 'analytics', 'templates')}${search}`, init, this.timeout);
+    }
+
+    // Organizations
+
+    /* Up to 30 seconds stale when updated via another backend replica */
+    async listOrgs(init: RequestInit = this.defaultInit): Promise<Record<string, Org>> {
+        return rpc(this.path(Client.orgsResource), init, this.timeout);
+    }
+
+    async getOrg(id: string, init: RequestInit = this.defaultInit): Promise<Org | null> {
+        return rpc(this.path(Client.orgsResource, id), init, this.timeout);
+    }
+
+    async updateOrg(id: string, org: Org, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.orgsResource, id), {
+            method: 'PUT',
+            body: JSON.stringify(org),
+            ...init
+        }, this.timeout);
+    }
+
+    async deleteOrg(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.orgsResource, id), {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Clears flags of a session, resuming it if it was suspended */
+    async resumeSession(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path('admin', Client.sessionsResource, id, 'resume'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    async listAuditEvents(init: RequestInit = this.defaultInit): Promise<AuditEvent[]> {
+        return rpc(this.path('admin', 'audit'), init, this.timeout);
+    }
+
+    /* Executes a GraphQL query, e.g. `{ sessions { id pod { node { pool { name } } } } }`. Only available if enabled on the backend */
+    async graphql<T>(query: string, variables?: Record<string, unknown>, init: RequestInit = this.defaultInit): Promise<T> {
+        const response = await fetchWithTimeout(this.path('graphql'), {
+            method: 'POST',
+            headers: {'Content-Type': 'application/json'},
+            body: JSON.stringify({query, variables}),
+            ...init
+        }, this.timeout);
+        const { data, errors } = await response.json();
+        if (errors) {
+            return Promise.reject(errors);
+        }
+        return data;
+    }
+
+    async listWebhookDeliveries(init: RequestInit = this.defaultInit): Promise<WebhookDelivery[]> {
+        return rpc(this.path('admin', 'webhooks', 'deliveries'), init, this.timeout);
+    }
+
+    async getDiagnostics(init: RequestInit = this.defaultInit): Promise<Diagnostics> {
+        return rpc(this.path('admin', 'diagnostics'), init, this.timeout);
+    }
+
     // Current Session
 
     async getCurrentSession(init: RequestInit = this.defaultInit): Promise<Session | null> {
@@ -86,6 +299,15 @@ export class Client {
         }, this.timeout);
     }
 
+    /* Validates the current session creation without performing it */
+    async planCurrentSession(conf: SessionConfiguration, init: RequestInit = this.defaultInit): Promise<SessionPlan> {
+        return rpc(`${this.path(Client.sessionResource)}?dry_run=true`, {
+            method: 'PUT',
+            body: JSON.stringify(conf),
+            ...init
+        }, this.timeout);
+    }
+
     async updateCurrentSession(conf: SessionUpdateConfiguration, init: RequestInit = this.defaultInit): Promise<void> {
         return rpc(this.path(Client.sessionResource), {
             method: 'PATCH',
@@ -103,6 +325,106 @@ export class Client {
 
     // Sessions
 
+    /* Returns null if the session workspace isn't a git repository */
+    async getSessionGitState(id: string, init: RequestInit = this.defaultInit): Promise<GitState | null> {
+        return rpc(this.path(Client.sessionsResource, id, 'git'), init, this.timeout);
+    }
+
+    /* Kubernetes events about the session resources, oldest first */
+    async listSessionEvents(id: string, init: RequestInit = this.defaultInit): Promise<SessionEvent[]> {
+        return rpc(this.path(Client.sessionsResource, id, 'events'), init, this.timeout);
+    }
+
+    /* Returns why the session was evicted, if it was since its creation */
+    async getSessionEviction(id: string, init: RequestInit = this.defaultInit): Promise<SessionEviction | null> {
+        return rpc(this.path(Client.sessionsResource, id, 'eviction'), init, this.timeout);
+    }
+
+    /* Returns the faucet response, e.g. a transaction hash */
+    async requestFunds(id: string, request: FaucetRequest, init: RequestInit = this.defaultInit): Promise<unknown> {
+        return rpc(this.path(Client.sessionsResource, id, 'faucet'), {
+            method: 'POST',
+            body: JSON.stringify(request),
+            ...init
+        }, this.timeout);
+    }
+
+    /* Returns the public url of the read-only view. Only available when session access is restricted. */
+    async publishSessionViewer(id: string, init: RequestInit = this.defaultInit): Promise<string> {
+        return rpc(this.path(Client.sessionsResource, id, 'viewer'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    async getSessionAccessUrl(id: string, init: RequestInit = this.defaultInit): Promise<string> {
+        return rpc(this.path(Client.sessionsResource, id, 'url'), {
+            ...init
+        }, this.timeout);
+    }
+
+    async unpublishSessionViewer(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.sessionsResource, id, 'viewer'), {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
+    /* The restarted session starts from a fresh workspace, uncommitted changes are lost */
+    async restartSession(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.sessionsResource, id, 'restart'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    async sessionHeartbeat(id: string, nonce?: string, init: RequestInit = this.defaultInit): Promise<Heartbeat> {
+        const query = nonce ? `?nonce=${encodeURIComponent(nonce)}` : '';
+        return rpc(`${this.path(Client.sessionsResource, id, 'heartbeat')}${query}`, {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Returns the nonce expected by the next heartbeat, once the previous one was lost */
+    async resyncSessionHeartbeat(id: string, init: RequestInit = this.defaultInit): Promise<Heartbeat> {
+        return rpc(this.path(Client.sessionsResource, id, 'heartbeat', 'resync'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    async publishTemplate(id: string, publication: TemplatePublication, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.sessionsResource, id, 'publish-template'), {
+            method: 'POST',
+            body: JSON.stringify(publication),
+            ...init
+        }, this.timeout);
+    }
+
+    async updateSessionEnv(id: string, update: SessionEnvUpdate, init: RequestInit = this.defaultInit): Promise<Record<string, string>> {
+        return rpc(this.path(Client.sessionsResource, id, 'env'), {
+            method: 'PATCH',
+            body: JSON.stringify(update),
+            ...init
+        }, this.timeout);
+    }
+
+    async addSessionPort(id: string, port: Port, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.sessionsResource, id, 'ports'), {
+            method: 'POST',
+            body: JSON.stringify(port),
+            ...init
+        }, this.timeout);
+    }
+
+    async removeSessionPort(id: string, name: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.sessionsResource, id, 'ports', name), {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
     async listSessions(init: RequestInit = this.defaultInit): Promise<Record<string, Session>> {
         return rpc(this.path(Client.sessionsResource), init, this.timeout);
     }
@@ -115,6 +437,15 @@ export class Client {
         }, this.timeout);
     }
 
+    /* Validates a session creation without performing it */
+    async planSession(id: string, conf: SessionConfiguration, init: RequestInit = this.defaultInit): Promise<SessionPlan> {
+        return rpc(`${this.path(Client.sessionsResource, id)}?dry_run=true`, {
+            method: 'PUT',
+            body: JSON.stringify(conf),
+            ...init
+        }, this.timeout);
+    }
+
     async updateSession(id: string, conf: SessionUpdateConfiguration, init: RequestInit = this.defaultInit): Promise<void> {
         return rpc(this.path(Client.sessionsResource, id), {
             method: 'PATCH',
@@ -130,6 +461,58 @@ export class Client {
         }, this.timeout);
     }
 
+    /* Restores a deleted session, during the deletion grace period */
+    async restoreSession(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.sessionsResource, id, 'restore'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Moves a session to another pool. Progress is reported via `Session#migration` */
+    async migrateSession(id: string, pool: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(`${this.path(Client.sessionsResource, id, 'migrate')}?pool=${encodeURIComponent(pool)}`, {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Terminates any session. Unless `tombstone` is false, `reason` is shown to the session owner */
+    async terminateSession(id: string, reason: string, tombstone = true, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(`${this.path('admin', Client.sessionsResource, id)}?reason=${encodeURIComponent(reason)}&tombstone=${tombstone}`, {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Deletes, extends or migrates all sessions matching `batch.filter` */
+    async runSessionBatch(batch: SessionBatch, init: RequestInit = this.defaultInit): Promise<SessionBatchResult[]> {
+        return rpc(this.path('admin', `${Client.sessionsResource}:batch`), {
+            method: 'POST',
+            body: JSON.stringify(batch),
+            ...init
+        }, this.timeout);
+    }
+
+    // Workshops
+
+    async listArtifacts(workshop: string, init: RequestInit = this.defaultInit): Promise<Artifact[]> {
+        return rpc(this.path(Client.workshopsResource, workshop, 'artifacts'), init, this.timeout);
+    }
+
+    /* Returns the raw artifact content */
+    async getArtifact(workshop: string, name: string, init: RequestInit = this.defaultInit): Promise<Response> {
+        return fetchWithTimeout(this.path(Client.workshopsResource, workshop, 'artifacts', name), init, this.timeout);
+    }
+
+    async publishArtifact(workshop: string, name: string, content: BodyInit, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path(Client.workshopsResource, workshop, 'artifacts', name), {
+            method: 'POST',
+            body: content,
+            ...init
+        }, this.timeout);
+    }
+
     // Pools
 
     async getPool(id: string, init: RequestInit = this.defaultInit): Promise<Pool | null> {
@@ -140,6 +523,45 @@ export class Client {
         return rpc(this.path(Client.poolsResource), init, this.timeout);
     }
 
+    async listUsablePools(userId: string, init: RequestInit = this.defaultInit): Promise<Record<string, UsablePool>> {
+        return rpc(`${this.path(Client.poolsResource)}?usable_by=${encodeURIComponent(userId)}`, init, this.timeout);
+    }
+
+    async prepullPool(id: string, template?: string, init: RequestInit = this.defaultInit): Promise<void> {
+        const query = template ? `?template=${encodeURIComponent(template)}` : '';
+        return rpc(`${this.path('admin', Client.poolsResource, id, 'prepull')}${query}`, {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Indexed by node hostname, then template id */
+    async getPrepullStatus(id: string, init: RequestInit = this.defaultInit): Promise<Record<string, Record<string, PrepullStatus>>> {
+        return rpc(this.path('admin', Client.poolsResource, id, 'prepull'), init, this.timeout);
+    }
+
+    // Reservations
+
+    async listReservations(init: RequestInit = this.defaultInit): Promise<Record<string, Reservation>> {
+        return rpc(this.path('admin', 'reservations'), init, this.timeout);
+    }
+
+    /* Returns the reservation id */
+    async createReservation(reservation: Reservation, init: RequestInit = this.defaultInit): Promise<string> {
+        return rpc(this.path('admin', 'reservations'), {
+            method: 'POST',
+            body: JSON.stringify(reservation),
+            ...init
+        }, this.timeout);
+    }
+
+    async deleteReservation(id: string, init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path('admin', 'reservations', id), {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
     // Login
 
     async login(bearer: string, init: RequestInit = this.defaultInit): Promise<Response> {
@@ -148,12 +570,39 @@ export class Client {
         }, this.timeout);
     }
 
+    async refreshLogin(init: RequestInit = this.defaultInit): Promise<void> {
+        return rpc(this.path('auth', 'refresh'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Revokes all sessions of the current user. Returns the number of revoked sessions */
+    async revokeLogins(init: RequestInit = this.defaultInit): Promise<number> {
+        return rpc(this.path('auth', 'sessions'), {
+            method: 'DELETE',
+            ...init
+        }, this.timeout);
+    }
+
+    /* Opens a websocket carrying updates of the current session, see `ControlChannel` */
+    async openControlChannel(init: RequestInit = this.defaultInit): Promise<ControlChannel> {
+        const ticket: string = await rpc(this.path('ws', 'ticket'), {
+            method: 'POST',
+            ...init
+        }, this.timeout);
+        const url = new URL(`${this.path('ws')}?ticket=${encodeURIComponent(ticket)}`, window.location.href);
+        url.protocol = url.protocol == 'https:' ? 'wss:' : 'ws:';
+        return new ControlChannel(url.toString());
+    }
+
     async logout(init: RequestInit = this.defaultInit): Promise<Response> {
         return fetchWithTimeout(this.path('logout'), init, this.timeout);
     }
 
 }
 
+export * from "./channel";
 export * from "./login";
 export * from "./rpc";
 export * from "./types";
@@ -54,6 +54,13 @@ async function call<T>(input: RequestInfo, init: RequestInit, timeout: number):
             if (response.status == 401) {
                 return Promise.reject(new RpcError(RpcErrorCode.INVALID_REQUEST, 'User unauthorized'));
             }
+            if (response.status == 409) {
+                // A request with the same Idempotency-Key is still running
+                return Promise.reject(new RpcError(RpcErrorCode.SERVER_ERROR, 'Conflicting request still running, retry later'));
+            }
+            if (response.status == 429) {
+                return Promise.reject(new RpcError(RpcErrorCode.SERVER_ERROR, `Too many requests, retry after ${response.headers.get('Retry-After')}s`));
+            }
             return Promise.reject(new RpcError(RpcErrorCode.SERVER_ERROR, response.statusText));
         }
     } catch (e) {
@@ -61,10 +68,20 @@ async function call<T>(input: RequestInfo, init: RequestInit, timeout: number):
     }
 }
 
+// Returns the CSRF token set by the backend on login, if any
+function csrfToken(): string | undefined {
+    if (typeof document === 'undefined') {
+        return undefined;
+    }
+    const cookie = document.cookie.split('; ').find(cookie => cookie.startsWith('csrf='));
+    return cookie?.substring('csrf='.length);
+}
+
 export async function rpc<T>(input: string, init: RequestInit, timeout: number): Promise<T> {
+    const token = csrfToken();
     return await call(input, {
         method: 'GET',
-        headers: {'Accept': 'application/json', 'Content-Type': 'application/json'},
+        headers: {'Accept': 'application/json', 'Content-Type': 'application/json', ...(token ? {'X-CSRF-Token': token} : {})},
         ...init
     }, timeout);
 }
@@ -3,6 +3,60 @@ export interface Playground {
     configuration: Configuration,
     templates: Record<string, Template>,
     user?: LoggedUser,
+    /* Set if the user session was terminated by an admin */
+    tombstone?: Tombstone,
+}
+
+export interface GitState {
+    /* Absent when HEAD is detached */
+    branch?: string,
+    /* Number of local commits not pushed upstream */
+    ahead: number,
+    changes: GitChange[],
+    filesChanged: number,
+    insertions: number,
+    deletions: number,
+}
+
+export interface SessionEvent {
+    type: 'Normal' | 'Warning',
+    /* e.g. `FailedScheduling` */
+    reason: string,
+    /* Only exposed to admins */
+    message?: string,
+    /* Kind and name of the resource, e.g. `Pod/session-jdoe` */
+    object: string,
+    count: number,
+    /* In seconds since epoch */
+    firstSeen?: number,
+    lastSeen?: number,
+}
+
+/* Why the pod of a session was evicted, kept after the pod is gone */
+export interface SessionEviction {
+    reason: 'Preempted' | 'Evicted',
+    /* Only exposed to admins */
+    message?: string,
+    /* In seconds since epoch */
+    time: number,
+}
+
+export interface GitChange {
+    /* Two letters status, as reported by `git status --porcelain` */
+    status: string,
+    path: string,
+}
+
+export interface Tombstone {
+    reason: string,
+    /* Seconds since epoch */
+    terminatedAt: number,
+}
+
+/* Notable events affecting the current session */
+export interface Notifications {
+    eviction?: SessionEviction,
+    tombstone?: Tombstone,
 }
 
 export interface Environment {
@@ -13,7 +67,37 @@ export interface Environment {
 
 export interface Configuration {
     githubClientId: string,
+    /* Domains sessions can be served under */
+    baseDomains: string[],
     session: SessionDefaults,
+    onboardingRequired: boolean,
+    legal: Legal,
+    /* Central telemetry server session nodes can forward to */
+    telemetryUrl?: string,
+    /* If set, sessions failing to start for transient reasons are recreated */
+    retryPolicy?: RetryPolicy,
+    /* If set, users can also log in via this OpenID Connect issuer */
+    oidc?: OidcConfiguration,
+    /* If set, templates can be published from sessions */
+    templateSnapshotRemote?: string,
+}
+
+export interface OidcConfiguration {
+    issuerUrl: string,
+    clientId: string,
+}
+
+export interface RetryPolicy {
+    maxAttempts: number,
+    /* Pool sessions lacking resources are moved to */
+    fallbackPool?: string,
+}
+
+export interface Legal {
+    termsUrl?: string,
+    /* When set, users must have accepted this version of the terms to create sessions */
+    termsVersion?: string,
+    banner?: string,
 }
 
 export interface SessionDefaults {
@@ -21,7 +105,31 @@ export interface SessionDefaults {
     duration: number,
     maxDuration: number,
     poolAffinity: string,
-    maxSessionsPerPod: string,
+    /* Number of sessions, whatever their role, a node can run */
+    maxSessionsPerPod: number,
+    /* Resources requested by session pods, if not the built-in ones */
+    resourceProfile?: ResourceProfile,
+    /* PriorityClass of session pods, e.g. `playground-high` */
+    priorityClass?: string,
+    /* Overrides per role, e.g. `admin`, `paritytech` or `user` */
+    roles: Record<string, RoleDefaults>,
+}
+
+/* Session defaults of a role, used when a session configuration omits values */
+export interface RoleDefaults {
+    /* In minutes */
+    duration?: number,
+    poolAffinity?: string,
+    resourceProfile?: ResourceProfile,
+    /* Overrides `SessionDefaults.maxSessionsPerPod` for sessions of this role */
+    maxSessionsPerPod?: number,
+    priorityClass?: string,
+}
+
+/* Resources requested by a session pod, as Kubernetes quantities */
+export interface ResourceProfile {
+    memory?: string,
+    cpu?: string,
 }
 
 export interface LoggedUser {
@@ -31,13 +139,35 @@ export interface LoggedUser {
     poolAffinity: string,
     canCustomizeDuration: boolean,
     canCustomizePoolAffinity: boolean,
+    onboarding: OnboardingState,
+    acceptedTermsVersion?: string,
+    orgRole?: string,
 }
 
+export type OnboardingState = 'Pending' | 'AcceptedTerms' | 'Completed';
+
 export interface User {
     admin: boolean,
     poolAffinity: string,
     canCustomizeDuration: boolean,
     canCustomizePoolAffinity: boolean,
+    /* Namespaced keys, e.g. `theia.theme` */
+    preferences: Record<string, string>,
+    onboarding: OnboardingState,
+    acceptedTermsVersion?: string,
+    /* Other accounts this user can log in with */
+    identities: Identity[],
+    /* Set while a deleted user can still be restored, in seconds since epoch */
+    deletedAt?: number,
+    /* Set for entries recording the state of users allowed via an organization or a role */
+    implicit?: boolean,
+}
+
+/* An account at an identity provider */
+export interface Identity {
+    provider: 'github' | 'oidc',
+    /* GitHub login or OIDC username */
+    subject: string,
 }
 
 export interface UserConfiguration {
@@ -45,6 +175,22 @@ export interface UserConfiguration {
     poolAffinity?: string,
     canCustomizeDuration: boolean,
     canCustomizePoolAffinity: boolean,
+    /* Namespaced keys, e.g. `theia.theme` */
+    preferences?: Record<string, string>,
+}
+
+/* Settings applied to members of a GitHub organization */
+export interface Org {
+    /* GitHub logins allowed to manage this organization, on top of admins */
+    admins: string[],
+    canCustomizeDuration: boolean,
+    canCustomizePoolAffinity: boolean,
+    poolAffinity?: string,
+    allowedTemplates?: string[],
+    /* Session minutes available to all members, per month */
+    budget?: number,
+    /* Role members get, one of the roles of `SESSION_ROLE_DEFAULTS` */
+    role?: string,
 }
 
 export interface UserUpdateConfiguration {
@@ -54,6 +200,161 @@ export interface UserUpdateConfiguration {
     canCustomizePoolAffinity: boolean,
 }
 
+/* A partial update of preferences. `null` values remove the associated key */
+export type UserPreferencesUpdate = Record<string, string | null>;
+
+/* Values set to `null` remove the associated variable */
+export type SessionEnvUpdate = Record<string, string | null>;
+
+export interface FaucetRequest {
+    /* Account to fund, e.g. an SS58 address */
+    address: string,
+    /* Identifies the chain when the faucet serves several */
+    chain?: string,
+}
+
+/* A file shared between sessions of a workshop, e.g. a chain spec */
+export interface Artifact {
+    name: string,
+    /* In bytes */
+    size: number,
+}
+
+export interface UserImportReport {
+    imported: string[],
+    failed: Record<string, string>,
+}
+
+/* All data held about a user */
+export interface UserExport {
+    id: string,
+    user?: User,
+    session?: Session,
+    sessionsHistory: SessionHistoryEntry[],
+    auditEvents: AuditEvent[],
+}
+
+export interface SessionHistoryEntry {
+    sessionId: string,
+    /* Seconds since epoch */
+    startedAt: number,
+    /* Seconds since epoch */
+    endedAt?: number,
+}
+
+/* Pool capacity set aside for a workshop during a time window */
+export interface Reservation {
+    pool: string,
+    /* Only sessions joining this workshop can use the reserved slots */
+    workshop: string,
+    slots: number,
+    /* Seconds since epoch */
+    start: number,
+    /* Seconds since epoch */
+    end: number,
+}
+
+export interface AuditEvent {
+    actor: string,
+    action: string,
+    target: string,
+    details?: string,
+    /* Seconds since epoch */
+    time: number,
+}
+
+export type WebhookDeliveryStatus = 'Pending' | 'Delivered' | 'DeadLetter';
+
+export interface WebhookDelivery {
+    id: string,
+    event: AuditEvent,
+    status: WebhookDeliveryStatus,
+    attempts: number,
+    lastError?: string,
+    /* Seconds since epoch */
+    lastAttempt?: number,
+    /* Seconds since epoch */
+    nextAttempt?: number,
+}
+
+export interface InvalidEntry {
+    configMap: string,
+    key: string,
+    error: string,
+    /* Path of the offending field, e.g. `runtime.ports[0].port` */
+    field?: string,
+    line?: number,
+    column?: number,
+}
+
+export interface Check {
+    name: string,
+    status: 'pass' | 'warn' | 'fail',
+    message?: string,
+}
+
+export interface Diagnostics {
+    /* Seconds since epoch */
+    checkedAt: number,
+    checks: Check[],
+    invalidEntries: InvalidEntry[],
+}
+
+export interface UserUsage {
+    role: string,
+    sessions: number,
+    /* The number of minutes of sessions */
+    minutes: number,
+}
+
+export interface StorageVersion {
+    version: number,
+    /* Version supported by the backend */
+    latest: number,
+}
+
+export interface StateArchive {
+    version: number,
+    /* Seconds since epoch */
+    createdAt: number,
+    configMaps: Record<string, ArchivedConfigMap>,
+    /* Set when handed off by another cluster */
+    sessions?: SessionHandoff[],
+}
+
+export interface SessionHandoff {
+    id: string,
+    template: Template,
+    /* Minutes left before the session expires */
+    remaining: number,
+    domain: string,
+    workshop?: string,
+}
+
+export interface RestoreReport {
+    sessions: string[],
+    failed: Record<string, string>,
+}
+
+export interface HandoffReport {
+    archive: StateArchive,
+    /* Sessions left on the previous cluster, with the reason */
+    skipped: Record<string, string>,
+}
+
+export interface ArchivedConfigMap {
+    storageVersion?: number,
+    data: Record<string, string>,
+}
+
+export interface TemplateAnalytics {
+    /* Creation attempts, including failed ones */
+    sessions: number,
+    failures: number,
+    /* Between 0 and 1 */
+    failureRate: number,
+}
+
 export interface Session {
     userId: string,
     url: string,
@@ -63,14 +364,51 @@ export interface Session {
     duration: number,
     maxDuration: number,
     node: string,
+    domain: string,
+    /* Set by policies when suspicious activity is detected, e.g. `sustained-cpu` or `suspended` */
+    flags: string[],
+    /* Progress of an ongoing migration to another pool: `scheduling`, `copying`, `switching` or `failed: <reason>` */
+    migration?: string,
+    backup?: SessionBackup,
+    workshop?: string,
+    /* Number of times the session was recreated after a transient failure */
+    retries: number,
+    /* Time of the last heartbeat, in seconds since epoch */
+    lastActivity?: number,
+    /* Set for sessions that don't time out for lack of activity, e.g. pull request previews */
+    unattended: boolean,
+    /* Set while a deleted session can still be restored, in seconds since epoch */
+    deletedAt?: number,
+    /* Propagation of the session DNS record, only set when records are managed by the playground */
+    dns?: DnsStatus,
+}
+
+export type DnsStatus = 'Pending' | 'Propagated';
+
+export interface Heartbeat {
+    /* Must be sent with the next heartbeat */
+    nonce: string,
+    /* Seconds to wait before the next heartbeat */
+    interval: number,
 }
 
 export interface Pool {
     name: string,
     instanceType?: string,
     nodes: Node[],
+    capacity?: number,
+}
+
+export interface UsablePool extends Pool {
+    freeSlots: number,
+    prepulledTemplates: string[],
+    latency: StartLatency,
 }
 
+export type StartLatency = 'Fast' | 'Slow' | 'Unavailable';
+
+export type PrepullStatus = 'Pending' | 'Pulled' | 'Failed';
+
 export interface Node {
     hostname: string,
 }
@@ -80,6 +418,38 @@ export interface SessionConfiguration {
     /* The number of minutes this session will be able to last */
     duration?: number,
     poolAffinity?: string,
+    /* One of `Configuration#baseDomains`, defaults to the first one */
+    domain?: string,
+    /* If set, the workspace is periodically pushed to a git remote */
+    backup?: SessionBackup,
+    /* Sessions joining the same workshop can reach each other as `workshop-<workshop>-<session>` */
+    workshop?: string,
+}
+
+export interface SessionBackup {
+    /* HTTPS url of the git remote */
+    remote: string,
+    branch: string,
+    /* Minutes between two backups, defaults to 30 */
+    interval?: number,
+    /* Used to authenticate against `remote`. Write only. */
+    token?: string,
+}
+
+/* What a session creation would result in */
+export interface SessionPlan {
+    id: string,
+    template: Template,
+    pool: string,
+    domain: string,
+    /* DNS label of `url` under `domain` */
+    subdomain: string,
+    url: string,
+    podName: string,
+    /* The number of minutes this session will be able to last */
+    duration: number,
+    resourceProfile?: ResourceProfile,
+    priorityClass?: string,
 }
 
 export interface SessionUpdateConfiguration {
@@ -87,6 +457,34 @@ export interface SessionUpdateConfiguration {
     duration?: number,
 }
 
+/* Sessions matching all set criteria */
+export interface SessionFilter {
+    template?: string,
+    pool?: string,
+    /* Minimum age, in minutes */
+    olderThan?: number,
+    users?: string[],
+}
+
+export type SessionBatchAction =
+    { type: 'delete' }
+    | {
+        type: 'extend',
+        /* Added to the duration of sessions */
+        minutes: number,
+    }
+    | { type: 'migrate', pool: string };
+
+export interface SessionBatch {
+    action: SessionBatchAction,
+    filter: SessionFilter,
+}
+
+export interface SessionBatchResult {
+    sessionId: string,
+    error?: string,
+}
+
 export interface NameValuePair {
     name: string,
     value: string,
@@ -111,9 +509,89 @@ export interface Template {
     description: string,
     tags?: Record<string, string>,
     runtime?: RuntimeConfiguration,
+    /* If set, sessions based on this template can only be scheduled on those pools */
+    allowed_pools?: string[],
+    /* Set while a new image is being rolled out */
+    canary?: Canary,
+    theia?: TheiaConfiguration,
+    /* Web IDE served by `image`, defaults to Theia */
+    ide?: Ide,
+    telemetry?: TelemetryConfiguration,
+    viewer?: ViewerConfiguration,
+    /* Workspace content restored once sessions run, set when published from a session */
+    snapshot?: WorkspaceSnapshot,
+}
+
+export interface WorkspaceSnapshot {
+    remote: string,
+    commit: string,
+}
+
+export interface TemplatePublication {
+    id: string,
+    name?: string,
+    description?: string,
+}
+
+export type Ide =
+    { type: 'theia' }
+    | { type: 'code-server' }
+    | { type: 'jupyter' }
+    | {
+        type: 'custom',
+        port: number,
+        /* Path the IDE is served under, defaults to `/` */
+        path?: string,
+        /* Path answering `200` once the IDE is ready */
+        healthCheck?: string,
+    };
+
+/* A read-only view of a session that owners can publish under a tokenized url, on a `viewer--<session>` host of its own */
+export interface ViewerConfiguration {
+    /* Port serving the view, received requests are prefixed with `/view/<token>` */
+    port: number,
+}
+
+/* Telemetry of the in-session node, exposed as `wss://<session>.<host>/telemetry` */
+export interface TelemetryConfiguration {
+    port: number,
+}
+
+export interface TheiaConfiguration {
+    /* Plugins installed on startup, e.g. `vscode:extension/matklad.rust-analyzer` */
+    plugins?: string[],
+    /* Initial user settings. Left untouched if the user already has some. */
+    settings?: Record<string, unknown>,
 }
 
-export type Phase = 'Pending' | 'Running' | 'Succeeded' | 'Failed' | 'Unknown';
+export interface Canary {
+    image: string,
+    /* Share of new sessions using this image, between 0 and 100 */
+    percentage: number,
+}
+
+export interface TemplateQuery {
+    q?: string,
+    /* Either a tag key or a `key=value` pair */
+    tag?: string,
+    sort?: 'id' | 'name',
+    page?: number,
+    /* Between 1 and 100 */
+    perPage?: number,
+    /* Admins only, fails if some templates are invalid instead of ignoring them */
+    strict?: boolean,
+}
+
+export type Entry<T> = T & { id: string };
+
+export interface Page<T> {
+    items: T[],
+    total: number,
+    page: number,
+    perPage: number,
+}
+
+export type Phase = 'Pending' | 'Running' | 'Succeeded' | 'Failed' | 'Unknown' | 'Suspended';
 export interface Pod {
     phase: Phase,
     reason: string,
@@ -121,6 +599,27 @@ export interface Pod {
     /* The number of seconds since this session started */
     startTime?: number,
     container?: ContainerStatus,
+    /* Deployment progress, in order */
+    steps: DeploymentStep[],
+    /* Set when the session can't start */
+    failure?: SessionFailure,
+}
+
+export type SessionFailureReason = 'ImagePull' | 'InsufficientResources' | 'VolumePending' | 'QuotaExceeded' | 'Crash' | 'Evicted' | 'Unknown';
+
+export interface SessionFailure {
+    reason: SessionFailureReason,
+    /* What users can do about it */
+    message: string,
+    /* Raw kubernetes reason and message, only exposed to admins */
+    detail?: string,
+}
+
+export interface DeploymentStep {
+    name: 'created' | 'scheduled' | 'image-pulled' | 'started' | 'ready',
+    completed: boolean,
+    /* Seconds since epoch, not always known */
+    completedAt?: number,
 }
 
 export type ContainerPhase = 'Running' | 'Terminated' | 'Waiting' | 'Unknown';
@@ -3,11 +3,11 @@ export enum EnvironmentType {dev, staging, production}
 export function playgroundBaseURL(env: EnvironmentType) {
     switch (env) {
         case EnvironmentType.dev:
-            return "http://playground-dev.substrate.test/api";
+            return "http://playground-dev.substrate.test/api/v1";
         case EnvironmentType.staging:
-            return "https://playground-staging.substrate.dev/api";
+            return "https://playground-staging.substrate.dev/api/v1";
         case EnvironmentType.production:
-            return "https://playground.substrate.dev/api";
+            return "https://playground.substrate.dev/api/v1";
         default:
             throw new Error(`Unrecognized env ${env}`);
     }
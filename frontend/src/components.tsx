@@ -240,7 +240,7 @@ export function Wrapper({ conf, params, thin = false, onPlayground, onStatsClick
             {!thin &&
             <Container style={{display: "flex", justifyContent: "space-between", alignItems: "center"}} component="footer" maxWidth={false}>
                 <Typography color="textSecondary">
-                    {params.base != "/api" &&
+                    {params.base != "/api/v1" &&
                     <>Connected to {params.base}</>}
                 </Typography>
                 <Link
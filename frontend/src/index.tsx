@@ -130,7 +130,7 @@ function App({ params }: { params: Params }): JSX.Element {
                         ? <CenteredContainer>
                             <ErrorMessage reason={error} action={restartAction} />
                         </CenteredContainer>
-                        : <LoginPanel client={client} />
+                        : <LoginPanel client={client} conf={conf} />
                         : <LoadingPanel />}
                 </Wrapper>}
             </div>
@@ -149,7 +149,7 @@ function extractParams(): Params {
     const deploy = params.get('deploy');
     return {deploy: deploy,
             version: process.env.GITHUB_SHA,
-            base: process.env.BASE || "/api"};
+            base: process.env.BASE || "/api/v1"};
 }
 
 function removeTransientsURLParams() {
@@ -37,7 +37,7 @@ import LastPageIcon from '@material-ui/icons/LastPage';
 import TableFooter from '@material-ui/core/TableFooter';
 import TablePagination from '@material-ui/core/TablePagination';
 import { Autocomplete } from '@material-ui/lab';
-import { Client, Configuration, LoggedUser, Pool, Session, SessionConfiguration, SessionUpdateConfiguration, Template, User, UserConfiguration, UserUpdateConfiguration } from '@substrate/playground-client';
+import { Client, Configuration, LoggedUser, Pool, Session, SessionConfiguration, SessionUpdateConfiguration, Template, UsablePool, User, UserConfiguration, UserUpdateConfiguration } from '@substrate/playground-client';
 import { CenteredContainer, ErrorSnackbar, LoadingPanel } from '../components';
 import { useInterval } from '../hooks';
 import { canCustomizeDuration, canCustomizePoolAffinity, hasAdminEditRights } from '../utils';
@@ -97,11 +97,12 @@ export function SessionCreationDialog({ client, conf, sessions, user, template,
     const [selectedTemplate, setTemplate] = React.useState<string | null>(null);
     const [duration, setDuration] = React.useState(conf.session.duration);
     const [poolAffinity, setPoolAffinity] = React.useState(conf.session.poolAffinity);
-    const [pools, setPools] = useState<Record<string, Pool> | null>(null);
+    const [pools, setPools] = useState<Record<string, Pool | UsablePool> | null>(null);
     const [users, setUsers] = useState<Record<string, User> | null>(null);
 
     useInterval(async () => {
-        setPools(await client.listPools());
+        // Sessions created for others can target any pool
+        setPools(allowUserSelection ? await client.listPools() : await client.listUsablePools(user.id));
         if (allowUserSelection) {
             setUsers(await client.listUsers());
         }
@@ -180,9 +181,9 @@ export function SessionCreationDialog({ client, conf, sessions, user, template,
                         label="Pool affinity"
                         >
                     {pools &&
-                    Object.keys(pools).map(id => (
-                        <MenuItem key={id} value={id}>
-                        {id}
+                    Object.entries(pools).map(([id, pool]) => (
+                        <MenuItem key={id} value={id} disabled={'latency' in pool && pool.latency == 'Unavailable'}>
+                        {'latency' in pool ? `${id} (${pool.latency.toLowerCase()})` : id}
                         </MenuItem>))
                     }
                     </TextField>
@@ -1,6 +1,6 @@
 import React, { useEffect, useRef, useState } from "react";
 import Paper from '@material-ui/core/Paper';
-import { Client, Template } from '@substrate/playground-client';
+import { Client, ControlChannel, Template } from '@substrate/playground-client';
 import { CenteredContainer, ErrorMessage, Loading } from '../components';
 import { fetchWithTimeout } from '../utils';
 
@@ -21,6 +21,7 @@ export function TheiaPanel({ client, autoDeploy, templates, onMissingSession, on
     const [error, setError] = useState<Error>();
     const [url, setUrl] = useState<string>();
     const [loading, setLoading] = useState<Loading>();
+    const [sessionId, setSessionId] = useState<string>();
 
     useEffect(() => {
         function createSession(template: string) {
@@ -32,10 +33,11 @@ export function TheiaPanel({ client, autoDeploy, templates, onMissingSession, on
             if (session) {
                 const phase = session.pod.phase;
                 if (phase == 'Running') {
-                    // Check URL is fine
-                    const url = `//${session.url}`;
+                    // Check URL is fine. It embeds a short-lived token when access to sessions is restricted
+                    const url = await client.getSessionAccessUrl(session.userId);
                     if ((await fetchWithTimeout(url)).ok) {
                         setUrl(url);
+                        setSessionId(session.userId);
                         return;
                     }
                 } else if (phase == 'Pending') {
@@ -103,6 +105,72 @@ export function TheiaPanel({ client, autoDeploy, templates, onMissingSession, on
         }
     }, []);
 
+    useEffect(() => {
+        if (!sessionId) {
+            return;
+        }
+        // Session updates and heartbeats go through a single control channel
+        const key = `heartbeat-nonce-${sessionId}`;
+        let channel: ControlChannel | undefined;
+        let timeout: number;
+        let closed = false;
+        function stop(reason: string) {
+            clearTimeout(timeout);
+            channel?.close();
+            setUrl(undefined);
+            setError({reason: reason, action: onMissingSession});
+        }
+        // Keeps the session from being considered idle. Each heartbeat returns the nonce expected by the next one.
+        async function heartbeat(channel: ControlChannel) {
+            let interval = 60;
+            try {
+                const next = await channel.heartbeat(localStorage.getItem(key) || undefined);
+                localStorage.setItem(key, next.nonce);
+                interval = next.interval;
+            } catch (e) {
+                console.error(e);
+                // The nonce was lost or rejected, fetch the one expected next
+                try {
+                    const resynced = await client.resyncSessionHeartbeat(sessionId);
+                    localStorage.setItem(key, resynced.nonce);
+                } catch (e) {
+                    localStorage.removeItem(key);
+                    console.error(e);
+                }
+            }
+            if (!closed) {
+                timeout = window.setTimeout(() => heartbeat(channel), interval * 1000);
+            }
+        }
+        client.openControlChannel().then(opened => {
+            if (closed) {
+                opened.close();
+                return;
+            }
+            channel = opened;
+            channel.onClose(() => closed = true);
+            channel.subscribe('notifications', notifications => {
+                // Sessions can be stopped to make room for higher priority ones
+                if (notifications?.eviction) {
+                    stop("Your session was stopped to free up resources, e.g. for higher priority sessions");
+                } else if (notifications?.tombstone) {
+                    stop(`Your session was terminated: ${notifications.tombstone.reason}`);
+                }
+            });
+            channel.subscribe('session', session => {
+                if (session === null) {
+                    stop("Your session has ended");
+                }
+            });
+            heartbeat(channel);
+        }).catch(console.error);
+        return () => {
+            closed = true;
+            clearTimeout(timeout);
+            channel?.close();
+        };
+    }, [sessionId]);
+
     if (url) {
         return <iframe ref={ref} src={url} frameBorder="0" width="100%" height="100%"></iframe>
     } else {